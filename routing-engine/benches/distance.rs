@@ -0,0 +1,33 @@
+//! Compares the cost of `haversine_distance` with `planar_distance` on street scale spans.
+//! Run with `cargo bench --bench distance`.
+
+use std::{hint::black_box, time::Instant};
+
+use routing_engine::engine::utils::{haversine_distance, planar_distance};
+
+const ITERATIONS: usize = 10_000_000;
+
+fn time(name: &str, distance: fn(f64, f64, f64, f64) -> f32) -> f64 {
+    let start = Instant::now();
+    let mut total = 0.0;
+    for i in 0..ITERATIONS {
+        let offset = (i % 1000) as f64 * 1e-5;
+        total += distance(
+            black_box(32.08),
+            black_box(34.78),
+            black_box(32.08 + offset),
+            black_box(34.78 - offset),
+        );
+    }
+    black_box(total);
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("{name}: {:.2} ns/call", elapsed * 1e9 / ITERATIONS as f64);
+    elapsed
+}
+
+fn main() {
+    let haversine = time("haversine_distance", haversine_distance);
+    let planar = time("planar_distance", planar_distance);
+    println!("speedup: {:.2}x", haversine / planar);
+}
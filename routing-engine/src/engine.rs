@@ -3,5 +3,7 @@ pub mod export;
 pub mod ordering;
 pub mod preprocess;
 pub mod query;
+#[cfg(test)]
+pub mod test_utils;
 pub mod utils;
 pub mod visitor;
@@ -1,7 +1,27 @@
-use rayon::slice::ParallelSliceMut;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::OnceLock,
+};
+
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::engine::preprocess::graph::Graph;
+use crate::engine::preprocess::graph::{Graph, Metric, RoadClass, WayNames};
+
+/// Magic bytes at the start of a serialized graph file.
+pub const GRAPH_FILE_MAGIC: [u8; 4] = *b"SHPG";
+
+/// Version of the serialized graph layout, bump it whenever `CSRGraph` changes.
+pub const GRAPH_FILE_VERSION: u32 = 9;
+
+/// Set in `CSRNode::flags` on turning circles.
+pub const NODE_FLAG_TURNING_CIRCLE: u8 = 1 << 0;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CSRNode {
@@ -9,23 +29,73 @@ pub struct CSRNode {
     pub osm_id: i64,
     pub rank: i32,
     pub flags: u8,
+    pub lat: f64,
+    pub lon: f64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct CSREdgeHot {
     pub id: usize,
     pub target: usize,
     pub weight: f32,
+    // The weight under `Metric::Secondary`.
+    pub alt_weight: f32,
+    // The travel time in seconds, whatever metric the weights are in.
+    pub time_s: f32,
+    // The number of original edges the edge unpacks to.
+    pub hops: u32,
+}
+
+/// How `CSRGraph` stores the weights of the hot edges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WeightStorage {
+    // Full `f32` weights.
+    #[default]
+    Full,
+    // 16-bit fixed point weights with a scale shared by the whole graph, each weight is off by
+    // at most half the scale. Weights too large for the range, including the saturated
+    // `f32::MAX`, decode as `f32::MAX`.
+    Quantized,
+}
+
+/// A hot edge with its weights in 16-bit fixed point, decoded by multiplying with the scale of
+/// its `QuantizedValues`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CSREdgeQuantized {
+    pub id: usize,
+    pub target: usize,
+    pub weight: u16,
+    pub alt_weight: u16,
+    pub time_s: u16,
+    pub hops: u32,
+}
+
+/// The hot edges of a graph built with `WeightStorage::Quantized`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuantizedValues {
+    // The weight of one quantization step.
+    pub scale: f32,
+    pub values: Vec<CSREdgeQuantized>,
 }
 
+// Marks a weight that doesn't fit the quantized range.
+const QUANTIZED_MAX: u16 = u16::MAX;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CSREdgeCold {
     id: usize,
     pub name: Option<String>,
+    // Both the local and English names, if the import kept them.
+    pub names: Option<WayNames>,
+    pub road_class: RoadClass,
+    // Length of the road the edge covers in meters, including the edges a shortcut unpacks to.
+    pub distance_m: f32,
     pub from_node: usize,
     pub to_node: usize,
     pub prev_edge: Option<usize>,
     pub next_edge: Option<usize>,
+    // Coordinates of the shape points between the endpoints of an original edge.
+    pub geometry: Vec<(f64, f64)>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -34,125 +104,478 @@ pub struct CSRGraph {
     pub row_fwd_ptr: Vec<usize>,
     pub cols_bwd: Vec<usize>,
     pub row_bwd_ptr: Vec<usize>,
+    // The hot edges, empty if the graph stores them in `quantized` instead.
     pub values_hot: Vec<CSREdgeHot>,
+    pub quantized: Option<QuantizedValues>,
     pub values_cold: Vec<CSREdgeCold>,
     pub nodes: Vec<CSRNode>,
+    // Maps the osm id of every node to its dense id.
+    pub osm_to_dense: HashMap<i64, usize>,
+    // Maps each street name to the ids of its edges, built on first use.
+    #[serde(skip)]
+    name_index: OnceLock<HashMap<String, Vec<usize>>>,
+    // The bounding box of the nodes, computed on first use.
+    #[serde(skip)]
+    bounds: OnceLock<Option<(f64, f64, f64, f64)>>,
 }
 
 impl CSRNode {
-    pub fn new(id: usize, osm_id: i64, rank: i32, flags: u8) -> Self {
+    pub fn new(id: usize, osm_id: i64, rank: i32, flags: u8, lat: f64, lon: f64) -> Self {
         Self {
             id,
             osm_id,
             rank,
             flags,
+            lat,
+            lon,
         }
     }
+
+    pub fn is_turning_circle(&self) -> bool {
+        self.flags & NODE_FLAG_TURNING_CIRCLE != 0
+    }
 }
 
 impl CSREdgeHot {
-    pub fn new(id: usize, target: usize, weight: f32) -> Self {
-        Self { id, target, weight }
+    pub fn new(
+        id: usize,
+        target: usize,
+        weight: f32,
+        alt_weight: f32,
+        time_s: f32,
+        hops: u32,
+    ) -> Self {
+        Self {
+            id,
+            target,
+            weight,
+            alt_weight,
+            time_s,
+            hops,
+        }
+    }
+
+    pub fn metric_weight(&self, metric: Metric) -> f32 {
+        match metric {
+            Metric::Primary => self.weight,
+            Metric::Secondary => self.alt_weight,
+        }
+    }
+}
+
+impl QuantizedValues {
+    // Quantizes the weights and times of `values` with a scale that fits the largest finite one.
+    fn new(values: &[CSREdgeHot]) -> Self {
+        let max_weight = values
+            .iter()
+            .flat_map(|edge| [edge.weight, edge.alt_weight, edge.time_s])
+            .filter(|weight| weight.is_finite() && *weight < f32::MAX)
+            .fold(0.0, f32::max);
+        let scale = if max_weight > 0.0 {
+            max_weight / f32::from(QUANTIZED_MAX - 1)
+        } else {
+            1.0
+        };
+
+        let values = values
+            .iter()
+            .map(|edge| CSREdgeQuantized {
+                id: edge.id,
+                target: edge.target,
+                weight: Self::encode(edge.weight, scale),
+                alt_weight: Self::encode(edge.alt_weight, scale),
+                time_s: Self::encode(edge.time_s, scale),
+                hops: edge.hops,
+            })
+            .collect();
+        Self { scale, values }
+    }
+
+    fn encode(weight: f32, scale: f32) -> u16 {
+        if weight.is_nan() || weight >= f32::MAX {
+            return QUANTIZED_MAX;
+        }
+        (weight.max(0.0) / scale)
+            .round()
+            .min(f32::from(QUANTIZED_MAX)) as u16
+    }
+
+    // Gets whether `weight` can be encoded without saturating to `f32::MAX`.
+    fn fits(&self, weight: f32) -> bool {
+        weight.is_nan() || weight >= f32::MAX || Self::encode(weight, self.scale) < QUANTIZED_MAX
+    }
+
+    // Widens the scale by an odd factor so that `weight` fits. With an odd factor, rounding a code
+    // to the nearest new step moves it by at most (factor - 1) / 2 old steps, so every weight stays
+    // within half a new step of the weight it was encoded from.
+    fn rescale_to_fit(&mut self, weight: f32) {
+        let mut factor = (weight / (self.scale * f32::from(QUANTIZED_MAX - 1))).ceil() as u32;
+        if factor.is_multiple_of(2) {
+            factor += 1;
+        }
+
+        let rescale = |weight: &mut u16| {
+            if *weight != QUANTIZED_MAX {
+                *weight = ((u32::from(*weight) + factor / 2) / factor) as u16;
+            }
+        };
+        for edge in &mut self.values {
+            rescale(&mut edge.weight);
+            rescale(&mut edge.alt_weight);
+            rescale(&mut edge.time_s);
+        }
+        self.scale *= factor as f32;
+    }
+
+    fn decode(&self, weight: u16) -> f32 {
+        if weight == QUANTIZED_MAX {
+            f32::MAX
+        } else {
+            f32::from(weight) * self.scale
+        }
+    }
+
+    fn get(&self, idx: usize) -> CSREdgeHot {
+        let edge = &self.values[idx];
+        CSREdgeHot::new(
+            edge.id,
+            edge.target,
+            self.decode(edge.weight),
+            self.decode(edge.alt_weight),
+            self.decode(edge.time_s),
+            edge.hops,
+        )
     }
 }
 
 impl CSREdgeCold {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: usize,
         name: Option<String>,
+        names: Option<WayNames>,
+        road_class: RoadClass,
+        distance_m: f32,
         from_node: usize,
         to_node: usize,
         prev_edge: Option<usize>,
         next_edge: Option<usize>,
+        geometry: Vec<(f64, f64)>,
     ) -> Self {
         Self {
             id,
             name,
+            names,
+            road_class,
+            distance_m,
             from_node,
             to_node,
             prev_edge,
             next_edge,
+            geometry,
         }
     }
 }
 
 impl CSRGraph {
     pub fn from_preprocessed_graph(graph: Graph) -> Self {
-        let mut values_hot: Vec<CSREdgeHot> = Vec::with_capacity(graph.num_edges());
-        let mut values_cold: Vec<CSREdgeCold> = Vec::with_capacity(graph.num_edges());
-        let mut fwd_cols = Vec::with_capacity(graph.get_num_fwd());
-        let mut fwd_row_ptr = Vec::with_capacity(graph.get_num_fwd());
-
-        fwd_row_ptr.push(0);
-        for edges in &graph.fwd_edge_list {
-            for id in edges {
-                let edge = graph.get_edge(*id);
-                let metadata = graph.get_edge_metadata(edge);
-                let new_index = values_hot.len();
-
-                values_hot.push(CSREdgeHot::new(*id, edge.dest_id, metadata.weight));
+        Self::from_preprocessed_graph_with_storage(graph, WeightStorage::Full)
+    }
 
-                values_cold.push(CSREdgeCold::new(
-                    *id,
-                    metadata.name.clone(),
-                    edge.src_id,
-                    edge.dest_id,
-                    metadata.prev_edge,
-                    metadata.next_edge,
-                ));
+    /// Builds the graph like `from_preprocessed_graph`, storing the weights as `storage`.
+    /// `WeightStorage::Quantized` shrinks the hot edges and the saved file, at the cost of a
+    /// small error in each weight, see `weight_tolerance`.
+    pub fn from_preprocessed_graph_with_storage(graph: Graph, storage: WeightStorage) -> Self {
+        let (fwd_cols, fwd_row_ptr, fwd_hot, fwd_cold) =
+            Self::build_rows(&graph, &graph.fwd_edge_list, 0, true);
+        let (bwd_cols, bwd_row_ptr, bwd_hot, bwd_cold) =
+            Self::build_rows(&graph, &graph.bwd_edge_list, fwd_cols.len(), false);
 
-                fwd_cols.push(new_index);
+        let mut values_hot = fwd_hot;
+        values_hot.extend(bwd_hot);
+        let quantized = match storage {
+            WeightStorage::Full => None,
+            WeightStorage::Quantized => {
+                Some(QuantizedValues::new(&std::mem::take(&mut values_hot)))
             }
+        };
+        let mut values_cold = fwd_cold;
+        values_cold.extend(bwd_cold);
 
-            fwd_row_ptr.push(fwd_cols.len());
+        values_cold.par_sort_by(|e1, e2| e1.id.cmp(&e2.id));
+
+        let nodes: Vec<CSRNode> = graph
+            .nodes
+            .iter()
+            .map(|node| {
+                let flags = if node.is_turning_circle {
+                    NODE_FLAG_TURNING_CIRCLE
+                } else {
+                    0
+                };
+                CSRNode::new(
+                    node.dense_id,
+                    node.osm_id,
+                    node.rank,
+                    flags,
+                    node.lat,
+                    node.lon,
+                )
+            })
+            .collect();
+        let osm_to_dense = nodes.iter().map(|node| (node.osm_id, node.id)).collect();
+
+        Self {
+            cols_bwd: bwd_cols,
+            cols_fwd: fwd_cols,
+            row_bwd_ptr: bwd_row_ptr,
+            row_fwd_ptr: fwd_row_ptr,
+            values_hot,
+            quantized,
+            values_cold,
+            nodes,
+            osm_to_dense,
+            name_index: OnceLock::new(),
+            bounds: OnceLock::new(),
         }
+    }
 
-        let mut bwd_cols = Vec::with_capacity(graph.get_num_bwd());
-        let mut bwd_row_ptr = Vec::with_capacity(graph.get_num_bwd());
+    // Lays out the edges of one direction, the edges of node v get the columns
+    // `row_ptr[v]..row_ptr[v + 1]`, shifted by `offset` into the values. The row pointers are a
+    // prefix sum of the degrees, so the values are written in parallel into pre-sized vectors.
+    fn build_rows(
+        graph: &Graph,
+        edge_lists: &[Vec<usize>],
+        offset: usize,
+        is_fwd: bool,
+    ) -> (Vec<usize>, Vec<usize>, Vec<CSREdgeHot>, Vec<CSREdgeCold>) {
+        let mut row_ptr = Vec::with_capacity(edge_lists.len() + 1);
+        row_ptr.push(0);
+        for edges in edge_lists {
+            row_ptr.push(row_ptr.last().unwrap() + edges.len());
+        }
+        let num_edges = *row_ptr.last().unwrap();
+        let edge_ids = edge_lists.concat();
 
-        bwd_row_ptr.push(0);
-        for edges in &graph.bwd_edge_list {
-            for id in edges {
-                let edge = graph.get_edge(*id);
+        let mut values_hot = Vec::with_capacity(num_edges);
+        edge_ids
+            .par_iter()
+            .map(|&id| {
+                let edge = graph.get_edge(id);
                 let metadata = graph.get_edge_metadata(edge);
-                let new_index = values_hot.len();
-
-                values_hot.push(CSREdgeHot::new(*id, edge.src_id, metadata.weight));
+                let target = if is_fwd { edge.dest_id } else { edge.src_id };
+                CSREdgeHot::new(
+                    id,
+                    target,
+                    metadata.weight,
+                    metadata.alt_weight,
+                    metadata.time_s,
+                    metadata.hops,
+                )
+            })
+            .collect_into_vec(&mut values_hot);
 
-                values_cold.push(CSREdgeCold::new(
-                    *id,
+        let mut values_cold = Vec::with_capacity(num_edges);
+        edge_ids
+            .par_iter()
+            .map(|&id| {
+                let edge = graph.get_edge(id);
+                let metadata = graph.get_edge_metadata(edge);
+                CSREdgeCold::new(
+                    id,
                     metadata.name.clone(),
+                    metadata.names.clone(),
+                    metadata.road_class,
+                    metadata.distance_m,
                     edge.src_id,
                     edge.dest_id,
                     metadata.prev_edge,
                     metadata.next_edge,
-                ));
+                    metadata.geometry.clone(),
+                )
+            })
+            .collect_into_vec(&mut values_cold);
 
-                bwd_cols.push(new_index);
-            }
+        let cols = (offset..offset + num_edges).collect();
+        (cols, row_ptr, values_hot, values_cold)
+    }
 
-            bwd_row_ptr.push(bwd_cols.len());
+    /// Sets the weight of an edge. Call `recustomize` afterwards to repair the weights of the
+    /// shortcuts built on top of it. Quantized weights are rescaled if the new weight is past
+    /// their range, which widens the weight tolerance.
+    pub fn update_edge_weight(&mut self, edge_id: usize, new_weight: f32) {
+        if let Some(quantized) = &mut self.quantized {
+            if !quantized.fits(new_weight) {
+                quantized.rescale_to_fit(new_weight);
+            }
         }
 
-        values_cold.par_sort_by(|e1, e2| e1.id.cmp(&e2.id));
+        let cold = self.get_fwd_edge_cold(edge_id);
+        let (from_node, to_node) = (cold.from_node, cold.to_node);
 
-        let nodes = graph
-            .nodes
+        let fwd_cols = &self.cols_fwd[self.row_fwd_ptr[from_node]..self.row_fwd_ptr[from_node + 1]];
+        let bwd_cols = &self.cols_bwd[self.row_bwd_ptr[to_node]..self.row_bwd_ptr[to_node + 1]];
+        for &idx in fwd_cols.iter().chain(bwd_cols) {
+            match &mut self.quantized {
+                Some(quantized) if quantized.values[idx].id == edge_id => {
+                    quantized.values[idx].weight =
+                        QuantizedValues::encode(new_weight, quantized.scale);
+                }
+                None if self.values_hot[idx].id == edge_id => {
+                    self.values_hot[idx].weight = new_weight;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Recomputes the weight of every shortcut from the two edges it unpacks to. Shortcuts are
+    /// repaired by increasing rank of the node they bypass, so the shortcuts a shortcut is built
+    /// on are always repaired first. Witness searches aren't rerun, so shortcuts that were
+    /// skipped during contraction stay missing even if the new weights would need them.
+    pub fn recustomize(&mut self) {
+        let mut shortcuts: Vec<(i32, usize, usize, usize)> = self
+            .values_cold
             .iter()
-            .map(|node| CSRNode::new(node.dense_id, node.osm_id, node.rank, 0))
+            .step_by(2)
+            .filter_map(|edge| match (edge.prev_edge, edge.next_edge) {
+                (Some(prev_edge), Some(next_edge)) => {
+                    let middle_node = self.get_fwd_edge_cold(prev_edge).to_node;
+                    let rank = self.nodes[middle_node].rank;
+                    Some((rank, edge.id, prev_edge, next_edge))
+                }
+                _ => None,
+            })
             .collect();
+        shortcuts.sort();
 
-        Self {
-            cols_bwd: bwd_cols,
-            cols_fwd: fwd_cols,
-            row_bwd_ptr: bwd_row_ptr,
-            row_fwd_ptr: fwd_row_ptr,
-            values_hot,
-            values_cold,
-            nodes,
+        for (_, edge_id, prev_edge, next_edge) in shortcuts {
+            let weight = self.edge_weight(prev_edge) + self.edge_weight(next_edge);
+            self.update_edge_weight(edge_id, weight);
         }
     }
 
+    /// Gets the current weight of an edge, including updates made by `update_edge_weight`.
+    pub fn edge_weight(&self, edge_id: usize) -> f32 {
+        let from_node = self.get_fwd_edge_cold(edge_id).from_node;
+        self.fwd_neighbors(from_node)
+            .find(|edge| edge.id == edge_id)
+            .map(|edge| edge.weight)
+            .unwrap()
+    }
+
+    /// Gets the travel time of an edge in seconds, whatever metric the graph is queried under.
+    pub fn edge_time(&self, edge_id: usize) -> f32 {
+        let from_node = self.get_fwd_edge_cold(edge_id).from_node;
+        self.fwd_neighbors(from_node)
+            .find(|edge| edge.id == edge_id)
+            .map(|edge| edge.time_s)
+            .unwrap()
+    }
+
+    /// Gets the name of the road an edge is part of, if it has one.
+    pub fn edge_name(&self, edge_id: usize) -> Option<&str> {
+        self.get_fwd_edge_cold(edge_id).name.as_deref()
+    }
+
+    /// Gets the dense ids of the (source, target) nodes of an edge.
+    pub fn edge_endpoints(&self, edge_id: usize) -> (usize, usize) {
+        let edge = self.get_fwd_edge_cold(edge_id);
+        (edge.from_node, edge.to_node)
+    }
+
+    /// Writes the graph to `path`, after a header with magic bytes and the format version.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&GRAPH_FILE_MAGIC)?;
+        writer.write_all(&GRAPH_FILE_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads a graph written by `save`, failing if the file isn't a graph file or was written
+    /// with a different format version.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != GRAPH_FILE_MAGIC {
+            anyhow::bail!("{} is not a graph file", path.display());
+        }
+        reader.read_exact(&mut version)?;
+
+        let version = u32::from_le_bytes(version);
+        if version != GRAPH_FILE_VERSION {
+            anyhow::bail!(
+                "{} has graph format version {version}, expected version {GRAPH_FILE_VERSION}, rebuild the graph",
+                path.display()
+            );
+        }
+
+        Ok(bincode::deserialize_from(reader)?)
+    }
+
+    /// Gets the dense id of the node with the given osm id.
+    pub fn node_by_osm(&self, osm_id: i64) -> Option<usize> {
+        self.osm_to_dense.get(&osm_id).copied()
+    }
+
+    /// Gets the ids of the edges of the streets named `name`, in increasing order. Shortcuts have
+    /// no name, so only original edges are returned.
+    pub fn edges_by_name(&self, name: &str) -> &[usize] {
+        let index = self.name_index.get_or_init(|| {
+            let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+            for edge in self.values_cold.iter().step_by(2) {
+                if let Some(name) = &edge.name {
+                    index.entry(name.clone()).or_default().push(edge.id);
+                }
+            }
+            index
+        });
+
+        index.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Gets the bounding box of the nodes as (min_lat, min_lon, max_lat, max_lon), e.g. to
+    /// center a map on the graph. `None` if the graph has no nodes.
+    pub fn bounds(&self) -> Option<(f64, f64, f64, f64)> {
+        *self.bounds.get_or_init(|| {
+            let first = self.nodes.first()?;
+            let init = (first.lat, first.lon, first.lat, first.lon);
+            Some(self.nodes.iter().fold(init, |bounds, node| {
+                (
+                    bounds.0.min(node.lat),
+                    bounds.1.min(node.lon),
+                    bounds.2.max(node.lat),
+                    bounds.3.max(node.lon),
+                )
+            }))
+        })
+    }
+
+    /// Expands an edge into the ids of the original edges it stands for, in travel order.
+    /// Original edges expand to themselves.
+    pub fn unpack_edge(&self, edge_id: usize) -> Vec<usize> {
+        let mut edge_ids = Vec::new();
+        let mut stack = vec![edge_id];
+
+        while let Some(id) = stack.pop() {
+            let edge = self.get_fwd_edge_cold(id);
+            match (edge.prev_edge, edge.next_edge) {
+                (Some(prev_edge), Some(next_edge)) => {
+                    stack.push(next_edge);
+                    stack.push(prev_edge);
+                }
+                _ => edge_ids.push(id),
+            }
+        }
+
+        edge_ids
+    }
+
     pub fn get_fwd_edge_cold(&self, edge_id: usize) -> &CSREdgeCold {
         assert!(edge_id * 2 < self.values_cold.len());
         &self.values_cold[edge_id * 2]
@@ -163,19 +586,447 @@ impl CSRGraph {
         &self.values_cold[(edge_id * 2) + 1]
     }
 
-    pub fn fwd_neighbors(&self, node: usize) -> impl Iterator<Item = &CSREdgeHot> {
+    /// Gets the largest error of a stored weight, half a quantization step if the weights are
+    /// quantized and 0 otherwise.
+    pub fn weight_tolerance(&self) -> f32 {
+        self.quantized
+            .as_ref()
+            .map_or(0.0, |quantized| quantized.scale / 2.0)
+    }
+
+    // Gets the hot edge at `edge_idx`, decoding its weights if they are quantized.
+    fn hot_edge(&self, edge_idx: usize) -> CSREdgeHot {
+        match &self.quantized {
+            Some(quantized) => quantized.get(edge_idx),
+            None => self.values_hot[edge_idx],
+        }
+    }
+
+    pub fn fwd_neighbors(&self, node: usize) -> impl Iterator<Item = CSREdgeHot> + '_ {
         let start = self.row_fwd_ptr[node];
         let end = self.row_fwd_ptr[node + 1];
         self.cols_fwd[start..end]
             .iter()
-            .map(|&edge_idx| &self.values_hot[edge_idx])
+            .map(|&edge_idx| self.hot_edge(edge_idx))
     }
 
-    pub fn bwd_neighbors(&self, node: usize) -> impl Iterator<Item = &CSREdgeHot> {
+    pub fn bwd_neighbors(&self, node: usize) -> impl Iterator<Item = CSREdgeHot> + '_ {
         let start = self.row_bwd_ptr[node];
         let end = self.row_bwd_ptr[node + 1];
         self.cols_bwd[start..end]
             .iter()
-            .map(|&edge_idx| &self.values_hot[edge_idx])
+            .map(|&edge_idx| self.hot_edge(edge_idx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::{
+        builder::from_osmpbf,
+        ch_preprocess::{contract_graph_in_order, ContractionOptions},
+        graph::{EdgeMetadata, Node},
+    };
+    use crate::engine::query::ch_query::{BiDirDijkstra, QueryOutcome};
+
+    // Runs a CH query, returning the weight of the path and its unpacked nodes.
+    fn route(graph: &CSRGraph, src: usize, dest: usize) -> (f64, Vec<usize>) {
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        query.init(src, dest).unwrap();
+        let QueryOutcome::Found(path, weight) = query.search(graph) else {
+            panic!("no path from {src} to {dest}");
+        };
+
+        let mut nodes = vec![src];
+        for edge_id in path
+            .iter()
+            .flat_map(|result| graph.unpack_edge(result.edge_id))
+        {
+            nodes.push(graph.edge_endpoints(edge_id).1);
+        }
+        (weight, nodes)
+    }
+
+    // The serialized single-threaded layout of the edges, which `from_preprocessed_graph` must
+    // reproduce byte for byte.
+    fn serial_csr(graph: &Graph) -> Vec<u8> {
+        let mut values_hot: Vec<CSREdgeHot> = Vec::new();
+        let mut values_cold: Vec<CSREdgeCold> = Vec::new();
+        let mut layout = [(Vec::new(), vec![0]), (Vec::new(), vec![0])];
+
+        for (is_fwd, (cols, row_ptr)) in [true, false].into_iter().zip(&mut layout) {
+            let edge_lists = if is_fwd {
+                &graph.fwd_edge_list
+            } else {
+                &graph.bwd_edge_list
+            };
+            for edges in edge_lists {
+                for &id in edges {
+                    let edge = graph.get_edge(id);
+                    let metadata = graph.get_edge_metadata(edge);
+                    let target = if is_fwd { edge.dest_id } else { edge.src_id };
+                    cols.push(values_hot.len());
+                    values_hot.push(CSREdgeHot::new(
+                        id,
+                        target,
+                        metadata.weight,
+                        metadata.alt_weight,
+                        metadata.time_s,
+                        metadata.hops,
+                    ));
+                    values_cold.push(CSREdgeCold::new(
+                        id,
+                        metadata.name.clone(),
+                        metadata.names.clone(),
+                        metadata.road_class,
+                        metadata.distance_m,
+                        edge.src_id,
+                        edge.dest_id,
+                        metadata.prev_edge,
+                        metadata.next_edge,
+                        metadata.geometry.clone(),
+                    ));
+                }
+                row_ptr.push(cols.len());
+            }
+        }
+        values_cold.sort_by_key(|e| e.id);
+
+        let [(cols_fwd, row_fwd_ptr), (cols_bwd, row_bwd_ptr)] = layout;
+        bincode::serialize(&(
+            cols_fwd,
+            row_fwd_ptr,
+            cols_bwd,
+            row_bwd_ptr,
+            values_hot,
+            values_cold,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let mut graph = from_osmpbf("tests/data/nz-car-only.osm.pbf").unwrap();
+        let order: Vec<usize> = (0..graph.num_nodes()).collect();
+        contract_graph_in_order(&mut graph, &order, &ContractionOptions::default(), |_| {})
+            .unwrap();
+
+        let expected = serial_csr(&graph);
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+        let actual = bincode::serialize(&(
+            &csr.cols_fwd,
+            &csr.row_fwd_ptr,
+            &csr.cols_bwd,
+            &csr.row_bwd_ptr,
+            &csr.values_hot,
+            &csr.values_cold,
+        ))
+        .unwrap();
+        assert!(actual == expected, "the parallel layout differs");
+    }
+
+    #[test]
+    fn test_road_class_carried_to_cold() {
+        let nodes = (0..2).map(|i| Node::new(i, 10 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata {
+            road_class: RoadClass::Motorway,
+            ..EdgeMetadata::new(1.0)
+        });
+        graph.add_edge(0, 1, 0);
+
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+        assert_eq!(csr.get_fwd_edge_cold(0).road_class, RoadClass::Motorway);
+        assert_eq!(csr.get_bwd_edge_cold(0).road_class, RoadClass::Motorway);
+    }
+
+    #[test]
+    fn test_node_by_osm() {
+        let nodes = (0..4).map(|i| Node::new(i, 1000 - i as i64 * 7)).collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata::new(1.0));
+        graph.add_edge(0, 1, 0);
+        graph.add_edge(2, 3, 0);
+
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+        for osm_id in [1000, 993, 986, 979, 42] {
+            let linear = csr.nodes.iter().find(|n| n.osm_id == osm_id).map(|n| n.id);
+            assert_eq!(csr.node_by_osm(osm_id), linear);
+        }
+
+        let bytes = bincode::serialize(&csr).unwrap();
+        let loaded: CSRGraph = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(loaded.node_by_osm(986), Some(2));
+    }
+
+    #[test]
+    fn test_update_edge_weight_reroutes() {
+        // 0 - 2 - 1 with weights 1, 1, and 0 - 3 - 1 with weights 3, 3.
+        let nodes = (0..4).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, weight) in [(0, 2, 1.0), (2, 1, 1.0), (0, 3, 3.0), (3, 1, 3.0)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+
+        // Contracting 2 first adds a 0 - 1 shortcut, 3 stays on top.
+        contract_graph_in_order(
+            &mut graph,
+            &[2, 0, 1, 3],
+            &ContractionOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let mut csr = CSRGraph::from_preprocessed_graph(graph);
+
+        assert_eq!(route(&csr, 0, 1), (2.0, vec![0, 2, 1]));
+
+        // Edge 0 is 0 -> 2, make the upper route longer than the lower one.
+        csr.update_edge_weight(0, 10.0);
+        csr.recustomize();
+
+        assert_eq!(route(&csr, 0, 1), (6.0, vec![0, 3, 1]));
+        assert_eq!(route(&csr, 1, 0), (2.0, vec![1, 2, 0]));
+    }
+
+    #[test]
+    fn test_unpack_edge() {
+        // 0 <-> 1 <-> 2 <-> 3, contracting 1 then 2 builds 0 -> 3 from the 0 -> 2 shortcut.
+        let nodes = (0..4).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for src in 0..3 {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(1.0));
+            graph.add_edge(src, src + 1, metadata_index);
+            graph.add_edge(src + 1, src, metadata_index);
+        }
+        contract_graph_in_order(
+            &mut graph,
+            &[1, 2, 0, 3],
+            &ContractionOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let (fwd_id, bwd_id) = (
+            graph.find_edge_id(0, 3).unwrap(),
+            graph.find_edge_id(3, 0).unwrap(),
+        );
+        let shortcut_0_2 = graph.find_edge_id(0, 2).unwrap();
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+
+        assert_eq!(csr.get_fwd_edge_cold(fwd_id).prev_edge, Some(shortcut_0_2));
+        assert_eq!(csr.unpack_edge(fwd_id), [0, 2, 4]);
+        assert_eq!(csr.unpack_edge(bwd_id), [5, 3, 1]);
+        assert_eq!(csr.unpack_edge(2), [2]);
+    }
+
+    #[test]
+    fn test_edge_accessors() {
+        // A named two-way street 0 <-> 1 and an unnamed one-way 1 -> 2.
+        let nodes = (0..3).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata {
+            name: Some("Herzl".to_string()),
+            ..EdgeMetadata::new(2.5)
+        });
+        graph.edge_metadata.push(EdgeMetadata::new(4.0));
+        graph.add_edge(0, 1, 0);
+        graph.add_edge(1, 0, 0);
+        graph.add_edge(1, 2, 1);
+        let mut csr = CSRGraph::from_preprocessed_graph(graph);
+        csr.update_edge_weight(2, 5.0);
+
+        for edge_id in 0..3 {
+            let cold = &csr.values_cold[edge_id * 2];
+            let hot = csr.values_hot.iter().find(|e| e.id == edge_id).unwrap();
+            assert_eq!(csr.edge_weight(edge_id), hot.weight);
+            assert_eq!(csr.edge_name(edge_id), cold.name.as_deref());
+            assert_eq!(csr.edge_endpoints(edge_id), (cold.from_node, cold.to_node));
+        }
+        assert_eq!(csr.edge_name(1), Some("Herzl"));
+        assert_eq!(csr.edge_endpoints(1), (1, 0));
+        assert_eq!(csr.edge_weight(2), 5.0);
+        assert_eq!(csr.edge_name(2), None);
+    }
+
+    #[test]
+    fn test_edges_by_name() {
+        let graph = from_osmpbf("tests/data/nz-car-only.osm.pbf").unwrap();
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+
+        let name = "Eliyahu Meron";
+        let expected: Vec<usize> = (0..csr.values_cold.len() / 2)
+            .filter(|&id| csr.edge_name(id) == Some(name))
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(csr.edges_by_name(name), expected);
+
+        assert!(csr.edges_by_name("No Such Street").is_empty());
+    }
+
+    #[test]
+    fn test_bounds() {
+        let graph = from_osmpbf("tests/data/nz-car-only.osm.pbf").unwrap();
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+
+        let lats = csr.nodes.iter().map(|node| node.lat);
+        let lons = csr.nodes.iter().map(|node| node.lon);
+        let expected = (
+            lats.clone().fold(f64::INFINITY, f64::min),
+            lons.clone().fold(f64::INFINITY, f64::min),
+            lats.fold(f64::NEG_INFINITY, f64::max),
+            lons.fold(f64::NEG_INFINITY, f64::max),
+        );
+        assert_eq!(csr.bounds(), Some(expected));
+        assert!(expected.0 < expected.2 && expected.1 < expected.3);
+        // Cached after the first call.
+        assert_eq!(csr.bounds(), Some(expected));
+
+        let empty = CSRGraph::from_preprocessed_graph(Graph::new(Vec::new()));
+        assert_eq!(empty.bounds(), None);
+    }
+
+    #[test]
+    fn test_quantized_weights() {
+        let mut graph = from_osmpbf("tests/data/nz-car-only.osm.pbf").unwrap();
+        let order: Vec<usize> = (0..graph.num_nodes()).collect();
+        contract_graph_in_order(&mut graph, &order, &ContractionOptions::default(), |_| {})
+            .unwrap();
+        let full = CSRGraph::from_preprocessed_graph(graph.clone());
+        let quantized =
+            CSRGraph::from_preprocessed_graph_with_storage(graph, WeightStorage::Quantized);
+
+        let tolerance = quantized.weight_tolerance();
+        assert_eq!(full.weight_tolerance(), 0.0);
+        assert!(tolerance > 0.0 && quantized.values_hot.is_empty());
+        for node in 0..full.nodes.len() {
+            for (exact, decoded) in full.fwd_neighbors(node).zip(quantized.fwd_neighbors(node)) {
+                assert_eq!((exact.id, exact.target), (decoded.id, decoded.target));
+                assert!(
+                    (exact.weight - decoded.weight).abs() <= tolerance * 1.001,
+                    "edge {}: {} decoded as {}",
+                    exact.id,
+                    exact.weight,
+                    decoded.weight
+                );
+            }
+        }
+
+        // Quantized queries find paths within the tolerance of each of their packed edges.
+        let num_nodes = full.nodes.len();
+        let mut query = BiDirDijkstra::new(num_nodes);
+        let mut num_found = 0;
+        for src in (0..num_nodes).step_by(num_nodes / 8) {
+            for dest in (0..num_nodes).step_by(num_nodes / 16) {
+                query.init(src, dest).unwrap();
+                let QueryOutcome::Found(exact_path, exact) = query.search(&full) else {
+                    continue;
+                };
+                query.init(src, dest).unwrap();
+                let QueryOutcome::Found(path, weight) = query.search(&quantized) else {
+                    panic!("{src} -> {dest} is only found with exact weights");
+                };
+
+                let edge_ids: Vec<usize> = path
+                    .iter()
+                    .flat_map(|result| full.unpack_edge(result.edge_id))
+                    .collect();
+                let mut node = src;
+                for &edge_id in &edge_ids {
+                    let (from_node, to_node) = full.edge_endpoints(edge_id);
+                    assert_eq!(from_node, node, "{src} -> {dest} is disconnected");
+                    node = to_node;
+                }
+                assert_eq!(node, dest);
+
+                let bound = f64::from(tolerance) * exact_path.len().max(path.len()) as f64 + 1e-3;
+                let true_weight: f64 = edge_ids
+                    .iter()
+                    .map(|&id| f64::from(full.edge_weight(id)))
+                    .sum();
+                assert!((weight - exact).abs() <= bound, "{weight} != {exact}");
+                assert!(
+                    true_weight - exact <= 2.0 * bound,
+                    "{true_weight} > {exact}"
+                );
+                num_found += 1;
+            }
+        }
+        assert!(num_found > 0);
+
+        let path = std::env::temp_dir().join(format!("shepherd-q-{}.bin", std::process::id()));
+        full.save(&path).unwrap();
+        let full_size = std::fs::metadata(&path).unwrap().len();
+        quantized.save(&path).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < full_size);
+
+        let loaded = CSRGraph::load(&path).unwrap();
+        assert_eq!(loaded.weight_tolerance(), tolerance);
+        for edge_id in (0..full.values_cold.len() / 2).step_by(97) {
+            assert_eq!(loaded.edge_weight(edge_id), quantized.edge_weight(edge_id));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_quantized_update_past_range() {
+        let nodes = (0..3).map(|i| Node::new(i, 10 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (i, weight) in [10.0, 1000.0].into_iter().enumerate() {
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(i, i + 1, i);
+        }
+        let mut csr =
+            CSRGraph::from_preprocessed_graph_with_storage(graph, WeightStorage::Quantized);
+        let edge_id = |csr: &CSRGraph, from: usize| csr.fwd_neighbors(from).next().unwrap().id;
+        let (first, second) = (edge_id(&csr, 0), edge_id(&csr, 1));
+        let old_tolerance = csr.weight_tolerance();
+
+        // Far past the largest weight the graph was quantized for.
+        csr.update_edge_weight(second, 250_000.0);
+        let tolerance = csr.weight_tolerance();
+        assert!(tolerance > old_tolerance);
+        assert!((csr.edge_weight(second) - 250_000.0).abs() <= tolerance);
+        assert!((csr.edge_weight(first) - 10.0).abs() <= tolerance);
+
+        // In range updates keep the scale.
+        csr.update_edge_weight(first, 20.0);
+        assert_eq!(csr.weight_tolerance(), tolerance);
+        assert!((csr.edge_weight(first) - 20.0).abs() <= tolerance);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let nodes = (0..2).map(|i| Node::new(i, 10 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata::new(1.5));
+        graph.add_edge(0, 1, 0);
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("shepherd-test-{}.bin", std::process::id()));
+        csr.save(&path).unwrap();
+
+        let loaded = CSRGraph::load(&path).unwrap();
+        assert_eq!(loaded.node_by_osm(11), Some(1));
+        assert_eq!(loaded.values_hot[0].weight, 1.5);
+
+        // Patch the version right after the magic bytes.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[4..8].copy_from_slice(&(GRAPH_FILE_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let err = CSRGraph::load(&path).err().unwrap().to_string();
+        assert!(
+            err.contains(&format!("version {}", GRAPH_FILE_VERSION + 1)),
+            "{err}"
+        );
+
+        std::fs::write(&path, b"not a graph").unwrap();
+        let err = CSRGraph::load(&path).err().unwrap().to_string();
+        assert!(err.contains("is not a graph file"), "{err}");
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
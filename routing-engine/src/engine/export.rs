@@ -1,2 +1,6 @@
+pub mod coord_order;
 pub mod csv_export;
+pub mod edge_list_export;
 pub mod export_provider;
+pub mod geojson_export;
+pub mod name_display;
@@ -0,0 +1,18 @@
+/// The order coordinates are emitted in. Most of the engine uses (lat, lon), while GeoJSON
+/// expects [lon, lat].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoordOrder {
+    #[default]
+    LatLon,
+    LonLat,
+}
+
+impl CoordOrder {
+    /// Arranges a coordinate pair in this order.
+    pub fn arrange<T>(self, lat: T, lon: T) -> [T; 2] {
+        match self {
+            CoordOrder::LatLon => [lat, lon],
+            CoordOrder::LonLat => [lon, lat],
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::export_provider::ExportProvider;
+use super::{coord_order::CoordOrder, export_provider::ExportProvider};
 use crate::engine::preprocess::graph::Graph;
 use csv::Writer;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
@@ -6,11 +6,24 @@ use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 pub struct CSVExport<'a> {
     pub graph: &'a Graph,
     pub path: String,
+    // Order of the coordinate columns, lat then lon by default.
+    pub coord_order: CoordOrder,
 }
 
 impl<'a> CSVExport<'a> {
     pub fn new(graph: &'a Graph, path: String) -> Self {
-        Self { graph, path }
+        Self {
+            graph,
+            path,
+            coord_order: CoordOrder::LatLon,
+        }
+    }
+
+    pub fn with_coord_order(self, coord_order: CoordOrder) -> Self {
+        Self {
+            coord_order,
+            ..self
+        }
     }
 }
 
@@ -24,11 +37,12 @@ impl<'a> ExportProvider for CSVExport<'a> {
         let records: Vec<Vec<String>> = nodes
             .par_iter()
             .map(|node| {
+                let [first, second] = self.coord_order.arrange(node.lat, node.lon);
                 vec![
                     node.dense_id.to_string(),
                     node.osm_id.to_string(),
-                    node.lat.to_string(),
-                    node.lon.to_string(),
+                    first.to_string(),
+                    second.to_string(),
                 ]
             })
             .collect();
@@ -40,3 +54,38 @@ impl<'a> ExportProvider for CSVExport<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::graph::Node;
+
+    #[test]
+    fn test_csv_coord_order() {
+        let node = Node {
+            lat: 32.5,
+            lon: 34.75,
+            ..Node::new(0, 100)
+        };
+        let graph = Graph::new(vec![node]);
+        let path = std::env::temp_dir().join(format!("shepherd-test-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        CSVExport::new(&graph, path.clone()).export().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "0,100,32.5,34.75\n"
+        );
+
+        CSVExport::new(&graph, path.clone())
+            .with_coord_order(CoordOrder::LonLat)
+            .export()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "0,100,34.75,32.5\n"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
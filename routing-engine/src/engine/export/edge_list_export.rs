@@ -0,0 +1,137 @@
+use super::{export_provider::ExportProvider, name_display::NameDisplay};
+use crate::engine::preprocess::graph::Graph;
+use csv::Writer;
+
+/// Exports the directed edges of an uncontracted graph as a CSV edge list with the columns
+/// `src_osm,dest_osm,weight,road_class,oneway`, for running other algorithms on the graph.
+/// Shortcuts are skipped, so a contracted graph dumps the edges it was imported with. A two-way
+/// street is listed once in each direction. Exporting returns the number of edges written.
+pub struct EdgeListExport<'a> {
+    pub graph: &'a Graph,
+    pub path: String,
+    // Which name of the street goes into a trailing `name` column, no column if `None`.
+    pub name_display: Option<NameDisplay>,
+}
+
+impl<'a> EdgeListExport<'a> {
+    pub fn new(graph: &'a Graph, path: String) -> Self {
+        Self {
+            graph,
+            path,
+            name_display: None,
+        }
+    }
+
+    pub fn with_names(self, name_display: NameDisplay) -> Self {
+        Self {
+            name_display: Some(name_display),
+            ..self
+        }
+    }
+}
+
+impl<'a> ExportProvider for EdgeListExport<'a> {
+    type ExportType = anyhow::Result<usize>;
+
+    fn export(&self) -> Self::ExportType {
+        let mut writer = Writer::from_path(&self.path)?;
+        let mut header = vec!["src_osm", "dest_osm", "weight", "road_class", "oneway"];
+        if self.name_display.is_some() {
+            header.push("name");
+        }
+        writer.write_record(header)?;
+
+        let mut num_edges = 0;
+        for edge in &self.graph.edges {
+            let metadata = self.graph.get_edge_metadata(edge);
+            if metadata.prev_edge.is_some() {
+                continue;
+            }
+
+            let mut record = vec![
+                self.graph.nodes[edge.src_id].osm_id.to_string(),
+                self.graph.nodes[edge.dest_id].osm_id.to_string(),
+                metadata.weight.to_string(),
+                format!("{:?}", metadata.road_class),
+                metadata.is_one_way.to_string(),
+            ];
+            if let Some(name_display) = self.name_display {
+                let name = name_display.label(metadata.name.as_deref(), metadata.names.as_ref());
+                record.push(name.unwrap_or_default());
+            }
+            writer.write_record(record)?;
+            num_edges += 1;
+        }
+        writer.flush()?;
+
+        Ok(num_edges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::{
+        builder::ImportConfig,
+        graph::{EdgeMetadata, Node, WayNames},
+    };
+
+    #[test]
+    fn test_edge_list_export() {
+        let graph = ImportConfig::new("tests/data/tiny.osm").build().unwrap();
+        let path = std::env::temp_dir().join(format!("shepherd-edges-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let num_edges = EdgeListExport::new(&graph, path.clone()).export().unwrap();
+        assert_eq!(num_edges, graph.num_edges());
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "src_osm,dest_osm,weight,road_class,oneway");
+        assert_eq!(lines.len(), graph.num_edges() + 1);
+
+        // The middle column of the grid is a oneway residential street from 2 to 8.
+        let row = lines.iter().find(|line| line.starts_with("2,8,")).unwrap();
+        let fields: Vec<&str> = row.split(',').collect();
+        let node = |osm_id: i64| graph.nodes.iter().position(|n| n.osm_id == osm_id).unwrap();
+        let edge = graph.find_edge(node(2), node(8)).unwrap();
+        let weight: f32 = fields[2].parse().unwrap();
+        assert_eq!(weight, graph.get_edge_metadata(edge).weight);
+        assert_eq!(fields[3..], ["Residential", "true"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_edge_list_export_names() {
+        // A one-way street 0 -> 1 with both its local and English names kept.
+        let nodes = (0..2).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        let mut metadata = EdgeMetadata::new(1.0);
+        metadata.name = Some("Herzl".to_string());
+        metadata.names = Some(WayNames {
+            local: Some("הרצל".to_string()),
+            en: Some("Herzl".to_string()),
+        });
+        graph.edge_metadata.push(metadata);
+        graph.add_edge(0, 1, 0);
+        let path = std::env::temp_dir().join(format!("shepherd-names-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let export = |name_display| {
+            EdgeListExport::new(&graph, path.clone())
+                .with_names(name_display)
+                .export()
+                .unwrap();
+            let text = std::fs::read_to_string(&path).unwrap();
+            text.lines().map(str::to_string).collect::<Vec<_>>()
+        };
+        let lines = export(NameDisplay::Local);
+        assert!(lines[0].ends_with(",name"), "{}", lines[0]);
+        assert!(lines[1].ends_with(",הרצל"), "{}", lines[1]);
+        let lines = export(NameDisplay::Bilingual);
+        assert!(lines[1].ends_with(",הרצל (Herzl)"), "{}", lines[1]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+use serde_json::{json, Value};
+
+use super::{coord_order::CoordOrder, export_provider::ExportProvider, name_display::NameDisplay};
+use crate::engine::query::route::Route;
+
+/// Exports a route as a GeoJSON LineString feature, with the street names along it.
+pub struct GeoJsonExport<'a> {
+    pub route: &'a Route,
+    // GeoJSON mandates [lon, lat], other orders are only meant for broken consumers.
+    pub coord_order: CoordOrder,
+    // Which name of each street is listed in the `names` property.
+    pub name_display: NameDisplay,
+}
+
+impl<'a> GeoJsonExport<'a> {
+    pub fn new(route: &'a Route) -> Self {
+        Self {
+            route,
+            coord_order: CoordOrder::LonLat,
+            name_display: NameDisplay::Primary,
+        }
+    }
+
+    pub fn with_coord_order(self, coord_order: CoordOrder) -> Self {
+        Self {
+            coord_order,
+            ..self
+        }
+    }
+
+    pub fn with_name_display(self, name_display: NameDisplay) -> Self {
+        Self {
+            name_display,
+            ..self
+        }
+    }
+}
+
+impl<'a> ExportProvider for GeoJsonExport<'a> {
+    type ExportType = Value;
+
+    fn export(&self) -> Self::ExportType {
+        let coordinates: Vec<[f64; 2]> = self
+            .route
+            .geometry
+            .iter()
+            .map(|&(lat, lon)| self.coord_order.arrange(lat, lon))
+            .collect();
+        let names: Vec<String> = self
+            .route
+            .segments
+            .iter()
+            .map(|segment| {
+                self.name_display
+                    .label(segment.name.as_deref(), segment.names.as_ref())
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "LineString",
+                "coordinates": coordinates,
+            },
+            "properties": {
+                "distance": self.route.total_distance_m,
+                "duration": self.route.total_time_s,
+                "names": names,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{preprocess::graph::WayNames, query::route::Segment};
+
+    #[test]
+    fn test_geojson_coord_order() {
+        let route = Route {
+            geometry: vec![(32.0, 34.8), (32.1, 34.9)],
+            ..Route::default()
+        };
+
+        let lon_lat = GeoJsonExport::new(&route).export();
+        assert_eq!(lon_lat["geometry"]["coordinates"][0], json!([34.8, 32.0]));
+
+        let lat_lon = GeoJsonExport::new(&route)
+            .with_coord_order(CoordOrder::LatLon)
+            .export();
+        assert_eq!(lat_lon["geometry"]["coordinates"][1], json!([32.1, 34.9]));
+    }
+
+    #[test]
+    fn test_geojson_name_display() {
+        let segment = Segment {
+            name: Some("Herzl".to_string()),
+            names: Some(WayNames {
+                local: Some("הרצל".to_string()),
+                en: Some("Herzl".to_string()),
+            }),
+            distance_m: 100.0,
+            time_s: 10.0,
+            edge_ids: vec![0],
+            edge_distances_m: vec![100.0],
+        };
+        let unnamed = Segment {
+            name: None,
+            names: None,
+            ..segment.clone()
+        };
+        let route = Route {
+            segments: vec![segment, unnamed],
+            ..Route::default()
+        };
+
+        let primary = GeoJsonExport::new(&route).export();
+        assert_eq!(primary["properties"]["names"], json!(["Herzl", ""]));
+
+        let bilingual = GeoJsonExport::new(&route)
+            .with_name_display(NameDisplay::Bilingual)
+            .export();
+        assert_eq!(
+            bilingual["properties"]["names"],
+            json!(["הרצל (Herzl)", ""])
+        );
+    }
+}
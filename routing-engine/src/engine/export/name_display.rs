@@ -0,0 +1,72 @@
+use crate::engine::preprocess::graph::WayNames;
+
+/// Which name of a street is emitted. The local and English names are only there if the graph
+/// was imported with `keep_both_names`, otherwise every choice falls back to the primary name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameDisplay {
+    // The name picked by the import's name keys and languages.
+    #[default]
+    Primary,
+    Local,
+    English,
+    // The local name followed by the English one in parentheses, e.g. "Rehov Herzl (Herzl
+    // Street)", or whichever of the two there is.
+    Bilingual,
+}
+
+impl NameDisplay {
+    /// Gets the label of a street with the primary name `name` and the kept `names`.
+    pub fn label(self, name: Option<&str>, names: Option<&WayNames>) -> Option<String> {
+        let local = names.and_then(|names| names.local.as_deref());
+        let en = names.and_then(|names| names.en.as_deref());
+        let label = match self {
+            NameDisplay::Primary => None,
+            NameDisplay::Local => local.map(str::to_string),
+            NameDisplay::English => en.map(str::to_string),
+            NameDisplay::Bilingual => match (local, en) {
+                (Some(local), Some(en)) if local != en => Some(format!("{local} ({en})")),
+                (Some(name), _) | (None, Some(name)) => Some(name.to_string()),
+                (None, None) => None,
+            },
+        };
+        label.or_else(|| name.map(str::to_string))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label() {
+        let names = WayNames {
+            local: Some("ローカル名".to_string()),
+            en: Some("Local Street".to_string()),
+        };
+        let label = |display: NameDisplay| display.label(Some("Local Street"), Some(&names));
+
+        assert_eq!(label(NameDisplay::Primary).as_deref(), Some("Local Street"));
+        assert_eq!(label(NameDisplay::Local).as_deref(), Some("ローカル名"));
+        assert_eq!(label(NameDisplay::English).as_deref(), Some("Local Street"));
+        assert_eq!(
+            label(NameDisplay::Bilingual).as_deref(),
+            Some("ローカル名 (Local Street)")
+        );
+
+        // Without the kept names every choice falls back to the primary name.
+        assert_eq!(
+            NameDisplay::Bilingual.label(Some("Herzl"), None).as_deref(),
+            Some("Herzl")
+        );
+        let local_only = WayNames {
+            local: Some("הרצל".to_string()),
+            en: None,
+        };
+        assert_eq!(
+            NameDisplay::Bilingual
+                .label(Some("Herzl"), Some(&local_only))
+                .as_deref(),
+            Some("הרצל")
+        );
+    }
+}
@@ -1,3 +1,4 @@
 pub mod heuristic_provider;
 pub mod identity_provider;
+pub mod order_file;
 pub mod provider;
@@ -0,0 +1,77 @@
+use crate::engine::preprocess::{
+    ch_preprocess::{initial_priorities, DEFAULT_MAX_SETTLED},
+    graph::Graph,
+};
+
+use super::provider::OrderingProvider;
+
+/// Contracts the nodes with the fewest incident edges first.
+pub struct DegreeOrdering;
+
+/// Contracts the nodes with the lowest edge difference first, the number of shortcuts
+/// contracting a node would add minus the number of edges it would remove.
+/// The edge differences are computed once on the uncontracted graph.
+pub struct EdgeDifferenceOrdering;
+
+impl OrderingProvider for DegreeOrdering {
+    fn order(&self, graph: &Graph) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..graph.num_nodes()).collect();
+        order.sort_by_key(|&id| {
+            graph.get_fwd_neighbors(id).len() + graph.get_bwd_neighbors(id).len()
+        });
+        order
+    }
+}
+
+impl OrderingProvider for EdgeDifferenceOrdering {
+    fn order(&self, graph: &Graph) -> Vec<usize> {
+        let edge_differences = initial_priorities(graph, DEFAULT_MAX_SETTLED);
+
+        let mut order: Vec<usize> = (0..graph.num_nodes()).collect();
+        order.sort_by_key(|&id| edge_differences[id]);
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::graph::{EdgeMetadata, Node};
+
+    // A star with node 0 in the middle, and a path 1 - 2 - 3 around it.
+    fn get_test_graph() -> Graph {
+        let nodes = (0..5).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest) in [(0, 1), (0, 2), (0, 3), (0, 4), (1, 2), (2, 3)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(1.0));
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        graph
+    }
+
+    #[test]
+    fn test_orderings_are_permutations() {
+        let graph = get_test_graph();
+
+        for mut order in [
+            DegreeOrdering.order(&graph),
+            EdgeDifferenceOrdering.order(&graph),
+        ] {
+            assert_eq!(order.len(), graph.num_nodes());
+            order.sort();
+            assert_eq!(order, (0..graph.num_nodes()).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_degree_ordering() {
+        let graph = get_test_graph();
+        let order = DegreeOrdering.order(&graph);
+
+        // The leaf goes first and the hub last.
+        assert_eq!(order.first(), Some(&4));
+        assert_eq!(order.last(), Some(&0));
+    }
+}
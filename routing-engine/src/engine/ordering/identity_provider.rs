@@ -0,0 +1,12 @@
+use crate::engine::preprocess::graph::Graph;
+
+use super::provider::OrderingProvider;
+
+/// Contracts the nodes in the order of their dense ids.
+pub struct IdentityOrdering;
+
+impl OrderingProvider for IdentityOrdering {
+    fn order(&self, graph: &Graph) -> Vec<usize> {
+        (0..graph.num_nodes()).collect()
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::engine::preprocess::graph::Graph;
+
+/// Magic bytes at the start of a cached contraction order file.
+pub const ORDER_FILE_MAGIC: [u8; 4] = *b"SHOR";
+
+/// Version of the cached order layout, bump it whenever the header or the order changes.
+pub const ORDER_FILE_VERSION: u32 = 1;
+
+/// Hashes the nodes, edges and weights of `graph` with FNV-1a, which unlike the std hasher is
+/// stable across builds, so an order cached for one graph isn't reused for another.
+pub fn graph_fingerprint(graph: &Graph) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET;
+    let mut write = |value: u64| {
+        for byte in value.to_le_bytes() {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+        }
+    };
+
+    write(graph.num_nodes() as u64);
+    for node in &graph.nodes {
+        write(node.osm_id as u64);
+    }
+    for edge in &graph.edges {
+        write(edge.src_id as u64);
+        write(edge.dest_id as u64);
+        write(u64::from(graph.get_edge_metadata(edge).weight.to_bits()));
+    }
+    hash
+}
+
+/// Writes `order`, computed for `graph`, to `path` after a header with magic bytes, the format
+/// version, and the node count and fingerprint of the graph.
+pub fn save_order(path: impl AsRef<Path>, graph: &Graph, order: &[usize]) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&ORDER_FILE_MAGIC)?;
+    writer.write_all(&ORDER_FILE_VERSION.to_le_bytes())?;
+    writer.write_all(&(graph.num_nodes() as u64).to_le_bytes())?;
+    writer.write_all(&graph_fingerprint(graph).to_le_bytes())?;
+    bincode::serialize_into(&mut writer, order)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads an order written by `save_order`, failing if the file isn't an order file, was written
+/// with a different format version or was computed for another graph.
+pub fn load_order(path: impl AsRef<Path>, graph: &Graph) -> anyhow::Result<Vec<usize>> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 4];
+    if reader.read_exact(&mut magic).is_err() || magic != ORDER_FILE_MAGIC {
+        anyhow::bail!("{} is not an order file", path.display());
+    }
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != ORDER_FILE_VERSION {
+        anyhow::bail!(
+            "{} has order format version {version}, expected version {ORDER_FILE_VERSION}",
+            path.display()
+        );
+    }
+
+    let mut num_nodes = [0u8; 8];
+    let mut fingerprint = [0u8; 8];
+    reader.read_exact(&mut num_nodes)?;
+    reader.read_exact(&mut fingerprint)?;
+    let num_nodes = u64::from_le_bytes(num_nodes);
+    if num_nodes != graph.num_nodes() as u64 {
+        anyhow::bail!(
+            "{} orders {num_nodes} nodes but the graph has {}",
+            path.display(),
+            graph.num_nodes()
+        );
+    }
+    if u64::from_le_bytes(fingerprint) != graph_fingerprint(graph) {
+        anyhow::bail!("{} was computed for another graph", path.display());
+    }
+
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::graph::{EdgeMetadata, Node};
+
+    // A path 0 - 1 - 2.
+    fn get_test_graph() -> Graph {
+        let nodes = (0..3).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest) in [(0, 1), (1, 2)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(1.0));
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        graph
+    }
+
+    #[test]
+    fn test_order_file() {
+        let path = std::env::temp_dir().join(format!("shepherd-order-{}.bin", std::process::id()));
+        let graph = get_test_graph();
+        save_order(&path, &graph, &[2, 0, 1]).unwrap();
+        assert_eq!(load_order(&path, &graph).unwrap(), vec![2, 0, 1]);
+
+        // Same node count, different weights.
+        let mut reweighted = get_test_graph();
+        reweighted.edge_metadata[0].weight = 2.0;
+        let err = load_order(&path, &reweighted).unwrap_err().to_string();
+        assert!(err.contains("another graph"), "{err}");
+
+        let mut grown = get_test_graph();
+        grown.nodes.push(Node::new(3, 103));
+        let err = load_order(&path, &grown).unwrap_err().to_string();
+        assert!(err.contains("orders 3 nodes but the graph has 4"), "{err}");
+
+        std::fs::write(&path, b"not an order").unwrap();
+        let err = load_order(&path, &graph).unwrap_err().to_string();
+        assert!(err.contains("is not an order file"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,7 @@
-pub trait OrderingProvider {
-    type Item;
+use crate::engine::preprocess::graph::Graph;
 
-    fn get_node_order() -> Self::Item;
+/// Decides the order in which the nodes of a graph are contracted.
+pub trait OrderingProvider {
+    /// Returns the dense ids of all the nodes of `graph`, in contraction order.
+    fn order(&self, graph: &Graph) -> Vec<usize>;
 }
@@ -1,4 +1,9 @@
 pub mod builder;
 pub mod ch_preprocess;
+pub mod cost_model;
+pub mod edge_based;
+pub mod elevation;
 pub mod graph;
+pub mod graph_diff;
+pub mod profile;
 pub mod witness_search;
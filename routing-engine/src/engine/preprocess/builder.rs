@@ -1,26 +1,56 @@
 use itertools::Itertools;
 use multimap::MultiMap;
-use osmpbf::{Element, ElementReader, Way};
-use std::collections::{BTreeMap, HashMap};
-
-use super::graph::{Edge, EdgeMetadata, Graph, Node};
+use osmpbf::{Element, ElementReader, IndexedReader, RelMemberType};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+    time::Instant,
+};
+
+use super::cost_model::CostModel;
+use super::edge_based::{EdgeBasedGraph, RestrictionKind, TurnRestriction};
+use super::graph::{
+    Edge, EdgeMetadata, Graph, Node, RoadClass, RoundaboutPosition, VehicleConstraints, WayNames,
+};
+use super::profile::{Access, AccessTags, Barrier, Profile};
 use crate::engine::utils;
 
 #[derive(Debug, Clone)]
 struct NodeParseData {
-    dense_index: usize,
-    lat: f32,
-    lon: f32,
+    lat: f64,
+    lon: f64,
     is_traffic_signal: bool,
+    barrier: Option<Barrier>,
+    is_turning_circle: bool,
 }
 
 #[derive(Debug, Clone)]
 struct WayParseData {
     id: i64,
     name: Option<String>,
+    // The local and English names, only parsed if the options keep both.
+    names: Option<WayNames>,
     max_speed: Option<u8>,
+    road_class: RoadClass,
     is_roundabout: bool,
     is_oneway: bool,
+    // Tagged oneway=reversible or oneway=alternating.
+    is_reversible: bool,
+    access: AccessTags,
+    // The surface and smoothness tags, which slow down some profiles.
+    surface: Option<String>,
+    smoothness: Option<String>,
+    // The vertical layer of the way, from the layer tag or implied by bridge and tunnel tags.
+    layer: i8,
+    // The crossing duration in seconds if the way is a ferry route.
+    ferry_duration_s: Option<f32>,
+    // The maxheight, maxweight in tonnes and maxwidth tags.
+    max_height_m: Option<f32>,
+    max_weight_t: Option<f32>,
+    max_width_m: Option<f32>,
+    // Tagged with a lifecycle state, e.g. highway=construction or disused:highway=*, so the road
+    // is closed.
+    is_lifecycle: bool,
     refs: Vec<i64>,
 }
 
@@ -37,123 +67,838 @@ struct BuildEdgeListResult {
     edge_metadata: Vec<EdgeMetadata>,
 }
 
+/// Counts of what an import read and kept, see `from_osmpbf_with_report`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    // Nodes read from the file, only those referenced by routable ways are read.
+    pub nodes_read: usize,
+    // Ways read from the file, routable or not.
+    pub ways_read: usize,
+    // Ways that passed the profile and the other filters.
+    pub ways_kept: usize,
+    // Edges of the built graph.
+    pub edges_created: usize,
+    // Way refs to nodes missing from the file, at which the ways were cut.
+    pub skipped_missing_nodes: usize,
+    // Nodes dropped for coordinates out of range, or at (0, 0) with `drop_null_island`. Ways
+    // are cut at them like at missing nodes.
+    pub invalid_coords: usize,
+    // Time spent reading and parsing the file, in milliseconds.
+    pub parse_ms: u128,
+}
+
+/// The tags a way's name is read from by default, in order of preference.
+pub const DEFAULT_NAME_KEYS: &[&str] = &["name:en", "name", "ref"];
+
+/// Lifecycle states of a road that isn't open, used as highway values and as key prefixes, e.g.
+/// `highway=construction` or `disused:highway=residential`.
+pub const LIFECYCLE_STATES: &[&str] = &["construction", "proposed", "abandoned", "disused"];
+
+/// The weight zero-length edges, such as those between coincident nodes, are clamped to.
+pub const MIN_EDGE_WEIGHT: f32 = 0.01;
+
+/// The crossing duration in seconds of ferries without a duration tag.
+pub const DEFAULT_FERRY_DURATION_S: f32 = 1800.0;
+
+/// The factor the weight of access=destination ways is multiplied by, so they are only used
+/// to reach a destination on them.
+pub const DEFAULT_DESTINATION_PENALTY: f32 = 10.0;
+
+/// How ways whose direction changes over time, tagged oneway=reversible or
+/// oneway=alternating, are imported for profiles that respect oneway tags.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReversiblePolicy {
+    // The ways are dropped.
+    #[default]
+    Exclude,
+    // The ways are imported as two-way, their travel time multiplied by the given factor.
+    TwoWay(f32),
+}
+
+/// Options controlling how an OSM extract is turned into a graph.
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    // The profile the graph is built for, ways it can't access are dropped.
+    pub profile: Profile,
+    // Optional (min_lat, min_lon, max_lat, max_lon) box to clip the graph to.
+    pub bbox: Option<(f64, f64, f64, f64)>,
+    // The tags a way's name is read from, the first one that is present is used.
+    pub name_keys: Vec<String>,
+    // Preferred languages by precedence, e.g. ["he", "en"]. Their name:<lang> tags are tried
+    // in order before `name_keys`.
+    pub languages: Vec<String>,
+    // If set, zero, negative and non-finite edge weights are clamped to this minimum.
+    pub min_edge_weight: Option<f32>,
+    // Whether route=ferry ways are imported.
+    pub ferries: bool,
+    // The crossing duration in seconds of ferries without a duration tag.
+    pub ferry_duration_s: f32,
+    // Converts the ways into edge weights.
+    pub cost_model: CostModel,
+    // Whether edge lengths are measured with the cheaper planar approximation, which falls back
+    // to haversine for long edges.
+    pub planar_distances: bool,
+    // Whether a node shared by ways on different layers, inside both of them, is ignored as an
+    // intersection, so a bridge doesn't connect to the road beneath it.
+    pub respect_layers: bool,
+    // How reversible and alternating ways are imported.
+    pub reversible: ReversiblePolicy,
+    // The factor the weight of ways the profile may only use to reach a destination, e.g.
+    // access=destination, is multiplied by. Their travel time is kept as is.
+    pub destination_penalty: f32,
+    // OSM ids of ways to skip, e.g. to work around bad data.
+    pub excluded_ways: HashSet<i64>,
+    // OSM ids of nodes to skip. Ways are cut where they pass an excluded node, as if it was
+    // missing from the file.
+    pub excluded_nodes: HashSet<i64>,
+    // Whether nodes at exactly (0, 0), usually left there by broken edits, are dropped like
+    // nodes with out of range coordinates.
+    pub drop_null_island: bool,
+    // Whether the local and English names are kept side by side in `EdgeMetadata::names`, on
+    // top of the one picked for `name`.
+    pub keep_both_names: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            profile: Profile::default(),
+            bbox: None,
+            name_keys: DEFAULT_NAME_KEYS.iter().map(|k| k.to_string()).collect(),
+            languages: Vec::new(),
+            min_edge_weight: Some(MIN_EDGE_WEIGHT),
+            ferries: true,
+            ferry_duration_s: DEFAULT_FERRY_DURATION_S,
+            cost_model: CostModel::default(),
+            planar_distances: false,
+            respect_layers: false,
+            reversible: ReversiblePolicy::default(),
+            destination_penalty: DEFAULT_DESTINATION_PENALTY,
+            excluded_ways: HashSet::new(),
+            excluded_nodes: HashSet::new(),
+            drop_null_island: true,
+            keep_both_names: false,
+        }
+    }
+}
+
 pub fn from_osmpbf(path: &str) -> anyhow::Result<Graph> {
-    let parse_result = parse_osmpbf(path)?;
-    let intersections_map = create_intersections_map(path)?;
+    from_osmpbf_with_options(path, &ImportOptions::default())
+}
+
+/// Builds a graph from a PBF file, streaming the ways first and then only reading the nodes
+/// that are used by routable ways, so memory is bounded by the routable part of the extract.
+pub fn from_osmpbf_with_options(path: &str, options: &ImportOptions) -> anyhow::Result<Graph> {
+    from_osmpbf_counting_missing(path, options).map(|(graph, _)| graph)
+}
+
+/// Like `from_osmpbf_with_options`, also returning how many way refs point to nodes missing
+/// from the file. Ways are cut at such refs instead of failing the import, so a nonzero count
+/// means the extract is incomplete, e.g. clipped.
+pub fn from_osmpbf_counting_missing(
+    path: &str,
+    options: &ImportOptions,
+) -> anyhow::Result<(Graph, usize)> {
+    from_osmpbf_with_report(path, options)
+        .map(|(graph, report)| (graph, report.skipped_missing_nodes))
+}
+
+/// Like `from_osmpbf_with_options`, also returning counts of what was read and kept, to tell
+/// at a glance whether the profile filtered out too much or the extract is incomplete.
+pub fn from_osmpbf_with_report(
+    path: &str,
+    options: &ImportOptions,
+) -> anyhow::Result<(Graph, ImportReport)> {
+    let mut report = ImportReport::default();
+
+    let start = Instant::now();
+    let (parse_result, intersections_map) = parse_osmpbf_streaming(path, options, &mut report)?;
+    report.parse_ms = start.elapsed().as_millis();
+
+    let (graph, missing_refs) = build_graph(parse_result, intersections_map, options);
+    report.edges_created = graph.num_edges();
+    report.skipped_missing_nodes = missing_refs;
+    Ok((ensure_has_edges(graph, options)?, report))
+}
+
+/// Builds a graph from a PBF file by loading every node and way into memory.
+/// Only suitable for small extracts, prefer `from_osmpbf_with_options`.
+pub fn from_osmpbf_in_memory(path: &str, options: &ImportOptions) -> anyhow::Result<Graph> {
+    let (parse_result, intersections_map) = parse_osmpbf(path, options)?;
+    let (graph, _) = build_graph(parse_result, intersections_map, options);
+    ensure_has_edges(graph, options)
+}
+
+/// Builds a graph from a plain `.osm` XML file, handy for small hand-written fixtures. The whole
+/// file is loaded into memory, so prefer PBF for real extracts.
+pub fn from_osm_xml(path: &str, options: &ImportOptions) -> anyhow::Result<Graph> {
+    from_osm_xml_with_report(path, options).map(|(graph, _)| graph)
+}
+
+/// Like `from_osm_xml`, also returning counts of what was read and kept, see
+/// `from_osmpbf_with_report`.
+pub fn from_osm_xml_with_report(
+    path: &str,
+    options: &ImportOptions,
+) -> anyhow::Result<(Graph, ImportReport)> {
+    let mut report = ImportReport::default();
+
+    let start = Instant::now();
+    let (parse_result, intersections_map) = parse_osm_xml(path, options, &mut report)?;
+    report.parse_ms = start.elapsed().as_millis();
+
+    let (graph, missing_refs) = build_graph(parse_result, intersections_map, options);
+    report.edges_created = graph.num_edges();
+    report.skipped_missing_nodes = missing_refs;
+    Ok((ensure_has_edges(graph, options)?, report))
+}
+
+/// Builds a graph from an OSM file, reading `.osm` and `.xml` files as XML and anything else,
+/// e.g. `.osm.pbf`, as PBF.
+pub fn from_file(path: &str, options: &ImportOptions) -> anyhow::Result<Graph> {
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    match extension {
+        Some("osm" | "xml") => from_osm_xml(path, options),
+        _ => from_osmpbf_with_options(path, options),
+    }
+}
+
+/// Reads the turn restriction relations of an OSM file that apply to `profile`, dispatching on
+/// the extension like `from_file`. Only restrictions via a single node are read, those via ways
+/// are skipped, as are those whose `except` tag exempts the profile.
+pub fn read_turn_restrictions(
+    path: &str,
+    profile: Profile,
+) -> anyhow::Result<Vec<TurnRestriction>> {
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    match extension {
+        Some("osm" | "xml") => read_turn_restrictions_xml(path, profile),
+        _ => read_turn_restrictions_pbf(path, profile),
+    }
+}
+
+/// Collects the options of an import along with the file to read, so new options don't change
+/// the signature of the import functions, e.g.
+/// `ImportConfig::new(path).profile(Profile::Bike).bbox(bbox).build()?`.
+#[derive(Debug, Clone)]
+pub struct ImportConfig {
+    path: String,
+    options: ImportOptions,
+    // Whether chains of degree-2 nodes are merged into single edges after the import.
+    compress_chains: bool,
+    // If set, the geometry of the edges is simplified with this tolerance in meters.
+    simplify_epsilon_m: Option<f64>,
+    // If set, the edges the vehicle doesn't fit on are removed after the import.
+    vehicle: Option<VehicleConstraints>,
+}
+
+impl ImportConfig {
+    /// Starts a config reading `path` with the default options. `.osm` and `.xml` files are read
+    /// as XML and anything else as PBF.
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            options: ImportOptions::default(),
+            compress_chains: false,
+            simplify_epsilon_m: None,
+            vehicle: None,
+        }
+    }
+
+    /// Replaces all the import options at once, keeping the post-processing steps.
+    pub fn options(mut self, options: ImportOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.options.profile = profile;
+        self
+    }
+
+    /// Clips the graph to (min_lat, min_lon, max_lat, max_lon).
+    pub fn bbox(mut self, bbox: (f64, f64, f64, f64)) -> Self {
+        self.options.bbox = Some(bbox);
+        self
+    }
+
+    /// Sets the cost model the edges are weighted by.
+    pub fn cost_model(mut self, cost_model: CostModel) -> Self {
+        self.options.cost_model = cost_model;
+        self
+    }
+
+    /// Sets the tags a way's name is read from, by precedence.
+    pub fn name_keys<S: Into<String>>(mut self, keys: impl IntoIterator<Item = S>) -> Self {
+        self.options.name_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the preferred languages of the names, by precedence.
+    pub fn languages<S: Into<String>>(mut self, languages: impl IntoIterator<Item = S>) -> Self {
+        self.options.languages = languages.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn min_edge_weight(mut self, min_edge_weight: Option<f32>) -> Self {
+        self.options.min_edge_weight = min_edge_weight;
+        self
+    }
+
+    pub fn ferries(mut self, ferries: bool) -> Self {
+        self.options.ferries = ferries;
+        self
+    }
+
+    pub fn planar_distances(mut self, planar_distances: bool) -> Self {
+        self.options.planar_distances = planar_distances;
+        self
+    }
+
+    pub fn respect_layers(mut self, respect_layers: bool) -> Self {
+        self.options.respect_layers = respect_layers;
+        self
+    }
+
+    pub fn reversible(mut self, reversible: ReversiblePolicy) -> Self {
+        self.options.reversible = reversible;
+        self
+    }
+
+    /// Sets the factor the weight of access=destination ways is multiplied by, 1 to treat
+    /// them like any other way.
+    pub fn destination_penalty(mut self, destination_penalty: f32) -> Self {
+        self.options.destination_penalty = destination_penalty;
+        self
+    }
+
+    /// Adds OSM ids of ways to skip.
+    pub fn exclude_ways(mut self, ids: impl IntoIterator<Item = i64>) -> Self {
+        self.options.excluded_ways.extend(ids);
+        self
+    }
+
+    /// Adds OSM ids of nodes to skip.
+    pub fn exclude_nodes(mut self, ids: impl IntoIterator<Item = i64>) -> Self {
+        self.options.excluded_nodes.extend(ids);
+        self
+    }
+
+    pub fn drop_null_island(mut self, drop_null_island: bool) -> Self {
+        self.options.drop_null_island = drop_null_island;
+        self
+    }
+
+    /// Keeps the local and English names of the ways side by side, see `ImportOptions`.
+    pub fn keep_both_names(mut self, keep_both_names: bool) -> Self {
+        self.options.keep_both_names = keep_both_names;
+        self
+    }
+
+    /// Merges chains of degree-2 nodes with `Graph::compress_chains` after the import.
+    pub fn compress_chains(mut self, compress_chains: bool) -> Self {
+        self.compress_chains = compress_chains;
+        self
+    }
+
+    /// Simplifies the edge geometry with `Graph::simplify_geometry` after the import. Only
+    /// merged chains have geometry, so this is meant to be used with `compress_chains`.
+    pub fn simplify(mut self, epsilon_m: f64) -> Self {
+        self.simplify_epsilon_m = Some(epsilon_m);
+        self
+    }
+
+    /// Removes the edges the vehicle exceeds the height, weight or width limits of with
+    /// `Graph::restrict_to_vehicle` after the import, so the graph can be contracted for it.
+    pub fn vehicle(mut self, vehicle: VehicleConstraints) -> Self {
+        self.vehicle = Some(vehicle);
+        self
+    }
+
+    /// Gets the import options collected so far.
+    pub fn import_options(&self) -> &ImportOptions {
+        &self.options
+    }
+
+    /// Imports the file and runs the requested post-processing steps.
+    pub fn build(&self) -> anyhow::Result<Graph> {
+        let mut graph = from_file(&self.path, &self.options)?;
+        if let Some(vehicle) = &self.vehicle {
+            graph.restrict_to_vehicle(vehicle);
+        }
+        if self.compress_chains {
+            graph.compress_chains();
+        }
+        if let Some(epsilon_m) = self.simplify_epsilon_m {
+            graph.simplify_geometry(epsilon_m);
+        }
+        Ok(graph)
+    }
+
+    /// Imports the file like `build` and turns it into an `EdgeBasedGraph` honoring the turn
+    /// restrictions of the file, for the car and bike profiles, and charging turn penalties with
+    /// the cost model. Merged chains keep the way id of their first way, so restrictions from
+    /// a later way of a chain are missed with `compress_chains`.
+    pub fn build_edge_based(&self) -> anyhow::Result<EdgeBasedGraph> {
+        let graph = self.build()?;
+        let restrictions = match self.options.profile {
+            Profile::Foot => Vec::new(),
+            profile => read_turn_restrictions(&self.path, profile)?,
+        };
+        Ok(EdgeBasedGraph::new(
+            &graph,
+            &restrictions,
+            &self.options.cost_model,
+        ))
+    }
+}
+
+// Fails if no edges are left after filtering, which usually means the profile doesn't match
+// the extract, so it's reported here instead of as every query finding no path.
+fn ensure_has_edges(graph: Graph, options: &ImportOptions) -> anyhow::Result<Graph> {
+    if graph.num_edges() == 0 {
+        anyhow::bail!(
+            "the graph has no edges: none of the ways are routable with the {:?} profile{}",
+            options.profile,
+            if options.bbox.is_some() {
+                " inside the bounding box"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(graph)
+}
+
+// Builds the graph, returning it with the number of way refs to missing nodes that were skipped.
+fn build_graph(
+    parse_result: PBFParseResult,
+    intersections_map: MultiMap<i64, i64>,
+    options: &ImportOptions,
+) -> (Graph, usize) {
+    let (parse_result, intersections_map, missing_refs) =
+        split_at_missing_refs(parse_result, intersections_map);
+    let (parse_result, intersections_map) = match options.bbox {
+        Some(bbox) => clip_to_bbox(parse_result, intersections_map, bbox),
+        None => (parse_result, intersections_map),
+    };
 
     let nodes = build_nodes(&parse_result.osm_id_to_node);
-    let build_edge_lists_result = build_edge_lists(parse_result, &nodes, intersections_map);
+    let build_edge_lists_result =
+        build_edge_lists(parse_result, &nodes, intersections_map, options);
 
-    Ok(Graph {
+    let mut graph = Graph {
         fwd_edge_list: build_edge_lists_result.fwd_edge_list,
         bwd_edge_list: build_edge_lists_result.bwd_edge_list,
         edges: build_edge_lists_result.edges,
         edge_metadata: build_edge_lists_result.edge_metadata,
         nodes,
-    })
+    };
+    // Nodes only referenced by ways that were dropped or cut are left without edges.
+    graph.compact();
+
+    if let Some(min_edge_weight) = options.min_edge_weight {
+        graph.clamp_weights(min_edge_weight);
+    }
+
+    (graph, missing_refs)
+}
+
+// Gets an id below every way id in the map, for ways split off during the import.
+fn next_synthetic_way_id(ways: &BTreeMap<i64, WayParseData>) -> i64 {
+    ways.keys().next().map_or(-1, |&id| id.min(0) - 1)
+}
+
+// Splits the ways at refs to nodes that are missing from the parsed nodes, as with clipped
+// extracts, dropping pieces too short for an edge. Ferries are dropped instead, since a piece of
+// a ferry route doesn't end at a terminal. Returns the number of missing refs.
+fn split_at_missing_refs(
+    maps: PBFParseResult,
+    intersections_map: MultiMap<i64, i64>,
+) -> (PBFParseResult, MultiMap<i64, i64>, usize) {
+    let PBFParseResult {
+        osm_id_to_node,
+        ways,
+    } = maps;
+    let mut split_ways = BTreeMap::new();
+    let mut split_intersections = MultiMap::new();
+    let mut next_synthetic_way_id = next_synthetic_way_id(&ways);
+    let mut missing_refs = 0;
+
+    for (way_id, way_data) in ways {
+        let is_present = |id: &i64| osm_id_to_node.contains_key(id);
+        if way_data.refs.iter().all(is_present) {
+            if let Some(ids) = intersections_map.get_vec(&way_id) {
+                split_intersections.insert_many_from_slice(way_id, ids);
+            }
+            split_ways.insert(way_id, way_data);
+            continue;
+        }
+
+        missing_refs += way_data.refs.iter().filter(|id| !is_present(id)).count();
+        if way_data.ferry_duration_s.is_some() {
+            continue;
+        }
+
+        let pieces = way_data
+            .refs
+            .split(|id| !is_present(id))
+            .filter(|piece| piece.len() >= 2)
+            .map(<[i64]>::to_vec)
+            .collect_vec();
+
+        let way_intersections = intersections_map.get_vec(&way_id);
+        for (i, piece) in pieces.into_iter().enumerate() {
+            let piece_id = if i == 0 {
+                way_id
+            } else {
+                let id = next_synthetic_way_id;
+                next_synthetic_way_id -= 1;
+                id
+            };
+
+            // The cut ends become dead ends, they split the piece like the way's intersections.
+            if let Some(way_intersections) = way_intersections {
+                let ends = [piece[0], piece[piece.len() - 1]];
+                for id in &piece {
+                    if ends.contains(id) || way_intersections.contains(id) {
+                        split_intersections.insert(piece_id, *id);
+                    }
+                }
+            }
+
+            let mut piece_data = way_data.clone();
+            piece_data.id = piece_id;
+            piece_data.refs = piece;
+            split_ways.insert(piece_id, piece_data);
+        }
+    }
+
+    (
+        PBFParseResult {
+            osm_id_to_node,
+            ways: split_ways,
+        },
+        split_intersections,
+        missing_refs,
+    )
 }
 
 fn parse_polyline_data(way_data: &WayParseData) -> Vec<i64> {
     way_data.refs.to_vec()
 }
 
-fn calc_weight_with_turn(prev_id: i64, curr_id: i64, next_id: i64, maps: &PBFParseResult) -> f32 {
+// Gets the distance in meters between two nodes, as configured by the options.
+fn calc_distance(curr: &NodeParseData, next: &NodeParseData, options: &ImportOptions) -> f32 {
+    if options.planar_distances {
+        utils::fast_distance(curr.lat, curr.lon, next.lat, next.lon)
+    } else {
+        utils::haversine_distance(curr.lat, curr.lon, next.lat, next.lon)
+    }
+}
+
+fn calc_weight_with_turn(
+    prev_id: i64,
+    curr_id: i64,
+    next_id: i64,
+    way_data: &WayParseData,
+    maps: &PBFParseResult,
+    options: &ImportOptions,
+) -> f32 {
     let prev = maps.osm_id_to_node.get(&prev_id).unwrap();
     let curr = maps.osm_id_to_node.get(&curr_id).unwrap();
     let next: &NodeParseData = maps.osm_id_to_node.get(&next_id).unwrap();
 
-    let dist = utils::haversine_distance(curr.lat, curr.lon, next.lat, next.lon);
+    let cost_model = &options.cost_model;
+    let dist = calc_distance(curr, next, options);
     let turn_cost =
         utils::calc_turn_cost(prev.lat, prev.lon, curr.lat, curr.lon, next.lat, next.lon);
 
-    turn_cost * dist
+    calc_travel_time(dist, way_data, options) + cost_model.turn_penalty(turn_cost)
 }
 
-fn calc_weight_without_turn(curr_id: i64, next_id: i64, maps: &PBFParseResult) -> f32 {
+fn calc_weight_without_turn(
+    curr_id: i64,
+    next_id: i64,
+    way_data: &WayParseData,
+    maps: &PBFParseResult,
+    options: &ImportOptions,
+) -> f32 {
     let curr = maps.osm_id_to_node.get(&curr_id).unwrap();
     let next: &NodeParseData = maps.osm_id_to_node.get(&next_id).unwrap();
 
-    utils::haversine_distance(curr.lat, curr.lon, next.lat, next.lon)
+    let dist = calc_distance(curr, next, options);
+    calc_travel_time(dist, way_data, options)
+}
+
+// Gets the time it takes to travel `dist` meters along the way.
+fn calc_travel_time(dist: f32, way_data: &WayParseData, options: &ImportOptions) -> f32 {
+    let cost_model = &options.cost_model;
+    let factor = cost_model.surface_factor(
+        way_data.surface.as_deref(),
+        way_data.smoothness.as_deref(),
+        options.profile,
+    ) * cost_model.road_class_factor(way_data.road_class)
+        * calc_reversible_factor(way_data, options)
+        * calc_destination_factor(way_data, options);
+
+    cost_model.travel_time(
+        dist,
+        way_data.max_speed,
+        way_data.road_class,
+        options.profile,
+    ) * factor
+}
+
+// Gets the weight factor of a way the profile may only use to reach a destination on it.
+fn calc_destination_factor(way_data: &WayParseData, options: &ImportOptions) -> f32 {
+    match options.profile.resolve_access(&way_data.access) {
+        Access::Destination => options.destination_penalty,
+        _ => 1.0,
+    }
+}
+
+// Gets the travel time factor of a reversible way imported as two-way.
+fn calc_reversible_factor(way_data: &WayParseData, options: &ImportOptions) -> f32 {
+    match options.reversible {
+        ReversiblePolicy::TwoWay(factor)
+            if way_data.is_reversible && options.profile.respects_oneway() =>
+        {
+            factor
+        }
+        _ => 1.0,
+    }
+}
+
+// Gets the time lost at traffic lights passing the given way nodes in order. The first node is
+// skipped, so a traffic light at a junction is only charged to the edges arriving at it.
+fn calc_traffic_light_penalty(refs: &[i64], maps: &PBFParseResult, cost_model: &CostModel) -> f32 {
+    let num_traffic_lights = refs
+        .iter()
+        .skip(1)
+        .filter(|id| {
+            maps.osm_id_to_node
+                .get(id)
+                .is_some_and(|n| n.is_traffic_signal)
+        })
+        .count();
+    cost_model.traffic_light_penalty(num_traffic_lights)
+}
+
+// Gets the extra weight of passing the given way nodes in order, or None if a barrier on one of
+// them blocks the profile. Like traffic lights, the first node is not charged, so a barrier on a
+// node shared by two spans is only paid once.
+fn calc_barrier_penalty(refs: &[i64], maps: &PBFParseResult, profile: Profile) -> Option<f32> {
+    refs.iter()
+        .enumerate()
+        .filter_map(|(i, id)| {
+            maps.osm_id_to_node
+                .get(id)
+                .and_then(|n| n.barrier)
+                .map(|barrier| (i, barrier))
+        })
+        .try_fold(0.0, |acc, (i, barrier)| {
+            let penalty = profile.barrier_penalty(barrier)?;
+            Some(if i == 0 { acc } else { acc + penalty })
+        })
+}
+
+// Gets the time lost at the traffic lights and barriers along the given way nodes, passing them
+// in order and in reverse, or None if a barrier blocks the profile. Each direction is charged at
+// the nodes it arrives at, so the two directions of a span differ when only one end has a light.
+fn calc_node_penalties(
+    refs: &[i64],
+    maps: &PBFParseResult,
+    options: &ImportOptions,
+) -> Option<(f32, f32)> {
+    let penalty = |refs: &[i64]| {
+        Some(
+            calc_barrier_penalty(refs, maps, options.profile)?
+                + calc_traffic_light_penalty(refs, maps, &options.cost_model),
+        )
+    };
+    let reversed: Vec<i64> = refs.iter().rev().copied().collect();
+    Some((penalty(refs)?, penalty(&reversed)?))
+}
+
+// Finds the position of each of `ids` in `refs`, where `ids` is an ordered subsequence of `refs`,
+// or None if one of them is missing.
+fn find_ref_positions(refs: &[i64], ids: &[i64]) -> Option<Vec<usize>> {
+    let mut positions = Vec::with_capacity(ids.len());
+    let mut cursor = 0;
+    for id in ids {
+        cursor += refs[cursor..].iter().position(|r| r == id)?;
+        positions.push(cursor);
+    }
+    Some(positions)
+}
+
+// Gets the length in meters of the way along the given nodes.
+fn calc_span_distance(refs: &[i64], maps: &PBFParseResult, options: &ImportOptions) -> f32 {
+    refs.iter()
+        .map(|id| maps.osm_id_to_node.get(id).unwrap())
+        .tuple_windows()
+        .map(|(curr, next)| calc_distance(curr, next, options))
+        .sum()
+}
+
+// Creates the metadata of an edge along the way. The travel time is taken from the distance and
+// the resolved speed of the way, or the duration of a ferry, whatever the weight is.
+fn create_way_metadata(
+    way_data: &WayParseData,
+    weight: f32,
+    distance_m: f32,
+    options: &ImportOptions,
+) -> EdgeMetadata {
+    let time_s = way_data.ferry_duration_s.unwrap_or_else(|| {
+        options.cost_model.travel_time(
+            distance_m,
+            way_data.max_speed,
+            way_data.road_class,
+            options.profile,
+        )
+    });
+    EdgeMetadata {
+        weight,
+        alt_weight: distance_m,
+        distance_m,
+        time_s,
+        time_factor: 1.0,
+        is_one_way: way_data.is_oneway,
+        is_roundabout: way_data.is_roundabout,
+        roundabout: None,
+        name: way_data.name.clone(),
+        names: way_data.names.clone(),
+        speed_limit: way_data.max_speed,
+        road_class: way_data.road_class,
+        max_height_m: way_data.max_height_m,
+        max_weight_t: way_data.max_weight_t,
+        max_width_m: way_data.max_width_m,
+        is_ferry: way_data.ferry_duration_s.is_some(),
+        is_reversible: way_data.is_reversible,
+        prev_edge: None,
+        next_edge: None,
+        geometry: Vec::new(),
+        hops: 1,
+        way_id: Some(way_data.id),
+    }
 }
 
 fn build_edge_lists(
     maps: PBFParseResult,
     nodes: &[Node],
     intersections_map: MultiMap<i64, i64>,
+    options: &ImportOptions,
 ) -> BuildEdgeListResult {
     let osm_to_dense: BTreeMap<i64, usize> = nodes.iter().map(|n| (n.osm_id, n.dense_id)).collect();
-    let mut fwd_edge_list: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
-    let mut bwd_edge_list: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
-    let mut edge_metadata: Vec<EdgeMetadata> = Vec::new();
-    let mut edges: Vec<Edge> = Vec::new();
+    let profile = options.profile;
+    let mut result = BuildEdgeListResult {
+        fwd_edge_list: vec![Vec::new(); nodes.len()],
+        bwd_edge_list: vec![Vec::new(); nodes.len()],
+        edges: Vec::new(),
+        edge_metadata: Vec::new(),
+    };
 
     for way_data in maps.ways.values() {
         if way_data.refs.is_empty() {
             continue;
         }
 
-        if way_data.is_roundabout {
+        // Ways closed to the profile, e.g. foot=no, were already dropped, so the profile only
+        // decides whether the oneway tag applies.
+        let is_two_way =
+            !(way_data.is_oneway || way_data.is_roundabout) || !profile.respects_oneway();
+
+        if let Some(duration_s) = way_data.ferry_duration_s {
+            // Ferries only stop at their terminals, so a single edge spans the whole route. A
+            // oneway ferry only sails one way, whoever is on board. Without a terminal it can't
+            // be boarded, so it's skipped.
+            let (Some(first_node), Some(last_node)) = (
+                way_data.refs.first().and_then(|id| osm_to_dense.get(id)),
+                way_data.refs.last().and_then(|id| osm_to_dense.get(id)),
+            ) else {
+                continue;
+            };
+            result.add_edges(
+                *first_node,
+                *last_node,
+                create_way_metadata(
+                    way_data,
+                    duration_s,
+                    calc_span_distance(&way_data.refs, &maps, options),
+                    options,
+                ),
+                (!way_data.is_oneway).then_some(duration_s),
+            );
+        } else if way_data.is_roundabout {
+            // Every node of a roundabout is kept, so the edges follow the way in driving order
+            // and their index along it is their position on the roundabout.
             let polyline_data = parse_polyline_data(way_data);
-            for (curr_id, next_id) in polyline_data.iter().tuple_windows() {
-                let weight = calc_weight_without_turn(*curr_id, *next_id, &maps);
+            for (index, (curr_id, next_id)) in polyline_data.iter().tuple_windows().enumerate() {
+                let Some((fwd_penalty, bwd_penalty)) =
+                    calc_node_penalties(&[*curr_id, *next_id], &maps, options)
+                else {
+                    continue;
+                };
+                let weight = calc_weight_without_turn(*curr_id, *next_id, way_data, &maps, options);
                 let curr_node = osm_to_dense.get(curr_id).unwrap();
                 let next_node = osm_to_dense.get(next_id).unwrap();
 
-                let metadata_index = edge_metadata.len();
-                let metadata = EdgeMetadata {
-                    weight,
-                    is_one_way: way_data.is_oneway,
-                    is_roundabout: way_data.is_roundabout,
-                    name: way_data.name.clone(),
-                    speed_limit: way_data.max_speed,
-                    prev_edge: None,
-                    next_edge: None,
-                };
-                edge_metadata.push(metadata);
-
-                let edge_index_fwd = edges.len();
-                edges.push(Edge::new(*curr_node, *next_node, metadata_index));
-                fwd_edge_list[*curr_node].push(edge_index_fwd);
-                bwd_edge_list[*next_node].push(edge_index_fwd);
-
-                if !(way_data.is_oneway || way_data.is_roundabout) && curr_id != next_id {
-                    let edge_index_bwd = edges.len();
-                    edges.push(Edge::new(*next_node, *curr_node, metadata_index));
-                    fwd_edge_list[*next_node].push(edge_index_bwd);
-                    bwd_edge_list[*curr_node].push(edge_index_bwd);
-                }
+                result.add_edges(
+                    *curr_node,
+                    *next_node,
+                    EdgeMetadata {
+                        roundabout: Some(RoundaboutPosition {
+                            id: way_data.id,
+                            index: index as u32,
+                        }),
+                        ..create_way_metadata(
+                            way_data,
+                            weight + fwd_penalty,
+                            calc_span_distance(&[*curr_id, *next_id], &maps, options),
+                            options,
+                        )
+                    },
+                    is_two_way.then_some(weight + bwd_penalty),
+                );
             }
         } else {
             let osm_ids = intersections_map.get_vec(&way_data.id);
             if let Some(osm_ids) = osm_ids {
-                for (prev_id, curr_id, next_id) in osm_ids.iter().tuple_windows() {
-                    let weight = calc_weight_with_turn(*prev_id, *curr_id, *next_id, &maps);
+                // The intersections are found from the refs, so a missing one means the way is
+                // inconsistent, and its spans can't be trusted.
+                let Some(positions) = find_ref_positions(&way_data.refs, osm_ids) else {
+                    continue;
+                };
+                for ((prev_id, curr_id, next_id), (prev_pos, _, next_pos)) in osm_ids
+                    .iter()
+                    .tuple_windows()
+                    .zip(positions.iter().tuple_windows())
+                {
+                    let span = &way_data.refs[*prev_pos..=*next_pos];
+                    let Some((fwd_penalty, bwd_penalty)) =
+                        calc_node_penalties(span, &maps, options)
+                    else {
+                        continue;
+                    };
+                    let weight = calc_weight_with_turn(
+                        *prev_id, *curr_id, *next_id, way_data, &maps, options,
+                    );
                     let prev_node = osm_to_dense.get(prev_id).unwrap();
                     let next_node = osm_to_dense.get(next_id).unwrap();
 
-                    let metadata_index = edge_metadata.len();
-                    let metadata = EdgeMetadata {
-                        weight,
-                        is_one_way: way_data.is_oneway,
-                        is_roundabout: way_data.is_roundabout,
-                        name: way_data.name.clone(),
-                        speed_limit: way_data.max_speed,
-                        prev_edge: None,
-                        next_edge: None,
-                    };
-                    edge_metadata.push(metadata);
-
-                    let edge_index_fwd = edges.len();
-                    edges.push(Edge::new(*prev_node, *next_node, metadata_index));
-                    fwd_edge_list[*prev_node].push(edge_index_fwd);
-                    bwd_edge_list[*next_node].push(edge_index_fwd);
-
-                    if !way_data.is_oneway {
-                        let edge_index_bwd = edges.len();
-                        edges.push(Edge::new(*next_node, *prev_node, metadata_index));
-                        fwd_edge_list[*next_node].push(edge_index_bwd);
-                        bwd_edge_list[*prev_node].push(edge_index_bwd);
-                    }
+                    result.add_edges(
+                        *prev_node,
+                        *next_node,
+                        create_way_metadata(
+                            way_data,
+                            weight + fwd_penalty,
+                            calc_span_distance(span, &maps, options),
+                            options,
+                        ),
+                        is_two_way.then_some(weight + bwd_penalty),
+                    );
                 }
             } else {
                 let polyline_data = parse_polyline_data(way_data);
@@ -161,44 +906,222 @@ fn build_edge_lists(
                 let next_id = polyline_data.last();
 
                 if let (Some(curr_id), Some(next_id)) = (curr_id, next_id) {
-                    let weight = calc_weight_without_turn(*curr_id, *next_id, &maps);
+                    let Some((fwd_penalty, bwd_penalty)) =
+                        calc_node_penalties(&polyline_data, &maps, options)
+                    else {
+                        continue;
+                    };
+                    let weight =
+                        calc_weight_without_turn(*curr_id, *next_id, way_data, &maps, options);
                     let curr_node = osm_to_dense.get(curr_id).unwrap();
                     let next_node = osm_to_dense.get(next_id).unwrap();
 
-                    let metadata_index = edge_metadata.len();
-                    let metadata = EdgeMetadata {
-                        weight,
-                        is_one_way: way_data.is_oneway,
-                        is_roundabout: way_data.is_roundabout,
-                        name: way_data.name.clone(),
-                        speed_limit: way_data.max_speed,
-                        prev_edge: None,
-                        next_edge: None,
-                    };
-                    edge_metadata.push(metadata);
-
-                    let edge_index_fwd = edges.len();
-                    edges.push(Edge::new(*curr_node, *next_node, metadata_index));
-                    fwd_edge_list[*curr_node].push(edge_index_fwd);
-                    bwd_edge_list[*next_node].push(edge_index_fwd);
-
-                    if !(way_data.is_oneway || way_data.is_roundabout) && curr_id != next_id {
-                        let edge_index_bwd = edges.len();
-                        edges.push(Edge::new(*next_node, *curr_node, metadata_index));
-                        fwd_edge_list[*next_node].push(edge_index_bwd);
-                        bwd_edge_list[*curr_node].push(edge_index_bwd);
-                    }
+                    result.add_edges(
+                        *curr_node,
+                        *next_node,
+                        create_way_metadata(
+                            way_data,
+                            weight + fwd_penalty,
+                            calc_span_distance(&polyline_data, &maps, options),
+                            options,
+                        ),
+                        is_two_way.then_some(weight + bwd_penalty),
+                    );
                 }
             }
         }
     }
 
-    BuildEdgeListResult {
-        fwd_edge_list,
-        bwd_edge_list,
-        edges,
-        edge_metadata,
+    result
+}
+
+impl BuildEdgeListResult {
+    // Adds the edge src -> dest, and dest -> src weighing `bwd_weight` if set. The reverse edge
+    // shares `metadata` unless its weight differs, e.g. with a traffic light at only one end.
+    // Self-loops, e.g. from a way whose refs repeat a node or that ends where it started,
+    // are skipped since they never lie on a shortest path.
+    fn add_edges(
+        &mut self,
+        src: usize,
+        dest: usize,
+        metadata: EdgeMetadata,
+        bwd_weight: Option<f32>,
+    ) {
+        if src == dest {
+            return;
+        }
+
+        let metadata_index = self.edge_metadata.len();
+        let bwd_metadata = bwd_weight
+            .filter(|&weight| weight != metadata.weight)
+            .map(|weight| EdgeMetadata {
+                weight,
+                ..metadata.clone()
+            });
+        self.edge_metadata.push(metadata);
+
+        let edge_index_fwd = self.edges.len();
+        self.edges.push(Edge::new(src, dest, metadata_index));
+        self.fwd_edge_list[src].push(edge_index_fwd);
+        self.bwd_edge_list[dest].push(edge_index_fwd);
+
+        if bwd_weight.is_some() {
+            let metadata_index = match bwd_metadata {
+                Some(bwd_metadata) => {
+                    self.edge_metadata.push(bwd_metadata);
+                    self.edge_metadata.len() - 1
+                }
+                None => metadata_index,
+            };
+            let edge_index_bwd = self.edges.len();
+            self.edges.push(Edge::new(dest, src, metadata_index));
+            self.fwd_edge_list[dest].push(edge_index_bwd);
+            self.bwd_edge_list[src].push(edge_index_bwd);
+        }
+    }
+}
+
+fn bbox_contains(bbox: (f64, f64, f64, f64), node: &NodeParseData) -> bool {
+    let (min_lat, min_lon, max_lat, max_lon) = bbox;
+    (min_lat..=max_lat).contains(&node.lat) && (min_lon..=max_lon).contains(&node.lon)
+}
+
+// Finds where the segment from an inside node to an outside node crosses the box boundary.
+fn calc_bbox_crossing(
+    bbox: (f64, f64, f64, f64),
+    inside: &NodeParseData,
+    outside: &NodeParseData,
+) -> (f64, f64) {
+    let (min_lat, min_lon, max_lat, max_lon) = bbox;
+    let d_lat = outside.lat - inside.lat;
+    let d_lon = outside.lon - inside.lon;
+
+    // The fraction of the segment that can be travelled before leaving the box on each axis.
+    let t_for = |d: f64, from: f64, min: f64, max: f64| {
+        if d > 0.0 {
+            (max - from) / d
+        } else if d < 0.0 {
+            (min - from) / d
+        } else {
+            1.0
+        }
+    };
+    let t = t_for(d_lat, inside.lat, min_lat, max_lat)
+        .min(t_for(d_lon, inside.lon, min_lon, max_lon))
+        .clamp(0.0, 1.0);
+
+    // Rounding can put the crossing just outside the box, where it would be dropped with the
+    // outside nodes.
+    (
+        (inside.lat + t * d_lat).clamp(min_lat, max_lat),
+        (inside.lon + t * d_lon).clamp(min_lon, max_lon),
+    )
+}
+
+// Drops the nodes outside the box and clips the ways to the parts inside it. A way that leaves the
+// box is split, and a synthetic node is created where it crosses the boundary. Synthetic nodes and
+// the extra way pieces get negative ids, which are never used by OSM extracts.
+fn clip_to_bbox(
+    maps: PBFParseResult,
+    intersections_map: MultiMap<i64, i64>,
+    bbox: (f64, f64, f64, f64),
+) -> (PBFParseResult, MultiMap<i64, i64>) {
+    let PBFParseResult {
+        mut osm_id_to_node,
+        ways,
+    } = maps;
+    let mut clipped_ways = BTreeMap::new();
+    let mut clipped_intersections = MultiMap::new();
+    let mut next_synthetic_node_id = -1;
+    let mut next_synthetic_way_id = next_synthetic_way_id(&ways);
+
+    for (way_id, way_data) in ways {
+        let is_inside = |id: &i64| {
+            osm_id_to_node
+                .get(id)
+                .is_some_and(|n| bbox_contains(bbox, n))
+        };
+
+        if way_data.refs.iter().all(is_inside) {
+            if let Some(ids) = intersections_map.get_vec(&way_id) {
+                clipped_intersections.insert_many_from_slice(way_id, ids);
+            }
+            clipped_ways.insert(way_id, way_data);
+            continue;
+        }
+
+        let mut synthetic_nodes = Vec::new();
+        let mut pieces: Vec<Vec<i64>> = Vec::new();
+        let mut current: Vec<i64> = Vec::new();
+        let mut add_crossing = |inside_id: i64, outside_id: i64, piece: &mut Vec<i64>| {
+            if let (Some(inside), Some(outside)) = (
+                osm_id_to_node.get(&inside_id),
+                osm_id_to_node.get(&outside_id),
+            ) {
+                let (lat, lon) = calc_bbox_crossing(bbox, inside, outside);
+                let node_data = NodeParseData {
+                    lat,
+                    lon,
+                    is_traffic_signal: false,
+                    barrier: None,
+                    is_turning_circle: false,
+                };
+                synthetic_nodes.push((next_synthetic_node_id, node_data));
+                piece.push(next_synthetic_node_id);
+                next_synthetic_node_id -= 1;
+            }
+        };
+
+        for (i, id) in way_data.refs.iter().enumerate() {
+            if is_inside(id) {
+                if current.is_empty() && i > 0 {
+                    add_crossing(*id, way_data.refs[i - 1], &mut current);
+                }
+                current.push(*id);
+            } else if let Some(&last) = current.last() {
+                add_crossing(last, *id, &mut current);
+                pieces.push(std::mem::take(&mut current));
+            }
+        }
+        pieces.push(current);
+
+        let way_intersections = intersections_map.get_vec(&way_id);
+        for (i, piece) in pieces.into_iter().filter(|p| p.len() >= 2).enumerate() {
+            let piece_id = if i == 0 {
+                way_id
+            } else {
+                let id = next_synthetic_way_id;
+                next_synthetic_way_id -= 1;
+                id
+            };
+
+            // The boundary crossings are the ends of the piece, so they also split it.
+            if let Some(way_intersections) = way_intersections {
+                for id in &piece {
+                    if *id < 0 || way_intersections.contains(id) {
+                        clipped_intersections.insert(piece_id, *id);
+                    }
+                }
+            }
+
+            let mut piece_data = way_data.clone();
+            piece_data.id = piece_id;
+            piece_data.refs = piece;
+            clipped_ways.insert(piece_id, piece_data);
+        }
+
+        osm_id_to_node.extend(synthetic_nodes);
     }
+
+    osm_id_to_node.retain(|_, n| bbox_contains(bbox, n));
+
+    (
+        PBFParseResult {
+            osm_id_to_node,
+            ways: clipped_ways,
+        },
+        clipped_intersections,
+    )
 }
 
 fn build_nodes(nodes_map: &BTreeMap<i64, NodeParseData>) -> Vec<Node> {
@@ -213,126 +1136,649 @@ fn build_nodes(nodes_map: &BTreeMap<i64, NodeParseData>) -> Vec<Node> {
             lat: data.lat,
             lon: data.lon,
             is_traffic_light: data.is_traffic_signal,
+            is_turning_circle: data.is_turning_circle,
+            is_barrier: data.barrier.is_some(),
         })
         .collect()
 }
 
-fn parse_way_name(way: &Way) -> Option<String> {
-    way.tags().find_map(|(k, v)| {
-        if k == "name:en" {
-            Some(v.to_owned())
+// Gets the name of a way from the first of `keys` that is tagged.
+fn parse_way_name(tags: &[(&str, &str)], languages: &[String], keys: &[String]) -> Option<String> {
+    languages
+        .iter()
+        .map(|lang| format!("name:{lang}"))
+        .chain(keys.iter().cloned())
+        .find_map(|key| parse_tag(tags, &key))
+}
+
+// Gets the local and English names of a way, `None` if it has neither.
+fn parse_way_names(tags: &[(&str, &str)]) -> Option<WayNames> {
+    let names = WayNames {
+        local: parse_tag(tags, "name"),
+        en: parse_tag(tags, "name:en"),
+    };
+    (names != WayNames::default()).then_some(names)
+}
+
+fn parse_way_max_speed(tags: &[(&str, &str)]) -> Option<u8> {
+    tags.iter().find_map(|&(k, v)| {
+        // A zero speed limit would make the travel time infinite.
+        if k == "maxspeed" {
+            v.parse().ok().filter(|&speed| speed > 0)
         } else {
             None
         }
     })
 }
 
-fn parse_way_max_speed(way: &Way) -> Option<u8> {
-    way.tags().find_map(|(k, v)| {
-        if k == "maxspeed" {
-            v.parse().ok()
+fn parse_way_road_class(tags: &[(&str, &str)]) -> RoadClass {
+    tags.iter()
+        .find(|&&(k, _)| k == "highway")
+        .map_or(RoadClass::Unknown, |&(_, v)| RoadClass::from_highway(v))
+}
+
+fn parse_node_barrier<'a>(mut tags: impl Iterator<Item = (&'a str, &'a str)>) -> Option<Barrier> {
+    tags.find_map(|(k, v)| {
+        if k == "barrier" {
+            Barrier::parse(v)
         } else {
             None
         }
     })
 }
 
-fn create_intersections_map(path: &str) -> anyhow::Result<MultiMap<i64, i64>> {
-    let reader = ElementReader::from_path(path)?;
+fn parse_way(
+    id: i64,
+    tags: &[(&str, &str)],
+    refs: Vec<i64>,
+    options: &ImportOptions,
+) -> WayParseData {
+    WayParseData {
+        id,
+        name: parse_way_name(tags, &options.languages, &options.name_keys),
+        names: options
+            .keep_both_names
+            .then(|| parse_way_names(tags))
+            .flatten(),
+        max_speed: parse_way_max_speed(tags),
+        road_class: parse_way_road_class(tags),
+        is_roundabout: tags.iter().any(|&(_, v)| v == "roundabout"),
+        is_oneway: tags.iter().any(|&(k, v)| k == "oneway" && v == "yes"),
+        is_reversible: tags
+            .iter()
+            .any(|&(k, v)| k == "oneway" && matches!(v, "reversible" | "alternating")),
+        access: AccessTags::from_tags(tags),
+        surface: parse_tag(tags, "surface"),
+        smoothness: parse_tag(tags, "smoothness"),
+        layer: parse_way_layer(tags),
+        ferry_duration_s: parse_way_ferry_duration(tags, options),
+        max_height_m: parse_tag(tags, "maxheight").and_then(|v| parse_dimension(&v)),
+        max_weight_t: parse_tag(tags, "maxweight").and_then(|v| parse_dimension(&v)),
+        max_width_m: parse_tag(tags, "maxwidth").and_then(|v| parse_dimension(&v)),
+        is_lifecycle: parse_way_lifecycle(tags),
+        refs,
+    }
+}
 
-    // Key = way_id, value = osm_id, in a multimap several values could be associated with a key
-    let mut intersections_map: MultiMap<i64, i64> = MultiMap::new();
-    let mut node_count = HashMap::new();
+// Checks if a way is tagged with a lifecycle state, either as its highway value or as the
+// prefix of a highway key. A prefixed key only counts without a highway tag in use, since e.g.
+// construction:highway=primary on a highway=secondary is an open road being upgraded.
+fn parse_way_lifecycle(tags: &[(&str, &str)]) -> bool {
+    match tags.iter().find(|&&(k, _)| k == "highway") {
+        Some(&(_, highway)) => LIFECYCLE_STATES.contains(&highway),
+        None => tags.iter().any(|&(k, _)| {
+            k.split_once(':')
+                .is_some_and(|(state, key)| key == "highway" && LIFECYCLE_STATES.contains(&state))
+        }),
+    }
+}
 
-    _ = reader.for_each(|elem| {
-        if let Element::Way(way) = elem {
-            let refs: Vec<i64> = way.refs().collect();
-            intersections_map.insert_many_from_slice(way.id(), &refs);
-            refs.iter().for_each(|id| {
-                *node_count.entry(*id).or_insert(0) += 1;
-            });
-        }
-    });
+// Gets the value of a tag, if present.
+fn parse_tag(tags: &[(&str, &str)], key: &str) -> Option<String> {
+    tags.iter()
+        .find(|&&(k, _)| k == key)
+        .map(|&(_, v)| v.to_string())
+}
 
-    let mut filtered_way_nodes = MultiMap::new();
-    for (way_id, nodes) in intersections_map {
-        for node in &nodes {
-            if node_count[node] > 1 {
-                filtered_way_nodes.insert(way_id, *node);
-            }
-        }
+// Gets the layer of a way, bridges and tunnels without a layer tag are taken to be one level
+// above or below the ground.
+fn parse_way_layer(tags: &[(&str, &str)]) -> i8 {
+    let tag = |key: &str| tags.iter().find(|&&(k, _)| k == key).map(|&(_, v)| v);
+    if let Some(layer) = tag("layer").and_then(|v| v.trim().parse().ok()) {
+        return layer;
     }
 
-    Ok(filtered_way_nodes)
+    match (tag("bridge"), tag("tunnel")) {
+        (Some(v), _) if v != "no" => 1,
+        (_, Some(v)) if v != "no" => -1,
+        _ => 0,
+    }
 }
 
-fn parse_osmpbf(path: &str) -> anyhow::Result<PBFParseResult> {
-    let reader = ElementReader::from_path(path)?;
-
-    // Map osm id -> (dense_index, lat, lon, is_traffic_signal)
-    let mut osm_id_to_node: BTreeMap<i64, NodeParseData> = BTreeMap::new();
-    let mut ways: BTreeMap<i64, WayParseData> = BTreeMap::new();
+// Gets the crossing duration of a route=ferry way, or None if the way isn't a ferry.
+fn parse_way_ferry_duration(tags: &[(&str, &str)], options: &ImportOptions) -> Option<f32> {
+    if !tags.contains(&("route", "ferry")) {
+        return None;
+    }
 
-    reader.for_each(|elem| match elem {
-        Element::DenseNode(node) => {
-            let is_traffic_signal = node.tags().any(|e| e.1 == "traffic_signals");
-            let node_data = NodeParseData {
-                dense_index: osm_id_to_node.len(),
-                lat: node.lat() as f32,
-                lon: node.lon() as f32,
-                is_traffic_signal,
-            };
-            osm_id_to_node.insert(node.id(), node_data);
-        }
-        Element::Node(node) => {
-            let is_traffic_signal = node.tags().any(|e| e.1 == "traffic_signals");
-            let node_data = NodeParseData {
-                dense_index: osm_id_to_node.len(),
-                lat: node.lat() as f32,
-                lon: node.lon() as f32,
-                is_traffic_signal,
-            };
-            osm_id_to_node.insert(node.id(), node_data);
-        }
-        Element::Way(way) => {
-            let name = parse_way_name(&way);
-            let max_speed = parse_way_max_speed(&way);
-            let is_oneway = way.tags().any(|(k, v)| k == "oneway" && v == "yes");
-            let is_roundabout = way.tags().any(|(_, v)| v == "roundabout");
-            let refs: Vec<i64> = way.refs().collect();
-
-            let way_data = WayParseData {
-                id: way.id(),
-                name,
-                max_speed,
-                is_roundabout,
-                is_oneway,
-                refs,
-            };
+    let duration_s = tags
+        .iter()
+        .find(|&&(k, _)| k == "duration")
+        .and_then(|&(_, v)| parse_duration(v))
+        .unwrap_or(options.ferry_duration_s);
+    Some(duration_s)
+}
 
-            ways.insert(way.id(), way_data);
+// Parses a maxheight, maxwidth or maxweight tag into meters or tonnes. Plain numbers are taken to
+// be in meters or tonnes already, feet may be given as 12'6" or with a unit like lengths in ft
+// and weights in kg or lbs. Values like "none" or "default" aren't limits.
+fn parse_dimension(value: &str) -> Option<f32> {
+    let value = value.trim();
+    if let Some((feet, inches)) = value.split_once('\'') {
+        let feet = feet.trim().parse::<f32>().ok()?;
+        let inches = inches.trim().trim_end_matches('"').trim();
+        let inches = if inches.is_empty() {
+            0.0
+        } else {
+            inches.parse::<f32>().ok()?
+        };
+        return Some((feet * 12.0 + inches) * 0.0254).filter(|v| v.is_finite() && *v > 0.0);
+    }
+
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let number = value[..split].parse::<f32>().ok()?;
+    let value = match value[split..].trim() {
+        "" | "m" | "t" => number,
+        "ft" => number * 0.3048,
+        "kg" => number / 1000.0,
+        "lbs" | "lb" => number * 0.000_453_592,
+        "st" => number * 0.907_185,
+        _ => return None,
+    };
+    Some(value).filter(|v| v.is_finite() && *v > 0.0)
+}
+
+// Parses a duration tag in seconds, either as "HH:MM", "HH:MM:SS" or a number of minutes.
+fn parse_duration(value: &str) -> Option<f32> {
+    let parts: Vec<f32> = value
+        .split(':')
+        .map(|part| part.trim().parse::<f32>().ok().filter(|p| p.is_finite()))
+        .collect::<Option<_>>()?;
+
+    match parts[..] {
+        [minutes] => Some(minutes * 60.0),
+        [hours, minutes] => Some(hours * 3600.0 + minutes * 60.0),
+        [hours, minutes, seconds] => Some(hours * 3600.0 + minutes * 60.0 + seconds),
+        _ => None,
+    }
+}
+
+// Checks if a way should be part of the graph built with the given options.
+fn is_way_routable(way_data: &WayParseData, options: &ImportOptions) -> bool {
+    if options.excluded_ways.contains(&way_data.id) {
+        return false;
+    }
+    if way_data.ferry_duration_s.is_some() && !options.ferries {
+        return false;
+    }
+    if way_data.is_lifecycle {
+        return false;
+    }
+    if way_data.is_reversible
+        && options.profile.respects_oneway()
+        && options.reversible == ReversiblePolicy::Exclude
+    {
+        return false;
+    }
+
+    options
+        .profile
+        .resolve_access(&way_data.access)
+        .is_routable()
+}
+
+// Counts the references of ways to each node, to tell which nodes are intersections.
+#[derive(Debug, Default)]
+struct NodeRefCounter {
+    respect_layers: bool,
+    // The number of references to each node, across all ways.
+    counts: HashMap<i64, u32>,
+    // The number of references to each node per layer, only counted when layers are respected.
+    layer_counts: HashMap<(i64, i8), u32>,
+    // The nodes a way starts or ends at, only collected when layers are respected.
+    endpoints: HashSet<i64>,
+}
+
+impl NodeRefCounter {
+    fn new(options: &ImportOptions) -> Self {
+        Self {
+            respect_layers: options.respect_layers,
+            ..Self::default()
+        }
+    }
+
+    fn add_way(&mut self, refs: &[i64], layer: i8) {
+        for id in refs {
+            *self.counts.entry(*id).or_insert(0) += 1;
+        }
+
+        if self.respect_layers {
+            for id in refs {
+                *self.layer_counts.entry((*id, layer)).or_insert(0) += 1;
+            }
+            self.endpoints
+                .extend(refs.first().into_iter().chain(refs.last()));
+        }
+    }
+
+    // Checks if a node splits a way on the given layer. With layers respected, a node shared by
+    // ways on different layers only counts when one of them starts or ends there, as a bridge
+    // joins the road at its ends.
+    fn is_intersection(&self, id: i64, layer: i8) -> bool {
+        let count = self.counts.get(&id).copied().unwrap_or(0);
+        if !self.respect_layers || count <= 1 {
+            return count > 1;
+        }
+
+        self.endpoints.contains(&id) || self.layer_counts.get(&(id, layer)).is_some_and(|&c| c > 1)
+    }
+}
+
+fn parse_node<'a>(
+    lat: f64,
+    lon: f64,
+    tags: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+) -> NodeParseData {
+    NodeParseData {
+        lat,
+        lon,
+        is_traffic_signal: tags.clone().any(|e| e.1 == "traffic_signals"),
+        is_turning_circle: tags
+            .clone()
+            .any(|(k, v)| k == "highway" && matches!(v, "turning_circle" | "turning_loop")),
+        barrier: parse_node_barrier(tags),
+    }
+}
+
+// Checks that a node's coordinates are in range, and not at (0, 0) if the options drop null
+// island. Haversine distances to a broken node would otherwise make huge edge weights.
+fn is_valid_coord(lat: f64, lon: f64, options: &ImportOptions) -> bool {
+    (-90.0..=90.0).contains(&lat)
+        && (-180.0..=180.0).contains(&lon)
+        && !(options.drop_null_island && lat == 0.0 && lon == 0.0)
+}
+
+// Keeps the nodes of each way that are intersections across all ways.
+fn filter_intersections(
+    ways: &BTreeMap<i64, WayParseData>,
+    node_refs: &NodeRefCounter,
+) -> MultiMap<i64, i64> {
+    let mut intersections_map = MultiMap::new();
+    for way_data in ways.values() {
+        for node in &way_data.refs {
+            if node_refs.is_intersection(*node, way_data.layer) {
+                intersections_map.insert(way_data.id, *node);
+            }
+        }
+    }
+
+    intersections_map
+}
+
+// Parses the file in two passes: the ways are read first, counting node references to find the
+// intersections, and then only the nodes referenced by routable ways are read.
+fn parse_osmpbf_streaming(
+    path: &str,
+    options: &ImportOptions,
+    report: &mut ImportReport,
+) -> anyhow::Result<(PBFParseResult, MultiMap<i64, i64>)> {
+    let mut reader = IndexedReader::from_path(path)?;
+
+    let mut osm_id_to_node: BTreeMap<i64, NodeParseData> = BTreeMap::new();
+    let mut ways: BTreeMap<i64, WayParseData> = BTreeMap::new();
+    let mut node_refs = NodeRefCounter::new(options);
+
+    reader.read_ways_and_deps(
+        |way| {
+            let tags: Vec<(&str, &str)> = way.tags().collect();
+            let way_data = parse_way(way.id(), &tags, way.refs().collect(), options);
+            node_refs.add_way(&way_data.refs, way_data.layer);
+            report.ways_read += 1;
+            let is_routable = is_way_routable(&way_data, options);
+            if is_routable {
+                report.ways_kept += 1;
+                ways.insert(way.id(), way_data);
+            }
+
+            is_routable
+        },
+        |elem| match elem {
+            Element::DenseNode(node) => {
+                report.nodes_read += 1;
+                if options.excluded_nodes.contains(&node.id()) {
+                    return;
+                }
+                if is_valid_coord(node.lat(), node.lon(), options) {
+                    osm_id_to_node
+                        .insert(node.id(), parse_node(node.lat(), node.lon(), node.tags()));
+                } else {
+                    report.invalid_coords += 1;
+                }
+            }
+            Element::Node(node) => {
+                report.nodes_read += 1;
+                if options.excluded_nodes.contains(&node.id()) {
+                    return;
+                }
+                if is_valid_coord(node.lat(), node.lon(), options) {
+                    osm_id_to_node
+                        .insert(node.id(), parse_node(node.lat(), node.lon(), node.tags()));
+                } else {
+                    report.invalid_coords += 1;
+                }
+            }
+            _ => {}
+        },
+    )?;
+
+    let intersections_map = filter_intersections(&ways, &node_refs);
+
+    Ok((
+        PBFParseResult {
+            osm_id_to_node,
+            ways,
+        },
+        intersections_map,
+    ))
+}
+
+// Parses an OSM XML file like `parse_osmpbf_streaming`. Relations are skipped, as they are for
+// PBF.
+fn parse_osm_xml(
+    path: &str,
+    options: &ImportOptions,
+    report: &mut ImportReport,
+) -> anyhow::Result<(PBFParseResult, MultiMap<i64, i64>)> {
+    let text = std::fs::read_to_string(path)?;
+    let document = roxmltree::Document::parse(&text)?;
+
+    let mut osm_id_to_node: BTreeMap<i64, NodeParseData> = BTreeMap::new();
+    let mut ways: BTreeMap<i64, WayParseData> = BTreeMap::new();
+    let mut node_refs = NodeRefCounter::new(options);
+
+    let attribute = |elem: roxmltree::Node, name: &str| -> anyhow::Result<String> {
+        elem.attribute(name).map(str::to_owned).ok_or_else(|| {
+            anyhow::anyhow!(
+                "{path}: <{}> at byte {} has no {name} attribute",
+                elem.tag_name().name(),
+                elem.range().start
+            )
+        })
+    };
+    let tags = |elem: roxmltree::Node<'_, '_>| -> anyhow::Result<Vec<(String, String)>> {
+        elem.children()
+            .filter(|child| child.has_tag_name("tag"))
+            .map(|tag| Ok((attribute(tag, "k")?, attribute(tag, "v")?)))
+            .collect()
+    };
+
+    for elem in document.root_element().children() {
+        match elem.tag_name().name() {
+            "node" => {
+                let id: i64 = attribute(elem, "id")?.parse()?;
+                report.nodes_read += 1;
+                if options.excluded_nodes.contains(&id) {
+                    continue;
+                }
+                let lat: f64 = attribute(elem, "lat")?.parse()?;
+                let lon: f64 = attribute(elem, "lon")?.parse()?;
+                if !is_valid_coord(lat, lon, options) {
+                    report.invalid_coords += 1;
+                    continue;
+                }
+                let tags = tags(elem)?;
+                let tags = tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                osm_id_to_node.insert(id, parse_node(lat, lon, tags));
+            }
+            "way" => {
+                let id: i64 = attribute(elem, "id")?.parse()?;
+                let refs = elem
+                    .children()
+                    .filter(|child| child.has_tag_name("nd"))
+                    .map(|nd| Ok(attribute(nd, "ref")?.parse()?))
+                    .collect::<anyhow::Result<Vec<i64>>>()?;
+                let tags = tags(elem)?;
+                let tags: Vec<(&str, &str)> =
+                    tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+
+                let way_data = parse_way(id, &tags, refs, options);
+                node_refs.add_way(&way_data.refs, way_data.layer);
+                report.ways_read += 1;
+                if is_way_routable(&way_data, options) {
+                    report.ways_kept += 1;
+                    ways.insert(id, way_data);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let intersections_map = filter_intersections(&ways, &node_refs);
+
+    Ok((
+        PBFParseResult {
+            osm_id_to_node,
+            ways,
+        },
+        intersections_map,
+    ))
+}
+
+// Reads the turn restrictions of a PBF file, see `parse_turn_restriction`.
+fn read_turn_restrictions_pbf(
+    path: &str,
+    profile: Profile,
+) -> anyhow::Result<Vec<TurnRestriction>> {
+    let reader = ElementReader::from_path(path)?;
+    let mut restrictions = Vec::new();
+
+    reader.for_each(|elem| {
+        if let Element::Relation(rel) = elem {
+            let tags: Vec<(&str, &str)> = rel.tags().collect();
+            let members: Vec<(&str, i64, &str)> = rel
+                .members()
+                .map(|member| {
+                    let member_type = match member.member_type {
+                        RelMemberType::Node => "node",
+                        RelMemberType::Way => "way",
+                        RelMemberType::Relation => "relation",
+                    };
+                    (member_type, member.member_id, member.role().unwrap_or(""))
+                })
+                .collect();
+            restrictions.extend(parse_turn_restriction(&tags, &members, profile));
         }
-        _ => {}
     })?;
 
-    Ok(PBFParseResult {
-        osm_id_to_node,
-        ways,
+    Ok(restrictions)
+}
+
+// Reads the turn restrictions of an OSM XML file, see `parse_turn_restriction`.
+fn read_turn_restrictions_xml(
+    path: &str,
+    profile: Profile,
+) -> anyhow::Result<Vec<TurnRestriction>> {
+    let text = std::fs::read_to_string(path)?;
+    let document = roxmltree::Document::parse(&text)?;
+    let mut restrictions = Vec::new();
+
+    for rel in document
+        .root_element()
+        .children()
+        .filter(|elem| elem.has_tag_name("relation"))
+    {
+        let tags: Vec<(&str, &str)> = rel
+            .children()
+            .filter(|child| child.has_tag_name("tag"))
+            .filter_map(|tag| Some((tag.attribute("k")?, tag.attribute("v")?)))
+            .collect();
+        let members: Vec<(&str, i64, &str)> = rel
+            .children()
+            .filter(|child| child.has_tag_name("member"))
+            .filter_map(|member| {
+                Some((
+                    member.attribute("type")?,
+                    member.attribute("ref")?.parse().ok()?,
+                    member.attribute("role").unwrap_or(""),
+                ))
+            })
+            .collect();
+        restrictions.extend(parse_turn_restriction(&tags, &members, profile));
+    }
+
+    Ok(restrictions)
+}
+
+// Parses a type=restriction relation from its tags and its (type, ref, role) members. Returns
+// `None` for other relations, unknown restriction values, restrictions via ways and those
+// `profile` is exempt from.
+fn parse_turn_restriction(
+    tags: &[(&str, &str)],
+    members: &[(&str, i64, &str)],
+    profile: Profile,
+) -> Option<TurnRestriction> {
+    if parse_tag(tags, "type")? != "restriction" {
+        return None;
+    }
+    if parse_tag(tags, "except").is_some_and(|except| profile.is_exempt(&except)) {
+        return None;
+    }
+    let restriction = parse_tag(tags, "restriction")?;
+    let kind = if restriction.starts_with("no_") {
+        RestrictionKind::No
+    } else if restriction.starts_with("only_") {
+        RestrictionKind::Only
+    } else {
+        return None;
+    };
+
+    let member = |member_type: &str, role: &str| {
+        let mut found = members
+            .iter()
+            .filter(|&&(_, _, r)| r == role)
+            .map(|&(t, id, _)| (t, id));
+        match (found.next(), found.next()) {
+            (Some((t, id)), None) if t == member_type => Some(id),
+            _ => None,
+        }
+    };
+
+    Some(TurnRestriction {
+        from_way: member("way", "from")?,
+        via_node: member("node", "via")?,
+        to_way: member("way", "to")?,
+        kind,
     })
 }
 
+// Parses the whole file in a single pass, counting the node references of every way on the way
+// to find the intersections.
+fn parse_osmpbf(
+    path: &str,
+    options: &ImportOptions,
+) -> anyhow::Result<(PBFParseResult, MultiMap<i64, i64>)> {
+    let reader = ElementReader::from_path(path)?;
+
+    let mut osm_id_to_node: BTreeMap<i64, NodeParseData> = BTreeMap::new();
+    let mut ways: BTreeMap<i64, WayParseData> = BTreeMap::new();
+    let mut node_refs = NodeRefCounter::new(options);
+
+    reader.for_each(|elem| match elem {
+        Element::DenseNode(node)
+            if !options.excluded_nodes.contains(&node.id())
+                && is_valid_coord(node.lat(), node.lon(), options) =>
+        {
+            osm_id_to_node.insert(node.id(), parse_node(node.lat(), node.lon(), node.tags()));
+        }
+        Element::Node(node)
+            if !options.excluded_nodes.contains(&node.id())
+                && is_valid_coord(node.lat(), node.lon(), options) =>
+        {
+            osm_id_to_node.insert(node.id(), parse_node(node.lat(), node.lon(), node.tags()));
+        }
+        Element::Way(way) => {
+            let tags: Vec<(&str, &str)> = way.tags().collect();
+            let way_data = parse_way(way.id(), &tags, way.refs().collect(), options);
+            node_refs.add_way(&way_data.refs, way_data.layer);
+
+            if is_way_routable(&way_data, options) {
+                ways.insert(way.id(), way_data);
+            }
+        }
+        _ => {}
+    })?;
+
+    let intersections_map = filter_intersections(&ways, &node_refs);
+
+    Ok((
+        PBFParseResult {
+            osm_id_to_node,
+            ways,
+        },
+        intersections_map,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::{csr::csr_graph::CSRGraph, preprocess::graph::GraphError};
+
+    static TEST_FILE_PATH: &str = "tests/data/nz-car-only.osm.pbf";
+
+    // The intersections as they were found before `parse_osmpbf` counted them, in a separate
+    // pass over every way.
+    fn create_intersections_map(
+        path: &str,
+        options: &ImportOptions,
+    ) -> anyhow::Result<MultiMap<i64, i64>> {
+        let reader = ElementReader::from_path(path)?;
+
+        // Key = way_id, value = (refs, layer)
+        let mut way_refs: BTreeMap<i64, (Vec<i64>, i8)> = BTreeMap::new();
+        let mut node_refs = NodeRefCounter::new(options);
+
+        _ = reader.for_each(|elem| {
+            if let Element::Way(way) = elem {
+                let refs: Vec<i64> = way.refs().collect();
+                let tags: Vec<(&str, &str)> = way.tags().collect();
+                let layer = parse_way_layer(&tags);
+                node_refs.add_way(&refs, layer);
+                way_refs.insert(way.id(), (refs, layer));
+            }
+        });
+
+        // Key = way_id, value = osm_id, in a multimap several values could be associated with a key
+        let mut filtered_way_nodes = MultiMap::new();
+        for (way_id, (nodes, layer)) in way_refs {
+            for node in &nodes {
+                if node_refs.is_intersection(*node, layer) {
+                    filtered_way_nodes.insert(way_id, *node);
+                }
+            }
+        }
 
-    static TEST_FILE_PATH: &str = "tests/data/nz-car-only.pbf.osm";
+        Ok(filtered_way_nodes)
+    }
 
     #[test]
     fn test_parse_osmpbf() {
         let target_node_id = 1439390172;
         let target_way_id = 1232194195;
 
-        let maps = parse_osmpbf(TEST_FILE_PATH).unwrap();
+        let (maps, _) = parse_osmpbf(TEST_FILE_PATH, &ImportOptions::default()).unwrap();
 
         let node = maps
             .osm_id_to_node
@@ -356,26 +1802,1458 @@ mod tests {
     }
 
     #[test]
-    fn test_build_nodes() {
-        let mut nodes_map: BTreeMap<i64, NodeParseData> = BTreeMap::new();
-        nodes_map.insert(
-            100,
-            NodeParseData {
-                dense_index: 0,
-                lat: 10.0,
-                lon: 20.0,
-                is_traffic_signal: false,
+    fn test_single_pass_matches_two_passes() {
+        for respect_layers in [false, true] {
+            let options = ImportOptions {
+                respect_layers,
+                ..ImportOptions::default()
+            };
+            let (parse_result, intersections_map) = parse_osmpbf(TEST_FILE_PATH, &options).unwrap();
+            let two_pass_map = create_intersections_map(TEST_FILE_PATH, &options).unwrap();
+
+            assert!(!parse_result.ways.is_empty());
+            for way_id in parse_result.ways.keys() {
+                assert_eq!(
+                    intersections_map.get_vec(way_id),
+                    two_pass_map.get_vec(way_id),
+                    "way {way_id}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_excluded_ids() {
+        // Way 385225599 is the only way from 733900601 to 733900686.
+        let (way_id, src, dest) = (385225599, 733900601, 733900686);
+        let has_edge = |graph: &Graph| {
+            edges_by_osm_id(graph)
+                .iter()
+                .any(|&(s, d, _)| (s, d) == (src, dest) || (s, d) == (dest, src))
+        };
+        let has_node = |graph: &Graph| graph.nodes.iter().any(|n| n.osm_id == src);
+
+        let graph = from_osmpbf(TEST_FILE_PATH).unwrap();
+        assert!(has_edge(&graph) && has_node(&graph));
+
+        let options = ImportOptions {
+            excluded_ways: HashSet::from([way_id]),
+            ..ImportOptions::default()
+        };
+        let graph = from_osmpbf_with_options(TEST_FILE_PATH, &options).unwrap();
+        assert!(!has_edge(&graph));
+        let in_memory = from_osmpbf_in_memory(TEST_FILE_PATH, &options).unwrap();
+        assert!(!has_edge(&in_memory));
+
+        let options = ImportOptions {
+            excluded_nodes: HashSet::from([src]),
+            ..ImportOptions::default()
+        };
+        let (graph, missing_refs) = from_osmpbf_counting_missing(TEST_FILE_PATH, &options).unwrap();
+        assert!(!has_edge(&graph) && !has_node(&graph));
+        assert!(missing_refs > 0);
+    }
+
+    #[test]
+    fn test_import_report() {
+        let options = ImportOptions::default();
+        let mut all_refs = HashSet::new();
+        let mut kept_refs = HashSet::new();
+        let (mut ways_read, mut ways_kept) = (0, 0);
+        ElementReader::from_path(TEST_FILE_PATH)
+            .unwrap()
+            .for_each(|elem| match elem {
+                Element::DenseNode(node) => {
+                    all_refs.insert(node.id());
+                }
+                Element::Node(node) => {
+                    all_refs.insert(node.id());
+                }
+                Element::Way(way) => {
+                    let tags: Vec<(&str, &str)> = way.tags().collect();
+                    let way_data = parse_way(way.id(), &tags, way.refs().collect(), &options);
+                    ways_read += 1;
+                    if is_way_routable(&way_data, &options) {
+                        ways_kept += 1;
+                        kept_refs.extend(way_data.refs);
+                    }
+                }
+                _ => {}
+            })
+            .unwrap();
+
+        let (graph, report) = from_osmpbf_with_report(TEST_FILE_PATH, &options).unwrap();
+        assert_eq!(report.ways_read, ways_read);
+        assert_eq!(report.ways_kept, ways_kept);
+        assert!(report.ways_kept < report.ways_read);
+        assert_eq!(report.nodes_read, kept_refs.intersection(&all_refs).count());
+        assert_eq!(report.edges_created, graph.num_edges());
+        assert_eq!(report.skipped_missing_nodes, 0);
+    }
+
+    #[test]
+    fn test_invalid_coords_rejected() {
+        // A street from 1 to 3 through a node with an out of range latitude, one from 4 to 6
+        // through null island and a valid one from 7 to 8.
+        let path =
+            std::env::temp_dir().join(format!("shepherd-coords-test-{}.osm", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+  <node id="1" lat="32.000" lon="34.000"/>
+  <node id="2" lat="95.000" lon="34.001"/>
+  <node id="3" lat="32.000" lon="34.002"/>
+  <node id="4" lat="32.001" lon="34.000"/>
+  <node id="5" lat="0" lon="0"/>
+  <node id="6" lat="32.001" lon="34.002"/>
+  <node id="7" lat="32.002" lon="34.000"/>
+  <node id="8" lat="32.002" lon="34.002"/>
+  <way id="10"><nd ref="1"/><nd ref="2"/><nd ref="3"/><tag k="highway" v="residential"/></way>
+  <way id="11"><nd ref="4"/><nd ref="5"/><nd ref="6"/><tag k="highway" v="residential"/></way>
+  <way id="12"><nd ref="7"/><nd ref="8"/><tag k="highway" v="residential"/></way>
+</osm>
+"#,
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let (graph, report) = from_osm_xml_with_report(path, &ImportOptions::default()).unwrap();
+        assert_eq!(report.nodes_read, 8);
+        assert_eq!(report.invalid_coords, 2);
+        assert!(report.skipped_missing_nodes >= 2);
+        assert!(graph.nodes.iter().all(|n| n.osm_id != 2 && n.osm_id != 5));
+        assert!(graph.edge_metadata.iter().all(|m| m.distance_m < 1000.0));
+
+        let options = ImportOptions {
+            drop_null_island: false,
+            ..ImportOptions::default()
+        };
+        let (_, report) = from_osm_xml_with_report(path, &options).unwrap();
+        assert_eq!(report.invalid_coords, 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_roundabout_positions() {
+        let path = std::env::temp_dir().join(format!(
+            "shepherd-roundabout-test-{}.osm",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+  <node id="1" lat="32.0000" lon="34.0000"/>
+  <node id="2" lat="32.0002" lon="34.0002"/>
+  <node id="3" lat="32.0000" lon="34.0004"/>
+  <node id="4" lat="31.9998" lon="34.0002"/>
+  <way id="50">
+    <nd ref="1"/><nd ref="4"/><nd ref="3"/><nd ref="2"/><nd ref="1"/>
+    <tag k="highway" v="primary"/>
+    <tag k="junction" v="roundabout"/>
+  </way>
+</osm>
+"#,
+        )
+        .unwrap();
+        let graph = from_file(path.to_str().unwrap(), &ImportOptions::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // One way around, in the order of the way.
+        let mut positions: Vec<(u32, i64, i64)> = graph
+            .edges
+            .iter()
+            .map(|edge| {
+                let position = graph.get_edge_metadata(edge).roundabout.unwrap();
+                assert_eq!(position.id, 50);
+                let osm_id = |id: usize| graph.nodes[id].osm_id;
+                (position.index, osm_id(edge.src_id), osm_id(edge.dest_id))
+            })
+            .collect();
+        positions.sort();
+        assert_eq!(positions, [(0, 1, 4), (1, 4, 3), (2, 3, 2), (3, 2, 1)]);
+    }
+
+    #[test]
+    fn test_from_osm_xml() {
+        let graph = from_file("tests/data/tiny.osm", &ImportOptions::default()).unwrap();
+
+        let pairs: Vec<(i64, i64)> = edges_by_osm_id(&graph)
+            .into_iter()
+            .map(|(src, dest, _)| (src, dest))
+            .collect();
+        // Every street crosses three others, the footway is closed to cars but still splits the
+        // rows, and the middle column is one way.
+        assert_eq!(
+            pairs,
+            [
+                (1, 3),
+                (1, 7),
+                (2, 8),
+                (3, 1),
+                (4, 6),
+                (6, 4),
+                (7, 1),
+                (7, 9),
+                (9, 7)
+            ]
+        );
+
+        let node = |osm_id: i64| graph.nodes.iter().find(|n| n.osm_id == osm_id).unwrap();
+        let edge = |src: i64, dest: i64| {
+            let edge = graph
+                .find_edge(node(src).dense_id, node(dest).dense_id)
+                .unwrap();
+            graph.get_edge_metadata(edge)
+        };
+        assert_eq!(edge(1, 3).name.as_deref(), Some("Rothschild & Sons"));
+        // The middle row passes the traffic light at node 5, the top row is otherwise the same.
+        assert!(edge(4, 6).weight > edge(7, 9).weight);
+    }
+
+    #[test]
+    fn test_read_turn_restrictions() {
+        // The route relation and the restriction via a way are skipped, and cars are exempt
+        // from the no_left_turn at node 7.
+        let no_right_turn = TurnRestriction {
+            from_way: 200,
+            via_node: 7,
+            to_way: 102,
+            kind: RestrictionKind::No,
+        };
+        let restrictions = read_turn_restrictions("tests/data/tiny.osm", Profile::Car).unwrap();
+        assert_eq!(restrictions, [no_right_turn]);
+        let restrictions = read_turn_restrictions("tests/data/tiny.osm", Profile::Bike).unwrap();
+        assert_eq!(
+            restrictions,
+            [
+                no_right_turn,
+                TurnRestriction {
+                    from_way: 102,
+                    via_node: 7,
+                    to_way: 200,
+                    kind: RestrictionKind::No,
+                }
+            ]
+        );
+
+        let members = [("way", 1, "from"), ("node", 2, "via"), ("way", 3, "to")];
+        let only = parse_turn_restriction(
+            &[("type", "restriction"), ("restriction", "only_straight_on")],
+            &members,
+            Profile::Car,
+        );
+        assert_eq!(only.map(|r| r.kind), Some(RestrictionKind::Only));
+        let unknown = parse_turn_restriction(
+            &[("type", "restriction"), ("restriction", "give_way")],
+            &members,
+            Profile::Car,
+        );
+        assert_eq!(unknown, None);
+        // Buses being exempt doesn't exempt cars.
+        let except_bus = parse_turn_restriction(
+            &[
+                ("type", "restriction"),
+                ("restriction", "no_left_turn"),
+                ("except", "bus;psv"),
+            ],
+            &members,
+            Profile::Car,
+        );
+        assert_eq!(except_bus.map(|r| r.kind), Some(RestrictionKind::No));
+    }
+
+    #[test]
+    fn test_build_edge_based() {
+        let config = ImportConfig::new("tests/data/tiny.osm");
+        let graph = config.build().unwrap();
+        let ebg = config.build_edge_based().unwrap();
+
+        assert_eq!(
+            ebg.graph.num_nodes(),
+            2 * graph.num_nodes() + graph.num_edges()
+        );
+        assert!((0..ebg.graph.num_edges()).all(|edge_id| ebg
+            .graph
+            .get_edge_metadata(ebg.graph.get_edge(edge_id))
+            .weight
+            >= 0.0));
+
+        // Turning right from 1 -> 7 onto 7 -> 9 is forbidden, while turning left from 9 -> 7
+        // onto 7 -> 1 is allowed since cars are exempt from the restriction against it.
+        let node = |osm_id: i64| graph.nodes.iter().position(|n| n.osm_id == osm_id).unwrap();
+        let edge_node = |src, dest| {
+            let edge_id = graph.find_edge_id(node(src), node(dest)).unwrap();
+            (0..ebg.graph.num_nodes())
+                .find(|&n| ebg.original_edge(n) == Some(edge_id))
+                .unwrap()
+        };
+        assert!(ebg
+            .graph
+            .find_edge(edge_node(1, 7), edge_node(7, 9))
+            .is_none());
+        assert!(ebg
+            .graph
+            .find_edge(edge_node(9, 7), edge_node(7, 1))
+            .is_some());
+        // Without restrictions the turn is there.
+        let unrestricted = EdgeBasedGraph::new(&graph, &[], &CostModel::default());
+        assert!(unrestricted
+            .graph
+            .find_edge(edge_node(1, 7), edge_node(7, 9))
+            .is_some());
+    }
+
+    // Writes the nodes and ways of a PBF file as OSM XML.
+    fn write_osm_xml(pbf_path: &str, xml_path: &Path) {
+        let escape = |s: &str| {
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+        };
+        let write_tags = |xml: &mut String, tags: &mut dyn Iterator<Item = (&str, &str)>| {
+            for (k, v) in tags {
+                xml.push_str(&format!("<tag k=\"{}\" v=\"{}\"/>", escape(k), escape(v)));
+            }
+        };
+
+        let mut xml =
+            String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<osm version=\"0.6\">\n");
+        ElementReader::from_path(pbf_path)
+            .unwrap()
+            .for_each(|elem| match elem {
+                Element::DenseNode(node) => {
+                    xml.push_str(&format!(
+                        "<node id=\"{}\" lat=\"{}\" lon=\"{}\">",
+                        node.id(),
+                        node.lat(),
+                        node.lon()
+                    ));
+                    write_tags(&mut xml, &mut node.tags());
+                    xml.push_str("</node>\n");
+                }
+                Element::Node(node) => {
+                    xml.push_str(&format!(
+                        "<node id=\"{}\" lat=\"{}\" lon=\"{}\">",
+                        node.id(),
+                        node.lat(),
+                        node.lon()
+                    ));
+                    write_tags(&mut xml, &mut node.tags());
+                    xml.push_str("</node>\n");
+                }
+                Element::Way(way) => {
+                    xml.push_str(&format!("<way id=\"{}\">", way.id()));
+                    for id in way.refs() {
+                        xml.push_str(&format!("<nd ref=\"{id}\"/>"));
+                    }
+                    write_tags(&mut xml, &mut way.tags());
+                    xml.push_str("</way>\n");
+                }
+                _ => {}
+            })
+            .unwrap();
+        xml.push_str("</osm>\n");
+        std::fs::write(xml_path, xml).unwrap();
+    }
+
+    #[test]
+    fn test_osm_xml_matches_pbf() {
+        let path =
+            std::env::temp_dir().join(format!("shepherd-xml-test-{}.osm", std::process::id()));
+        write_osm_xml(TEST_FILE_PATH, &path);
+
+        let options = ImportOptions::default();
+        let from_xml = from_file(path.to_str().unwrap(), &options).unwrap();
+        let from_pbf = from_file(TEST_FILE_PATH, &options).unwrap();
+        assert_eq!(edges_by_osm_id(&from_xml), edges_by_osm_id(&from_pbf));
+        assert_eq!(from_xml.num_nodes(), from_pbf.num_nodes());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_two_way_edges_share_metadata() {
+        let graph = from_file("tests/data/tiny.osm", &ImportOptions::default()).unwrap();
+
+        let mut num_two_way = 0;
+        for edge in &graph.edges {
+            if let Some(reverse) = graph.find_edge(edge.dest_id, edge.src_id) {
+                assert_eq!(edge.metadata_index, reverse.metadata_index);
+                num_two_way += 1;
+            }
+        }
+        // Every street of the grid but the oneway column is two way.
+        assert_eq!(num_two_way, graph.num_edges() - 1);
+        assert_eq!(graph.edge_metadata.len(), num_two_way / 2 + 1);
+    }
+
+    #[test]
+    fn test_import_config() {
+        let cost_model = CostModel {
+            traffic_light_penalty_s: 0.0,
+            ..CostModel::default()
+        };
+        let graph = ImportConfig::new("tests/data/tiny.osm")
+            .profile(Profile::Foot)
+            .cost_model(cost_model)
+            .name_keys(["ref"])
+            .build()
+            .unwrap();
+
+        let pairs: Vec<(i64, i64)> = edges_by_osm_id(&graph)
+            .into_iter()
+            .map(|(src, dest, _)| (src, dest))
+            .collect();
+        // The footway is walkable and walkers ignore the oneway.
+        assert!(pairs.contains(&(3, 9)) && pairs.contains(&(9, 3)));
+        assert!(pairs.contains(&(8, 2)));
+
+        let node = |osm_id: i64| graph.nodes.iter().find(|n| n.osm_id == osm_id).unwrap();
+        let edge = |src: i64, dest: i64| {
+            let edge = graph
+                .find_edge(node(src).dense_id, node(dest).dense_id)
+                .unwrap();
+            graph.get_edge_metadata(edge)
+        };
+        // Only the ref tag is read, and the traffic light at node 5 costs nothing.
+        assert_eq!(edge(1, 3).name, None);
+        assert!((edge(4, 6).weight - edge(7, 9).weight).abs() < 0.01);
+
+        // Simplifying drops shape points of the merged chains.
+        let num_shape_points =
+            |graph: &Graph| -> usize { graph.edge_metadata.iter().map(|m| m.geometry.len()).sum() };
+        let config = ImportConfig::new(TEST_FILE_PATH).compress_chains(true);
+        let compressed = config.build().unwrap();
+        let simplified = config.simplify(100.0).build().unwrap();
+        assert_eq!(compressed.num_edges(), simplified.num_edges());
+        assert!(num_shape_points(&simplified) < num_shape_points(&compressed));
+    }
+
+    #[test]
+    fn test_coords_keep_pbf_precision() {
+        let mut pbf_coords: HashMap<i64, (f64, f64)> = HashMap::new();
+        ElementReader::from_path(TEST_FILE_PATH)
+            .unwrap()
+            .for_each(|elem| match elem {
+                Element::DenseNode(node) => {
+                    pbf_coords.insert(node.id(), (node.lat(), node.lon()));
+                }
+                Element::Node(node) => {
+                    pbf_coords.insert(node.id(), (node.lat(), node.lon()));
+                }
+                _ => {}
+            })
+            .unwrap();
+
+        let graph = from_osmpbf(TEST_FILE_PATH).unwrap();
+        let csr = CSRGraph::from_preprocessed_graph(graph.clone());
+        for node in graph.nodes.iter().take(100) {
+            assert_eq!(node.get_lat_lon(), pbf_coords[&node.osm_id]);
+            let csr_node = &csr.nodes[node.dense_id];
+            assert_eq!((csr_node.lat, csr_node.lon), pbf_coords[&node.osm_id]);
+        }
+    }
+
+    // Gets the edges of a graph keyed by the osm ids of their endpoints.
+    fn edges_by_osm_id(graph: &Graph) -> Vec<(i64, i64, u32)> {
+        let mut edges: Vec<_> = graph
+            .edges
+            .iter()
+            .map(|e| {
+                (
+                    graph.get_node(e.src_id).osm_id,
+                    graph.get_node(e.dest_id).osm_id,
+                    graph.get_edge_metadata(e).weight.to_bits(),
+                )
+            })
+            .collect();
+        edges.sort();
+        edges
+    }
+
+    // An untagged node at the given coordinates.
+    fn node_at(lat: f64, lon: f64) -> NodeParseData {
+        NodeParseData {
+            lat,
+            lon,
+            is_traffic_signal: false,
+            barrier: None,
+            is_turning_circle: false,
+        }
+    }
+
+    // A way's id, tags and refs, before parsing.
+    type RawWay<'a> = (i64, &'a [(&'a str, &'a str)], Vec<i64>);
+
+    // The parse result of `nodes` and of the ways parsed from their id, tags and refs.
+    fn parse_result(
+        nodes: impl IntoIterator<Item = (i64, NodeParseData)>,
+        ways: &[RawWay],
+        options: &ImportOptions,
+    ) -> PBFParseResult {
+        PBFParseResult {
+            osm_id_to_node: nodes.into_iter().collect(),
+            ways: ways
+                .iter()
+                .map(|(id, tags, refs)| (*id, parse_way(*id, tags, refs.clone(), options)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_streaming_matches_in_memory() {
+        let options = ImportOptions::default();
+        let (streamed, streamed_intersections) =
+            parse_osmpbf_streaming(TEST_FILE_PATH, &options, &mut ImportReport::default()).unwrap();
+        let (in_memory, in_memory_intersections) = parse_osmpbf(TEST_FILE_PATH, &options).unwrap();
+
+        assert_eq!(
+            streamed.ways.keys().collect_vec(),
+            in_memory.ways.keys().collect_vec()
+        );
+        for way_id in streamed.ways.keys() {
+            assert_eq!(
+                streamed_intersections.get_vec(way_id),
+                in_memory_intersections.get_vec(way_id)
+            );
+        }
+
+        // Only the nodes used by ways are kept.
+        assert!(streamed.osm_id_to_node.len() <= in_memory.osm_id_to_node.len());
+        for way in streamed.ways.values() {
+            assert!(way
+                .refs
+                .iter()
+                .all(|id| streamed.osm_id_to_node.contains_key(id)));
+        }
+
+        let streamed_graph = from_osmpbf_with_options(TEST_FILE_PATH, &options).unwrap();
+        let in_memory_graph = from_osmpbf_in_memory(TEST_FILE_PATH, &options).unwrap();
+        assert!(streamed_graph.num_edges() > 0);
+        assert_eq!(
+            edges_by_osm_id(&streamed_graph),
+            edges_by_osm_id(&in_memory_graph)
+        );
+    }
+
+    #[test]
+    fn test_way_name_fallback() {
+        let options = ImportOptions::default();
+
+        let way = parse_way(
+            0,
+            &[("name", "Herbert Street"), ("name:en", "Herbert St")],
+            vec![],
+            &options,
+        );
+        assert_eq!(way.name.as_deref(), Some("Herbert St"));
+
+        let way = parse_way(
+            1,
+            &[("highway", "primary"), ("name", "Herbert Street")],
+            vec![],
+            &options,
+        );
+        assert_eq!(way.name.as_deref(), Some("Herbert Street"));
+
+        let way = parse_way(
+            2,
+            &[("highway", "motorway"), ("ref", "SH1")],
+            vec![],
+            &options,
+        );
+        assert_eq!(way.name.as_deref(), Some("SH1"));
+
+        let way = parse_way(3, &[("highway", "service")], vec![], &options);
+        assert_eq!(way.name, None);
+
+        let ref_first = ImportOptions {
+            name_keys: vec!["ref".to_string(), "name".to_string()],
+            ..Default::default()
+        };
+        let way = parse_way(
+            4,
+            &[("name", "Motorway"), ("ref", "SH1")],
+            vec![],
+            &ref_first,
+        );
+        assert_eq!(way.name.as_deref(), Some("SH1"));
+    }
+
+    #[test]
+    fn test_way_name_languages() {
+        let tags = [
+            ("name", "הרצל"),
+            ("name:en", "Herzl"),
+            ("name:he", "הרצל"),
+            ("name:ar", "هرتسل"),
+        ];
+        let name = |languages: &[&str]| {
+            let options = ImportOptions {
+                languages: languages.iter().map(|l| l.to_string()).collect(),
+                ..Default::default()
+            };
+            parse_way(0, &tags, vec![], &options).name
+        };
+
+        assert_eq!(name(&["he", "en"]).as_deref(), Some("הרצל"));
+        assert_eq!(name(&["en", "he"]).as_deref(), Some("Herzl"));
+        assert_eq!(name(&["fr", "ar", "en"]).as_deref(), Some("هرتسل"));
+        // Without a matching language the name keys apply.
+        assert_eq!(name(&["fr"]).as_deref(), Some("Herzl"));
+        assert_eq!(name(&[]).as_deref(), Some("Herzl"));
+    }
+
+    #[test]
+    fn test_parse_road_class() {
+        let options = ImportOptions::default();
+
+        let way = parse_way(0, &[("highway", "motorway")], vec![0, 1], &options);
+        assert_eq!(way.road_class, RoadClass::Motorway);
+
+        let way = parse_way(1, &[("highway", "motorway_link")], vec![0, 1], &options);
+        assert_eq!(way.road_class, RoadClass::Motorway);
+
+        let way = parse_way(2, &[("highway", "raceway")], vec![0, 1], &options);
+        assert_eq!(way.road_class, RoadClass::Unknown);
+
+        let way = parse_way(3, &[("name", "No highway tag")], vec![0, 1], &options);
+        assert_eq!(way.road_class, RoadClass::Unknown);
+
+        let metadata = create_way_metadata(
+            &parse_way(4, &[("highway", "residential")], vec![0, 1], &options),
+            1.0,
+            1.0,
+            &options,
+        );
+        assert_eq!(metadata.road_class, RoadClass::Residential);
+    }
+
+    #[test]
+    fn test_keep_both_names() {
+        let tags = [
+            ("highway", "residential"),
+            ("name", "ローカル名"),
+            ("name:en", "Local Street"),
+        ];
+        let options = ImportOptions {
+            keep_both_names: true,
+            ..Default::default()
+        };
+        let metadata = create_way_metadata(
+            &parse_way(1, &tags, vec![0, 1], &options),
+            1.0,
+            1.0,
+            &options,
+        );
+        assert_eq!(metadata.name.as_deref(), Some("Local Street"));
+        assert_eq!(
+            metadata.names,
+            Some(WayNames {
+                local: Some("ローカル名".to_string()),
+                en: Some("Local Street".to_string()),
+            })
+        );
+
+        let options = ImportOptions::default();
+        let metadata = create_way_metadata(
+            &parse_way(1, &tags, vec![0, 1], &options),
+            1.0,
+            1.0,
+            &options,
+        );
+        assert_eq!(metadata.names, None);
+    }
+
+    #[test]
+    fn test_private_way_dropped_for_car() {
+        let car = ImportOptions::default();
+        let bike = ImportOptions {
+            profile: Profile::Bike,
+            ..Default::default()
+        };
+
+        let private = parse_way(
+            1,
+            &[("highway", "residential"), ("access", "private")],
+            vec![0, 1],
+            &ImportOptions::default(),
+        );
+        assert!(!is_way_routable(&private, &car));
+
+        let no_cars = parse_way(
+            2,
+            &[("highway", "track"), ("motor_vehicle", "no")],
+            vec![0, 1],
+            &ImportOptions::default(),
+        );
+        assert!(!is_way_routable(&no_cars, &car));
+        assert!(is_way_routable(&no_cars, &bike));
+
+        let bike_footway = parse_way(
+            3,
+            &[("highway", "footway"), ("access", "no"), ("bicycle", "yes")],
+            vec![0, 1],
+            &ImportOptions::default(),
+        );
+        assert!(!is_way_routable(&bike_footway, &car));
+        assert!(is_way_routable(&bike_footway, &bike));
+
+        let public = parse_way(
+            4,
+            &[("highway", "residential")],
+            vec![0, 1],
+            &ImportOptions::default(),
+        );
+        assert!(is_way_routable(&public, &car));
+    }
+
+    #[test]
+    fn test_lifecycle_excluded() {
+        let options = ImportOptions::default();
+        let routable = |tags: &[(&str, &str)]| {
+            is_way_routable(&parse_way(0, tags, vec![0, 1], &options), &options)
+        };
+
+        assert!(!routable(&[
+            ("highway", "construction"),
+            ("construction", "residential")
+        ]));
+        assert!(!routable(&[("highway", "proposed")]));
+        assert!(!routable(&[("disused:highway", "residential")]));
+        assert!(!routable(&[("abandoned:highway", "primary")]));
+        assert!(!routable(&[
+            ("highway", "construction"),
+            ("construction:highway", "residential")
+        ]));
+
+        assert!(routable(&[("highway", "residential")]));
+        // A road in use that is being upgraded or has an upgrade planned stays open.
+        assert!(routable(&[
+            ("highway", "secondary"),
+            ("construction:highway", "primary")
+        ]));
+        assert!(routable(&[
+            ("highway", "residential"),
+            ("proposed:highway", "tertiary")
+        ]));
+        assert!(routable(&[
+            ("highway", "residential"),
+            ("construction", "no")
+        ]));
+    }
+
+    #[test]
+    fn test_bollard_blocks_car() {
+        let nodes_map = [None, Some(Barrier::Bollard), None]
+            .into_iter()
+            .enumerate()
+            .map(|(i, barrier)| {
+                let node = node_at(32.0 + i as f64 * 0.001, 34.8);
+                (i as i64, NodeParseData { barrier, ..node })
+            });
+        let maps = parse_result(
+            nodes_map,
+            &[(0, &[("highway", "residential")], vec![0, 1, 2])],
+            &ImportOptions::default(),
+        );
+        let nodes = build_nodes(&maps.osm_id_to_node);
+
+        let car = build_edge_lists(
+            maps.clone(),
+            &nodes,
+            MultiMap::new(),
+            &ImportOptions::default(),
+        );
+        assert!(car.edges.is_empty());
+
+        let foot = build_edge_lists(
+            maps,
+            &nodes,
+            MultiMap::new(),
+            &ImportOptions {
+                profile: Profile::Foot,
+                ..Default::default()
             },
         );
-        nodes_map.insert(
-            200,
-            NodeParseData {
-                dense_index: 1,
-                lat: 30.0,
-                lon: 40.0,
-                is_traffic_signal: true,
+        assert_eq!(foot.edges.len(), 2);
+    }
+
+    #[test]
+    fn test_gate_on_shared_node_charged_once() {
+        // 0 -> 1 -> 2 split into two ways at the gate on 1.
+        let nodes_map = (0..3).map(|i| {
+            let node = node_at(32.0 + i as f64 * 0.001, 34.8);
+            let barrier = (i == 1).then_some(Barrier::Gate);
+            (i, NodeParseData { barrier, ..node })
+        });
+        let maps = parse_result(nodes_map, &[], &ImportOptions::default());
+
+        let gate = Profile::Car.barrier_penalty(Barrier::Gate).unwrap();
+        let through = |spans: &[&[i64]]| {
+            spans
+                .iter()
+                .map(|span| calc_barrier_penalty(span, &maps, Profile::Car).unwrap())
+                .sum::<f32>()
+        };
+        assert_eq!(through(&[&[0, 1], &[1, 2]]), gate);
+        assert_eq!(through(&[&[2, 1], &[1, 0]]), gate);
+        assert_eq!(through(&[&[0, 1, 2]]), gate);
+    }
+
+    #[test]
+    fn test_node_penalties_charged_per_direction() {
+        // A two-way street 0 - 1 with a gate on 0 and a traffic light on 1.
+        let nodes_map = (0..2).map(|i| {
+            let node = NodeParseData {
+                is_traffic_signal: i == 1,
+                barrier: (i == 0).then_some(Barrier::Gate),
+                ..node_at(32.0 + i as f64 * 0.001, 34.8)
+            };
+            (i, node)
+        });
+        let options = ImportOptions::default();
+        let maps = parse_result(
+            nodes_map,
+            &[(0, &[("highway", "residential")], vec![0, 1])],
+            &options,
+        );
+        let (graph, _) = build_graph(maps, MultiMap::new(), &options);
+        let weight = |src: i64, dest: i64| {
+            let node = |osm_id| graph.nodes.iter().position(|n| n.osm_id == osm_id).unwrap();
+            graph
+                .get_edge_metadata(graph.find_edge(node(src), node(dest)).unwrap())
+                .weight
+        };
+        let light = options.cost_model.traffic_light_penalty(1);
+        let gate = Profile::Car.barrier_penalty(Barrier::Gate).unwrap();
+        // Each direction pays for what it arrives at.
+        assert!((weight(0, 1) - light - (weight(1, 0) - gate)).abs() < 1e-3);
+        assert_ne!(light, gate);
+    }
+
+    #[test]
+    fn test_find_ref_positions() {
+        assert_eq!(
+            find_ref_positions(&[10, 20, 30, 40], &[10, 30, 40]),
+            Some(vec![0, 2, 3])
+        );
+        assert_eq!(find_ref_positions(&[10, 20, 30, 40], &[10, 50, 40]), None);
+        assert_eq!(find_ref_positions(&[10, 20, 30, 40], &[30, 20]), None);
+    }
+
+    #[test]
+    fn test_foot_ignores_oneway() {
+        let nodes_map = (0..2).map(|i| (i, node_at(32.0 + i as f64 * 0.001, 34.8)));
+        let tags = [("highway", "residential"), ("oneway", "yes")];
+        let maps = parse_result(
+            nodes_map,
+            &[(0, &tags, vec![0, 1])],
+            &ImportOptions::default(),
+        );
+        let nodes = build_nodes(&maps.osm_id_to_node);
+
+        let car = build_edge_lists(
+            maps.clone(),
+            &nodes,
+            MultiMap::new(),
+            &ImportOptions::default(),
+        );
+        assert_eq!(car.edges.len(), 1);
+
+        let foot = build_edge_lists(
+            maps,
+            &nodes,
+            MultiMap::new(),
+            &ImportOptions {
+                profile: Profile::Foot,
+                ..Default::default()
             },
         );
+        assert_eq!(foot.edges.len(), 2);
+        assert_eq!(foot.edges[0].metadata_index, foot.edges[1].metadata_index);
+    }
+
+    #[test]
+    fn test_reversible_policy() {
+        let nodes_map: BTreeMap<_, _> = (0..2)
+            .map(|i| (i, node_at(32.0 + i as f64 * 0.001, 34.8)))
+            .collect();
+        let nodes = build_nodes(&nodes_map);
+        let build = |tags: &[(&str, &str)], options: &ImportOptions| {
+            let maps = parse_result(nodes_map.clone(), &[(0, tags, vec![0, 1])], options);
+            if !is_way_routable(&maps.ways[&0], options) {
+                return None;
+            }
+            Some(build_edge_lists(maps, &nodes, MultiMap::new(), options))
+        };
+
+        let reversible = [("highway", "residential"), ("oneway", "reversible")];
+        assert!(build(&reversible, &ImportOptions::default()).is_none());
+
+        let two_way = ImportOptions {
+            reversible: ReversiblePolicy::TwoWay(2.0),
+            ..Default::default()
+        };
+        let plain = build(&[("highway", "residential")], &two_way).unwrap();
+        let result = build(&reversible, &two_way).unwrap();
+        assert_eq!(result.edges.len(), 2);
+        let metadata = &result.edge_metadata[0];
+        assert!(metadata.is_reversible && !metadata.is_one_way);
+        assert!((metadata.weight - 2.0 * plain.edge_metadata[0].weight).abs() < 1e-3);
+
+        // Pedestrians walk both ways regardless.
+        let foot = ImportOptions {
+            profile: Profile::Foot,
+            ..Default::default()
+        };
+        let alternating = [("highway", "residential"), ("oneway", "alternating")];
+        assert_eq!(build(&alternating, &foot).unwrap().edges.len(), 2);
+    }
+
+    #[test]
+    fn test_coincident_nodes_weight_clamped() {
+        let maps = parse_result(
+            [(10, node_at(32.0, 34.8)), (20, node_at(32.0, 34.8))],
+            &[(
+                0,
+                &[("highway", "residential"), ("oneway", "yes")],
+                vec![10, 20],
+            )],
+            &ImportOptions::default(),
+        );
+
+        let options = ImportOptions {
+            min_edge_weight: None,
+            ..ImportOptions::default()
+        };
+        let (graph, _) = build_graph(maps.clone(), MultiMap::new(), &options);
+        assert_eq!(
+            graph.validate(),
+            Err(vec![GraphError::InvalidWeight {
+                edge_id: 0,
+                weight: 0.0
+            }])
+        );
+
+        let (graph, _) = build_graph(maps, MultiMap::new(), &ImportOptions::default());
+        assert_eq!(graph.validate(), Ok(()));
+        assert_eq!(graph.edge_metadata[0].weight, MIN_EDGE_WEIGHT);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("01:30"), Some(5400.0));
+        assert_eq!(parse_duration("0:45:30"), Some(2730.0));
+        assert_eq!(parse_duration("20"), Some(1200.0));
+        assert_eq!(parse_duration("about an hour"), None);
+        assert_eq!(parse_duration("NaN"), None);
+    }
+
+    #[test]
+    fn test_parse_dimension() {
+        assert_eq!(parse_dimension("3.5"), Some(3.5));
+        assert_eq!(parse_dimension("4 m"), Some(4.0));
+        assert_eq!(parse_dimension("7.5 t"), Some(7.5));
+        assert_eq!(parse_dimension("3500 kg"), Some(3.5));
+        assert!((parse_dimension("10 ft").unwrap() - 3.048).abs() < 1e-4);
+        assert!((parse_dimension("12'6\"").unwrap() - 3.81).abs() < 1e-4);
+        assert!((parse_dimension("13'").unwrap() - 3.9624).abs() < 1e-4);
+        assert_eq!(parse_dimension("none"), None);
+        assert_eq!(parse_dimension("default"), None);
+        assert_eq!(parse_dimension("0"), None);
+        assert_eq!(parse_dimension("3 furlongs"), None);
+    }
+
+    #[test]
+    fn test_ferry_edge() {
+        let nodes_map = [(10, -41.28), (20, -41.3), (30, -41.29)]
+            .map(|(osm_id, lat)| (osm_id, node_at(lat, 174.78)));
+        let tags = [("route", "ferry"), ("duration", "01:30")];
+        let maps = parse_result(
+            nodes_map,
+            &[(0, &tags, vec![10, 30, 20])],
+            &ImportOptions::default(),
+        );
+
+        let (graph, _) = build_graph(maps, MultiMap::new(), &ImportOptions::default());
+        assert_eq!(
+            edges_by_osm_id(&graph),
+            vec![(10, 20, 5400f32.to_bits()), (20, 10, 5400f32.to_bits())]
+        );
+        assert!(graph.edge_metadata.iter().all(|m| m.is_ferry));
+
+        let no_ferries = ImportOptions {
+            ferries: false,
+            ..ImportOptions::default()
+        };
+        let way = parse_way(0, &tags, vec![10, 20], &no_ferries);
+        assert!(!is_way_routable(&way, &no_ferries));
+
+        let way = parse_way(0, &[("route", "ferry")], vec![10, 20], &no_ferries);
+        assert_eq!(way.ferry_duration_s, Some(DEFAULT_FERRY_DURATION_S));
+    }
+
+    #[test]
+    fn test_ferry_missing_terminal() {
+        // The ferry 10 - 30 - 40 sails to a terminal missing from the extract, the street
+        // 10 - 20 is complete.
+        let nodes_map = [(10, -41.28), (20, -41.281), (30, -41.29)]
+            .map(|(osm_id, lat)| (osm_id, node_at(lat, 174.78)));
+        let maps = parse_result(
+            nodes_map,
+            &[
+                (0, &[("route", "ferry")], vec![10, 30, 40]),
+                (1, &[("highway", "residential")], vec![10, 20]),
+            ],
+            &ImportOptions::default(),
+        );
+
+        let (graph, missing_refs) = build_graph(maps, MultiMap::new(), &ImportOptions::default());
+        assert_eq!(missing_refs, 1);
+        assert!(graph.edge_metadata.iter().all(|m| !m.is_ferry));
+        assert_eq!(graph.num_edges(), 2);
+    }
+
+    #[test]
+    fn test_destination_access_penalty() {
+        use crate::engine::query::simple_router::SimpleRouter;
+
+        // A destination-only street 10 - 20, a detour 10 - 30 - 20 around it and a
+        // destination-only dead end 20 - 40.
+        let nodes_map = [
+            (10, 32.0, 34.0),
+            (20, 32.0, 34.002),
+            (30, 32.001, 34.001),
+            (40, 32.0, 34.004),
+        ]
+        .map(|(osm_id, lat, lon)| (osm_id, node_at(lat, lon)));
+
+        let route = |options: &ImportOptions, src: i64, dest: i64| {
+            let residential = [("highway", "residential")];
+            let destination = [("highway", "residential"), ("access", "destination")];
+            let maps = parse_result(
+                nodes_map.clone(),
+                &[
+                    (1, &destination, vec![10, 20]),
+                    (2, &residential, vec![10, 30]),
+                    (3, &residential, vec![30, 20]),
+                    (4, &destination, vec![20, 40]),
+                ],
+                options,
+            );
+            let (graph, _) = build_graph(maps, MultiMap::new(), options);
+            let dense = |osm_id| graph.nodes.iter().position(|n| n.osm_id == osm_id).unwrap();
+            let (_, path) = SimpleRouter::new(&graph)
+                .route(dense(src), dense(dest))
+                .unwrap();
+            path.iter()
+                .map(|&node| graph.nodes[node].osm_id)
+                .collect::<Vec<_>>()
+        };
+
+        // The through route takes the detour, but the dead end is the only way to 40.
+        let options = ImportOptions::default();
+        assert_eq!(route(&options, 10, 20), [10, 30, 20]);
+        assert_eq!(route(&options, 30, 40), [30, 20, 40]);
+
+        let options = ImportOptions {
+            destination_penalty: 1.0,
+            ..ImportOptions::default()
+        };
+        assert_eq!(route(&options, 10, 20), [10, 20]);
+    }
+
+    #[test]
+    fn test_residential_default_speed() {
+        let options = ImportOptions::default();
+        let maps = parse_result(
+            [(10, node_at(32.0, 34.8)), (20, node_at(32.01, 34.8))],
+            &[(0, &[("highway", "residential")], vec![10, 20])],
+            &options,
+        );
+        assert_eq!(maps.ways[&0].max_speed, None);
+
+        let (graph, _) = build_graph(maps, MultiMap::new(), &options);
+        let speed_kmh = options
+            .cost_model
+            .default_speeds
+            .get(Profile::Car, RoadClass::Residential);
+        let dist = utils::haversine_distance(32.0, 34.8, 32.01, 34.8);
+        assert_eq!(graph.edge_metadata[0].weight, dist / (speed_kmh / 3.6));
+        assert_eq!(graph.edge_metadata[0].distance_m, dist);
+    }
+
+    #[test]
+    fn test_planar_distances() {
+        let options = ImportOptions {
+            planar_distances: true,
+            ..ImportOptions::default()
+        };
+        let maps = parse_result(
+            [(10, node_at(32.0, 34.8)), (20, node_at(32.01, 34.81))],
+            &[(0, &[("highway", "residential")], vec![10, 20])],
+            &options,
+        );
+
+        let (graph, _) = build_graph(maps, MultiMap::new(), &options);
+        let planar = utils::planar_distance(32.0, 34.8, 32.01, 34.81);
+        let haversine = utils::haversine_distance(32.0, 34.8, 32.01, 34.81);
+        assert_eq!(graph.edge_metadata[0].distance_m, planar);
+        assert!((planar - haversine).abs() / haversine < 0.001);
+    }
+
+    #[test]
+    fn test_surface_slows_bikes() {
+        let nodes_map = [(10, 32.0, 34.8), (20, 32.01, 34.8), (30, 31.99, 34.8)]
+            .map(|(osm_id, lat, lon)| (osm_id, node_at(lat, lon)));
+
+        // Two ways of equal length from 10, north on asphalt and south on cobblestone.
+        let weights = |profile| {
+            let options = ImportOptions {
+                profile,
+                ..ImportOptions::default()
+            };
+            let asphalt = [("highway", "residential"), ("surface", "asphalt")];
+            let cobblestone = [("highway", "residential"), ("surface", "cobblestone")];
+            let maps = parse_result(
+                nodes_map.clone(),
+                &[(0, &asphalt, vec![10, 20]), (1, &cobblestone, vec![10, 30])],
+                &options,
+            );
+            let (graph, _) = build_graph(maps, MultiMap::new(), &options);
+            let metadata = &graph.edge_metadata;
+            assert!((metadata[0].distance_m - metadata[1].distance_m).abs() < 1.0);
+            (metadata[0].weight, metadata[1].weight)
+        };
+
+        let (asphalt, cobblestone) = weights(Profile::Bike);
+        assert!(cobblestone > asphalt * 1.5);
+        let (asphalt, cobblestone) = weights(Profile::Car);
+        assert!((cobblestone - asphalt).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_road_class_multiplier() {
+        let nodes_map = [(10, 32.0, 34.8), (20, 32.01, 34.8), (30, 32.005, 34.801)]
+            .map(|(osm_id, lat, lon)| (osm_id, node_at(lat, lon)));
+
+        // From 10 to 20 straight along a residential street, or slightly longer through 30
+        // along a primary road, both limited to 30 km/h.
+        let route_class = |cost_model: CostModel| {
+            let options = ImportOptions {
+                cost_model,
+                ..ImportOptions::default()
+            };
+            let residential = [("highway", "residential"), ("maxspeed", "30")];
+            let primary = [("highway", "primary"), ("maxspeed", "30")];
+            let maps = parse_result(
+                nodes_map.clone(),
+                &[
+                    (0, &residential, vec![10, 20]),
+                    (1, &primary, vec![10, 30, 20]),
+                ],
+                &options,
+            );
+            let (graph, _) = build_graph(maps, MultiMap::new(), &options);
+            let fastest = graph
+                .edge_metadata
+                .iter()
+                .min_by(|a, b| a.weight.total_cmp(&b.weight))
+                .unwrap();
+            fastest.road_class
+        };
+
+        assert_eq!(route_class(CostModel::default()), RoadClass::Residential);
+        let mut cost_model = CostModel::default();
+        cost_model
+            .road_class_multipliers
+            .insert(RoadClass::Residential, 1.2);
+        assert_eq!(route_class(cost_model), RoadClass::Primary);
+    }
+
+    #[test]
+    fn test_traffic_light_penalty() {
+        // Two identical parallel ways, 10 -> 20 -> 30 and 40 -> 50 -> 60, with traffic lights
+        // only on 50.
+        let nodes_map = [
+            (10, 32.0, 34.8),
+            (20, 32.001, 34.8),
+            (30, 32.002, 34.8),
+            (40, 32.0, 34.9),
+            (50, 32.001, 34.9),
+            (60, 32.002, 34.9),
+        ]
+        .map(|(osm_id, lat, lon)| {
+            let node = node_at(lat, lon);
+            let is_traffic_signal = osm_id == 50;
+            (
+                osm_id,
+                NodeParseData {
+                    is_traffic_signal,
+                    ..node
+                },
+            )
+        });
+
+        let options = ImportOptions::default();
+        let residential = [("highway", "residential")];
+        let maps = parse_result(
+            nodes_map,
+            &[
+                (0, &residential, vec![10, 20, 30]),
+                (1, &residential, vec![40, 50, 60]),
+            ],
+            &options,
+        );
+
+        let (graph, _) = build_graph(maps, MultiMap::new(), &options);
+        let weight = |src: i64, dest: i64| {
+            let edge = graph
+                .edges
+                .iter()
+                .find(|e| {
+                    graph.get_node(e.src_id).osm_id == src
+                        && graph.get_node(e.dest_id).osm_id == dest
+                })
+                .unwrap();
+            graph.get_edge_metadata(edge).weight
+        };
+
+        let without_lights = weight(10, 30);
+        let with_lights = weight(40, 60);
+        assert!(with_lights > without_lights);
+        assert!(
+            (with_lights - without_lights - options.cost_model.traffic_light_penalty_s).abs()
+                < 1e-3
+        );
+    }
+
+    #[test]
+    fn test_layers_dont_intersect() {
+        // A road 10 -> 30 -> 20 and a bridge 40 -> 30 -> 50 above it, sharing the node 30.
+        let nodes_map = [
+            (10, 32.0, 34.8),
+            (20, 32.002, 34.8),
+            (30, 32.001, 34.8),
+            (40, 32.001, 34.799),
+            (50, 32.001, 34.801),
+        ]
+        .map(|(osm_id, lat, lon)| (osm_id, node_at(lat, lon)));
+
+        let options = ImportOptions {
+            respect_layers: true,
+            ..ImportOptions::default()
+        };
+        let maps = parse_result(
+            nodes_map,
+            &[
+                (0, &[("highway", "primary")], vec![10, 30, 20]),
+                (
+                    1,
+                    &[("highway", "primary"), ("bridge", "yes"), ("layer", "1")],
+                    vec![40, 30, 50],
+                ),
+            ],
+            &options,
+        );
+        assert_eq!(maps.ways[&1].layer, 1);
+
+        let count_refs = |options: &ImportOptions| {
+            let mut node_refs = NodeRefCounter::new(options);
+            for way in maps.ways.values() {
+                node_refs.add_way(&way.refs, way.layer);
+            }
+            filter_intersections(&maps.ways, &node_refs)
+        };
+        let default_intersections = count_refs(&ImportOptions::default());
+        assert_eq!(default_intersections.get_vec(&0), Some(&vec![30]));
+        assert_eq!(default_intersections.get_vec(&1), Some(&vec![30]));
+        let intersections = count_refs(&options);
+        assert!(intersections.is_empty());
+
+        let (graph, _) = build_graph(maps, intersections, &options);
+        let osm_id = |id: usize| graph.get_node(id).osm_id;
+        assert!(graph.nodes.iter().all(|node| node.osm_id != 30));
+        for edge in &graph.edges {
+            let endpoints = [osm_id(edge.src_id), osm_id(edge.dest_id)];
+            let on_road = endpoints.iter().filter(|id| [10, 20].contains(*id)).count();
+            assert!(
+                on_road == 0 || on_road == 2,
+                "{endpoints:?} joins the layers"
+            );
+        }
+    }
+
+    #[test]
+    fn test_missing_node_skipped() {
+        let nodes_map = [(10, 32.0), (20, 32.001), (30, 32.003), (40, 32.004)]
+            .map(|(osm_id, lat)| (osm_id, node_at(lat, 34.8)));
+
+        // The way's node 99 isn't in the extract.
+        let options = ImportOptions::default();
+        let maps = parse_result(
+            nodes_map,
+            &[(0, &[("highway", "residential")], vec![10, 20, 99, 30, 40])],
+            &options,
+        );
+
+        let (graph, missing_refs) = build_graph(maps, MultiMap::new(), &options);
+        assert_eq!(missing_refs, 1);
+        let mut edges = graph
+            .edges
+            .iter()
+            .map(|e| {
+                (
+                    graph.get_node(e.src_id).osm_id,
+                    graph.get_node(e.dest_id).osm_id,
+                )
+            })
+            .collect_vec();
+        edges.sort();
+        assert_eq!(edges, [(10, 20), (20, 10), (30, 40), (40, 30)]);
+    }
+
+    #[test]
+    fn test_no_edges_error() {
+        // Only a footway closed to motor vehicles, which the parser drops for cars.
+        let options = ImportOptions::default();
+        let tags = [("highway", "footway"), ("motor_vehicle", "no")];
+        let mut maps = parse_result(
+            [(10, node_at(32.0, 34.8)), (20, node_at(32.001, 34.8))],
+            &[(0, &tags, vec![10, 20])],
+            &options,
+        );
+        maps.ways.retain(|_, way| is_way_routable(way, &options));
+
+        let (graph, _) = build_graph(maps, MultiMap::new(), &options);
+        let err = ensure_has_edges(graph, &options).err().unwrap().to_string();
+        assert!(err.contains("no edges"), "{err}");
+        assert!(err.contains("Car profile"), "{err}");
+    }
+
+    #[test]
+    fn test_no_self_loops() {
+        let nodes_map = [(10, 32.0), (20, 32.001), (30, 32.002)]
+            .map(|(osm_id, lat)| (osm_id, node_at(lat, 34.8)));
+
+        // A roundabout with a repeated consecutive ref, and a closed way with no intersections.
+        let maps = parse_result(
+            nodes_map,
+            &[
+                (
+                    0,
+                    &[("highway", "residential"), ("junction", "roundabout")],
+                    vec![10, 20, 20, 30],
+                ),
+                (1, &[("highway", "residential")], vec![30, 20, 30]),
+            ],
+            &ImportOptions::default(),
+        );
+
+        let (graph, _) = build_graph(maps, MultiMap::new(), &ImportOptions::default());
+
+        assert!(graph.edges.iter().all(|e| e.src_id != e.dest_id));
+        assert_eq!(graph.num_edges(), 2);
+        assert_eq!(graph.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_isolated_node_pruned() {
+        let nodes_map = [(10, 32.0), (20, 32.001), (30, 32.002), (40, 32.003)]
+            .map(|(osm_id, lat)| (osm_id, node_at(lat, 34.8)));
+
+        // Node 20 is a standalone POI that isn't referenced by any way.
+        let residential = [("highway", "residential")];
+        let maps = parse_result(
+            nodes_map,
+            &[
+                (0, &residential, vec![10, 30]),
+                (1, &residential, vec![30, 40]),
+            ],
+            &ImportOptions::default(),
+        );
+
+        let (graph, _) = build_graph(maps, MultiMap::new(), &ImportOptions::default());
+
+        assert_eq!(graph.num_nodes(), 3);
+        assert!(graph.nodes.iter().all(|n| n.osm_id != 20));
+        for (i, node) in graph.nodes.iter().enumerate() {
+            assert_eq!(node.dense_id, i);
+        }
+        assert_eq!(graph.fwd_edge_list.len(), 3);
+        assert_eq!(graph.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_bbox_truncates_straddling_way() {
+        let maps = parse_result(
+            [(1, 32.0), (2, 32.5), (3, 33.5)].map(|(osm_id, lat)| (osm_id, node_at(lat, 34.8))),
+            &[(7, &[("highway", "residential")], vec![1, 2, 3])],
+            &ImportOptions::default(),
+        );
+
+        let bbox = (31.0, 34.0, 33.0, 35.0);
+        let (clipped, _) = clip_to_bbox(maps.clone(), MultiMap::new(), bbox);
+
+        let way = &clipped.ways[&7];
+        assert_eq!(way.refs.len(), 3);
+        assert_eq!(way.refs[..2], [1, 2]);
+        assert!(!clipped.osm_id_to_node.contains_key(&3));
+
+        let crossing = &clipped.osm_id_to_node[&way.refs[2]];
+        assert!((crossing.lat - 33.0).abs() < 1e-5);
+        assert!((crossing.lon - 34.8).abs() < 1e-5);
+
+        let options = ImportOptions {
+            bbox: Some(bbox),
+            ..Default::default()
+        };
+        let (graph, _) = build_graph(maps, MultiMap::new(), &options);
+        assert!(graph.nodes.iter().all(|n| n.lat <= 33.0 + 1e-5));
+        assert!(graph.nodes.iter().any(|n| (n.lat - 33.0).abs() < 1e-5));
+        assert!(graph.num_edges() > 0);
+    }
+
+    #[test]
+    fn test_bbox_crossing_stays_inside() {
+        let node = |lat| node_at(lat, 0.5);
+        let bbox = (0.0, 0.0, 0.3, 1.0);
+        // 0.032 + ((0.3 - 0.032) / d) * d rounds to 0.30000000000000004.
+        let (lat, _) = calc_bbox_crossing(bbox, &node(0.032), &node(1.13));
+        assert_eq!(lat, 0.3);
+
+        let maps = parse_result(
+            [(1, node(0.032)), (2, node(1.13))],
+            &[(7, &[("highway", "residential")], vec![1, 2])],
+            &ImportOptions::default(),
+        );
+
+        let (clipped, _) = clip_to_bbox(maps, MultiMap::new(), bbox);
+        let way = &clipped.ways[&7];
+        assert_eq!(way.refs.len(), 2);
+        assert!(way
+            .refs
+            .iter()
+            .all(|id| clipped.osm_id_to_node.contains_key(id)));
+    }
+
+    #[test]
+    fn test_build_nodes() {
+        let nodes_map = BTreeMap::from([
+            (100, node_at(10.0, 20.0)),
+            (
+                200,
+                NodeParseData {
+                    is_traffic_signal: true,
+                    ..node_at(30.0, 40.0)
+                },
+            ),
+        ]);
 
         let nodes = build_nodes(&nodes_map);
 
@@ -396,34 +3274,31 @@ mod tests {
 
     #[test]
     fn test_build_edge_lists() {
-        let mut nodes_map: BTreeMap<i64, NodeParseData> = BTreeMap::new();
-        let nodes_data = [
-            (10.0, 20.0, false),
-            (20.0, 30.0, false),
-            (30.0, 40.0, false),
-            (40.0, 50.0, false),
-        ];
-
-        for (i, (lat, lon, is_traffic_signal)) in nodes_data.into_iter().enumerate() {
-            nodes_map.insert(
-                i as i64,
-                NodeParseData {
-                    dense_index: i,
-                    lat,
-                    lon,
-                    is_traffic_signal,
-                },
-            );
-        }
+        let nodes_map: BTreeMap<i64, NodeParseData> = (0..4)
+            .map(|i| (i, node_at(10.0 * (i + 1) as f64, 10.0 * (i + 2) as f64)))
+            .collect();
 
         let mut ways: BTreeMap<i64, WayParseData> = BTreeMap::new();
         ways.insert(
             0,
             WayParseData {
+                id: 0,
                 name: None,
+                names: None,
                 max_speed: None,
+                road_class: RoadClass::Unknown,
                 is_roundabout: false,
                 is_oneway: false,
+                is_reversible: false,
+                access: AccessTags::default(),
+                surface: None,
+                smoothness: None,
+                layer: 0,
+                ferry_duration_s: None,
+                max_height_m: None,
+                max_weight_t: None,
+                max_width_m: None,
+                is_lifecycle: false,
                 refs: vec![0, 1, 2, 3],
             },
         );
@@ -434,7 +3309,7 @@ mod tests {
         };
 
         let nodes = build_nodes(&nodes_map);
-        let result = build_edge_lists(maps, &nodes);
+        let result = build_edge_lists(maps, &nodes, MultiMap::new(), &ImportOptions::default());
 
         let fwd_edge_list = result.fwd_edge_list;
         let bwd_edge_list = result.bwd_edge_list;
@@ -450,7 +3325,7 @@ mod tests {
         assert_eq!(edge_fwd.dest_id, 3);
         assert_eq!(edge_fwd.metadata_index, 0);
 
-        let edge_id_bwd = bwd_edge_list[3][0];
+        let edge_id_bwd = bwd_edge_list[0][0];
         let edge_bwd = &edges[edge_id_bwd];
 
         assert_eq!(edge_bwd.src_id, 3);
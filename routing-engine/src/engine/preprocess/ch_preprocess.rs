@@ -1,142 +1,733 @@
 use core::f32;
-use std::cmp::Reverse;
+use std::{
+    cmp::Reverse,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
 
-use super::graph::EdgeMetadata;
+use super::graph::{add_weights, is_sane_weight, Direction, Edge, EdgeMetadata, Metric, RoadClass};
 use super::{graph::Graph, witness_search::Dijkstra};
 
 use priority_queue::PriorityQueue;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
 
-pub fn contract_graph(mut graph: Graph, overlay: &mut Graph, dijkstra: &mut Dijkstra) {
-    let mut queue = PriorityQueue::with_capacity(graph.num_nodes());
+/// A snapshot of the contraction progress, reported after each contracted node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContractionProgress {
+    // Number of nodes contracted so far.
+    pub contracted: usize,
+    // Total number of nodes to contract, which excludes the core of a partial contraction.
+    pub total: usize,
+    // Number of shortcut edges added to the overlay so far.
+    pub shortcuts_added: usize,
+    // Approximate memory usage of the overlay in bytes.
+    pub mem_bytes: usize,
+}
+
+/// Default cap on the nodes settled by a single witness search.
+pub const DEFAULT_MAX_SETTLED: usize = 500;
+
+/// The rank of the nodes left uncontracted by a partial contraction. The core nodes share it,
+/// so the upward searches of a query relax every edge between them and run a plain Dijkstra
+/// once they reach the core.
+pub const CORE_RANK: i32 = i32::MAX;
+
+/// Settings that trade preprocessing time against the size of the hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContractionOptions {
+    // Cap on the nodes settled by each witness search. Witnesses beyond it are missed and the
+    // shortcut is added anyway, so a tight cap adds shortcuts but bounds the contraction time.
+    pub max_settled: usize,
+    // Also keep the shortest paths under `Metric::Secondary`, so the hierarchy can be queried
+    // under either metric at the cost of more shortcuts.
+    pub secondary_metric: bool,
+    // Keep a shortcut whose witnesses are only as short as it but have more hops, so that
+    // queries preferring fewer hops return straighter paths among ones of equal weight.
+    pub prefer_fewer_hops: bool,
+    // Fraction of the nodes, the most important ones, left uncontracted as a core ranked
+    // `CORE_RANK`. Contracting the top of the hierarchy is the most expensive part, so a small
+    // core cuts the preprocessing time at the cost of slower queries. 0 contracts every node.
+    pub stop_fraction: f32,
+}
 
-    for (i, node) in graph.nodes.iter().enumerate() {
-        if i != 0 && i % 10_000 == 0 {
-            println!("Ranked {i} nodes");
+impl Default for ContractionOptions {
+    fn default() -> Self {
+        Self {
+            max_settled: DEFAULT_MAX_SETTLED,
+            secondary_metric: false,
+            prefer_fewer_hops: false,
+            stop_fraction: 0.0,
         }
-        queue.push(
-            node.dense_id,
-            Reverse(rank_node(overlay, dijkstra, node.dense_id)),
-        );
     }
+}
 
-    while let Some((contracted_id, _)) = queue.pop() {
-        println!("{} {}", overlay.get_mem_usage_str(), queue.len());
+impl ContractionOptions {
+    // The metrics whose shortest paths the hierarchy keeps.
+    fn metrics(&self) -> &'static [Metric] {
+        if self.secondary_metric {
+            &[Metric::Primary, Metric::Secondary]
+        } else {
+            &[Metric::Primary]
+        }
+    }
 
-        let neighbor_rank = overlay.get_node(contracted_id).get_rank() + 1;
-        contract_node(&mut graph, overlay, dijkstra, contracted_id);
+    // The number of nodes contracted before the rest are left as the core.
+    fn num_to_contract(&self, num_nodes: usize) -> usize {
+        let core_size = (num_nodes as f64 * f64::from(self.stop_fraction.clamp(0.0, 1.0))).ceil();
+        num_nodes - (core_size as usize).min(num_nodes)
+    }
+}
 
-        let fwd_neighbors = overlay.get_fwd_neighbors(contracted_id).to_vec();
-        let bwd_neighbors = overlay.get_bwd_neighbors(contracted_id).to_vec();
+/// Where and how often `contract_graph_with_checkpoints` saves its progress.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckpointOptions {
+    // The file checkpoints are written to, each one replacing the previous.
+    pub path: PathBuf,
+    // Number of nodes contracted between checkpoints.
+    pub every: usize,
+}
 
-        for neighbor_id in bwd_neighbors.iter().chain(fwd_neighbors.iter()) {
-            let edge = overlay.get_edge(*neighbor_id);
-            let neighbor_id = if edge.src_id == contracted_id {
-                edge.dest_id
-            } else {
-                edge.src_id
-            };
+/// Magic bytes at the start of a contraction checkpoint file.
+pub const CHECKPOINT_FILE_MAGIC: [u8; 4] = *b"SHCK";
+
+/// Version of the checkpoint layout, bump it whenever `ContractionCheckpoint` or `Graph` changes.
+pub const CHECKPOINT_FILE_VERSION: u32 = 2;
+
+/// The state of an interrupted `contract_graph` run, continued with `resume_contraction`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ContractionCheckpoint {
+    // The overlay as contracted so far.
+    pub overlay: Graph,
+    // The nodes left to contract with their priorities.
+    queue: Vec<(usize, i32)>,
+    pub contracted: usize,
+    pub shortcuts_added: usize,
+}
+
+// Mirrors the layout of `ContractionCheckpoint`, so a checkpoint can be written without
+// cloning the overlay.
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    overlay: &'a Graph,
+    queue: Vec<(usize, i32)>,
+    contracted: usize,
+    shortcuts_added: usize,
+}
+
+impl ContractionCheckpoint {
+    /// Reads a checkpoint written by `contract_graph_with_checkpoints`, failing if the file isn't
+    /// a checkpoint or was written with a different format version.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
 
-            let rank = rank_node(overlay, dijkstra, neighbor_id);
-            overlay.get_node_mut(neighbor_id).raise_rank(neighbor_rank);
-            queue.change_priority(&neighbor_id, Reverse(rank));
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != CHECKPOINT_FILE_MAGIC {
+            anyhow::bail!("{} is not a checkpoint file", path.display());
         }
+        reader.read_exact(&mut version)?;
 
-        remove_edges_from_neighbors(&mut graph, contracted_id);
+        let version = u32::from_le_bytes(version);
+        if version != CHECKPOINT_FILE_VERSION {
+            anyhow::bail!(
+                "{} has checkpoint format version {version}, expected version {CHECKPOINT_FILE_VERSION}",
+                path.display()
+            );
+        }
+
+        Ok(bincode::deserialize_from(reader)?)
     }
 }
 
-fn remove_edges_from_neighbors(graph: &mut Graph, contracted_id: usize) {
-    let fwd_edges: Vec<_> = graph.fwd_edge_list[contracted_id].clone();
-    let bwd_edges: Vec<_> = graph.bwd_edge_list[contracted_id].clone();
+// The queue of a contraction run. Ties between priorities are broken by node id, so the order
+// doesn't depend on the history of the queue and a resumed run contracts like an uninterrupted
+// one.
+type ContractionQueue = PriorityQueue<usize, Reverse<(i32, usize)>>;
 
-    for edge_idx in fwd_edges {
-        let edge = graph.get_edge(edge_idx).clone();
-        graph.bwd_edge_list[edge.dest_id].retain(|&e| e != edge_idx);
+// The progress of a contraction run, as saved in checkpoints.
+struct ContractionState {
+    queue: ContractionQueue,
+    contracted: usize,
+    shortcuts_added: usize,
+}
+
+impl ContractionState {
+    fn new(graph: &Graph, options: &ContractionOptions) -> Self {
+        let mut queue = PriorityQueue::with_capacity(graph.num_nodes());
+        for (node_id, priority) in initial_priorities(graph, options.max_settled)
+            .into_iter()
+            .enumerate()
+        {
+            queue.push(node_id, Reverse((priority, node_id)));
+        }
+
+        Self {
+            queue,
+            contracted: 0,
+            shortcuts_added: 0,
+        }
     }
 
-    for edge_idx in bwd_edges {
-        let edge = graph.get_edge(edge_idx).clone();
-        graph.fwd_edge_list[edge.src_id].retain(|&e| e != edge_idx);
+    // Writes the checkpoint next to `path` and renames it into place once it is on disk, so a
+    // run killed while saving leaves the previous checkpoint intact.
+    fn save(&self, overlay: &Graph, path: &Path) -> anyhow::Result<()> {
+        let checkpoint = CheckpointRef {
+            overlay,
+            queue: self
+                .queue
+                .iter()
+                .map(|(&node, &Reverse((priority, _)))| (node, priority))
+                .collect(),
+            contracted: self.contracted,
+            shortcuts_added: self.shortcuts_added,
+        };
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&CHECKPOINT_FILE_MAGIC)?;
+        writer.write_all(&CHECKPOINT_FILE_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, &checkpoint)?;
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
     }
+}
 
-    graph.fwd_edge_list[contracted_id].clear();
-    graph.bwd_edge_list[contracted_id].clear();
+/// Contracts `graph` in place, turning it into the overlay: shortcuts are added to it and its
+/// nodes are ranked, while contracted nodes are only flagged so no second copy of the graph is
+/// needed. Calls `progress` after each contracted node, pass `|_| {}` to contract silently.
+pub fn contract_graph(
+    graph: &mut Graph,
+    dijkstra: &mut Dijkstra,
+    options: &ContractionOptions,
+    progress: impl FnMut(ContractionProgress),
+) {
+    let mut state = ContractionState::new(graph, options);
+    // Without checkpoints nothing is written, so nothing can fail.
+    _ = contract_queued(graph, dijkstra, options, &mut state, None, None, progress);
 }
 
-fn contract_node(graph: &mut Graph, overlay: &mut Graph, dijkstra: &mut Dijkstra, node_id: usize) {
-    let fwd_indices = graph.get_fwd_neighbors(node_id).clone();
-    let bwd_indices = graph.get_bwd_neighbors(node_id).clone();
+/// Contracts `graph` in place like `contract_graph`, saving a `ContractionCheckpoint` every
+/// `checkpoint.every` nodes so a run that is cut short can be resumed with
+/// `resume_contraction`.
+pub fn contract_graph_with_checkpoints(
+    graph: &mut Graph,
+    dijkstra: &mut Dijkstra,
+    options: &ContractionOptions,
+    checkpoint: &CheckpointOptions,
+    progress: impl FnMut(ContractionProgress),
+) -> anyhow::Result<()> {
+    let mut state = ContractionState::new(graph, options);
+    contract_queued(
+        graph,
+        dijkstra,
+        options,
+        &mut state,
+        Some(checkpoint),
+        None,
+        progress,
+    )
+}
 
-    for &bwd_edge_index in &bwd_indices {
-        let bwd_edge = graph.get_edge(bwd_edge_index).clone();
-        let w = bwd_edge.src_id;
+/// Continues the contraction saved in `checkpoint` and returns the finished overlay. Further
+/// checkpoints are saved if `checkpoints` is set. `options` should match the interrupted run.
+pub fn resume_contraction(
+    checkpoint: ContractionCheckpoint,
+    dijkstra: &mut Dijkstra,
+    options: &ContractionOptions,
+    checkpoints: Option<&CheckpointOptions>,
+    progress: impl FnMut(ContractionProgress),
+) -> anyhow::Result<Graph> {
+    let ContractionCheckpoint {
+        mut overlay,
+        queue,
+        contracted,
+        shortcuts_added,
+    } = checkpoint;
+    let mut state = ContractionState {
+        queue: queue
+            .into_iter()
+            .map(|(node, priority)| (node, Reverse((priority, node))))
+            .collect(),
+        contracted,
+        shortcuts_added,
+    };
 
-        dijkstra.init(w, node_id);
-        for &fwd_edge_index in &fwd_indices {
-            let fwd_edge = graph.get_edge(fwd_edge_index);
-            let v = fwd_edge.dest_id;
+    contract_queued(
+        &mut overlay,
+        dijkstra,
+        options,
+        &mut state,
+        checkpoints,
+        None,
+        progress,
+    )?;
+    Ok(overlay)
+}
 
-            if v == w || v == node_id || w == node_id {
+// Contracts the queued nodes in priority order until only the core is left, or until `stop_at`
+// nodes were contracted in total, saving checkpoints if requested.
+fn contract_queued(
+    graph: &mut Graph,
+    dijkstra: &mut Dijkstra,
+    options: &ContractionOptions,
+    state: &mut ContractionState,
+    checkpoint: Option<&CheckpointOptions>,
+    stop_at: Option<usize>,
+    mut progress: impl FnMut(ContractionProgress),
+) -> anyhow::Result<()> {
+    let total = options.num_to_contract(graph.num_nodes());
+    let mem_tracker = MemTracker::new(graph);
+
+    while state.contracted < total && stop_at.is_none_or(|stop_at| state.contracted < stop_at) {
+        let Some((contracted_id, _)) = state.queue.pop() else {
+            break;
+        };
+        let neighbor_rank = graph.get_node(contracted_id).get_rank() + 1;
+        state.shortcuts_added += contract_node(graph, contracted_id, options);
+
+        let incident_edges: Vec<_> = graph.incident_edges(contracted_id).collect();
+
+        for (edge_id, direction) in incident_edges {
+            let edge = graph.get_edge(edge_id);
+            let neighbor_id = match direction {
+                Direction::Fwd => edge.dest_id,
+                Direction::Bwd => edge.src_id,
+            };
+            // A self-loop leads back to the node being contracted, which was already popped.
+            if neighbor_id == contracted_id {
                 continue;
             }
 
-            let weight_v_u = overlay.get_edge_metadata(&bwd_edge).weight;
-            let weight_u_w = overlay.get_edge_metadata(fwd_edge).weight;
-            let combined_weight = weight_v_u + weight_u_w;
+            update_neighbor(
+                graph,
+                dijkstra,
+                &mut state.queue,
+                neighbor_id,
+                neighbor_rank,
+                options.max_settled,
+            );
+        }
 
-            let witness_weight = dijkstra.search(graph, v, combined_weight, 500);
+        graph.get_node_mut(contracted_id).set_is_contracted(true);
 
-            if witness_weight > combined_weight {
-                add_shortcut(
-                    overlay,
-                    w,
-                    v,
-                    combined_weight,
-                    bwd_edge_index,
-                    fwd_edge_index,
-                );
-                add_shortcut(graph, w, v, combined_weight, bwd_edge_index, fwd_edge_index);
+        state.contracted += 1;
+        progress(ContractionProgress {
+            contracted: state.contracted,
+            total,
+            shortcuts_added: state.shortcuts_added,
+            mem_bytes: mem_tracker.mem_bytes(graph),
+        });
+
+        if let Some(checkpoint) = checkpoint {
+            if state.contracted.is_multiple_of(checkpoint.every.max(1)) {
+                state.save(graph, &checkpoint.path)?;
             }
         }
     }
+
+    if state.contracted >= total {
+        for (&node_id, _) in state.queue.iter() {
+            graph.get_node_mut(node_id).set_rank(CORE_RANK);
+        }
+    }
+
+    Ok(())
+}
+
+// Re-ranks a neighbor of a node that was just contracted and updates its priority, returning
+// whether it was updated. Nodes contracted earlier are no longer queued and are skipped: their
+// rank is final, raising it could lift them above the nodes their shortcuts lead to.
+fn update_neighbor(
+    graph: &mut Graph,
+    dijkstra: &mut Dijkstra,
+    queue: &mut ContractionQueue,
+    neighbor_id: usize,
+    neighbor_rank: i32,
+    max_settled: usize,
+) -> bool {
+    if graph.get_node(neighbor_id).get_is_contracted() {
+        return false;
+    }
+
+    let rank = rank_node(graph, dijkstra, neighbor_id, max_settled);
+    graph.get_node_mut(neighbor_id).raise_rank(neighbor_rank);
+    let old_priority = queue.change_priority(&neighbor_id, Reverse((rank, neighbor_id)));
+    debug_assert!(
+        old_priority.is_some(),
+        "uncontracted node {neighbor_id} is missing from the queue"
+    );
+    true
 }
 
-fn add_shortcut(
+/// Contracts `graph` in place like `contract_graph`, in the fixed `order`, which must hold every
+/// dense id once. Each node is ranked by its position in `order`, and the nodes at the end of it
+/// left as the core by `stop_fraction` are ranked `CORE_RANK`. Fails without touching the graph
+/// if `order` isn't a permutation of its nodes.
+pub fn contract_graph_in_order(
     graph: &mut Graph,
+    order: &[usize],
+    options: &ContractionOptions,
+    mut progress: impl FnMut(ContractionProgress),
+) -> anyhow::Result<()> {
+    let num_nodes = graph.num_nodes();
+    if order.len() != num_nodes {
+        anyhow::bail!(
+            "the order has {} nodes but the graph has {num_nodes}",
+            order.len()
+        );
+    }
+    let mut seen = vec![false; num_nodes];
+    for &node_id in order {
+        if node_id >= num_nodes {
+            anyhow::bail!("node {node_id} of the order is out of range for {num_nodes} nodes");
+        }
+        if std::mem::replace(&mut seen[node_id], true) {
+            anyhow::bail!("node {node_id} appears in the order more than once");
+        }
+    }
+
+    let total = options.num_to_contract(order.len());
+
+    let mem_tracker = MemTracker::new(graph);
+    let mut shortcuts_added = 0;
+
+    for &node_id in &order[total..] {
+        graph.get_node_mut(node_id).set_rank(CORE_RANK);
+    }
+    for (position, &node_id) in order[..total].iter().enumerate() {
+        graph.get_node_mut(node_id).set_rank(position as i32);
+        shortcuts_added += contract_node(graph, node_id, options);
+        graph.get_node_mut(node_id).set_is_contracted(true);
+
+        progress(ContractionProgress {
+            contracted: position + 1,
+            total,
+            shortcuts_added,
+            mem_bytes: mem_tracker.mem_bytes(graph),
+        });
+    }
+
+    Ok(())
+}
+
+// Every shortcut adds an edge and a metadata entry to the overlay, and one entry to each
+// adjacency list, so the memory usage can be tracked without rescanning the overlay.
+struct MemTracker {
+    base_mem_bytes: usize,
+    base_num_edges: usize,
+    base_num_metadata: usize,
+}
+
+impl MemTracker {
+    fn new(overlay: &Graph) -> Self {
+        Self {
+            base_mem_bytes: overlay.get_mem_usage(),
+            base_num_edges: overlay.num_edges(),
+            base_num_metadata: overlay.edge_metadata.len(),
+        }
+    }
+
+    fn mem_bytes(&self, overlay: &Graph) -> usize {
+        self.base_mem_bytes
+            + (overlay.num_edges() - self.base_num_edges)
+                * (std::mem::size_of::<Edge>() + 2 * std::mem::size_of::<usize>())
+            + (overlay.edge_metadata.len() - self.base_num_metadata)
+                * std::mem::size_of::<EdgeMetadata>()
+    }
+}
+
+/// A shortcut found while contracting a node, to be added from w to v.
+#[derive(Debug, Clone, PartialEq)]
+struct Shortcut {
     w: usize,
     v: usize,
-    combined_weight: f32,
-    prev_edge_idx: usize,
-    next_edge_idx: usize,
-) {
+    weight: f32,
+    alt_weight: f32,
+    hops: u32,
+    prev_edge: usize,
+    next_edge: usize,
+}
+
+// Edges to nodes that are already contracted are kept for the overlay, but are no longer part
+// of the graph being contracted.
+fn active_fwd_edges(graph: &Graph, node_id: usize) -> Vec<usize> {
+    graph
+        .get_fwd_neighbors(node_id)
+        .iter()
+        .copied()
+        .filter(|&id| {
+            !graph
+                .get_node(graph.get_edge(id).dest_id)
+                .get_is_contracted()
+        })
+        .collect()
+}
+
+fn active_bwd_edges(graph: &Graph, node_id: usize) -> Vec<usize> {
+    graph
+        .get_bwd_neighbors(node_id)
+        .iter()
+        .copied()
+        .filter(|&id| {
+            !graph
+                .get_node(graph.get_edge(id).src_id)
+                .get_is_contracted()
+        })
+        .collect()
+}
+
+// Contracts a single node, returning the number of shortcut edges added to the graph.
+// The witness searches are run in parallel, and the shortcuts are then applied serially.
+fn contract_node(graph: &mut Graph, node_id: usize, options: &ContractionOptions) -> usize {
+    let shortcuts = find_shortcuts(graph, node_id, options);
+
+    let mut shortcuts_added = 0;
+    for shortcut in &shortcuts {
+        if add_shortcut(graph, shortcut, options) {
+            shortcuts_added += 1;
+        }
+    }
+
+    shortcuts_added
+}
+
+// Finds the shortcuts needed to contract `node_id`, running the witness searches from each of its
+// backward edges in parallel.
+fn find_shortcuts(graph: &Graph, node_id: usize, options: &ContractionOptions) -> Vec<Shortcut> {
+    let fwd_indices = active_fwd_edges(graph, node_id);
+    let bwd_indices = active_bwd_edges(graph, node_id);
+    let num_nodes = graph.num_nodes();
+
+    let mut shortcuts: Vec<Shortcut> = bwd_indices
+        .par_iter()
+        .flat_map_iter(|&bwd_edge_index| {
+            Dijkstra::with_thread_local(num_nodes, |dijkstra| {
+                find_shortcuts_from(
+                    graph,
+                    dijkstra,
+                    node_id,
+                    bwd_edge_index,
+                    &fwd_indices,
+                    options,
+                )
+            })
+        })
+        .collect();
+
+    sort_shortcuts(&mut shortcuts);
+    shortcuts
+}
+
+// Sorts shortcuts so they are applied in the same order regardless of how the searches ran.
+fn sort_shortcuts(shortcuts: &mut [Shortcut]) {
+    shortcuts.sort_by_key(|s| (s.w, s.v, s.prev_edge, s.next_edge));
+}
+
+// Runs the witness searches from the source of a single backward edge of `node_id`, returning
+// the shortcuts needed to preserve the shortest paths through `node_id` under every metric the
+// hierarchy is built for.
+fn find_shortcuts_from(
+    graph: &Graph,
+    dijkstra: &mut Dijkstra,
+    node_id: usize,
+    bwd_edge_index: usize,
+    fwd_indices: &[usize],
+    options: &ContractionOptions,
+) -> Vec<Shortcut> {
+    let bwd_edge = graph.get_edge(bwd_edge_index);
+    let bwd_metadata = graph.get_edge_metadata(bwd_edge);
+    let w = bwd_edge.src_id;
+    debug_assert!(
+        fwd_indices
+            .iter()
+            .map(|&id| graph.get_edge_metadata(graph.get_edge(id)))
+            .chain([bwd_metadata])
+            .all(|m| is_sane_weight(m.weight) && is_sane_weight(m.alt_weight)),
+        "an edge around node {node_id} has a weight outside 0..=f32::MAX"
+    );
+
+    // A single search per metric, up to the heaviest path through `node_id`, finds the
+    // witnesses to every target.
+    let mut needed = vec![false; fwd_indices.len()];
+    dijkstra.set_prefer_fewer_hops(options.prefer_fewer_hops);
+    for &metric in options.metrics() {
+        let combined_weights: Vec<Option<f32>> = fwd_indices
+            .iter()
+            .map(|&fwd_edge_index| {
+                let fwd_edge = graph.get_edge(fwd_edge_index);
+                let v = fwd_edge.dest_id;
+                (v != w && v != node_id && w != node_id).then(|| {
+                    add_weights(
+                        bwd_metadata.metric_weight(metric),
+                        graph.get_edge_metadata(fwd_edge).metric_weight(metric),
+                    )
+                })
+            })
+            .collect();
+        let Some(limit_weight) = combined_weights.iter().flatten().copied().reduce(f32::max) else {
+            break;
+        };
+
+        dijkstra.init_with_metric(w, node_id, metric);
+        dijkstra.search_all(graph, limit_weight, options.max_settled);
+        for (i, &fwd_edge_index) in fwd_indices.iter().enumerate() {
+            if let Some(combined_weight) = combined_weights[i] {
+                let fwd_edge = graph.get_edge(fwd_edge_index);
+                let v = fwd_edge.dest_id;
+                let via_hops = bwd_metadata.hops + graph.get_edge_metadata(fwd_edge).hops;
+                let witness_weight = dijkstra.weight(v);
+                // Saturated weights can't be told apart, so the shortcut is kept to be safe.
+                needed[i] |= !dijkstra.is_reached(v)
+                    || witness_weight > combined_weight
+                    || combined_weight == f32::MAX
+                    || (options.prefer_fewer_hops
+                        && witness_weight == combined_weight
+                        && dijkstra.hops(v) > via_hops);
+            }
+        }
+    }
+
+    fwd_indices
+        .iter()
+        .zip(needed)
+        .filter(|&(_, needed)| needed)
+        .map(|(&fwd_edge_index, _)| {
+            let fwd_edge = graph.get_edge(fwd_edge_index);
+            let fwd_metadata = graph.get_edge_metadata(fwd_edge);
+            Shortcut {
+                w,
+                v: fwd_edge.dest_id,
+                weight: add_weights(bwd_metadata.weight, fwd_metadata.weight),
+                alt_weight: add_weights(bwd_metadata.alt_weight, fwd_metadata.alt_weight),
+                hops: bwd_metadata.hops + fwd_metadata.hops,
+                prev_edge: bwd_edge_index,
+                next_edge: fwd_edge_index,
+            }
+        })
+        .collect()
+}
+
+// Adds a w -> v shortcut, returning true if a new edge was created. An existing w -> v edge that
+// is no longer under every metric makes the shortcut redundant, and one the shortcut is no
+// longer than is replaced by it. Otherwise the shortcut is added as a parallel edge, which only
+// happens when the two metrics disagree on the shorter one. Hops break ties in length when
+// fewer hops are preferred.
+fn add_shortcut(graph: &mut Graph, shortcut: &Shortcut, options: &ContractionOptions) -> bool {
+    let Shortcut {
+        w,
+        v,
+        prev_edge,
+        next_edge,
+        ..
+    } = *shortcut;
+    let distance_m = graph
+        .get_edge_metadata(graph.get_edge(prev_edge))
+        .distance_m
+        + graph
+            .get_edge_metadata(graph.get_edge(next_edge))
+            .distance_m;
+    let time_s = graph.get_edge_metadata(graph.get_edge(prev_edge)).time_s
+        + graph.get_edge_metadata(graph.get_edge(next_edge)).time_s;
     let shortcut_metadata = EdgeMetadata {
-        weight: combined_weight,
+        weight: shortcut.weight,
+        alt_weight: shortcut.alt_weight,
+        distance_m,
+        time_s,
+        time_factor: 1.0,
         speed_limit: None,
         name: None,
+        names: None,
         is_one_way: true,
         is_roundabout: false,
-        next_edge: Some(next_edge_idx),
-        prev_edge: Some(prev_edge_idx),
+        roundabout: None,
+        road_class: RoadClass::Unknown,
+        max_height_m: None,
+        max_weight_t: None,
+        max_width_m: None,
+        is_ferry: false,
+        is_reversible: false,
+        next_edge: Some(next_edge),
+        prev_edge: Some(prev_edge),
+        geometry: Vec::new(),
+        hops: shortcut.hops,
+        way_id: None,
     };
 
+    let no_longer = |a: &EdgeMetadata, b: &EdgeMetadata| {
+        let no_heavier =
+            |x: f32, y: f32| x < y || (x == y && (!options.prefer_fewer_hops || a.hops <= b.hops));
+        no_heavier(a.weight, b.weight)
+            && (!options.secondary_metric || no_heavier(a.alt_weight, b.alt_weight))
+    };
+    let existing_ids: Vec<usize> = graph
+        .get_fwd_neighbors(w)
+        .iter()
+        .copied()
+        .filter(|&id| graph.get_edge(id).dest_id == v)
+        .collect();
+    let existing = |id: usize| graph.get_edge_metadata(graph.get_edge(id));
+
+    if existing_ids
+        .iter()
+        .any(|&id| no_longer(existing(id), &shortcut_metadata))
+    {
+        return false;
+    }
+    if let Some(&existing_id) = existing_ids
+        .iter()
+        .find(|&&id| no_longer(&shortcut_metadata, existing(id)))
+    {
+        // The metadata may be shared with the reverse edge, so point at fresh metadata
+        // instead of updating it in place.
+        let metadata_index = graph.edge_metadata.len();
+        graph.edge_metadata.push(shortcut_metadata);
+        graph.get_edge_mut(existing_id).metadata_index = metadata_index;
+        return false;
+    }
+
     let metadata_index = graph.edge_metadata.len();
     graph.edge_metadata.push(shortcut_metadata);
     graph.add_shortcut_edge(w, v, metadata_index);
+    true
 }
 
-fn rank_node(graph: &Graph, dijkstra: &mut Dijkstra, node_id: usize) -> i32 {
-    let in_deg = graph.bwd_edge_list[node_id].len() as i32;
-    let out_deg = graph.fwd_edge_list[node_id].len() as i32;
-    let node_degree = in_deg + out_deg;
+// Ranks every node of the uncontracted graph in parallel. Each rayon worker reuses its own
+// thread-local witness search instead of allocating one per node.
+pub(crate) fn initial_priorities(graph: &Graph, max_settled: usize) -> Vec<i32> {
+    let num_nodes = graph.num_nodes();
+    (0..num_nodes)
+        .into_par_iter()
+        .map(|node_id| {
+            Dijkstra::with_thread_local(num_nodes, |dijkstra| {
+                rank_node(graph, dijkstra, node_id, max_settled)
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn rank_node(
+    graph: &Graph,
+    dijkstra: &mut Dijkstra,
+    node_id: usize,
+    max_settled: usize,
+) -> i32 {
+    let bwd_ids = active_bwd_edges(graph, node_id);
+    let fwd_ids = active_fwd_edges(graph, node_id);
+    let node_degree = (bwd_ids.len() + fwd_ids.len()) as i32;
     let mut contracted_count = 0;
 
-    for bwd_id in graph.get_bwd_neighbors(node_id) {
-        let bwd_edge = graph.get_edge(*bwd_id);
+    for &bwd_id in &bwd_ids {
+        let bwd_edge = graph.get_edge(bwd_id);
         let bwd_src_id = bwd_edge.src_id;
 
         dijkstra.init(bwd_src_id, node_id);
-        for fwd_id in graph.get_fwd_neighbors(node_id) {
-            let fwd_edge = graph.get_edge(*fwd_id);
+        for &fwd_id in &fwd_ids {
+            let fwd_edge = graph.get_edge(fwd_id);
             let fwd_dest_id = fwd_edge.dest_id;
 
             if fwd_dest_id == bwd_src_id {
@@ -145,10 +736,10 @@ fn rank_node(graph: &Graph, dijkstra: &mut Dijkstra, node_id: usize) -> i32 {
 
             let weight_v_u = graph.get_edge_metadata(fwd_edge).weight;
             let weight_u_w = graph.get_edge_metadata(bwd_edge).weight;
-            let combined_weight = weight_u_w + weight_v_u;
+            let combined_weight = add_weights(weight_u_w, weight_v_u);
 
-            let witness_weight = dijkstra.search(graph, fwd_dest_id, combined_weight, 500);
-            if witness_weight > combined_weight {
+            let witness_weight = dijkstra.search(graph, fwd_dest_id, combined_weight, max_settled);
+            if witness_weight > combined_weight || combined_weight == f32::MAX {
                 contracted_count += 1;
             }
         }
@@ -159,13 +750,10 @@ fn rank_node(graph: &Graph, dijkstra: &mut Dijkstra, node_id: usize) -> i32 {
 
 #[cfg(test)]
 mod tests {
-    use crate::engine::{
-        preprocess::graph::{Edge, Node},
-        query::{
-            self,
-            ch_query::{self, BiDirDijkstra},
-        },
-    };
+    use std::collections::BinaryHeap;
+
+    use crate::engine::preprocess::{graph::Node, witness_search::HeapItem};
+    use crate::engine::test_utils::assert_ch_matches_dijkstra;
 
     use super::*;
 
@@ -254,104 +842,20 @@ mod tests {
         ];
 
         let edge_metadata = vec![
-            EdgeMetadata {
-                weight: 10.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 10.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 3.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 3.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 6.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 6.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 7.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 7.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 8.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 8.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 9.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 9.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 4.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 4.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
+            EdgeMetadata::new(10.0),
+            EdgeMetadata::new(10.0),
+            EdgeMetadata::new(3.0),
+            EdgeMetadata::new(3.0),
+            EdgeMetadata::new(6.0),
+            EdgeMetadata::new(6.0),
+            EdgeMetadata::new(7.0),
+            EdgeMetadata::new(7.0),
+            EdgeMetadata::new(8.0),
+            EdgeMetadata::new(8.0),
+            EdgeMetadata::new(9.0),
+            EdgeMetadata::new(9.0),
+            EdgeMetadata::new(4.0),
+            EdgeMetadata::new(4.0),
         ];
 
         Graph {
@@ -363,18 +867,863 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_initial_priorities_match_fresh_searches() {
+        // The test graph and a 12x12 grid.
+        for graph in [get_test_graph(), get_grid_graph(12)] {
+            let fresh: Vec<i32> = (0..graph.num_nodes())
+                .map(|id| {
+                    let mut dijkstra = Dijkstra::new(graph.num_nodes());
+                    rank_node(&graph, &mut dijkstra, id, DEFAULT_MAX_SETTLED)
+                })
+                .collect();
+            assert_eq!(initial_priorities(&graph, DEFAULT_MAX_SETTLED), fresh);
+        }
+    }
+
+    #[test]
+    fn test_contracted_neighbor_not_reranked() {
+        let mut graph = get_test_graph();
+        let options = ContractionOptions::default();
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        let mut state = ContractionState::new(&graph, &options);
+
+        // Node 1 was contracted earlier and left the queue, then its neighbor 2 is contracted.
+        state.queue.remove(&1);
+        contract_node(&mut graph, 1, &options);
+        graph.get_node_mut(1).set_rank(0);
+        graph.get_node_mut(1).set_is_contracted(true);
+        state.queue.remove(&2);
+        contract_node(&mut graph, 2, &options);
+
+        let queued = state.queue.len();
+        assert!(!update_neighbor(
+            &mut graph,
+            &mut dijkstra,
+            &mut state.queue,
+            1,
+            5,
+            options.max_settled,
+        ));
+        assert_eq!(graph.get_node(1).get_rank(), 0);
+        assert_eq!(state.queue.len(), queued);
+        assert!(state.queue.get(&1).is_none());
+
+        assert!(update_neighbor(
+            &mut graph,
+            &mut dijkstra,
+            &mut state.queue,
+            3,
+            5,
+            options.max_settled,
+        ));
+        assert!(graph.get_node(3).get_rank() >= 5);
+    }
+
+    #[test]
+    fn test_contraction_with_self_loop() {
+        // The self-loop leads back to the node being contracted, which was already popped.
+        let mut graph = get_test_graph();
+        let metadata_index = graph.edge_metadata.len();
+        graph.edge_metadata.push(EdgeMetadata::new(1.0));
+        graph.add_edge(2, 2, metadata_index);
+
+        let mut overlay = graph.clone();
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        contract_graph(
+            &mut overlay,
+            &mut dijkstra,
+            &ContractionOptions::default(),
+            |_| {},
+        );
+        assert!(overlay.nodes.iter().all(|node| node.get_is_contracted()));
+        assert_ch_matches_dijkstra(&graph, &overlay);
+    }
+
+    #[test]
+    fn test_contract_node_dedups_shortcut() {
+        // 0 -> 1 -> 2 with weight 1 each, and a direct 0 -> 2 edge with weight 5.
+        let nodes = (0..3).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, weight) in [(0, 1, 1.0), (1, 2, 1.0), (0, 2, 5.0)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+        }
+
+        contract_node(&mut graph, 1, &ContractionOptions::default());
+
+        let edges_0_2: Vec<_> = graph
+            .get_fwd_neighbors(0)
+            .iter()
+            .filter(|&&e| graph.get_edge(e).dest_id == 2)
+            .collect();
+        assert_eq!(edges_0_2.len(), 1);
+
+        let metadata = graph.get_edge_metadata(graph.get_edge(*edges_0_2[0]));
+        assert_eq!(metadata.weight, 2.0);
+        assert_eq!(metadata.prev_edge, Some(0));
+        assert_eq!(metadata.next_edge, Some(1));
+        assert_eq!(graph.num_edges(), 3);
+
+        // A longer shortcut than the existing edge is skipped.
+        let longer = Shortcut {
+            w: 0,
+            v: 2,
+            weight: 10.0,
+            alt_weight: 10.0,
+            hops: 2,
+            prev_edge: 0,
+            next_edge: 1,
+        };
+        add_shortcut(&mut graph, &longer, &ContractionOptions::default());
+        assert_eq!(graph.num_edges(), 3);
+        assert_eq!(
+            graph
+                .get_edge_metadata(graph.find_edge(0, 2).unwrap())
+                .weight,
+            2.0
+        );
+    }
+
+    #[test]
+    fn test_huge_weights_keep_shortcut_decisions() {
+        // 0 -> 1 -> 2 through the contracted node, and a witness 0 -> 3 -> 2 of the given
+        // weights, all of them a sizable fraction of f32::MAX.
+        let contract = |via: f32, witness: f32| {
+            let nodes = (0..4).map(|i| Node::new(i, 100 + i as i64)).collect();
+            let mut graph = Graph::new(nodes);
+            for (src, dest, weight) in [(0, 1, via), (1, 2, via), (0, 3, witness), (3, 2, witness)]
+            {
+                let metadata_index = graph.edge_metadata.len();
+                graph.edge_metadata.push(EdgeMetadata::new(weight));
+                graph.add_edge(src, dest, metadata_index);
+            }
+            let added = contract_node(&mut graph, 1, &ContractionOptions::default());
+            let weight = graph
+                .find_edge(0, 2)
+                .map(|edge| graph.get_edge_metadata(edge).weight);
+            (added, weight)
+        };
+
+        let max = f32::MAX;
+        assert_eq!(
+            contract(max * 0.2, max * 0.3),
+            (1, Some(max * 0.2 + max * 0.2))
+        );
+        assert_eq!(contract(max * 0.3, max * 0.2), (0, None));
+        // Past f32::MAX the shortcut saturates rather than becoming infinite, and is kept even
+        // though the witness saturates too.
+        assert_eq!(contract(max * 0.75, max), (1, Some(max)));
+    }
+
+    #[test]
+    fn test_contraction_progress() {
+        let mut graph = get_test_graph();
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        let num_nodes = graph.num_nodes();
+
+        let mut reports = Vec::new();
+        contract_graph(
+            &mut graph,
+            &mut dijkstra,
+            &ContractionOptions::default(),
+            |p| reports.push(p),
+        );
+
+        assert_eq!(reports.len(), num_nodes);
+        for (i, report) in reports.iter().enumerate() {
+            assert_eq!(report.contracted, i + 1);
+            assert_eq!(report.total, num_nodes);
+        }
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].shortcuts_added <= w[1].shortcuts_added));
+
+        let last = reports.last().unwrap();
+        assert_eq!(last.mem_bytes, graph.get_mem_usage());
+        assert!(graph.nodes.iter().all(|node| node.get_is_contracted()));
+        assert_ch_matches_dijkstra(&get_test_graph(), &graph);
+    }
+
+    #[test]
+    fn test_resume_contraction() {
+        let options = ContractionOptions::default();
+        let mut uninterrupted = get_test_graph();
+        let num_nodes = uninterrupted.num_nodes();
+        contract_graph(
+            &mut uninterrupted,
+            &mut Dijkstra::new(num_nodes),
+            &options,
+            |_| {},
+        );
+
+        // Contract the first half of the nodes, checkpointing at the end of it.
+        let mut graph = get_test_graph();
+        let mut dijkstra = Dijkstra::new(num_nodes);
+        let half = num_nodes / 2;
+        let path = std::env::temp_dir().join(format!(
+            "shepherd-checkpoint-test-{}.bin",
+            std::process::id()
+        ));
+        let checkpoints = CheckpointOptions {
+            path: path.clone(),
+            every: half,
+        };
+        let mut state = ContractionState::new(&graph, &options);
+        contract_queued(
+            &mut graph,
+            &mut dijkstra,
+            &options,
+            &mut state,
+            Some(&checkpoints),
+            Some(half),
+            |_| {},
+        )
+        .unwrap();
+
+        let checkpoint = ContractionCheckpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.contracted, half);
+        let resumed = resume_contraction(
+            checkpoint,
+            &mut Dijkstra::new(num_nodes),
+            &options,
+            None,
+            |_| {},
+        )
+        .unwrap();
+
+        let hierarchy = |graph: &Graph| {
+            let nodes: Vec<_> = graph
+                .nodes
+                .iter()
+                .map(|node| (node.rank, node.is_contracted))
+                .collect();
+            let edges: Vec<_> = graph
+                .edges
+                .iter()
+                .map(|edge| {
+                    let metadata = graph.get_edge_metadata(edge);
+                    (
+                        edge.src_id,
+                        edge.dest_id,
+                        metadata.weight.to_bits(),
+                        metadata.prev_edge,
+                        metadata.next_edge,
+                    )
+                })
+                .collect();
+            (nodes, edges)
+        };
+        assert_eq!(hierarchy(&resumed), hierarchy(&uninterrupted));
+        assert_ch_matches_dijkstra(&get_test_graph(), &resumed);
+    }
+
+    #[test]
+    fn test_checkpoint_file() {
+        let graph = get_test_graph();
+        let path = std::env::temp_dir().join(format!(
+            "shepherd-checkpoint-file-test-{}.bin",
+            std::process::id()
+        ));
+        let state = ContractionState::new(&graph, &ContractionOptions::default());
+        state.save(&graph, &path).unwrap();
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        assert!(!Path::new(&tmp_path).exists());
+        let checkpoint = ContractionCheckpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.overlay.num_nodes(), graph.num_nodes());
+        assert_eq!(checkpoint.queue.len(), graph.num_nodes());
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[4..8].copy_from_slice(&(CHECKPOINT_FILE_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let err = ContractionCheckpoint::load(&path)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains(&format!("version {}", CHECKPOINT_FILE_VERSION + 1)),
+            "{err}"
+        );
+
+        std::fs::write(&path, b"not a checkpoint").unwrap();
+        let err = ContractionCheckpoint::load(&path)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("is not a checkpoint file"), "{err}");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Finds the shortcuts like `find_shortcuts_from`, with a separate witness search per target.
+    fn find_shortcuts_per_target(
+        graph: &Graph,
+        dijkstra: &mut Dijkstra,
+        node_id: usize,
+        bwd_edge_index: usize,
+        fwd_indices: &[usize],
+    ) -> Vec<Shortcut> {
+        let bwd_edge = graph.get_edge(bwd_edge_index);
+        let w = bwd_edge.src_id;
+        let mut shortcuts = Vec::new();
+        for &fwd_edge_index in fwd_indices {
+            let fwd_edge = graph.get_edge(fwd_edge_index);
+            let v = fwd_edge.dest_id;
+            if v == w || v == node_id || w == node_id {
+                continue;
+            }
+
+            let weight =
+                graph.get_edge_metadata(bwd_edge).weight + graph.get_edge_metadata(fwd_edge).weight;
+            dijkstra.init(w, node_id);
+            if dijkstra.search(graph, v, weight, DEFAULT_MAX_SETTLED) > weight {
+                shortcuts.push(Shortcut {
+                    w,
+                    v,
+                    weight,
+                    alt_weight: weight,
+                    hops: 2,
+                    prev_edge: bwd_edge_index,
+                    next_edge: fwd_edge_index,
+                });
+            }
+        }
+        shortcuts
+    }
+
+    #[test]
+    fn test_single_witness_search_per_source() {
+        let graph = get_test_graph();
+        let mut single = Dijkstra::new(graph.num_nodes());
+        let mut per_target = Dijkstra::new(graph.num_nodes());
+
+        for node_id in 0..graph.num_nodes() {
+            let fwd_indices = graph.get_fwd_neighbors(node_id).clone();
+            for &bwd_edge_index in graph.get_bwd_neighbors(node_id) {
+                let found = find_shortcuts_from(
+                    &graph,
+                    &mut single,
+                    node_id,
+                    bwd_edge_index,
+                    &fwd_indices,
+                    &ContractionOptions::default(),
+                );
+                let expected = find_shortcuts_per_target(
+                    &graph,
+                    &mut per_target,
+                    node_id,
+                    bwd_edge_index,
+                    &fwd_indices,
+                );
+                assert_eq!(found, expected, "mismatch contracting node {node_id}");
+            }
+        }
+
+        assert!(single.num_searches() < per_target.num_searches());
+    }
+
+    // A size x size two-way grid with uneven weights.
+    fn get_grid_graph(size: usize) -> Graph {
+        let nodes = (0..size * size)
+            .map(|i| Node::new(i, 100 + i as i64))
+            .collect();
+        let mut graph = Graph::new(nodes);
+        for id in 0..size * size {
+            for next in [id + 1, id + size] {
+                if (next == id + 1 && id % size == size - 1) || next >= size * size {
+                    continue;
+                }
+                let metadata_index = graph.edge_metadata.len();
+                graph
+                    .edge_metadata
+                    .push(EdgeMetadata::new(1.0 + ((id * 7 + next) % 5) as f32));
+                graph.add_edge(id, next, metadata_index);
+                graph.add_edge(next, id, metadata_index);
+            }
+        }
+        graph
+    }
+
+    // A seeded random graph: a two-way ring, so every node is reachable, and random extra edges,
+    // about half of them oneway.
+    fn get_random_graph(num_nodes: usize, num_extra_edges: usize, seed: u64) -> Graph {
+        // xorshift64, the seed must not be 0.
+        let mut state = seed;
+        let mut next = |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+
+        let mut edges: Vec<_> = (0..num_nodes)
+            .map(|id| (id, (id + 1) % num_nodes, true))
+            .collect();
+        for _ in 0..num_extra_edges {
+            let (src, dest) = (next(num_nodes), next(num_nodes));
+            if src != dest {
+                edges.push((src, dest, next(2) == 0));
+            }
+        }
+
+        let nodes = (0..num_nodes)
+            .map(|i| Node::new(i, 100 + i as i64))
+            .collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, is_two_way) in edges {
+            let metadata_index = graph.edge_metadata.len();
+            graph
+                .edge_metadata
+                .push(EdgeMetadata::new(1.0 + next(20) as f32));
+            graph.add_edge(src, dest, metadata_index);
+            if is_two_way {
+                graph.add_edge(dest, src, metadata_index);
+            }
+        }
+        graph
+    }
+
+    // Finds the shortcuts contracting `node_id` needs the way contraction did before the searches
+    // ran in parallel: a single Dijkstra and a witness search for every pair of edges around it.
+    fn find_shortcuts_serial(
+        graph: &Graph,
+        dijkstra: &mut Dijkstra,
+        node_id: usize,
+        options: &ContractionOptions,
+    ) -> Vec<Shortcut> {
+        let mut shortcuts = Vec::new();
+        dijkstra.set_prefer_fewer_hops(options.prefer_fewer_hops);
+        for bwd_edge_index in active_bwd_edges(graph, node_id) {
+            let bwd_edge = graph.get_edge(bwd_edge_index);
+            let bwd_metadata = graph.get_edge_metadata(bwd_edge);
+            let w = bwd_edge.src_id;
+
+            for fwd_edge_index in active_fwd_edges(graph, node_id) {
+                let fwd_edge = graph.get_edge(fwd_edge_index);
+                let fwd_metadata = graph.get_edge_metadata(fwd_edge);
+                let v = fwd_edge.dest_id;
+                if v == w || v == node_id || w == node_id {
+                    continue;
+                }
+
+                let via_hops = bwd_metadata.hops + fwd_metadata.hops;
+                let needed = options.metrics().iter().any(|&metric| {
+                    let combined_weight = add_weights(
+                        bwd_metadata.metric_weight(metric),
+                        fwd_metadata.metric_weight(metric),
+                    );
+                    dijkstra.init_with_metric(w, node_id, metric);
+                    let witness_weight =
+                        dijkstra.search(graph, v, combined_weight, options.max_settled);
+                    !dijkstra.is_reached(v)
+                        || witness_weight > combined_weight
+                        || combined_weight == f32::MAX
+                        || (options.prefer_fewer_hops
+                            && witness_weight == combined_weight
+                            && dijkstra.hops(v) > via_hops)
+                });
+                if needed {
+                    shortcuts.push(Shortcut {
+                        w,
+                        v,
+                        weight: add_weights(bwd_metadata.weight, fwd_metadata.weight),
+                        alt_weight: add_weights(bwd_metadata.alt_weight, fwd_metadata.alt_weight),
+                        hops: via_hops,
+                        prev_edge: bwd_edge_index,
+                        next_edge: fwd_edge_index,
+                    });
+                }
+            }
+        }
+
+        sort_shortcuts(&mut shortcuts);
+        shortcuts
+    }
+
+    #[test]
+    fn test_parallel_shortcuts_match_serial() {
+        let options = ContractionOptions::default();
+        for mut graph in [get_test_graph(), get_grid_graph(6)] {
+            let mut dijkstra = Dijkstra::new(graph.num_nodes());
+            let mut total_shortcuts = 0;
+
+            // Contracting as we go, so later nodes are searched on a graph with shortcuts.
+            for node_id in 0..graph.num_nodes() {
+                let serial = find_shortcuts_serial(&graph, &mut dijkstra, node_id, &options);
+                assert_eq!(
+                    find_shortcuts(&graph, node_id, &options),
+                    serial,
+                    "mismatch contracting node {node_id}"
+                );
+                total_shortcuts += serial.len();
+
+                contract_node(&mut graph, node_id, &options);
+                graph.get_node_mut(node_id).set_is_contracted(true);
+            }
+
+            assert!(total_shortcuts > 0);
+        }
+    }
+
+    // Shortest distance in a contracted overlay, meeting at the top of two upward searches.
+    fn ch_distance(overlay: &Graph, src: usize, dest: usize) -> f32 {
+        let upward = |start: usize, fwd: bool| {
+            let mut weights = vec![f32::INFINITY; overlay.num_nodes()];
+            let mut queue = BinaryHeap::new();
+            weights[start] = 0.0;
+            queue.push(HeapItem(start, 0.0));
+
+            while let Some(HeapItem(u, weight)) = queue.pop() {
+                if weight > weights[u] {
+                    continue;
+                }
+                let edge_ids = if fwd {
+                    overlay.get_fwd_neighbors(u)
+                } else {
+                    overlay.get_bwd_neighbors(u)
+                };
+                for &edge_id in edge_ids {
+                    let edge = overlay.get_edge(edge_id);
+                    let v = if fwd { edge.dest_id } else { edge.src_id };
+                    if overlay.get_node(v).get_rank() < overlay.get_node(u).get_rank() {
+                        continue;
+                    }
+                    let alt = weights[u] + overlay.get_edge_metadata(edge).weight;
+                    if alt < weights[v] {
+                        weights[v] = alt;
+                        queue.push(HeapItem(v, alt));
+                    }
+                }
+            }
+            weights
+        };
+
+        let fwd = upward(src, true);
+        let bwd = upward(dest, false);
+        fwd.iter()
+            .zip(&bwd)
+            .map(|(f, b)| f + b)
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    #[test]
+    fn test_contract_in_fixed_order() {
+        let mut overlay = get_test_graph();
+        let order = [5, 0, 6, 3, 1, 4, 2];
+
+        let mut reports = Vec::new();
+        contract_graph_in_order(&mut overlay, &order, &ContractionOptions::default(), |p| {
+            reports.push(p)
+        })
+        .unwrap();
+
+        assert_eq!(reports.len(), order.len());
+        for (position, &node_id) in order.iter().enumerate() {
+            assert_eq!(overlay.get_node(node_id).get_rank(), position as i32);
+        }
+
+        // The uncontracted graph is undirected, so the expected distances are symmetric.
+        #[rustfmt::skip]
+        let expected = [
+            [0.0, 10.0, 13.0, 19.0, 26.0, 34.0, 22.0],
+            [10.0, 0.0, 3.0, 9.0, 16.0, 24.0, 12.0],
+            [13.0, 3.0, 0.0, 6.0, 13.0, 21.0, 9.0],
+            [19.0, 9.0, 6.0, 0.0, 7.0, 15.0, 11.0],
+            [26.0, 16.0, 13.0, 7.0, 0.0, 8.0, 4.0],
+            [34.0, 24.0, 21.0, 15.0, 8.0, 0.0, 12.0],
+            [22.0, 12.0, 9.0, 11.0, 4.0, 12.0, 0.0],
+        ];
+        for (src, row) in expected.iter().enumerate() {
+            for (dest, &weight) in row.iter().enumerate() {
+                assert_eq!(
+                    ch_distance(&overlay, src, dest),
+                    weight,
+                    "mismatch for {src} -> {dest}"
+                );
+            }
+        }
+        assert_ch_matches_dijkstra(&get_test_graph(), &overlay);
+    }
+
+    #[test]
+    fn test_contract_in_order_rejects_non_permutations() {
+        for (order, error) in [
+            (vec![5, 0, 6, 3, 1, 4], "has 6 nodes but the graph has 7"),
+            (
+                vec![5, 0, 6, 3, 1, 4, 7],
+                "node 7 of the order is out of range",
+            ),
+            (
+                vec![5, 0, 6, 3, 1, 4, 0],
+                "node 0 appears in the order more than once",
+            ),
+        ] {
+            let mut graph = get_test_graph();
+            let err =
+                contract_graph_in_order(&mut graph, &order, &ContractionOptions::default(), |_| {})
+                    .unwrap_err();
+            assert!(err.to_string().contains(error), "{err}");
+            assert!(graph.nodes.iter().all(|node| !node.get_is_contracted()));
+        }
+    }
+
+    #[test]
+    fn test_settled_cap_keeps_distances() {
+        let contract = |max_settled| {
+            let mut graph = get_test_graph();
+            let mut shortcuts_added = 0;
+            // 2 -> 3 -> 4 has a witness through 6, which is contracted last.
+            contract_graph_in_order(
+                &mut graph,
+                &[3, 5, 0, 1, 2, 4, 6],
+                &ContractionOptions {
+                    max_settled,
+                    ..Default::default()
+                },
+                |p| shortcuts_added = p.shortcuts_added,
+            )
+            .unwrap();
+            (graph, shortcuts_added)
+        };
+
+        let (bounded, bounded_shortcuts) = contract(1);
+        let (unbounded, unbounded_shortcuts) = contract(DEFAULT_MAX_SETTLED);
+        assert!(
+            bounded_shortcuts > unbounded_shortcuts,
+            "{bounded_shortcuts} <= {unbounded_shortcuts}"
+        );
+
+        for src in 0..bounded.num_nodes() {
+            for dest in 0..bounded.num_nodes() {
+                assert_eq!(
+                    ch_distance(&bounded, src, dest),
+                    ch_distance(&unbounded, src, dest),
+                    "mismatch for {src} -> {dest}"
+                );
+            }
+        }
+        assert_ch_matches_dijkstra(&get_test_graph(), &bounded);
+        assert_ch_matches_dijkstra(&get_test_graph(), &unbounded);
+    }
+
+    #[test]
+    fn test_contraction_matches_dijkstra_on_grid() {
+        // Big enough for nodes to be contracted next to neighbors that were contracted before them.
+        let graph = get_grid_graph(6);
+        let mut overlay = graph.clone();
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        contract_graph(
+            &mut overlay,
+            &mut dijkstra,
+            &ContractionOptions::default(),
+            |_| {},
+        );
+
+        for src in 0..graph.num_nodes() {
+            for dest in 0..graph.num_nodes() {
+                dijkstra.init(src, usize::MAX);
+                let expected = dijkstra.search(&graph, dest, f32::INFINITY, usize::MAX);
+                assert_eq!(
+                    ch_distance(&overlay, src, dest),
+                    expected,
+                    "mismatch for {src} -> {dest}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefer_fewer_hops_on_uniform_grid() {
+        // With every edge weighing 1, paths of equal weight have equal hops, so the witnesses tie
+        // on both and preferring fewer hops mustn't add shortcuts.
+        let mut graph = get_grid_graph(6);
+        for metadata in &mut graph.edge_metadata {
+            metadata.weight = 1.0;
+            metadata.alt_weight = 1.0;
+        }
+        let contract = |prefer_fewer_hops| {
+            let mut overlay = graph.clone();
+            let options = ContractionOptions {
+                prefer_fewer_hops,
+                ..ContractionOptions::default()
+            };
+            contract_graph(
+                &mut overlay,
+                &mut Dijkstra::new(graph.num_nodes()),
+                &options,
+                |_| {},
+            );
+            overlay
+        };
+        let edges = |overlay: &Graph| {
+            overlay
+                .edges
+                .iter()
+                .map(|edge| {
+                    let metadata = overlay.get_edge_metadata(edge);
+                    (
+                        edge.src_id,
+                        edge.dest_id,
+                        metadata.weight.to_bits(),
+                        metadata.hops,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let fewer_hops = contract(true);
+        assert_eq!(edges(&fewer_hops), edges(&contract(false)));
+        assert_ch_matches_dijkstra(&graph, &fewer_hops);
+    }
+
+    #[test]
+    fn test_contraction_matches_dijkstra_on_random_graphs() {
+        for seed in [1, 42] {
+            let graph = get_random_graph(120, 180, seed);
+            let mut overlay = graph.clone();
+            let mut dijkstra = Dijkstra::new(graph.num_nodes());
+            contract_graph(
+                &mut overlay,
+                &mut dijkstra,
+                &ContractionOptions::default(),
+                |_| {},
+            );
+
+            // Shortcuts built on shortcuts, i.e. between nodes that both had neighbors contracted
+            // before them.
+            let is_shortcut = |edge_id: usize| {
+                overlay
+                    .get_edge_metadata(overlay.get_edge(edge_id))
+                    .prev_edge
+                    .is_some()
+            };
+            assert!(
+                overlay.edge_metadata.iter().any(|m| m
+                    .prev_edge
+                    .zip(m.next_edge)
+                    .is_some_and(|(prev, next)| is_shortcut(prev) || is_shortcut(next))),
+                "seed {seed} has no nested shortcuts"
+            );
+            assert_ch_matches_dijkstra(&graph, &overlay);
+        }
+    }
+
+    #[test]
+    fn test_contract_in_place_shortcuts() {
+        let mut graph = get_test_graph();
+        let num_original = graph.num_edges();
+        contract_graph_in_order(
+            &mut graph,
+            &[2, 4, 6, 1, 3, 5, 0],
+            &ContractionOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+
+        // Contracted nodes keep their edges, so the original edges are untouched.
+        assert_eq!(graph.edges[..num_original], get_test_graph().edges[..]);
+
+        let shortcuts: Vec<_> = graph.edges[num_original..]
+            .iter()
+            .map(|e| {
+                let metadata = graph.get_edge_metadata(e);
+                (
+                    e.src_id,
+                    e.dest_id,
+                    metadata.weight,
+                    metadata.prev_edge.unwrap(),
+                    metadata.next_edge.unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            shortcuts,
+            [
+                (1, 3, 9.0, 2, 4),
+                (1, 6, 12.0, 2, 10),
+                (3, 1, 9.0, 5, 3),
+                (6, 1, 12.0, 11, 3),
+                (3, 5, 15.0, 6, 8),
+                (3, 6, 11.0, 6, 12),
+                (5, 3, 15.0, 9, 7),
+                (5, 6, 12.0, 9, 12),
+                (6, 3, 11.0, 13, 7),
+                (6, 5, 12.0, 13, 8),
+                (0, 3, 19.0, 0, 14),
+                (3, 0, 19.0, 16, 1),
+                (0, 5, 34.0, 24, 18),
+                (5, 0, 34.0, 20, 25),
+            ]
+        );
+        assert_ch_matches_dijkstra(&get_test_graph(), &graph);
+    }
+
+    #[test]
+    fn test_partial_contraction() {
+        for stop_fraction in [0.3, 0.5, 1.0] {
+            let options = ContractionOptions {
+                stop_fraction,
+                ..Default::default()
+            };
+            let core_size = (7.0 * stop_fraction).ceil() as usize;
+            let core = |overlay: &Graph| {
+                overlay
+                    .nodes
+                    .iter()
+                    .filter(|n| n.get_rank() == CORE_RANK)
+                    .inspect(|n| assert!(!n.get_is_contracted()))
+                    .count()
+            };
+
+            let mut overlay = get_test_graph();
+            let mut dijkstra = Dijkstra::new(overlay.num_nodes());
+            let mut total = 0;
+            contract_graph(&mut overlay, &mut dijkstra, &options, |p| total = p.total);
+            assert_eq!(core(&overlay), core_size);
+            assert_eq!(total, 7 - core_size);
+            assert_eq!(
+                overlay
+                    .nodes
+                    .iter()
+                    .filter(|n| n.get_is_contracted())
+                    .count(),
+                total
+            );
+            assert_ch_matches_dijkstra(&get_test_graph(), &overlay);
+
+            let mut overlay = get_test_graph();
+            let order = [5, 0, 6, 3, 1, 4, 2];
+            contract_graph_in_order(&mut overlay, &order, &options, |_| {}).unwrap();
+            assert_eq!(core(&overlay), core_size);
+            assert!(order[7 - core_size..]
+                .iter()
+                .all(|&id| overlay.get_node(id).get_rank() == CORE_RANK));
+            assert_ch_matches_dijkstra(&get_test_graph(), &overlay);
+        }
+    }
+
     #[test]
     fn test_graph_contraction() {
         // TODO: Chnage tests to use the csr graph. maybe move the code to integration testing folder
-        let graph = get_test_graph();
         let mut overlay = get_test_graph();
         let mut dijkstra = Dijkstra::new(overlay.num_nodes());
 
         for node in &overlay.nodes {
-            println!("{}", rank_node(&graph, node.dense_id));
+            println!(
+                "{}",
+                rank_node(&overlay, &mut dijkstra, node.dense_id, DEFAULT_MAX_SETTLED)
+            );
         }
 
-        contract_graph(graph, &mut overlay, &mut dijkstra);
+        contract_graph(
+            &mut overlay,
+            &mut dijkstra,
+            &ContractionOptions::default(),
+            |_| {},
+        );
 
         for node in &overlay.nodes {
             println!("{:?}", node);
@@ -385,9 +1734,6 @@ mod tests {
             println!("\n\n");
         }
 
-        // let query = BiDirDijkstra::new(overlay.num_nodes());
-        // query.init(1, 4);
-
-        // println!("{:?}", query.search(overlay));
+        assert_ch_matches_dijkstra(&get_test_graph(), &overlay);
     }
 }
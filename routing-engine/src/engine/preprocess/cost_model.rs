@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+
+use super::graph::RoadClass;
+use super::profile::Profile;
+
+/// The speeds in km/h assumed on ways without a maxspeed tag, by profile and road class.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DefaultSpeeds {
+    // Speeds set with `set`, replacing the built-in ones.
+    overrides: HashMap<(Profile, RoadClass), f32>,
+}
+
+impl DefaultSpeeds {
+    /// Gets the default speed in km/h of a road class for a profile.
+    pub fn get(&self, profile: Profile, road_class: RoadClass) -> f32 {
+        self.overrides
+            .get(&(profile, road_class))
+            .copied()
+            .unwrap_or_else(|| Self::built_in(profile, road_class))
+    }
+
+    /// Overrides the default speed in km/h of a road class for a profile.
+    pub fn set(&mut self, profile: Profile, road_class: RoadClass, speed_kmh: f32) {
+        self.overrides.insert((profile, road_class), speed_kmh);
+    }
+
+    // The speeds used without overrides. The car speeds match on every road class, so adding
+    // one doesn't compile until it gets a speed, bikes and pedestrians have a catch-all.
+    fn built_in(profile: Profile, road_class: RoadClass) -> f32 {
+        match profile {
+            Profile::Car => match road_class {
+                RoadClass::Motorway => 100.0,
+                RoadClass::Trunk => 80.0,
+                RoadClass::Primary => 65.0,
+                RoadClass::Secondary => 55.0,
+                RoadClass::Tertiary => 40.0,
+                RoadClass::Unclassified => 30.0,
+                RoadClass::Residential => 25.0,
+                RoadClass::LivingStreet => 10.0,
+                RoadClass::Service | RoadClass::Track => 15.0,
+                RoadClass::Cycleway
+                | RoadClass::Path
+                | RoadClass::Footway
+                | RoadClass::Pedestrian
+                | RoadClass::Steps => 5.0,
+                RoadClass::Unknown => 30.0,
+            },
+            Profile::Bike => match road_class {
+                RoadClass::Footway | RoadClass::Pedestrian => 6.0,
+                RoadClass::Steps => 2.0,
+                _ => 15.0,
+            },
+            Profile::Foot => match road_class {
+                RoadClass::Steps => 2.0,
+                _ => 5.0,
+            },
+        }
+    }
+}
+
+/// Factors the travel time on a way is multiplied by for its surface and smoothness tags, by
+/// profile. Tag values without an entry, and every value for profiles without one, leave the
+/// travel time unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfaceMultipliers {
+    surface: HashMap<(Profile, String), f32>,
+    smoothness: HashMap<(Profile, String), f32>,
+}
+
+impl Default for SurfaceMultipliers {
+    fn default() -> Self {
+        let mut multipliers = Self {
+            surface: HashMap::new(),
+            smoothness: HashMap::new(),
+        };
+
+        // Cars are assumed to drive at the speed limit whatever the surface.
+        for (value, bike, foot) in [
+            ("paving_stones", 1.2, 1.0),
+            ("compacted", 1.2, 1.0),
+            ("fine_gravel", 1.3, 1.0),
+            ("sett", 1.5, 1.1),
+            ("cobblestone", 1.6, 1.1),
+            ("unhewn_cobblestone", 2.0, 1.2),
+            ("gravel", 1.5, 1.1),
+            ("unpaved", 1.5, 1.1),
+            ("dirt", 1.6, 1.1),
+            ("ground", 1.6, 1.1),
+            ("grass", 2.0, 1.2),
+            ("sand", 3.0, 1.5),
+            ("mud", 3.0, 1.5),
+        ] {
+            multipliers.set_surface(Profile::Bike, value, bike);
+            multipliers.set_surface(Profile::Foot, value, foot);
+        }
+        for (value, bike, foot) in [
+            ("intermediate", 1.1, 1.0),
+            ("bad", 1.3, 1.0),
+            ("very_bad", 1.6, 1.1),
+            ("horrible", 2.0, 1.2),
+            ("very_horrible", 3.0, 1.5),
+        ] {
+            multipliers.set_smoothness(Profile::Bike, value, bike);
+            multipliers.set_smoothness(Profile::Foot, value, foot);
+        }
+
+        multipliers
+    }
+}
+
+impl SurfaceMultipliers {
+    /// Gets the multiplier of a surface tag value for a profile.
+    pub fn surface(&self, profile: Profile, value: &str) -> f32 {
+        self.surface
+            .get(&(profile, value.to_string()))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Gets the multiplier of a smoothness tag value for a profile.
+    pub fn smoothness(&self, profile: Profile, value: &str) -> f32 {
+        self.smoothness
+            .get(&(profile, value.to_string()))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Overrides the multiplier of a surface tag value for a profile.
+    pub fn set_surface(&mut self, profile: Profile, value: &str, multiplier: f32) {
+        self.surface
+            .insert((profile, value.to_string()), multiplier);
+    }
+
+    /// Overrides the multiplier of a smoothness tag value for a profile.
+    pub fn set_smoothness(&mut self, profile: Profile, value: &str, multiplier: f32) {
+        self.smoothness
+            .insert((profile, value.to_string()), multiplier);
+    }
+}
+
+/// Turns the geometry and tags of a way into edge weights, measured in seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostModel {
+    // Speeds used on ways without a maxspeed tag.
+    pub default_speeds: DefaultSpeeds,
+    // Time lost passing through a node with traffic lights, in seconds.
+    pub traffic_light_penalty_s: f32,
+    // Time lost making a U-turn, gentler turns are charged proportionally less, in seconds.
+    pub u_turn_penalty_s: f32,
+    // Slow down travel on rough surfaces.
+    pub surface_multipliers: SurfaceMultipliers,
+    // Factors the travel time on each road class is multiplied by, e.g. to steer traffic off
+    // residential streets. Classes without an entry aren't scaled.
+    pub road_class_multipliers: HashMap<RoadClass, f32>,
+    // Time charged for moving between roads more than one level of the hierarchy apart, e.g.
+    // off a motorway onto a tertiary road, in seconds. Only an `EdgeBasedGraph` can charge it, since it
+    // depends on the pair of edges.
+    pub road_class_transition_penalty_s: f32,
+    // Leaves out U-turns, turning back onto the road just travelled, except at turning circles.
+    // Like the transition penalty, only an `EdgeBasedGraph` sees the turns it applies to.
+    pub forbid_u_turns: bool,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            default_speeds: DefaultSpeeds::default(),
+            traffic_light_penalty_s: 15.0,
+            u_turn_penalty_s: 20.0,
+            surface_multipliers: SurfaceMultipliers::default(),
+            road_class_multipliers: HashMap::new(),
+            road_class_transition_penalty_s: 0.0,
+            forbid_u_turns: false,
+        }
+    }
+}
+
+impl CostModel {
+    /// Gets the time it takes `profile` to travel `distance_m` meters on a way of `road_class`
+    /// with the given speed limit. Only cars are assumed to drive at the speed limit, other
+    /// profiles use their default speed capped by it.
+    pub fn travel_time(
+        &self,
+        distance_m: f32,
+        speed_limit: Option<u8>,
+        road_class: RoadClass,
+        profile: Profile,
+    ) -> f32 {
+        let default_speed = self.default_speeds.get(profile, road_class);
+        let speed_kmh = match (profile, speed_limit) {
+            (_, None) => default_speed,
+            (Profile::Car, Some(limit)) => f32::from(limit),
+            (_, Some(limit)) => default_speed.min(f32::from(limit)),
+        };
+        distance_m / (speed_kmh / 3.6)
+    }
+
+    /// Gets the factor the travel time of `profile` is multiplied by on a way with the given
+    /// surface and smoothness tags.
+    pub fn surface_factor(
+        &self,
+        surface: Option<&str>,
+        smoothness: Option<&str>,
+        profile: Profile,
+    ) -> f32 {
+        let multipliers = &self.surface_multipliers;
+        surface.map_or(1.0, |value| multipliers.surface(profile, value))
+            * smoothness.map_or(1.0, |value| multipliers.smoothness(profile, value))
+    }
+
+    /// Gets the factor the travel time on a way of `road_class` is multiplied by.
+    pub fn road_class_factor(&self, road_class: RoadClass) -> f32 {
+        self.road_class_multipliers
+            .get(&road_class)
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Gets the time lost on a turn, from a turn cost as returned by `utils::calc_turn_cost`,
+    /// which ranges from 1 when going straight to 3 on a U-turn.
+    pub fn turn_penalty(&self, turn_cost: f32) -> f32 {
+        (turn_cost - 1.0) / 2.0 * self.u_turn_penalty_s
+    }
+
+    /// Gets the time lost moving from a road of class `from` onto one of class `to`, charged
+    /// when they are more than one level of the road hierarchy apart. Roads outside the
+    /// hierarchy, e.g. tracks or unknown roads, are never charged.
+    pub fn transition_penalty(&self, from: RoadClass, to: RoadClass) -> f32 {
+        match (from.level(), to.level()) {
+            (Some(from), Some(to)) if from.abs_diff(to) > 1 => self.road_class_transition_penalty_s,
+            _ => 0.0,
+        }
+    }
+
+    /// Gets the time lost passing through `num_traffic_lights` traffic lights.
+    pub fn traffic_light_penalty(&self, num_traffic_lights: usize) -> f32 {
+        num_traffic_lights as f32 * self.traffic_light_penalty_s
+    }
+}
+
+/// Seconds in a day, times of day are taken modulo this.
+pub const SECONDS_PER_DAY: u32 = 24 * 3600;
+
+/// A period of the day in which traffic slows down travel on some road classes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakPeriod {
+    // Seconds since midnight the period starts and ends at, it wraps around midnight if it ends
+    // before it starts.
+    pub start_s: u32,
+    pub end_s: u32,
+    // Factors the travel time on each road class is multiplied by during the period. Classes
+    // without an entry aren't slowed down.
+    pub road_class_multipliers: HashMap<RoadClass, f32>,
+}
+
+/// Time-of-day dependent travel time factors, e.g. for rush hours. A graph is weighted for a
+/// departure time with `Graph::reweight_at`, the factor at departure holds for the whole route.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TimeOfDayProfile {
+    pub peaks: Vec<PeakPeriod>,
+}
+
+impl PeakPeriod {
+    /// Checks if the period covers `time_of_day_s` seconds since midnight.
+    pub fn contains(&self, time_of_day_s: u32) -> bool {
+        let time_of_day_s = time_of_day_s % SECONDS_PER_DAY;
+        if self.start_s <= self.end_s {
+            (self.start_s..self.end_s).contains(&time_of_day_s)
+        } else {
+            time_of_day_s >= self.start_s || time_of_day_s < self.end_s
+        }
+    }
+}
+
+impl TimeOfDayProfile {
+    /// Adds a period from `start_s` to `end_s` seconds since midnight in which the travel time
+    /// on `road_classes` is multiplied by `multiplier`.
+    pub fn with_peak(
+        mut self,
+        start_s: u32,
+        end_s: u32,
+        road_classes: impl IntoIterator<Item = RoadClass>,
+        multiplier: f32,
+    ) -> Self {
+        self.peaks.push(PeakPeriod {
+            start_s,
+            end_s,
+            road_class_multipliers: road_classes
+                .into_iter()
+                .map(|road_class| (road_class, multiplier))
+                .collect(),
+        });
+        self
+    }
+
+    /// Gets the factor the travel time on a way of `road_class` is multiplied by at
+    /// `time_of_day_s` seconds since midnight. The slowest of overlapping periods wins.
+    pub fn factor(&self, road_class: RoadClass, time_of_day_s: u32) -> f32 {
+        self.peaks
+            .iter()
+            .filter(|peak| peak.contains(time_of_day_s))
+            .filter_map(|peak| peak.road_class_multipliers.get(&road_class).copied())
+            .fold(1.0, f32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost_model() {
+        let cost_model = CostModel::default();
+
+        let residential = RoadClass::Residential;
+        let travel_time = |speed_limit, profile| {
+            cost_model.travel_time(1000.0, speed_limit, residential, profile)
+        };
+        assert!((travel_time(Some(36), Profile::Car) - 100.0).abs() < 1e-3);
+        assert!((travel_time(None, Profile::Car) - 144.0).abs() < 1e-3);
+        assert!((travel_time(Some(50), Profile::Bike) - 240.0).abs() < 1e-3);
+        assert!((travel_time(Some(10), Profile::Bike) - 360.0).abs() < 1e-3);
+        assert_eq!(cost_model.turn_penalty(1.0), 0.0);
+        assert_eq!(cost_model.turn_penalty(3.0), cost_model.u_turn_penalty_s);
+        assert_eq!(
+            cost_model.traffic_light_penalty(2),
+            2.0 * cost_model.traffic_light_penalty_s
+        );
+    }
+
+    #[test]
+    fn test_transition_penalty() {
+        let cost_model = CostModel {
+            road_class_transition_penalty_s: 30.0,
+            ..CostModel::default()
+        };
+        let penalty = |from, to| cost_model.transition_penalty(from, to);
+        assert_eq!(penalty(RoadClass::Motorway, RoadClass::Motorway), 0.0);
+        assert_eq!(penalty(RoadClass::Motorway, RoadClass::Trunk), 0.0);
+        assert_eq!(penalty(RoadClass::Motorway, RoadClass::Primary), 30.0);
+        assert_eq!(penalty(RoadClass::Residential, RoadClass::Motorway), 30.0);
+        assert_eq!(penalty(RoadClass::Unknown, RoadClass::Service), 0.0);
+        assert_eq!(penalty(RoadClass::Motorway, RoadClass::Footway), 0.0);
+        assert_eq!(
+            CostModel::default().transition_penalty(RoadClass::Motorway, RoadClass::Residential),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_time_of_day_factor() {
+        let profile = TimeOfDayProfile::default()
+            .with_peak(
+                7 * 3600,
+                10 * 3600,
+                [RoadClass::Primary, RoadClass::Residential],
+                1.5,
+            )
+            .with_peak(8 * 3600, 9 * 3600, [RoadClass::Primary], 2.0)
+            .with_peak(22 * 3600, 3600, [RoadClass::Motorway], 1.2);
+
+        assert_eq!(profile.factor(RoadClass::Primary, 3 * 3600), 1.0);
+        assert_eq!(profile.factor(RoadClass::Primary, 7 * 3600), 1.5);
+        assert_eq!(profile.factor(RoadClass::Primary, 8 * 3600 + 1800), 2.0);
+        assert_eq!(profile.factor(RoadClass::Residential, 8 * 3600 + 1800), 1.5);
+        assert_eq!(profile.factor(RoadClass::Primary, 10 * 3600), 1.0);
+        assert_eq!(profile.factor(RoadClass::Motorway, 23 * 3600), 1.2);
+        assert_eq!(profile.factor(RoadClass::Motorway, 1800), 1.2);
+        // A day later is the same time of day.
+        assert_eq!(
+            profile.factor(RoadClass::Motorway, SECONDS_PER_DAY + 1800),
+            1.2
+        );
+        assert_eq!(profile.factor(RoadClass::Motorway, 12 * 3600), 1.0);
+    }
+
+    #[test]
+    fn test_default_speeds_override() {
+        let mut speeds = DefaultSpeeds::default();
+        assert_eq!(speeds.get(Profile::Car, RoadClass::Motorway), 100.0);
+        assert_eq!(speeds.get(Profile::Bike, RoadClass::Motorway), 15.0);
+
+        speeds.set(Profile::Car, RoadClass::Motorway, 110.0);
+        assert_eq!(speeds.get(Profile::Car, RoadClass::Motorway), 110.0);
+        assert_eq!(speeds.get(Profile::Car, RoadClass::Trunk), 80.0);
+        assert_eq!(speeds.get(Profile::Foot, RoadClass::Unknown), 5.0);
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+
+use super::cost_model::CostModel;
+use super::graph::{Edge, EdgeMetadata, Graph, Node};
+use crate::engine::utils;
+
+/// Whether a turn restriction forbids its turn, e.g. restriction=no_left_turn, or makes it the
+/// only allowed one, e.g. restriction=only_straight_on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestrictionKind {
+    No,
+    Only,
+}
+
+/// A turn restriction relation, from a way onto another one over the node they share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurnRestriction {
+    pub from_way: i64,
+    pub via_node: i64,
+    pub to_way: i64,
+    pub kind: RestrictionKind,
+}
+
+/// An edge-based version of a graph, whose nodes are the directed edges of the original graph
+/// and whose edges are the turns allowed between them, so turn restrictions and turn costs are
+/// part of the graph and are kept by the contraction like any other weight.
+///
+/// Every original node also gets a source node, with edges onto the edges leaving it, and a
+/// sink node, reached from the edges entering it, so a route between two original nodes is a
+/// single query from `source(src)` to `sink(dest)`. The edges entering an edge node carry its
+/// metadata, plus the turn penalty, so the weights and distances of a route add up as in the
+/// original graph.
+#[derive(Clone)]
+pub struct EdgeBasedGraph {
+    pub graph: Graph,
+    // The number of nodes of the original graph.
+    num_original_nodes: usize,
+    // The original edge and its (source, target) nodes for each edge node, in node order.
+    original_edges: Vec<(usize, usize, usize)>,
+}
+
+impl EdgeBasedGraph {
+    /// Builds the edge-based graph of an uncontracted `graph`. Turns forbidden by
+    /// `restrictions`, which are matched against the `way_id` of the edges, are left out, as
+    /// are U-turns away from turning circles if `cost_model.forbid_u_turns` is set. The others
+    /// are charged `cost_model.turn_penalty` and `cost_model.transition_penalty`.
+    pub fn new(graph: &Graph, restrictions: &[TurnRestriction], cost_model: &CostModel) -> Self {
+        let num_original_nodes = graph.num_nodes();
+        let original_edges: Vec<(usize, usize, usize)> = graph
+            .edges
+            .iter()
+            .enumerate()
+            .filter(|&(_, edge)| graph.get_edge_metadata(edge).prev_edge.is_none())
+            .map(|(edge_id, edge)| (edge_id, edge.src_id, edge.dest_id))
+            .collect();
+        let edge_node: HashMap<usize, usize> = original_edges
+            .iter()
+            .enumerate()
+            .map(|(i, &(edge_id, _, _))| (edge_id, 2 * num_original_nodes + i))
+            .collect();
+
+        // Sources, then sinks, then one node per edge at the node it leads to.
+        let mut nodes = Vec::with_capacity(2 * num_original_nodes + original_edges.len());
+        let original_node = |id: usize| &graph.nodes[id];
+        for id in (0..num_original_nodes).chain(0..num_original_nodes) {
+            nodes.push(Self::node_at(nodes.len(), original_node(id)));
+        }
+        for &(_, _, dest_id) in &original_edges {
+            nodes.push(Self::node_at(nodes.len(), original_node(dest_id)));
+        }
+
+        // The restrictions by the way they turn from and the OSM id of the node they turn at.
+        let mut restrictions_at: HashMap<(i64, i64), Vec<&TurnRestriction>> = HashMap::new();
+        for restriction in restrictions {
+            restrictions_at
+                .entry((restriction.from_way, restriction.via_node))
+                .or_default()
+                .push(restriction);
+        }
+
+        let mut ebg = Graph::new(nodes);
+        let add_edge = |ebg: &mut Graph, src: usize, dest: usize, metadata: EdgeMetadata| {
+            let metadata_index = ebg.edge_metadata.len();
+            ebg.edge_metadata.push(metadata);
+            ebg.add_edge(src, dest, metadata_index);
+        };
+
+        for &(edge_id, src_id, dest_id) in &original_edges {
+            let edge = graph.get_edge(edge_id);
+            let metadata = graph.get_edge_metadata(edge);
+            let node = edge_node[&edge_id];
+            add_edge(&mut ebg, src_id, node, metadata.clone());
+            add_edge(
+                &mut ebg,
+                node,
+                num_original_nodes + dest_id,
+                EdgeMetadata {
+                    distance_m: 0.0,
+                    ..EdgeMetadata::new(0.0)
+                },
+            );
+
+            let via_restrictions = metadata
+                .way_id
+                .and_then(|way_id| restrictions_at.get(&(way_id, graph.nodes[dest_id].osm_id)))
+                .map_or(&[][..], Vec::as_slice);
+
+            for &next_id in &graph.fwd_edge_list[dest_id] {
+                let Some(&next_node) = edge_node.get(&next_id) else {
+                    continue;
+                };
+                let next = graph.get_edge(next_id);
+                let next_metadata = graph.get_edge_metadata(next);
+                if !Self::is_turn_allowed(via_restrictions, next_metadata.way_id)
+                    || (cost_model.forbid_u_turns
+                        && next.dest_id == src_id
+                        && !graph.nodes[dest_id].is_turning_circle)
+                {
+                    continue;
+                }
+
+                let turn_penalty = cost_model.turn_penalty(Self::turn_cost(graph, edge, next))
+                    + cost_model.transition_penalty(metadata.road_class, next_metadata.road_class);
+                add_edge(
+                    &mut ebg,
+                    node,
+                    next_node,
+                    EdgeMetadata {
+                        weight: next_metadata.weight + turn_penalty,
+                        alt_weight: next_metadata.alt_weight + turn_penalty,
+                        time_s: next_metadata.time_s + turn_penalty,
+                        ..next_metadata.clone()
+                    },
+                );
+            }
+        }
+
+        Self {
+            graph: ebg,
+            num_original_nodes,
+            original_edges,
+        }
+    }
+
+    /// Gets the node routes from original node `node` start at. A route from a node to itself
+    /// has to leave it, so check for that before querying.
+    pub fn source(&self, node: usize) -> usize {
+        node
+    }
+
+    /// Gets the node routes to original node `node` end at.
+    pub fn sink(&self, node: usize) -> usize {
+        self.num_original_nodes + node
+    }
+
+    /// Gets the original edge an edge node stands for, `None` for sources and sinks.
+    pub fn original_edge(&self, node: usize) -> Option<usize> {
+        node.checked_sub(2 * self.num_original_nodes)
+            .map(|i| self.original_edges[i].0)
+    }
+
+    /// Maps a path through the edge-based graph, e.g. from `ShortcutVisitor`, back to the
+    /// original nodes along it.
+    pub fn to_node_path(&self, path: &[usize]) -> Vec<usize> {
+        let mut nodes = Vec::with_capacity(path.len());
+        for &node in path {
+            let original = match node.checked_sub(2 * self.num_original_nodes) {
+                Some(i) => self.original_edges[i].2,
+                None => node % self.num_original_nodes,
+            };
+            if nodes.last() != Some(&original) {
+                nodes.push(original);
+            }
+        }
+        nodes
+    }
+
+    fn node_at(dense_id: usize, original: &Node) -> Node {
+        Node {
+            dense_id,
+            rank: 0,
+            is_contracted: false,
+            ..original.clone()
+        }
+    }
+
+    // Checks if the restrictions at the end of an edge allow turning onto a way.
+    fn is_turn_allowed(restrictions: &[&TurnRestriction], to_way: Option<i64>) -> bool {
+        restrictions.iter().all(|r| match r.kind {
+            RestrictionKind::No => Some(r.to_way) != to_way,
+            RestrictionKind::Only => Some(r.to_way) == to_way,
+        })
+    }
+
+    // Gets the turn cost from `edge` onto `next`, measured between the shape points closest to
+    // the node they share.
+    fn turn_cost(graph: &Graph, edge: &Edge, next: &Edge) -> f32 {
+        let prev = graph
+            .get_edge_metadata(edge)
+            .geometry
+            .last()
+            .copied()
+            .unwrap_or(graph.nodes[edge.src_id].get_lat_lon());
+        let curr = graph.nodes[edge.dest_id].get_lat_lon();
+        let next = graph
+            .get_edge_metadata(next)
+            .geometry
+            .first()
+            .copied()
+            .unwrap_or(graph.nodes[next.dest_id].get_lat_lon());
+        utils::calc_turn_cost(prev.0, prev.1, curr.0, curr.1, next.0, next.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        csr::csr_graph::CSRGraph,
+        preprocess::{
+            ch_preprocess::{contract_graph, ContractionOptions},
+            witness_search::Dijkstra,
+        },
+        query::ch_query::{BiDirDijkstra, QueryOutcome},
+        visitor::{shortcut_visitor::ShortcutVisitor, visitable::Visitable},
+    };
+
+    // A junction at 1 where way 10 from the west crosses way 11 to the north, with a detour
+    // around the block through 2 and 4.
+    //
+    //        3 ---- 4
+    //        |      |
+    //  0 --- 1 ---- 2
+    fn get_junction() -> Graph {
+        let coords = [
+            (0.0, 0.0),
+            (0.0, 0.001),
+            (0.0, 0.002),
+            (0.001, 0.001),
+            (0.001, 0.002),
+        ];
+        let nodes = coords
+            .iter()
+            .enumerate()
+            .map(|(i, &(lat, lon))| Node {
+                lat,
+                lon,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+
+        for (src, dest, way_id) in [(0, 1, 10), (1, 2, 10), (1, 3, 11), (2, 4, 12), (4, 3, 13)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata {
+                way_id: Some(way_id),
+                ..EdgeMetadata::new(1.0)
+            });
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        graph
+    }
+
+    // Routes over the contraction of `graph`, returning the weight and the nodes of the path.
+    fn route(mut graph: Graph, src: usize, dest: usize) -> (f64, Vec<usize>) {
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        contract_graph(
+            &mut graph,
+            &mut dijkstra,
+            &ContractionOptions::default(),
+            |_| {},
+        );
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+
+        let mut query = BiDirDijkstra::new(csr.nodes.len());
+        query.init(src, dest).unwrap();
+        let QueryOutcome::Found(path, weight) = query.search(&csr) else {
+            panic!("no path from {src} to {dest}");
+        };
+        (weight, ShortcutVisitor::new(&csr, &path).visit())
+    }
+
+    #[test]
+    fn test_forbidden_turn_detours() {
+        let graph = get_junction();
+        let cost_model = CostModel::default();
+        let no_left_turn = TurnRestriction {
+            from_way: 10,
+            via_node: 101,
+            to_way: 11,
+            kind: RestrictionKind::No,
+        };
+
+        let (_, path) = route(graph.clone(), 0, 3);
+        assert_eq!(path, [0, 1, 3]);
+
+        let unrestricted = EdgeBasedGraph::new(&graph, &[], &cost_model);
+        let (src, dest) = (unrestricted.source(0), unrestricted.sink(3));
+        let (weight, path) = route(unrestricted.graph.clone(), src, dest);
+        assert_eq!(unrestricted.to_node_path(&path), [0, 1, 3]);
+        // Two edges and a right angle turn.
+        assert!((weight - f64::from(2.0 + cost_model.turn_penalty(2.0))).abs() < 1e-3);
+
+        let restricted = EdgeBasedGraph::new(&graph, &[no_left_turn], &cost_model);
+        let (weight, path) = route(restricted.graph.clone(), src, dest);
+        assert_eq!(restricted.to_node_path(&path), [0, 1, 2, 4, 3]);
+        assert!((weight - f64::from(4.0 + 2.0 * cost_model.turn_penalty(2.0))).abs() < 1e-3);
+
+        // Only going straight on allows the same turns here.
+        let straight_on = TurnRestriction {
+            to_way: 10,
+            kind: RestrictionKind::Only,
+            ..no_left_turn
+        };
+        let only = EdgeBasedGraph::new(&graph, &[straight_on], &cost_model);
+        let (_, path) = route(only.graph.clone(), src, dest);
+        assert_eq!(only.to_node_path(&path), [0, 1, 2, 4, 3]);
+    }
+
+    #[test]
+    fn test_forbid_u_turns() {
+        // Way 10 from 0 ends at 1, where it meets the spur 11 to the dead end at 2 and way 12 to
+        // 3. Turning left from 10 onto 12 is forbidden, so getting to 3 takes a U-turn at 2.
+        //
+        //        3
+        //        |
+        //  0 --- 1 --- 2
+        let coords = [(0.0, 0.0), (0.0, 0.001), (0.0, 0.002), (0.001, 0.001)];
+        let nodes = coords
+            .iter()
+            .enumerate()
+            .map(|(i, &(lat, lon))| Node {
+                lat,
+                lon,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, way_id) in [(0, 1, 10), (1, 2, 11), (1, 3, 12)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata {
+                way_id: Some(way_id),
+                ..EdgeMetadata::new(1.0)
+            });
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        let no_left_turn = TurnRestriction {
+            from_way: 10,
+            via_node: 101,
+            to_way: 12,
+            kind: RestrictionKind::No,
+        };
+
+        let route_with = |graph: &Graph, forbid_u_turns| {
+            let cost_model = CostModel {
+                forbid_u_turns,
+                ..CostModel::default()
+            };
+            let ebg = EdgeBasedGraph::new(graph, &[no_left_turn], &cost_model);
+            let mut overlay = ebg.graph.clone();
+            let mut dijkstra = Dijkstra::new(overlay.num_nodes());
+            contract_graph(
+                &mut overlay,
+                &mut dijkstra,
+                &ContractionOptions::default(),
+                |_| {},
+            );
+            let csr = CSRGraph::from_preprocessed_graph(overlay);
+
+            let mut query = BiDirDijkstra::new(csr.nodes.len());
+            query.init(ebg.source(0), ebg.sink(3)).unwrap();
+            match query.search(&csr) {
+                QueryOutcome::Found(path, _) => {
+                    Some(ebg.to_node_path(&ShortcutVisitor::new(&csr, &path).visit()))
+                }
+                _ => None,
+            }
+        };
+
+        assert_eq!(route_with(&graph, false), Some(vec![0, 1, 2, 1, 3]));
+        assert_eq!(route_with(&graph, true), None);
+
+        graph.nodes[2].set_is_turning_circle(true);
+        assert_eq!(route_with(&graph, true), Some(vec![0, 1, 2, 1, 3]));
+    }
+
+    #[test]
+    fn test_transition_penalty_prefers_fewer_ramps() {
+        use crate::engine::preprocess::graph::RoadClass::{Motorway, Tertiary};
+
+        // Two roads from 0 to 4, one hopping on and off the motorway at 1, 2 and 3, and a
+        // slower one staying on it from 5 to 7.
+        let nodes = (0..8).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, road_class, weight) in [
+            (0, 1, Tertiary, 1.0),
+            (1, 2, Motorway, 1.0),
+            (2, 3, Tertiary, 1.0),
+            (3, 4, Motorway, 1.0),
+            (0, 5, Tertiary, 1.0),
+            (5, 6, Motorway, 1.25),
+            (6, 7, Motorway, 1.25),
+            (7, 4, Tertiary, 1.0),
+        ] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata {
+                road_class,
+                ..EdgeMetadata::new(weight)
+            });
+            graph.add_edge(src, dest, metadata_index);
+        }
+
+        let route_with = |penalty_s| {
+            let cost_model = CostModel {
+                u_turn_penalty_s: 0.0,
+                road_class_transition_penalty_s: penalty_s,
+                ..CostModel::default()
+            };
+            let ebg = EdgeBasedGraph::new(&graph, &[], &cost_model);
+            let (weight, path) = route(ebg.graph.clone(), ebg.source(0), ebg.sink(4));
+            (weight, ebg.to_node_path(&path))
+        };
+
+        let (weight, path) = route_with(0.0);
+        assert_eq!(path, [0, 1, 2, 3, 4]);
+        assert!((weight - 4.0).abs() < 1e-3);
+
+        // Three transitions against two.
+        let (weight, path) = route_with(10.0);
+        assert_eq!(path, [0, 5, 6, 7, 4]);
+        assert!((weight - 24.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_layout() {
+        let graph = get_junction();
+        let ebg = EdgeBasedGraph::new(&graph, &[], &CostModel::default());
+        let num_nodes = graph.num_nodes();
+
+        assert_eq!(ebg.graph.num_nodes(), 2 * num_nodes + graph.num_edges());
+        for node in 0..num_nodes {
+            assert_eq!(ebg.original_edge(ebg.source(node)), None);
+            assert_eq!(ebg.original_edge(ebg.sink(node)), None);
+            assert_eq!(
+                ebg.graph.get_fwd_neighbors(ebg.source(node)).len(),
+                graph.get_fwd_neighbors(node).len()
+            );
+            assert_eq!(
+                ebg.graph.get_bwd_neighbors(ebg.sink(node)).len(),
+                graph.get_bwd_neighbors(node).len()
+            );
+        }
+        for edge_id in 0..graph.num_edges() {
+            let node = 2 * num_nodes + edge_id;
+            assert_eq!(ebg.original_edge(node), Some(edge_id));
+            assert_eq!(
+                ebg.graph.nodes[node].osm_id,
+                graph.nodes[graph.edges[edge_id].dest_id].osm_id
+            );
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use super::graph::Graph;
+
+/// The factor travel time grows by per unit of uphill grade by default, so a 5% climb takes
+/// 50% longer.
+pub const DEFAULT_GRADE_PENALTY: f32 = 10.0;
+
+/// A source of ground elevation, e.g. SRTM tiles. Closures `Fn(lat, lon) -> f32` are providers
+/// too.
+pub trait ElevationProvider {
+    /// Gets the elevation in meters at the given coordinates, or `None` where there's no data.
+    fn elevation(&self, lat: f64, lon: f64) -> Option<f32>;
+}
+
+impl<F: Fn(f64, f64) -> f32> ElevationProvider for F {
+    fn elevation(&self, lat: f64, lon: f64) -> Option<f32> {
+        Some(self(lat, lon))
+    }
+}
+
+/// Makes uphill edges cost more, multiplying their weight by `1 + grade_penalty * grade`, where
+/// the grade is the climb between the endpoints over the length of the edge. Downhill and flat
+/// edges, and edges with an endpoint without elevation data, keep their weight. The directions
+/// of a two-way edge are given their own metadata when their weights end up differing.
+/// Must be called before contraction, since shortcut weights aren't recomputed.
+pub fn add_climb_penalty(
+    graph: &mut Graph,
+    elevation: &impl ElevationProvider,
+    grade_penalty: f32,
+) {
+    let elevations: Vec<Option<f32>> = graph
+        .nodes
+        .iter()
+        .map(|node| elevation.elevation(node.lat, node.lon))
+        .collect();
+    let base_weights: Vec<f32> = graph.edge_metadata.iter().map(|m| m.weight).collect();
+
+    let mut reweighted = vec![false; graph.edge_metadata.len()];
+    for edge_id in 0..graph.edges.len() {
+        let edge = &graph.edges[edge_id];
+        let metadata_index = edge.metadata_index;
+        let metadata = &graph.edge_metadata[metadata_index];
+
+        let climb = match (elevations[edge.src_id], elevations[edge.dest_id]) {
+            (Some(src), Some(dest)) => dest - src,
+            _ => 0.0,
+        };
+        let grade = if climb > 0.0 && metadata.distance_m > 0.0 {
+            climb / metadata.distance_m
+        } else {
+            0.0
+        };
+        let weight = base_weights[metadata_index] * (1.0 + grade_penalty * grade);
+
+        if !std::mem::replace(&mut reweighted[metadata_index], true) {
+            graph.edge_metadata[metadata_index].weight = weight;
+        } else if weight != metadata.weight {
+            let mut metadata = metadata.clone();
+            metadata.weight = weight;
+            graph.edges[edge_id].metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(metadata);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::graph::{EdgeMetadata, Node};
+
+    #[test]
+    fn test_uphill_costs_more() {
+        // A two-way street 0 <-> 1, climbing 10 meters over 100 meters from 0 to 1.
+        let nodes = (0..2)
+            .map(|i| Node {
+                lat: i as f64,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+        let mut metadata = EdgeMetadata::new(20.0);
+        metadata.distance_m = 100.0;
+        graph.edge_metadata.push(metadata);
+        graph.add_edge(0, 1, 0);
+        graph.add_edge(1, 0, 0);
+
+        add_climb_penalty(
+            &mut graph,
+            &|lat: f64, _| lat as f32 * 10.0,
+            DEFAULT_GRADE_PENALTY,
+        );
+
+        let weight = |src, dest| {
+            graph
+                .get_edge_metadata(graph.find_edge(src, dest).unwrap())
+                .weight
+        };
+        assert_eq!(weight(1, 0), 20.0);
+        assert!((weight(0, 1) - 40.0).abs() < 1e-4);
+        assert_eq!(graph.edge_metadata.len(), 2);
+
+        // Without climbs no metadata is split off.
+        add_climb_penalty(&mut graph, &|_, _| 0.0, DEFAULT_GRADE_PENALTY);
+        assert_eq!(graph.edge_metadata.len(), 2);
+    }
+}
@@ -1,7 +1,13 @@
+use std::fmt;
+
 use humansize::{format_size, DECIMAL};
+use serde::{Deserialize, Serialize};
+
+use super::cost_model::TimeOfDayProfile;
+use crate::engine::utils;
 
 /// A way node.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Node {
     // Dense index of the node.
     pub dense_id: usize,
@@ -12,34 +18,154 @@ pub struct Node {
     // Flag for identifying if the node was contracted or not
     pub is_contracted: bool,
     // lat
-    pub lat: f32,
+    pub lat: f64,
     // lon
-    pub lon: f32,
+    pub lon: f64,
     // Is traffic light.
     pub is_traffic_light: bool,
+    // Is a turning circle, where turning back is expected.
+    pub is_turning_circle: bool,
+    // Has a barrier that lets the profile through, e.g. a gate.
+    pub is_barrier: bool,
+}
+
+/// The class of a road, from the `highway=` tag. Ordered from the most to the least important.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub enum RoadClass {
+    Motorway,
+    Trunk,
+    Primary,
+    Secondary,
+    Tertiary,
+    Unclassified,
+    Residential,
+    LivingStreet,
+    Service,
+    Track,
+    Cycleway,
+    Path,
+    Footway,
+    Pedestrian,
+    Steps,
+    #[default]
+    Unknown,
+}
+
+/// The local and English names of a way, kept side by side for bilingual labels when
+/// `ImportOptions::keep_both_names` is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WayNames {
+    // The name in the local language, from the name tag.
+    pub local: Option<String>,
+    // The English name, from the name:en tag.
+    pub en: Option<String>,
+}
+
+/// Where an edge lies on a roundabout, so the edges of a roundabout can be put back in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoundaboutPosition {
+    // The OSM id of the junction=roundabout way.
+    pub id: i64,
+    // The position of the edge along the way, in driving order.
+    pub index: u32,
+}
+
+/// The dimensions of a vehicle, checked against the maxheight, maxweight and maxwidth limits
+/// of the edges by `Graph::restrict_to_vehicle`. The default of zeros fits under every limit.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VehicleConstraints {
+    pub height_m: f32,
+    // The gross weight in tonnes.
+    pub weight_t: f32,
+    pub width_m: f32,
+}
+
+impl VehicleConstraints {
+    /// Checks if the vehicle is within the dimension limits of an edge.
+    pub fn allows(&self, metadata: &EdgeMetadata) -> bool {
+        let fits = |size: f32, limit: Option<f32>| limit.is_none_or(|limit| size <= limit);
+        fits(self.height_m, metadata.max_height_m)
+            && fits(self.weight_t, metadata.max_weight_t)
+            && fits(self.width_m, metadata.max_width_m)
+    }
 }
 
 /// The metadata of an edge.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EdgeMetadata {
     // The weight of the edge.
     pub weight: f32,
+    // The weight of the edge under `Metric::Secondary`.
+    pub alt_weight: f32,
+    // The length of the edge along the way, in meters.
+    pub distance_m: f32,
+    // The time it takes to travel the edge in seconds, whatever metric the weights are in, so
+    // routes have an ETA under any metric.
+    pub time_s: f32,
+    // The time-of-day factor `weight` and `time_s` are scaled by, 1 until `reweight_at` is
+    // called. Dividing by it recovers the import values with all their penalties.
+    pub time_factor: f32,
     // Optional name of the edge (what road/street its part of).
     pub name: Option<String>,
+    // Both the local and English names, if the import kept them.
+    pub names: Option<WayNames>,
     // Optional maximum speed.
     pub speed_limit: Option<u8>,
     // Is one way street.
     pub is_one_way: bool,
     // Is part of a roundabout.
     pub is_roundabout: bool,
+    // The roundabout the edge is part of and its position on it, set by the import.
+    pub roundabout: Option<RoundaboutPosition>,
+    // The class of the road the edge is part of.
+    pub road_class: RoadClass,
+    // The maximum height, weight in tonnes and width of vehicles allowed on the edge, from the
+    // maxheight, maxweight and maxwidth tags.
+    pub max_height_m: Option<f32>,
+    pub max_weight_t: Option<f32>,
+    pub max_width_m: Option<f32>,
+    // Is a ferry crossing, weighted by its duration instead of its length.
+    pub is_ferry: bool,
+    // Is tagged oneway=reversible or oneway=alternating, so its direction changes over time.
+    pub is_reversible: bool,
     // Dense index of the previous edge.
     pub prev_edge: Option<usize>,
     // Dense index of the next edge.
     pub next_edge: Option<usize>,
+    // Coordinates of the shape points merged into the edge, from its source to its destination.
+    pub geometry: Vec<(f64, f64)>,
+    // The number of edges a shortcut unpacks to, 1 for other edges. Breaks ties between paths
+    // of equal weight when fewer hops are preferred.
+    pub hops: u32,
+    // The OSM id of the way the edge was imported from, `None` for shortcuts. Merged chains
+    // keep the id of their first way.
+    pub way_id: Option<i64>,
+}
+
+/// The direction of an edge relative to a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    // The node is the source of the edge.
+    Fwd,
+    // The node is the destination of the edge.
+    Bwd,
+}
+
+/// A metric edges are weighted by. A graph contracted with `secondary_metric` set in its
+/// `ContractionOptions` can be queried under either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum Metric {
+    // The weight the graph was built with, the travel time under its profile.
+    #[default]
+    Primary,
+    // The alternative weight, the length of the edge in meters for imported graphs.
+    Secondary,
 }
 
 /// An edge
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Edge {
     // The dense id of the source node.
     pub src_id: usize,
@@ -49,7 +175,96 @@ pub struct Edge {
     pub metadata_index: usize,
 }
 
-#[derive(Clone)]
+/// Maps the ids of a graph before `Graph::compact` to its ids after, `None` for the dropped
+/// nodes and edges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdMapping {
+    // Indexed by the old dense id of a node.
+    pub nodes: Vec<Option<usize>>,
+    // Indexed by the old id of an edge.
+    pub edges: Vec<Option<usize>>,
+}
+
+/// Summary statistics of a graph, from `Graph::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphStats {
+    pub num_nodes: usize,
+    pub num_edges: usize,
+    // Degrees count both the incoming and outgoing edges of a node.
+    pub min_degree: usize,
+    pub max_degree: usize,
+    pub mean_degree: f32,
+    // Number of edges added by contraction.
+    pub num_shortcuts: usize,
+    // Number of edges imported from the map.
+    pub num_original: usize,
+    pub num_one_way: usize,
+    // Share of the edges that have a name, between 0 and 1.
+    pub named_share: f32,
+    // Share of the edges that have a speed limit, between 0 and 1.
+    pub speed_share: f32,
+}
+
+/// An integrity violation found by `Graph::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphError {
+    // An adjacency list was not sized to the number of nodes.
+    AdjacencyLenMismatch {
+        fwd_len: usize,
+        bwd_len: usize,
+        num_nodes: usize,
+    },
+    // A forward adjacency list references an edge that does not exist.
+    FwdEdgeOutOfRange {
+        node_id: usize,
+        edge_id: usize,
+    },
+    // A backward adjacency list references an edge that does not exist.
+    BwdEdgeOutOfRange {
+        node_id: usize,
+        edge_id: usize,
+    },
+    // An edge has a source node that does not exist.
+    InvalidSrcNode {
+        edge_id: usize,
+        src_id: usize,
+    },
+    // An edge has a destination node that does not exist.
+    InvalidDestNode {
+        edge_id: usize,
+        dest_id: usize,
+    },
+    // An edge points at metadata that does not exist.
+    InvalidMetadataIndex {
+        edge_id: usize,
+        metadata_index: usize,
+    },
+    // An edge has a zero, negative or non-finite weight.
+    InvalidWeight {
+        edge_id: usize,
+        weight: f32,
+    },
+    // An edge is listed in the forward list of a node that is not its source.
+    FwdListMismatch {
+        node_id: usize,
+        edge_id: usize,
+    },
+    // An edge is listed in the backward list of a node that is not its destination.
+    BwdListMismatch {
+        node_id: usize,
+        edge_id: usize,
+    },
+    // An edge is in the forward list of its source but not in the backward list of its destination.
+    MissingBwdEntry {
+        edge_id: usize,
+    },
+    // An edge is in the backward list of its destination but not in the forward list of its source.
+    MissingFwdEntry {
+        edge_id: usize,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Graph {
     // A forward edge list, indexed by the dense id of a node.
     pub fwd_edge_list: Vec<Vec<usize>>,
@@ -64,6 +279,17 @@ pub struct Graph {
 }
 
 impl Graph {
+    // Creates a graph over the given nodes without any edges.
+    pub fn new(nodes: Vec<Node>) -> Self {
+        Self {
+            fwd_edge_list: vec![Vec::new(); nodes.len()],
+            bwd_edge_list: vec![Vec::new(); nodes.len()],
+            nodes,
+            edges: Vec::new(),
+            edge_metadata: Vec::new(),
+        }
+    }
+
     pub fn num_nodes(&self) -> usize {
         self.nodes.len()
     }
@@ -94,6 +320,20 @@ impl Graph {
         &self.bwd_edge_list[dense_id]
     }
 
+    /// Iterates over the ids of all the edges incident to a node, tagged with their direction.
+    /// Self-loops are yielded once, as forward edges.
+    pub fn incident_edges(&self, dense_id: usize) -> impl Iterator<Item = (usize, Direction)> + '_ {
+        let fwd = self.fwd_edge_list[dense_id]
+            .iter()
+            .map(|&edge_id| (edge_id, Direction::Fwd));
+        let bwd = self.bwd_edge_list[dense_id]
+            .iter()
+            .filter(move |&&edge_id| self.edges[edge_id].src_id != dense_id)
+            .map(|&edge_id| (edge_id, Direction::Bwd));
+
+        fwd.chain(bwd)
+    }
+
     // Gets a node by its dense id
     pub fn get_node(&self, dense_id: usize) -> &Node {
         &self.nodes[dense_id]
@@ -116,6 +356,21 @@ impl Graph {
         None
     }
 
+    // Gets the dense id of an edge from w to v, if one exists.
+    pub fn find_edge_id(&self, w: usize, v: usize) -> Option<usize> {
+        self.fwd_edge_list[w]
+            .iter()
+            .copied()
+            .find(|&edge_id| self.edges[edge_id].dest_id == v)
+    }
+
+    // Checks if there is an edge from v to w, only scanning the forward edges of v.
+    pub fn edge_exists(&self, v: usize, w: usize) -> bool {
+        self.fwd_edge_list[v]
+            .iter()
+            .any(|&edge_id| self.edges[edge_id].dest_id == w)
+    }
+
     // Gets the metadata of an edge.
     pub fn get_edge_metadata(&self, edge: &Edge) -> &EdgeMetadata {
         &self.edge_metadata[edge.metadata_index]
@@ -148,6 +403,506 @@ impl Graph {
         self.bwd_edge_list[dest_id].push(edge_id_forward);
     }
 
+    /// Recomputes the weight of every edge with `cost`, called with the metadata and the source
+    /// and destination nodes of the edge. Edges sharing metadata, like the two directions of a
+    /// two-way street, share the weight computed for the first of them.
+    /// Must be called before contraction, since shortcut weights aren't recomputed.
+    pub fn reweight(&mut self, cost: impl Fn(&EdgeMetadata, &Node, &Node) -> f32) {
+        let mut reweighted = vec![false; self.edge_metadata.len()];
+        for edge in &self.edges {
+            if std::mem::replace(&mut reweighted[edge.metadata_index], true) {
+                continue;
+            }
+
+            let metadata = &self.edge_metadata[edge.metadata_index];
+            let weight = cost(
+                metadata,
+                &self.nodes[edge.src_id],
+                &self.nodes[edge.dest_id],
+            );
+            self.edge_metadata[edge.metadata_index].weight = weight;
+        }
+    }
+
+    /// Weights every edge for a departure at `departure_s` seconds since midnight, scaling the
+    /// import weight and `time_s` by the factor of its road class in `profile`, so penalties
+    /// folded into the weights, like traffic lights and barriers, scale along. Replaces the
+    /// factor of any earlier call. Like `reweight`, this must be called before contraction.
+    pub fn reweight_at(&mut self, profile: &TimeOfDayProfile, departure_s: u32) {
+        for metadata in &mut self.edge_metadata {
+            let factor = profile.factor(metadata.road_class, departure_s);
+            let rescale = factor / metadata.time_factor;
+            metadata.weight *= rescale;
+            metadata.time_s *= rescale;
+            metadata.time_factor = factor;
+        }
+    }
+
+    /// Simplifies the geometry of every edge with `utils::simplify_polyline`, dropping shape
+    /// points less than `epsilon_m` meters off the simplified line. The endpoints of the edges
+    /// aren't part of the geometry, so the topology is unaffected. Meant to run after import and
+    /// `compress_chains`, to shrink exports. Returns the number of dropped shape points.
+    pub fn simplify_geometry(&mut self, epsilon_m: f64) -> usize {
+        let mut endpoints = vec![None; self.edge_metadata.len()];
+        for edge in &self.edges {
+            endpoints[edge.metadata_index].get_or_insert((edge.src_id, edge.dest_id));
+        }
+
+        let mut dropped = 0;
+        for (metadata, endpoints) in self.edge_metadata.iter_mut().zip(endpoints) {
+            let Some((src_id, dest_id)) = endpoints.filter(|_| !metadata.geometry.is_empty())
+            else {
+                continue;
+            };
+
+            let mut polyline = Vec::with_capacity(metadata.geometry.len() + 2);
+            polyline.push(self.nodes[src_id].get_lat_lon());
+            polyline.extend_from_slice(&metadata.geometry);
+            polyline.push(self.nodes[dest_id].get_lat_lon());
+
+            let simplified = utils::simplify_polyline(&polyline, epsilon_m);
+            dropped += polyline.len() - simplified.len();
+            metadata.geometry = simplified[1..simplified.len() - 1].to_vec();
+        }
+        dropped
+    }
+
+    /// Merges maximal chains of degree-2 nodes into single edges, summing their weights and
+    /// distances and keeping the coordinates of the merged nodes as the edge geometry. Traffic
+    /// lights, barriers, turning circles and nodes where the attributes of the way change are
+    /// never merged away.
+    /// Nodes are renumbered with `compact`, so this must run before ordering and contraction.
+    /// Returns the number of merged nodes.
+    pub fn compress_chains(&mut self) -> usize {
+        let num_nodes = self.num_nodes();
+        let mut is_chain: Vec<bool> = (0..num_nodes).map(|id| self.is_chain_node(id)).collect();
+        let mut visited = vec![false; num_nodes];
+
+        let mut chains = Vec::new();
+        for src_id in (0..num_nodes).filter(|&id| !is_chain[id]) {
+            for &edge_id in &self.fwd_edge_list[src_id] {
+                chains.push(self.follow_chain(edge_id, &is_chain, &mut visited));
+            }
+        }
+
+        // Rings made only of chain nodes are never reached from a kept node, keep them as is.
+        for id in 0..num_nodes {
+            if is_chain[id] && !visited[id] {
+                is_chain[id] = false;
+                for &edge_id in &self.fwd_edge_list[id] {
+                    chains.push((edge_id, self.get_edge(edge_id).dest_id, None));
+                }
+            }
+        }
+
+        // The merged chains replace the edges through the chain nodes, the chain nodes are
+        // left without edges for `compact` to drop. Edges that weren't merged stay as they are,
+        // sharing their metadata, e.g. both ways of a street. Merged edges don't, since their
+        // geometry follows the direction of travel.
+        for id in (0..num_nodes).filter(|&id| is_chain[id]) {
+            self.remove_node(id);
+        }
+        for (edge_id, dest_id, merged) in chains {
+            let src_id = self.edges[edge_id].src_id;
+            match merged {
+                _ if src_id == dest_id => self.unlink_edge(edge_id),
+                Some(merged) => {
+                    self.edge_metadata.push(merged);
+                    self.add_edge(src_id, dest_id, self.edge_metadata.len() - 1);
+                }
+                None => {}
+            }
+        }
+
+        self.compact();
+        is_chain.iter().filter(|&&is_chain| is_chain).count()
+    }
+
+    // Checks if a node only passes a single way through, either a one-way street with one edge
+    // in and one out, or a two-way street between two distinct neighbors.
+    fn is_chain_node(&self, dense_id: usize) -> bool {
+        let node = &self.nodes[dense_id];
+        if node.is_traffic_light || node.is_barrier || node.is_turning_circle {
+            return false;
+        }
+
+        let edge_ids = &self.fwd_edge_list[dense_id];
+        let targets: Vec<usize> = edge_ids.iter().map(|&id| self.edges[id].dest_id).collect();
+        let sources: Vec<usize> = self.bwd_edge_list[dense_id]
+            .iter()
+            .map(|&id| self.edges[id].src_id)
+            .collect();
+
+        let is_chain = match (targets.as_slice(), sources.as_slice()) {
+            ([b], [a]) => a != b,
+            ([b1, b2], [a1, a2]) => b1 != b2 && ((a1, a2) == (b1, b2) || (a1, a2) == (b2, b1)),
+            _ => false,
+        };
+        if !is_chain || targets.contains(&dense_id) {
+            return false;
+        }
+
+        // Merging is only lossless while the way stays the same.
+        let metadata: Vec<&EdgeMetadata> = edge_ids
+            .iter()
+            .chain(&self.bwd_edge_list[dense_id])
+            .map(|&id| self.get_edge_metadata(&self.edges[id]))
+            .collect();
+        metadata.iter().all(|m| m.prev_edge.is_none())
+            && metadata.windows(2).all(|w| {
+                (&w[0].name, w[0].speed_limit, w[0].road_class)
+                    == (&w[1].name, w[1].speed_limit, w[1].road_class)
+                    && (w[0].max_height_m, w[0].max_weight_t, w[0].max_width_m)
+                        == (w[1].max_height_m, w[1].max_weight_t, w[1].max_width_m)
+                    && (
+                        w[0].is_one_way,
+                        w[0].is_roundabout,
+                        w[0].is_ferry,
+                        w[0].is_reversible,
+                    ) == (
+                        w[1].is_one_way,
+                        w[1].is_roundabout,
+                        w[1].is_ferry,
+                        w[1].is_reversible,
+                    )
+            })
+    }
+
+    // Follows `edge_id` through chain nodes until it reaches a kept node. Returns the edge, the
+    // kept node it leads to, and the merged metadata if it passed any chain nodes.
+    fn follow_chain(
+        &self,
+        edge_id: usize,
+        is_chain: &[bool],
+        visited: &mut [bool],
+    ) -> (usize, usize, Option<EdgeMetadata>) {
+        let first = self.get_edge(edge_id);
+        let (mut prev_id, mut curr_id) = (first.src_id, first.dest_id);
+        let mut merged: Option<EdgeMetadata> = None;
+
+        while is_chain[curr_id] {
+            visited[curr_id] = true;
+            let next = self.fwd_edge_list[curr_id]
+                .iter()
+                .map(|&id| &self.edges[id])
+                .find(|edge| edge.dest_id != prev_id)
+                .unwrap();
+            let next_metadata = self.get_edge_metadata(next);
+
+            let metadata = merged.get_or_insert_with(|| self.get_edge_metadata(first).clone());
+            metadata.weight += next_metadata.weight;
+            metadata.alt_weight += next_metadata.alt_weight;
+            metadata.distance_m += next_metadata.distance_m;
+            metadata.time_s += next_metadata.time_s;
+            metadata.geometry.push(self.nodes[curr_id].get_lat_lon());
+            metadata.geometry.extend_from_slice(&next_metadata.geometry);
+
+            (prev_id, curr_id) = (curr_id, next.dest_id);
+        }
+
+        (edge_id, curr_id, merged)
+    }
+
+    /// Checks the structural integrity of the graph, reporting every violation found.
+    pub fn validate(&self) -> Result<(), Vec<GraphError>> {
+        let num_nodes = self.num_nodes();
+        let num_edges = self.num_edges();
+        let mut errors = Vec::new();
+
+        if self.fwd_edge_list.len() != num_nodes || self.bwd_edge_list.len() != num_nodes {
+            errors.push(GraphError::AdjacencyLenMismatch {
+                fwd_len: self.fwd_edge_list.len(),
+                bwd_len: self.bwd_edge_list.len(),
+                num_nodes,
+            });
+        }
+
+        for (edge_id, edge) in self.edges.iter().enumerate() {
+            if edge.src_id >= num_nodes {
+                errors.push(GraphError::InvalidSrcNode {
+                    edge_id,
+                    src_id: edge.src_id,
+                });
+            }
+            if edge.dest_id >= num_nodes {
+                errors.push(GraphError::InvalidDestNode {
+                    edge_id,
+                    dest_id: edge.dest_id,
+                });
+            }
+            match self.edge_metadata.get(edge.metadata_index) {
+                None => errors.push(GraphError::InvalidMetadataIndex {
+                    edge_id,
+                    metadata_index: edge.metadata_index,
+                }),
+                Some(metadata) if !is_valid_weight(metadata.weight) => {
+                    errors.push(GraphError::InvalidWeight {
+                        edge_id,
+                        weight: metadata.weight,
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (node_id, edge_ids) in self.fwd_edge_list.iter().enumerate() {
+            for &edge_id in edge_ids {
+                if edge_id >= num_edges {
+                    errors.push(GraphError::FwdEdgeOutOfRange { node_id, edge_id });
+                    continue;
+                }
+
+                let edge = &self.edges[edge_id];
+                if edge.src_id != node_id {
+                    errors.push(GraphError::FwdListMismatch { node_id, edge_id });
+                }
+                let in_bwd = self
+                    .bwd_edge_list
+                    .get(edge.dest_id)
+                    .is_some_and(|bwd| bwd.contains(&edge_id));
+                if !in_bwd {
+                    errors.push(GraphError::MissingBwdEntry { edge_id });
+                }
+            }
+        }
+
+        for (node_id, edge_ids) in self.bwd_edge_list.iter().enumerate() {
+            for &edge_id in edge_ids {
+                if edge_id >= num_edges {
+                    errors.push(GraphError::BwdEdgeOutOfRange { node_id, edge_id });
+                    continue;
+                }
+
+                let edge = &self.edges[edge_id];
+                if edge.dest_id != node_id {
+                    errors.push(GraphError::BwdListMismatch { node_id, edge_id });
+                }
+                let in_fwd = self
+                    .fwd_edge_list
+                    .get(edge.src_id)
+                    .is_some_and(|fwd| fwd.contains(&edge_id));
+                if !in_fwd {
+                    errors.push(GraphError::MissingFwdEntry { edge_id });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Clamps zero, negative and NaN weights up to `min_weight`, and infinite weights down to
+    /// `f32::MAX`. Returns the number of metadata entries that were changed.
+    pub fn clamp_weights(&mut self, min_weight: f32) -> usize {
+        let mut clamped = 0;
+        for metadata in &mut self.edge_metadata {
+            if is_valid_weight(metadata.weight) {
+                continue;
+            }
+
+            metadata.weight = if metadata.weight == f32::INFINITY {
+                f32::MAX
+            } else {
+                min_weight
+            };
+            clamped += 1;
+        }
+        clamped
+    }
+
+    /// Removes the edges `vehicle` doesn't fit on, so a graph contracted afterwards only routes
+    /// over edges the vehicle can use. Querying can't skip edges without breaking the
+    /// shortcuts, so each vehicle class needs a graph of its own. Nodes left without edges are
+    /// dropped too. Must be called before contraction, as it renumbers the nodes and edges with
+    /// `compact`. Returns the number of edges removed.
+    pub fn restrict_to_vehicle(&mut self, vehicle: &VehicleConstraints) -> usize {
+        let num_edges = self.num_edges();
+        for edge_id in 0..num_edges {
+            if !vehicle.allows(self.get_edge_metadata(&self.edges[edge_id])) {
+                self.unlink_edge(edge_id);
+            }
+        }
+        self.compact();
+        num_edges - self.num_edges()
+    }
+
+    // Removes an edge from the adjacency lists, it keeps its id until `compact` drops it.
+    fn unlink_edge(&mut self, edge_id: usize) {
+        let Edge {
+            src_id, dest_id, ..
+        } = self.edges[edge_id];
+        self.fwd_edge_list[src_id].retain(|&id| id != edge_id);
+        self.bwd_edge_list[dest_id].retain(|&id| id != edge_id);
+    }
+
+    /// Unlinks a node from the adjacency lists, along with every edge from or to it. The node
+    /// and its edges keep their ids until `compact` drops them.
+    pub fn remove_node(&mut self, dense_id: usize) {
+        for edge_id in std::mem::take(&mut self.fwd_edge_list[dense_id]) {
+            let dest_id = self.edges[edge_id].dest_id;
+            self.bwd_edge_list[dest_id].retain(|&id| id != edge_id);
+        }
+        for edge_id in std::mem::take(&mut self.bwd_edge_list[dense_id]) {
+            let src_id = self.edges[edge_id].src_id;
+            self.fwd_edge_list[src_id].retain(|&id| id != edge_id);
+        }
+    }
+
+    /// Renumbers the nodes, edges and metadata to gap-free ranges, keeping their order. Edges
+    /// no longer in the forward list of their source, nodes without edges and metadata no edge
+    /// uses are dropped, and the adjacency lists are rebuilt. Shortcuts refer to the ids of
+    /// their edges, so this must run before contraction.
+    pub fn compact(&mut self) -> IdMapping {
+        let mut is_linked = vec![false; self.num_edges()];
+        for &edge_id in self.fwd_edge_list.iter().flatten() {
+            is_linked[edge_id] = true;
+        }
+        let mut is_used = vec![false; self.num_nodes()];
+        for (edge, _) in self.edges.iter().zip(&is_linked).filter(|(_, &l)| l) {
+            is_used[edge.src_id] = true;
+            is_used[edge.dest_id] = true;
+        }
+
+        let mut node_ids = vec![None; self.num_nodes()];
+        let mut nodes = Vec::new();
+        for node in self.nodes.iter().filter(|node| is_used[node.dense_id]) {
+            node_ids[node.dense_id] = Some(nodes.len());
+            nodes.push(Node {
+                dense_id: nodes.len(),
+                ..node.clone()
+            });
+        }
+
+        let mut graph = Graph::new(nodes);
+        let mut metadata: Vec<Option<EdgeMetadata>> = std::mem::take(&mut self.edge_metadata)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let mut metadata_ids: Vec<Option<usize>> = vec![None; metadata.len()];
+        let mut edge_ids = vec![None; self.num_edges()];
+        for (edge_id, edge) in self.edges.iter().enumerate() {
+            if !is_linked[edge_id] {
+                continue;
+            }
+
+            let metadata_index = *metadata_ids[edge.metadata_index].get_or_insert_with(|| {
+                graph
+                    .edge_metadata
+                    .push(metadata[edge.metadata_index].take().unwrap());
+                graph.edge_metadata.len() - 1
+            });
+            let (src_id, dest_id) = (node_ids[edge.src_id], node_ids[edge.dest_id]);
+            edge_ids[edge_id] =
+                Some(graph.add_edge(src_id.unwrap(), dest_id.unwrap(), metadata_index));
+        }
+
+        *self = graph;
+        IdMapping {
+            nodes: node_ids,
+            edges: edge_ids,
+        }
+    }
+
+    /// Gets the exit number of leaving a roundabout through `exit_edge` after entering it
+    /// through `entry_edge`, counting the exits passed in driving order, e.g. 2 for "take the
+    /// 2nd exit". A node of the roundabout is an exit if an edge leaves the roundabout there.
+    /// Returns `None` if the entry edge doesn't lead onto a roundabout or the exit edge doesn't
+    /// leave it. Leaving where it was entered counts every exit of the roundabout. A roundabout
+    /// mapped as several ways is followed across all of them.
+    pub fn roundabout_exit_number(&self, entry_edge: usize, exit_edge: usize) -> Option<usize> {
+        let entry_node = self.edges[entry_edge].dest_id;
+        let exit = &self.edges[exit_edge];
+        if self.get_edge_metadata(exit).roundabout.is_some() {
+            return None;
+        }
+        // Stays on the same way where roundabouts touch, and moves on to the next way where a
+        // roundabout split into several ways continues.
+        let next_on_roundabout = |node: usize, id: Option<i64>| {
+            let mut next = None;
+            for &edge_id in &self.fwd_edge_list[node] {
+                let edge = &self.edges[edge_id];
+                let Some(position) = self.get_edge_metadata(edge).roundabout else {
+                    continue;
+                };
+                if Some(position.id) == id {
+                    return Some((edge.dest_id, position.id));
+                }
+                next.get_or_insert((edge.dest_id, position.id));
+            }
+            next
+        };
+        let is_exit = |node: usize| {
+            self.fwd_edge_list[node].iter().any(|&edge_id| {
+                let metadata = self.get_edge_metadata(&self.edges[edge_id]);
+                metadata.roundabout.is_none() && metadata.prev_edge.is_none()
+            })
+        };
+
+        let (mut node, mut id) = next_on_roundabout(entry_node, None)?;
+        let mut exit_number = 0;
+        // Bounded by the number of nodes in case the roundabout edges don't close a ring.
+        for _ in 0..self.num_nodes() {
+            if is_exit(node) {
+                exit_number += 1;
+            }
+            if node == exit.src_id {
+                return Some(exit_number);
+            }
+            if node == entry_node {
+                return None;
+            }
+            (node, id) = next_on_roundabout(node, Some(id))?;
+        }
+        None
+    }
+
+    /// Computes summary statistics of the graph, useful to check the quality of an import.
+    pub fn stats(&self) -> GraphStats {
+        let degrees = (0..self.num_nodes())
+            .map(|id| self.fwd_edge_list[id].len() + self.bwd_edge_list[id].len());
+        let total_degree: usize = degrees.clone().sum();
+
+        let mut num_shortcuts = 0;
+        let mut num_one_way = 0;
+        let mut num_named = 0;
+        let mut num_with_speed = 0;
+        for edge in &self.edges {
+            let metadata = self.get_edge_metadata(edge);
+            num_shortcuts += metadata.prev_edge.is_some() as usize;
+            num_one_way += metadata.is_one_way as usize;
+            num_named += metadata.name.is_some() as usize;
+            num_with_speed += metadata.speed_limit.is_some() as usize;
+        }
+
+        let share = |count: usize| {
+            if self.edges.is_empty() {
+                0.0
+            } else {
+                count as f32 / self.num_edges() as f32
+            }
+        };
+
+        GraphStats {
+            num_nodes: self.num_nodes(),
+            num_edges: self.num_edges(),
+            min_degree: degrees.clone().min().unwrap_or(0),
+            max_degree: degrees.max().unwrap_or(0),
+            mean_degree: if self.nodes.is_empty() {
+                0.0
+            } else {
+                total_degree as f32 / self.num_nodes() as f32
+            },
+            num_shortcuts,
+            num_original: self.num_edges() - num_shortcuts,
+            num_one_way,
+            named_share: share(num_named),
+            speed_share: share(num_with_speed),
+        }
+    }
+
     fn get_nodes_bytes(&self) -> usize {
         self.nodes.len() * std::mem::size_of::<Node>()
     }
@@ -174,6 +929,15 @@ impl Graph {
             .sum()
     }
 
+    // Gets the total memory usage of the graph in bytes.
+    pub fn get_mem_usage(&self) -> usize {
+        self.get_nodes_bytes()
+            + self.get_fwd_bytes()
+            + self.get_bwd_bytes()
+            + self.get_edges_bytes()
+            + self.get_edges_metadata_bytes()
+    }
+
     pub fn get_mem_usage_str(&self) -> String {
         let node_bytes = self.get_nodes_bytes();
         let fwd_bytes = self.get_fwd_bytes();
@@ -181,7 +945,7 @@ impl Graph {
         let edge_bytes = self.get_edges_bytes();
         let edge_metadata_bytes = self.get_edges_metadata_bytes();
 
-        let total = node_bytes + fwd_bytes + bwd_bytes + edge_bytes + edge_metadata_bytes;
+        let total = self.get_mem_usage();
 
         format!(
             "nodes={}, fwd_edges={}, bwd_edges={}, edges={}, edge_metadata={}, total={}",
@@ -195,6 +959,99 @@ impl Graph {
     }
 }
 
+/// Adds two weights, saturating at `f32::MAX` instead of overflowing to infinity, so that sums of
+/// huge weights stay finite and comparable. NaN is passed through.
+pub fn add_weights(a: f32, b: f32) -> f32 {
+    let sum = a + b;
+    if sum == f32::INFINITY {
+        f32::MAX
+    } else {
+        sum
+    }
+}
+
+/// Checks if a weight is one the contraction can sum safely, finite and not negative.
+pub fn is_sane_weight(weight: f32) -> bool {
+    (0.0..=f32::MAX).contains(&weight)
+}
+
+// Zero weights allow zero-cost cycles, which the witness search and queries don't expect.
+fn is_valid_weight(weight: f32) -> bool {
+    weight > 0.0 && weight.is_finite()
+}
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "nodes={}, edges={} (original={}, shortcuts={}, one_way={}), degree=min {} / max {} / mean {:.2}, named={:.1}%, with_speed={:.1}%",
+            self.num_nodes,
+            self.num_edges,
+            self.num_original,
+            self.num_shortcuts,
+            self.num_one_way,
+            self.min_degree,
+            self.max_degree,
+            self.mean_degree,
+            self.named_share * 100.0,
+            self.speed_share * 100.0,
+        )
+    }
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::AdjacencyLenMismatch {
+                fwd_len,
+                bwd_len,
+                num_nodes,
+            } => write!(
+                f,
+                "adjacency lists have fwd_len={fwd_len}, bwd_len={bwd_len} but there are {num_nodes} nodes"
+            ),
+            GraphError::FwdEdgeOutOfRange { node_id, edge_id } => {
+                write!(f, "node {node_id} has out of range forward edge {edge_id}")
+            }
+            GraphError::BwdEdgeOutOfRange { node_id, edge_id } => {
+                write!(f, "node {node_id} has out of range backward edge {edge_id}")
+            }
+            GraphError::InvalidSrcNode { edge_id, src_id } => {
+                write!(f, "edge {edge_id} has invalid src_id {src_id}")
+            }
+            GraphError::InvalidDestNode { edge_id, dest_id } => {
+                write!(f, "edge {edge_id} has invalid dest_id {dest_id}")
+            }
+            GraphError::InvalidMetadataIndex {
+                edge_id,
+                metadata_index,
+            } => write!(
+                f,
+                "edge {edge_id} has invalid metadata_index {metadata_index}"
+            ),
+            GraphError::InvalidWeight { edge_id, weight } => {
+                write!(f, "edge {edge_id} has invalid weight {weight}")
+            }
+            GraphError::FwdListMismatch { node_id, edge_id } => write!(
+                f,
+                "edge {edge_id} is in the forward list of node {node_id} which is not its source"
+            ),
+            GraphError::BwdListMismatch { node_id, edge_id } => write!(
+                f,
+                "edge {edge_id} is in the backward list of node {node_id} which is not its destination"
+            ),
+            GraphError::MissingBwdEntry { edge_id } => {
+                write!(f, "edge {edge_id} is missing from the backward list of its destination")
+            }
+            GraphError::MissingFwdEntry { edge_id } => {
+                write!(f, "edge {edge_id} is missing from the forward list of its source")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
 impl Node {
     pub fn new(dense_id: usize, osm_id: i64) -> Self {
         Self {
@@ -205,6 +1062,8 @@ impl Node {
             lat: 0.0,
             lon: 0.0,
             is_traffic_light: false,
+            is_turning_circle: false,
+            is_barrier: false,
         }
     }
 
@@ -230,12 +1089,12 @@ impl Node {
         self.is_contracted
     }
 
-    pub fn set_lat_lon(&mut self, lat: f32, lon: f32) {
+    pub fn set_lat_lon(&mut self, lat: f64, lon: f64) {
         self.lat = lat;
         self.lon = lon;
     }
 
-    pub fn get_lat_lon(&self) -> (f32, f32) {
+    pub fn get_lat_lon(&self) -> (f64, f64) {
         (self.lat, self.lon)
     }
 
@@ -246,6 +1105,97 @@ impl Node {
     pub fn get_is_traffic_light(&self) -> bool {
         self.is_traffic_light
     }
+
+    pub fn set_is_turning_circle(&mut self, is_turning_circle: bool) {
+        self.is_turning_circle = is_turning_circle;
+    }
+
+    pub fn get_is_turning_circle(&self) -> bool {
+        self.is_turning_circle
+    }
+}
+
+impl RoadClass {
+    // Maps the value of a `highway=` tag to a road class, links belong to the class they link.
+    pub fn from_highway(value: &str) -> Self {
+        match value {
+            "motorway" | "motorway_link" => RoadClass::Motorway,
+            "trunk" | "trunk_link" => RoadClass::Trunk,
+            "primary" | "primary_link" => RoadClass::Primary,
+            "secondary" | "secondary_link" => RoadClass::Secondary,
+            "tertiary" | "tertiary_link" => RoadClass::Tertiary,
+            "unclassified" => RoadClass::Unclassified,
+            "residential" => RoadClass::Residential,
+            "living_street" => RoadClass::LivingStreet,
+            "service" => RoadClass::Service,
+            "track" => RoadClass::Track,
+            "cycleway" => RoadClass::Cycleway,
+            "path" | "bridleway" => RoadClass::Path,
+            "footway" => RoadClass::Footway,
+            "pedestrian" => RoadClass::Pedestrian,
+            "steps" => RoadClass::Steps,
+            _ => RoadClass::Unknown,
+        }
+    }
+
+    /// Gets the level of the class in the road hierarchy, from 0 for motorways down to service
+    /// roads, or `None` for tracks, paths and unknown roads, which aren't part of it.
+    pub fn level(self) -> Option<u8> {
+        match self {
+            RoadClass::Motorway => Some(0),
+            RoadClass::Trunk => Some(1),
+            RoadClass::Primary => Some(2),
+            RoadClass::Secondary => Some(3),
+            RoadClass::Tertiary => Some(4),
+            RoadClass::Unclassified => Some(5),
+            RoadClass::Residential => Some(6),
+            RoadClass::LivingStreet => Some(7),
+            RoadClass::Service => Some(8),
+            RoadClass::Track
+            | RoadClass::Cycleway
+            | RoadClass::Path
+            | RoadClass::Footway
+            | RoadClass::Pedestrian
+            | RoadClass::Steps
+            | RoadClass::Unknown => None,
+        }
+    }
+}
+
+impl EdgeMetadata {
+    pub fn new(weight: f32) -> Self {
+        Self {
+            weight,
+            alt_weight: weight,
+            distance_m: 0.0,
+            time_s: weight,
+            time_factor: 1.0,
+            name: None,
+            names: None,
+            speed_limit: None,
+            is_one_way: false,
+            is_roundabout: false,
+            roundabout: None,
+            road_class: RoadClass::Unknown,
+            max_height_m: None,
+            max_weight_t: None,
+            max_width_m: None,
+            is_ferry: false,
+            is_reversible: false,
+            prev_edge: None,
+            next_edge: None,
+            geometry: Vec::new(),
+            hops: 1,
+            way_id: None,
+        }
+    }
+
+    pub fn metric_weight(&self, metric: Metric) -> f32 {
+        match metric {
+            Metric::Primary => self.weight,
+            Metric::Secondary => self.alt_weight,
+        }
+    }
 }
 
 impl Edge {
@@ -277,3 +1227,605 @@ impl Edge {
         self.metadata_index
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0 <-> 1 -> 2
+    fn get_test_graph() -> Graph {
+        let nodes = (0..3).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+
+        for (src, dest, weight) in [(0, 1, 1.0), (1, 0, 1.0), (1, 2, 2.0)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+        }
+
+        graph
+    }
+
+    #[test]
+    fn test_edge_exists_dense_node() {
+        // Node 0 is a hub connected to every other node, nodes 1..n form an unrelated chain.
+        let num_nodes = 50;
+        let nodes = (0..num_nodes).map(|i| Node::new(i, i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata::new(1.0));
+
+        for i in 1..num_nodes {
+            graph.add_edge(0, i, 0);
+            if i % 3 == 0 {
+                graph.add_edge(i, 0, 0);
+            }
+            if i + 1 < num_nodes {
+                graph.add_edge(i, i + 1, 0);
+            }
+        }
+
+        for v in 0..num_nodes {
+            for w in 0..num_nodes {
+                let linear = graph.edges.iter().any(|e| e.src_id == v && e.dest_id == w);
+                assert_eq!(graph.edge_exists(v, w), linear, "mismatch for {v} -> {w}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_incident_edges() {
+        let mut graph = get_test_graph();
+        graph.add_edge(1, 1, 0);
+
+        let mut incident: Vec<_> = graph.incident_edges(1).collect();
+        incident.sort_by_key(|&(edge_id, _)| edge_id);
+        assert_eq!(
+            incident,
+            vec![
+                (0, Direction::Bwd),
+                (1, Direction::Fwd),
+                (2, Direction::Fwd),
+                (3, Direction::Fwd),
+            ]
+        );
+
+        let incident: Vec<_> = graph.incident_edges(2).collect();
+        assert_eq!(incident, vec![(2, Direction::Bwd)]);
+    }
+
+    // 0 <-> 1 under a 3.5 m bridge, with a detour 0 <-> 2 <-> 1 without limits.
+    fn get_low_bridge_graph() -> Graph {
+        let nodes = (0..3).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata {
+            max_height_m: Some(3.5),
+            ..EdgeMetadata::new(1.0)
+        });
+        graph.edge_metadata.push(EdgeMetadata::new(2.0));
+        for (src, dest, metadata_index) in [(0, 1, 0), (0, 2, 1), (2, 1, 1)] {
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        graph
+    }
+
+    #[test]
+    fn test_vehicle_constraints_allows() {
+        let metadata = EdgeMetadata {
+            max_height_m: Some(3.5),
+            max_weight_t: Some(7.5),
+            ..EdgeMetadata::new(1.0)
+        };
+        let van = VehicleConstraints {
+            height_m: 2.5,
+            weight_t: 3.5,
+            width_m: 2.0,
+        };
+        assert!(van.allows(&metadata));
+        assert!(!VehicleConstraints {
+            height_m: 4.0,
+            ..van
+        }
+        .allows(&metadata));
+        assert!(!VehicleConstraints {
+            weight_t: 12.0,
+            ..van
+        }
+        .allows(&metadata));
+        // No width limit is tagged on the edge.
+        assert!(VehicleConstraints {
+            width_m: 5.0,
+            ..van
+        }
+        .allows(&metadata));
+    }
+
+    #[test]
+    fn test_tall_vehicle_avoids_low_bridge() {
+        use crate::engine::query::simple_router::SimpleRouter;
+
+        let mut graph = get_low_bridge_graph();
+        let car = VehicleConstraints {
+            height_m: 1.5,
+            ..Default::default()
+        };
+        assert_eq!(graph.restrict_to_vehicle(&car), 0);
+        assert_eq!(
+            SimpleRouter::new(&graph).route(0, 1),
+            Some((1.0, vec![0, 1]))
+        );
+
+        let truck = VehicleConstraints {
+            height_m: 4.0,
+            ..Default::default()
+        };
+        assert_eq!(graph.restrict_to_vehicle(&truck), 2);
+        assert!(!graph.edge_exists(0, 1) && !graph.edge_exists(1, 0));
+        assert_eq!(
+            SimpleRouter::new(&graph).route(0, 1),
+            Some((4.0, vec![0, 2, 1]))
+        );
+        assert_eq!(
+            SimpleRouter::new(&graph).route(1, 0),
+            Some((4.0, vec![1, 2, 0]))
+        );
+        assert!(graph.validate().is_ok());
+    }
+
+    #[test]
+    fn test_restrict_to_vehicle_drops_stranded_nodes() {
+        // 0 - 1 - 2, where 1 - 2 passes under a low bridge.
+        let nodes = (0..3).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata::new(1.0));
+        graph.edge_metadata.push(EdgeMetadata {
+            max_height_m: Some(3.5),
+            ..EdgeMetadata::new(1.0)
+        });
+        for (src, dest, metadata_index) in [(0, 1, 0), (1, 2, 1)] {
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+
+        let truck = VehicleConstraints {
+            height_m: 4.0,
+            ..Default::default()
+        };
+        assert_eq!(graph.restrict_to_vehicle(&truck), 2);
+        assert_eq!(graph.num_nodes(), 2);
+        assert!(graph.nodes.iter().all(|node| node.osm_id != 102));
+        assert_eq!(graph.edge_metadata.len(), 1);
+        assert!(graph.validate().is_ok());
+    }
+
+    // A straight street through 7 nodes, with the given weight on every segment.
+    fn get_chain_graph(is_two_way: bool) -> Graph {
+        let nodes = (0..7)
+            .map(|i| Node {
+                lat: i as f64,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+
+        for src in 0..6 {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata {
+                distance_m: 10.0,
+                ..EdgeMetadata::new(src as f32 + 1.0)
+            });
+            graph.add_edge(src, src + 1, metadata_index);
+            if is_two_way {
+                graph.add_edge(src + 1, src, metadata_index);
+            }
+        }
+        graph
+    }
+
+    #[test]
+    fn test_compact_after_removing_node() {
+        let mut graph = get_chain_graph(true);
+        graph.remove_node(3);
+        assert_eq!(graph.num_nodes(), 7);
+        assert!(!graph.edge_exists(2, 3) && !graph.edge_exists(4, 3));
+
+        let mapping = graph.compact();
+        assert_eq!(
+            mapping.nodes,
+            [Some(0), Some(1), Some(2), None, Some(3), Some(4), Some(5)]
+        );
+        // The four edges to and from node 3 are dropped.
+        assert_eq!(mapping.edges.iter().filter(|id| id.is_none()).count(), 4);
+        assert_eq!(graph.num_nodes(), 6);
+        assert_eq!(graph.num_edges(), 8);
+        assert_eq!(graph.edge_metadata.len(), 4);
+        assert!(graph.validate().is_ok());
+
+        for (dense_id, node) in graph.nodes.iter().enumerate() {
+            assert_eq!(node.dense_id, dense_id);
+        }
+        assert_eq!(graph.nodes[3].osm_id, 104);
+        assert!(graph.edge_exists(1, 2) && graph.edge_exists(3, 4));
+        assert!(!graph.edge_exists(2, 3));
+        // The metadata follows its edges, 4 -> 5 was the fifth segment with weight 5.
+        let edge = graph.find_edge(3, 4).unwrap();
+        assert_eq!(graph.get_edge_metadata(edge).weight, 5.0);
+
+        // A compact graph is left as is.
+        let mapping = graph.compact();
+        assert_eq!(mapping.nodes, (0..6).map(Some).collect::<Vec<_>>());
+        assert_eq!(mapping.edges, (0..8).map(Some).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_compress_chains() {
+        let mut graph = get_chain_graph(false);
+
+        assert_eq!(graph.compress_chains(), 5);
+        assert_eq!(graph.num_nodes(), 2);
+        assert_eq!(graph.num_edges(), 1);
+        assert_eq!(graph.get_node(1).osm_id, 106);
+        // The metadata of the merged segments is dropped with them.
+        assert_eq!(graph.edge_metadata.len(), 1);
+        assert!(graph.validate().is_ok());
+
+        let metadata = graph.get_edge_metadata(graph.find_edge(0, 1).unwrap());
+        assert_eq!(metadata.weight, 21.0);
+        assert_eq!(metadata.distance_m, 60.0);
+        let lats: Vec<f64> = metadata.geometry.iter().map(|&(lat, _)| lat).collect();
+        assert_eq!(lats, [1.0, 2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn test_simplify_geometry() {
+        // A straight chain gets merged into one edge with five shape points in between.
+        let mut graph = get_chain_graph(false);
+        graph.compress_chains();
+        let geometry = |graph: &Graph| {
+            graph
+                .get_edge_metadata(graph.find_edge(0, 1).unwrap())
+                .geometry
+                .clone()
+        };
+        assert_eq!(geometry(&graph).len(), 5);
+
+        // Bend the middle point about 110 meters off the line.
+        graph.edge_metadata[0].geometry[2].1 = 0.001;
+        assert_eq!(graph.simplify_geometry(100.0), 4);
+        assert_eq!(geometry(&graph), [(3.0, 0.001)]);
+        assert_eq!(graph.simplify_geometry(1e6), 1);
+        assert!(geometry(&graph).is_empty());
+        assert!(graph.validate().is_ok());
+    }
+
+    #[test]
+    fn test_compress_chains_keeps_traffic_lights() {
+        let mut graph = get_chain_graph(true);
+        graph.get_node_mut(3).set_is_traffic_light(true);
+
+        assert_eq!(graph.compress_chains(), 4);
+        assert_eq!(graph.num_nodes(), 3);
+        assert_eq!(graph.num_edges(), 4);
+        assert_eq!(graph.get_node(1).osm_id, 103);
+
+        // The geometry follows the direction of travel, so each way gets its own metadata.
+        let fwd = graph.get_edge_metadata(graph.find_edge(0, 1).unwrap());
+        let bwd = graph.get_edge_metadata(graph.find_edge(1, 0).unwrap());
+        assert_eq!((fwd.weight, bwd.weight), (6.0, 6.0));
+        assert_eq!(fwd.geometry, [(1.0, 0.0), (2.0, 0.0)]);
+        assert_eq!(bwd.geometry, [(2.0, 0.0), (1.0, 0.0)]);
+        assert_eq!(
+            graph
+                .get_edge_metadata(graph.find_edge(1, 2).unwrap())
+                .weight,
+            15.0
+        );
+    }
+
+    #[test]
+    fn test_reweight() {
+        let mut graph = get_test_graph();
+        for (metadata, (distance_m, speed_limit)) in
+            graph
+                .edge_metadata
+                .iter_mut()
+                .zip([(1000.0, 36), (1000.0, 36), (500.0, 90)])
+        {
+            metadata.distance_m = distance_m;
+            metadata.speed_limit = Some(speed_limit);
+        }
+
+        graph.reweight(|metadata, _, _| metadata.distance_m);
+        let weights: Vec<_> = graph.edge_metadata.iter().map(|m| m.weight).collect();
+        assert_eq!(weights, vec![1000.0, 1000.0, 500.0]);
+
+        graph.reweight(|metadata, _, _| {
+            metadata.distance_m / (metadata.speed_limit.unwrap() as f32 / 3.6)
+        });
+        let weights: Vec<_> = graph.edge_metadata.iter().map(|m| m.weight).collect();
+        assert_eq!(weights, vec![100.0, 100.0, 20.0]);
+
+        graph.reweight(|_, src, dest| (src.dense_id * 10 + dest.dense_id) as f32);
+        let weights: Vec<_> = graph.edge_metadata.iter().map(|m| m.weight).collect();
+        assert_eq!(weights, vec![1.0, 10.0, 12.0]);
+    }
+
+    #[test]
+    fn test_route_costs_more_at_peak_hour() {
+        use crate::engine::preprocess::builder::ImportConfig;
+        use crate::engine::query::simple_router::SimpleRouter;
+
+        let profile = TimeOfDayProfile::default().with_peak(
+            7 * 3600,
+            9 * 3600,
+            [RoadClass::Residential],
+            1.5,
+        );
+        let cost_at = |departure_s| {
+            let mut graph = ImportConfig::new("tests/data/tiny.osm").build().unwrap();
+            graph.reweight_at(&profile, departure_s);
+            let dense = |osm_id| graph.nodes.iter().position(|n| n.osm_id == osm_id).unwrap();
+            let (cost, _) = SimpleRouter::new(&graph).route(dense(1), dense(9)).unwrap();
+            cost
+        };
+
+        let off_peak = cost_at(3 * 3600);
+        let peak = cost_at(8 * 3600);
+        assert!(off_peak > 0.0);
+        assert!((peak - 1.5 * off_peak).abs() < 1e-3, "{peak} vs {off_peak}");
+        assert_eq!(cost_at(9 * 3600), off_peak);
+    }
+
+    #[test]
+    fn test_reweight_at_keeps_import_penalties() {
+        use crate::engine::preprocess::builder::ImportConfig;
+
+        let profile = TimeOfDayProfile::default().with_peak(
+            7 * 3600,
+            9 * 3600,
+            [RoadClass::Residential],
+            1.5,
+        );
+        let mut graph = ImportConfig::new("tests/data/tiny.osm").build().unwrap();
+        let dense = |graph: &Graph, osm_id| graph.nodes.iter().position(|n| n.osm_id == osm_id);
+        // 4 -> 6 passes the traffic light on node 5.
+        let (from, to) = (dense(&graph, 4).unwrap(), dense(&graph, 6).unwrap());
+        let metadata_index = graph.find_edge(from, to).unwrap().metadata_index;
+        let imported = graph.edge_metadata[metadata_index].clone();
+        assert!(imported.weight > imported.time_s);
+
+        graph.reweight_at(&profile, 8 * 3600);
+        let peak = &graph.edge_metadata[metadata_index];
+        assert!((peak.weight - 1.5 * imported.weight).abs() < 1e-3);
+        assert!((peak.time_s - 1.5 * imported.time_s).abs() < 1e-3);
+
+        graph.reweight_at(&profile, 3 * 3600);
+        let off_peak = &graph.edge_metadata[metadata_index];
+        assert!((off_peak.weight - imported.weight).abs() < 1e-3);
+        assert!((off_peak.time_s - imported.time_s).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut graph = get_test_graph();
+        graph.edge_metadata[2].name = Some("Main Street".to_string());
+        graph.edge_metadata[2].is_one_way = true;
+
+        // A 0 -> 2 shortcut through 1.
+        graph.edge_metadata.push(EdgeMetadata {
+            prev_edge: Some(0),
+            next_edge: Some(2),
+            is_one_way: true,
+            ..EdgeMetadata::new(3.0)
+        });
+        graph.add_shortcut_edge(0, 2, 3);
+
+        assert_eq!(
+            graph.stats(),
+            GraphStats {
+                num_nodes: 3,
+                num_edges: 4,
+                min_degree: 2,
+                max_degree: 3,
+                mean_degree: 8.0 / 3.0,
+                num_shortcuts: 1,
+                num_original: 3,
+                num_one_way: 2,
+                named_share: 0.25,
+                speed_share: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        assert_eq!(get_test_graph().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_adjacency_len() {
+        let mut graph = get_test_graph();
+        graph.bwd_edge_list.pop();
+
+        let errors = graph.validate().unwrap_err();
+        assert!(errors.contains(&GraphError::AdjacencyLenMismatch {
+            fwd_len: 3,
+            bwd_len: 2,
+            num_nodes: 3,
+        }));
+    }
+
+    #[test]
+    fn test_validate_edge_out_of_range() {
+        let mut graph = get_test_graph();
+        graph.fwd_edge_list[0].push(42);
+        graph.bwd_edge_list[2].push(43);
+
+        let errors = graph.validate().unwrap_err();
+        assert!(errors.contains(&GraphError::FwdEdgeOutOfRange {
+            node_id: 0,
+            edge_id: 42
+        }));
+        assert!(errors.contains(&GraphError::BwdEdgeOutOfRange {
+            node_id: 2,
+            edge_id: 43
+        }));
+    }
+
+    #[test]
+    fn test_validate_invalid_nodes() {
+        let mut graph = get_test_graph();
+        graph.edges[2].dest_id = 7;
+        graph.edges[1].src_id = 9;
+
+        let errors = graph.validate().unwrap_err();
+        assert!(errors.contains(&GraphError::InvalidDestNode {
+            edge_id: 2,
+            dest_id: 7
+        }));
+        assert!(errors.contains(&GraphError::InvalidSrcNode {
+            edge_id: 1,
+            src_id: 9
+        }));
+    }
+
+    #[test]
+    fn test_validate_invalid_metadata_index() {
+        let mut graph = get_test_graph();
+        graph.edges[0].metadata_index = 5;
+
+        let errors = graph.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![GraphError::InvalidMetadataIndex {
+                edge_id: 0,
+                metadata_index: 5
+            }]
+        );
+    }
+
+    #[test]
+    fn test_validate_invalid_weight() {
+        let mut graph = get_test_graph();
+        graph.edge_metadata[0].weight = 0.0;
+        graph.edge_metadata[1].weight = f32::INFINITY;
+
+        let errors = graph.validate().unwrap_err();
+        assert!(errors.contains(&GraphError::InvalidWeight {
+            edge_id: 0,
+            weight: 0.0
+        }));
+        assert!(errors.contains(&GraphError::InvalidWeight {
+            edge_id: 1,
+            weight: f32::INFINITY
+        }));
+
+        assert_eq!(graph.clamp_weights(0.01), 2);
+        assert_eq!(graph.edge_metadata[0].weight, 0.01);
+        assert_eq!(graph.edge_metadata[1].weight, f32::MAX);
+        assert_eq!(graph.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_inconsistent_lists() {
+        let mut graph = get_test_graph();
+        // Edge 2 (1 -> 2) removed from the backward list of node 2.
+        graph.bwd_edge_list[2].clear();
+        // Edge 0 (0 -> 1) listed under the wrong source.
+        graph.fwd_edge_list[2].push(0);
+
+        let errors = graph.validate().unwrap_err();
+        assert!(errors.contains(&GraphError::MissingBwdEntry { edge_id: 2 }));
+        assert!(errors.contains(&GraphError::FwdListMismatch {
+            node_id: 2,
+            edge_id: 0
+        }));
+    }
+
+    #[test]
+    fn test_validate_missing_fwd_entry() {
+        let mut graph = get_test_graph();
+        graph.fwd_edge_list[1].retain(|&e| e != 1);
+
+        let errors = graph.validate().unwrap_err();
+        assert_eq!(errors, vec![GraphError::MissingFwdEntry { edge_id: 1 }]);
+    }
+
+    #[test]
+    fn test_roundabout_exit_number() {
+        // A roundabout 0 -> 1 -> 2 -> 3 -> 0 with a two-way arm at each node, arm i + 4 at node
+        // i. The arm at 2 is a oneway street onto the roundabout, so it isn't an exit.
+        let nodes = (0..8).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for i in 0..4 {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata {
+                is_roundabout: true,
+                roundabout: Some(RoundaboutPosition {
+                    id: 50,
+                    index: i as u32,
+                }),
+                ..EdgeMetadata::new(1.0)
+            });
+            graph.add_edge(i, (i + 1) % 4, metadata_index);
+        }
+        graph.edge_metadata.push(EdgeMetadata::new(1.0));
+        let arm = graph.edge_metadata.len() - 1;
+        let mut entries = Vec::new();
+        let mut exits = Vec::new();
+        for i in 0..4 {
+            entries.push(graph.add_edge(i + 4, i, arm));
+            if i != 2 {
+                exits.push(Some(graph.add_edge(i, i + 4, arm)));
+            } else {
+                exits.push(None);
+            }
+        }
+        let exit_number = |entry: usize, exit: usize| {
+            graph.roundabout_exit_number(entries[entry], exits[exit].unwrap())
+        };
+
+        assert_eq!(exit_number(0, 1), Some(1));
+        // The oneway arm at 2 is passed without counting.
+        assert_eq!(exit_number(0, 3), Some(2));
+        assert_eq!(exit_number(2, 3), Some(1));
+        assert_eq!(exit_number(2, 1), Some(3));
+        // Going all the way around and back out where it was entered.
+        assert_eq!(exit_number(0, 0), Some(3));
+
+        // The exit has to leave the roundabout, and the entry lead onto it.
+        assert_eq!(graph.roundabout_exit_number(entries[0], 1), None);
+        assert_eq!(
+            graph.roundabout_exit_number(exits[1].unwrap(), exits[3].unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_roundabout_exit_number_across_ways() {
+        // The roundabout 0 -> 1 -> 2 -> 3 -> 0 is mapped as way 50 from 0 to 2 and way 51 from 2
+        // back to 0, with a two-way arm at each node, arm i + 4 at node i.
+        let nodes = (0..8).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for i in 0..4 {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata {
+                is_roundabout: true,
+                roundabout: Some(RoundaboutPosition {
+                    id: if i < 2 { 50 } else { 51 },
+                    index: i as u32 % 2,
+                }),
+                ..EdgeMetadata::new(1.0)
+            });
+            graph.add_edge(i, (i + 1) % 4, metadata_index);
+        }
+        graph.edge_metadata.push(EdgeMetadata::new(1.0));
+        let arm = graph.edge_metadata.len() - 1;
+        let entries: Vec<_> = (0..4).map(|i| graph.add_edge(i + 4, i, arm)).collect();
+        let exits: Vec<_> = (0..4).map(|i| graph.add_edge(i, i + 4, arm)).collect();
+
+        assert_eq!(graph.roundabout_exit_number(entries[1], exits[3]), Some(2));
+        assert_eq!(graph.roundabout_exit_number(entries[3], exits[2]), Some(3));
+        assert_eq!(graph.roundabout_exit_number(entries[0], exits[0]), Some(4));
+    }
+}
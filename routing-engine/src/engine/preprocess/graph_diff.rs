@@ -0,0 +1,183 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use super::graph::Graph;
+
+/// An edge whose weight differs between two graphs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightChange {
+    // The osm ids of the source and destination of the edge.
+    pub endpoints: (i64, i64),
+    pub old_weight: f32,
+    pub new_weight: f32,
+}
+
+/// The differences between two builds of a graph, from `graph_diff`. Nodes are keyed by osm id
+/// and edges by the osm ids of their endpoints, since dense ids shift between builds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GraphDiff {
+    pub nodes_added: Vec<i64>,
+    pub nodes_removed: Vec<i64>,
+    pub edges_added: Vec<(i64, i64)>,
+    pub edges_removed: Vec<(i64, i64)>,
+    pub weights_changed: Vec<WeightChange>,
+}
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for GraphDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "nodes: +{} -{}",
+            self.nodes_added.len(),
+            self.nodes_removed.len()
+        )?;
+        writeln!(
+            f,
+            "edges: +{} -{} ~{}",
+            self.edges_added.len(),
+            self.edges_removed.len(),
+            self.weights_changed.len()
+        )?;
+        for (src, dest) in &self.edges_added {
+            writeln!(f, "+ {src} -> {dest}")?;
+        }
+        for (src, dest) in &self.edges_removed {
+            writeln!(f, "- {src} -> {dest}")?;
+        }
+        for change in &self.weights_changed {
+            let (src, dest) = change.endpoints;
+            writeln!(
+                f,
+                "~ {src} -> {dest}: {} -> {}",
+                change.old_weight, change.new_weight
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Maps the osm endpoints of every original edge to its weight. Shortcuts are skipped, and of
+// parallel edges only the lightest is kept since it is the one routes use.
+fn edges_by_endpoints(graph: &Graph) -> BTreeMap<(i64, i64), f32> {
+    let mut edges: BTreeMap<(i64, i64), f32> = BTreeMap::new();
+    for edge in &graph.edges {
+        let metadata = graph.get_edge_metadata(edge);
+        if metadata.prev_edge.is_some() {
+            continue;
+        }
+
+        let endpoints = (
+            graph.get_node(edge.src_id).osm_id,
+            graph.get_node(edge.dest_id).osm_id,
+        );
+        edges
+            .entry(endpoints)
+            .and_modify(|weight| *weight = weight.min(metadata.weight))
+            .or_insert(metadata.weight);
+    }
+    edges
+}
+
+/// Compares two builds of a graph, reporting what `b` adds, removes or reweights relative to `a`.
+pub fn graph_diff(a: &Graph, b: &Graph) -> GraphDiff {
+    let nodes_a: BTreeSet<i64> = a.nodes.iter().map(|node| node.osm_id).collect();
+    let nodes_b: BTreeSet<i64> = b.nodes.iter().map(|node| node.osm_id).collect();
+    let edges_a = edges_by_endpoints(a);
+    let edges_b = edges_by_endpoints(b);
+
+    GraphDiff {
+        nodes_added: nodes_b.difference(&nodes_a).copied().collect(),
+        nodes_removed: nodes_a.difference(&nodes_b).copied().collect(),
+        edges_added: edges_b
+            .keys()
+            .filter(|endpoints| !edges_a.contains_key(endpoints))
+            .copied()
+            .collect(),
+        edges_removed: edges_a
+            .keys()
+            .filter(|endpoints| !edges_b.contains_key(endpoints))
+            .copied()
+            .collect(),
+        weights_changed: edges_a
+            .iter()
+            .filter_map(|(&endpoints, &old_weight)| {
+                let new_weight = *edges_b.get(&endpoints)?;
+                (old_weight != new_weight).then_some(WeightChange {
+                    endpoints,
+                    old_weight,
+                    new_weight,
+                })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::graph::{EdgeMetadata, Node};
+
+    // 0 <-> 1 -> 2
+    fn get_test_graph() -> Graph {
+        let nodes = (0..3).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, weight) in [(0, 1, 1.0), (1, 0, 1.0), (1, 2, 2.0)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+        }
+        graph
+    }
+
+    #[test]
+    fn test_diff_extra_edge() {
+        let a = get_test_graph();
+        let mut b = get_test_graph();
+        let metadata_index = b.edge_metadata.len();
+        b.edge_metadata.push(EdgeMetadata::new(5.0));
+        b.add_edge(2, 0, metadata_index);
+
+        assert!(graph_diff(&a, &a).is_empty());
+        assert_eq!(
+            graph_diff(&a, &b),
+            GraphDiff {
+                edges_added: vec![(102, 100)],
+                ..GraphDiff::default()
+            }
+        );
+        assert_eq!(graph_diff(&b, &a).edges_removed, [(102, 100)]);
+    }
+
+    #[test]
+    fn test_diff_ignores_dense_ids() {
+        let a = get_test_graph();
+
+        // The same graph with the nodes in reverse order, and a heavier 1 -> 2 edge.
+        let nodes = (0..3).map(|i| Node::new(i, 102 - i as i64)).collect();
+        let mut b = Graph::new(nodes);
+        for (src, dest, weight) in [(2, 1, 1.0), (1, 2, 1.0), (1, 0, 3.0)] {
+            let metadata_index = b.edge_metadata.len();
+            b.edge_metadata.push(EdgeMetadata::new(weight));
+            b.add_edge(src, dest, metadata_index);
+        }
+
+        let diff = graph_diff(&a, &b);
+        assert!(diff.edges_added.is_empty() && diff.edges_removed.is_empty());
+        assert_eq!(
+            diff.weights_changed,
+            [WeightChange {
+                endpoints: (101, 102),
+                old_weight: 2.0,
+                new_weight: 3.0,
+            }]
+        );
+    }
+}
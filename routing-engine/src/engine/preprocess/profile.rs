@@ -0,0 +1,196 @@
+/// The kind of traveller a graph is built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Profile {
+    #[default]
+    Car,
+    Bike,
+    Foot,
+}
+
+/// The resolved access of a way for a profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Access {
+    // Open to everyone.
+    Yes,
+    // Only allowed to reach a destination on the way.
+    Destination,
+    // Only allowed with permission of the owner.
+    Private,
+    // Not allowed.
+    No,
+}
+
+/// A barrier on a node that may block or slow down traversal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Barrier {
+    // barrier=gate and similar swinging gates.
+    Gate,
+    // barrier=lift_gate
+    LiftGate,
+    // barrier=bollard and similar posts that only let bikes and pedestrians through.
+    Bollard,
+    // barrier=wall, fence, etc. Impassable for everyone.
+    Wall,
+}
+
+/// The raw access tags of a way, before they are resolved for a profile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessTags {
+    // access=*
+    pub access: Option<Access>,
+    // vehicle=*
+    pub vehicle: Option<Access>,
+    // motor_vehicle=*
+    pub motor_vehicle: Option<Access>,
+    // motorcar=*
+    pub motorcar: Option<Access>,
+    // bicycle=*
+    pub bicycle: Option<Access>,
+    // foot=*
+    pub foot: Option<Access>,
+}
+
+impl Access {
+    // Parses the value of an access tag, unknown values are ignored.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "yes" | "permissive" | "designated" | "official" => Some(Access::Yes),
+            "destination" | "delivery" | "customers" => Some(Access::Destination),
+            "private" => Some(Access::Private),
+            "no" => Some(Access::No),
+            _ => None,
+        }
+    }
+
+    pub fn is_routable(&self) -> bool {
+        matches!(self, Access::Yes | Access::Destination)
+    }
+}
+
+impl Barrier {
+    // Parses the value of a barrier tag, barriers that don't affect routing are ignored.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "gate" | "swing_gate" | "sliding_gate" => Some(Barrier::Gate),
+            "lift_gate" => Some(Barrier::LiftGate),
+            "bollard" | "block" | "jersey_barrier" => Some(Barrier::Bollard),
+            "wall" | "fence" | "hedge" | "retaining_wall" | "city_wall" => Some(Barrier::Wall),
+            _ => None,
+        }
+    }
+}
+
+impl AccessTags {
+    pub fn from_tags(tags: &[(&str, &str)]) -> Self {
+        let mut access_tags = Self::default();
+        for &(k, v) in tags {
+            let slot = match k {
+                "access" => &mut access_tags.access,
+                "vehicle" => &mut access_tags.vehicle,
+                "motor_vehicle" => &mut access_tags.motor_vehicle,
+                "motorcar" => &mut access_tags.motorcar,
+                "bicycle" => &mut access_tags.bicycle,
+                "foot" => &mut access_tags.foot,
+                _ => continue,
+            };
+            *slot = Access::parse(v);
+        }
+        access_tags
+    }
+}
+
+impl Profile {
+    /// Resolves the access of a way for this profile, the most specific tag wins.
+    pub fn resolve_access(&self, tags: &AccessTags) -> Access {
+        let candidates = match self {
+            Profile::Car => [tags.motorcar, tags.motor_vehicle, tags.vehicle, tags.access],
+            Profile::Bike => [tags.bicycle, tags.vehicle, tags.access, None],
+            Profile::Foot => [tags.foot, tags.access, None, None],
+        };
+
+        candidates
+            .into_iter()
+            .flatten()
+            .next()
+            .unwrap_or(Access::Yes)
+    }
+
+    /// Checks if the `except` tag of a turn restriction, a `;` separated list of vehicle types,
+    /// exempts this profile from it.
+    pub fn is_exempt(&self, except: &str) -> bool {
+        except.split(';').map(str::trim).any(|vehicle| match self {
+            Profile::Car => matches!(vehicle, "motorcar" | "motor_vehicle"),
+            Profile::Bike => vehicle == "bicycle",
+            Profile::Foot => vehicle == "foot",
+        })
+    }
+
+    /// Checks if oneway tags apply to this profile, pedestrians may walk both ways on any street.
+    pub fn respects_oneway(&self) -> bool {
+        !matches!(self, Profile::Foot)
+    }
+
+    /// Gets the weight penalty for passing a barrier, or `None` if the barrier blocks this profile.
+    pub fn barrier_penalty(&self, barrier: Barrier) -> Option<f32> {
+        // Time lost stopping to open a gate, in seconds.
+        const GATE_PENALTY: f32 = 100.0;
+
+        match (self, barrier) {
+            (_, Barrier::Wall) => None,
+            (Profile::Car, Barrier::Bollard) => None,
+            (Profile::Car | Profile::Bike, Barrier::Gate | Barrier::LiftGate) => Some(GATE_PENALTY),
+            _ => Some(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_access() {
+        let tags = AccessTags::from_tags(&[("highway", "service"), ("access", "private")]);
+        assert_eq!(Profile::Car.resolve_access(&tags), Access::Private);
+        assert_eq!(Profile::Foot.resolve_access(&tags), Access::Private);
+
+        let tags = AccessTags::from_tags(&[("motor_vehicle", "no")]);
+        assert_eq!(Profile::Car.resolve_access(&tags), Access::No);
+        assert_eq!(Profile::Bike.resolve_access(&tags), Access::Yes);
+
+        let tags = AccessTags::from_tags(&[("access", "no"), ("bicycle", "yes")]);
+        assert_eq!(Profile::Car.resolve_access(&tags), Access::No);
+        assert_eq!(Profile::Bike.resolve_access(&tags), Access::Yes);
+
+        let tags = AccessTags::from_tags(&[("access", "destination")]);
+        assert_eq!(Profile::Car.resolve_access(&tags), Access::Destination);
+        assert!(Access::Destination.is_routable());
+    }
+
+    #[test]
+    fn test_barrier_penalty() {
+        assert_eq!(Barrier::parse("bollard"), Some(Barrier::Bollard));
+        assert_eq!(Barrier::parse("kerb"), None);
+
+        assert_eq!(Profile::Car.barrier_penalty(Barrier::Bollard), None);
+        assert_eq!(Profile::Bike.barrier_penalty(Barrier::Bollard), Some(0.0));
+        assert!(Profile::Car.barrier_penalty(Barrier::Gate).unwrap() > 0.0);
+        assert_eq!(Profile::Foot.barrier_penalty(Barrier::Wall), None);
+    }
+
+    #[test]
+    fn test_is_exempt() {
+        assert!(Profile::Car.is_exempt("motorcar"));
+        assert!(Profile::Car.is_exempt("psv; motor_vehicle"));
+        assert!(!Profile::Car.is_exempt("bus;psv"));
+        assert!(Profile::Bike.is_exempt("bicycle;psv"));
+        assert!(!Profile::Bike.is_exempt("motorcar"));
+    }
+
+    #[test]
+    fn test_respects_oneway() {
+        assert!(Profile::Car.respects_oneway());
+        assert!(Profile::Bike.respects_oneway());
+        assert!(!Profile::Foot.respects_oneway());
+    }
+}
@@ -1,16 +1,17 @@
 use core::f32;
-use std::{cmp::Ordering, collections::BinaryHeap};
+use std::{cell::RefCell, cmp::Ordering, collections::BinaryHeap};
 
-use super::graph::Graph;
+use super::graph::{add_weights, Graph, Metric};
 
+/// A node and its weight in a `BinaryHeap`, which pops the lightest item first.
 #[derive(Debug, Copy, Clone, PartialEq)]
-struct HeapItem(usize, f32);
+pub(crate) struct HeapItem(pub(crate) usize, pub(crate) f32);
 
 impl Eq for HeapItem {}
 
 impl Ord for HeapItem {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.1.partial_cmp(&self.1).unwrap_or(Ordering::Equal)
+        other.1.total_cmp(&self.1)
     }
 }
 
@@ -20,11 +21,31 @@ impl PartialOrd for HeapItem {
     }
 }
 
+thread_local! {
+    // A witness search owned by each thread, reused across calls to avoid reallocating.
+    static THREAD_DIJKSTRA: RefCell<Dijkstra> = RefCell::new(Dijkstra::new(0));
+}
+
 pub struct Dijkstra {
     src: usize,
     ignore: usize,
+    metric: Metric,
     weights: Vec<f32>,
+    // The hops of the path to each node. With `prefer_fewer_hops`, the fewest among the paths of
+    // equal weight.
+    hops: Vec<u32>,
+    // Whether a path as heavy as the best one found but with fewer hops replaces it, which costs
+    // relaxing nodes again.
+    prefer_fewer_hops: bool,
+    // Whether each node was reached. Weights saturate at `f32::MAX`, so a reached node's weight
+    // is finite, but a weight of `f32::MAX` doesn't tell how heavy the path really is.
+    visited: Vec<bool>,
+    // Nodes reached since the last reset, so only they need clearing and a reused search
+    // costs as much as the nodes it reaches rather than the whole graph.
+    touched: Vec<usize>,
     queue: BinaryHeap<HeapItem>,
+    // Number of searches run, across all initializations.
+    num_searches: usize,
 }
 
 impl Dijkstra {
@@ -32,22 +53,58 @@ impl Dijkstra {
         Self {
             src: 0,
             ignore: 0,
+            metric: Metric::Primary,
             weights: vec![f32::INFINITY; num_nodes],
+            hops: vec![0; num_nodes],
+            prefer_fewer_hops: false,
+            visited: vec![false; num_nodes],
+            touched: Vec::new(),
             queue: BinaryHeap::with_capacity(num_nodes),
+            num_searches: 0,
         }
     }
 
+    /// Runs `f` with the calling thread's Dijkstra, (re)allocating it if it isn't sized for
+    /// `num_nodes`.
+    pub fn with_thread_local<R>(num_nodes: usize, f: impl FnOnce(&mut Dijkstra) -> R) -> R {
+        THREAD_DIJKSTRA.with(|cell| {
+            let mut dijkstra = cell.borrow_mut();
+            if dijkstra.weights.len() != num_nodes {
+                *dijkstra = Dijkstra::new(num_nodes);
+            }
+            f(&mut dijkstra)
+        })
+    }
+
+    /// Makes the searches break ties between paths of equal weight by their hops, for
+    /// contractions with `ContractionOptions::prefer_fewer_hops`.
+    pub fn set_prefer_fewer_hops(&mut self, enabled: bool) {
+        self.prefer_fewer_hops = enabled;
+    }
+
     pub fn init(&mut self, src: usize, ignore: usize) {
+        self.init_with_metric(src, ignore, Metric::Primary);
+    }
+
+    /// Starts a search from `src` weighting the edges by `metric`.
+    pub fn init_with_metric(&mut self, src: usize, ignore: usize, metric: Metric) {
         self.reset();
 
         self.src = src;
         self.ignore = ignore;
+        self.metric = metric;
         self.queue.push(HeapItem(self.src, 0.0));
         self.weights[self.src] = 0.0;
+        self.hops[self.src] = 0;
+        self.visited[self.src] = true;
+        self.touched.push(self.src);
     }
 
     fn reset(&mut self) {
-        self.weights.fill(f32::INFINITY);
+        for node in self.touched.drain(..) {
+            self.weights[node] = f32::INFINITY;
+            self.visited[node] = false;
+        }
         self.queue.clear();
     }
 
@@ -56,34 +113,21 @@ impl Dijkstra {
         graph: &Graph,
         dest: usize,
         limit_weight: f32,
-        max_hops: usize,
+        max_settled: usize,
     ) -> f32 {
-        let mut num_hops = 0;
+        self.num_searches += 1;
+        let mut num_settled = 0;
         while let Some(HeapItem(curr_id, weight)) = self.queue.pop() {
             if weight > limit_weight {
                 return self.weights[dest];
             }
 
-            for id in graph.get_fwd_neighbors(curr_id) {
-                let neighbor_edge = graph.get_edge(*id);
-                let neighbor_id = neighbor_edge.dest_id;
-
-                if neighbor_id == self.ignore {
-                    continue;
-                }
-
-                let weight = self.weights[curr_id] + graph.get_edge_metadata(neighbor_edge).weight;
-                if weight == f32::INFINITY {
-                    continue;
-                }
-                if weight < self.weights[neighbor_id] {
-                    self.weights[neighbor_id] = weight;
-                    self.queue.push(HeapItem(neighbor_id, weight))
-                }
-            }
+            self.relax(graph, curr_id);
 
-            num_hops += 1;
-            if num_hops >= max_hops {
+            // Past the cap only the witnesses found so far count, which errs on the side of
+            // adding a shortcut.
+            num_settled += 1;
+            if num_settled >= max_settled {
                 break;
             }
 
@@ -94,6 +138,80 @@ impl Dijkstra {
 
         self.weights[dest]
     }
+
+    /// Settles every node up to `limit_weight` away from the source, or the first
+    /// `max_settled` of them, so the witness weights to all targets under the limit can be read
+    /// with `weight` after a single search.
+    pub fn search_all(&mut self, graph: &Graph, limit_weight: f32, max_settled: usize) {
+        self.num_searches += 1;
+        let mut num_settled = 0;
+        while let Some(HeapItem(curr_id, weight)) = self.queue.pop() {
+            if weight > limit_weight {
+                break;
+            }
+
+            self.relax(graph, curr_id);
+
+            num_settled += 1;
+            if num_settled >= max_settled {
+                break;
+            }
+        }
+    }
+
+    /// Gets the weight of the shortest path found to `node`, infinite if it wasn't reached.
+    /// Paths heavier than `f32::MAX` weigh `f32::MAX`.
+    pub fn weight(&self, node: usize) -> f32 {
+        self.weights[node]
+    }
+
+    /// Gets the number of edges on the path found to `node`, the fewest among the paths of its
+    /// weight if fewer hops are preferred.
+    pub fn hops(&self, node: usize) -> u32 {
+        self.hops[node]
+    }
+
+    /// Checks if the search found any path to `node`.
+    pub fn is_reached(&self, node: usize) -> bool {
+        self.visited[node]
+    }
+
+    pub fn num_searches(&self) -> usize {
+        self.num_searches
+    }
+
+    fn relax(&mut self, graph: &Graph, curr_id: usize) {
+        for id in graph.get_fwd_neighbors(curr_id) {
+            let neighbor_edge = graph.get_edge(*id);
+            let neighbor_id = neighbor_edge.dest_id;
+
+            if neighbor_id == self.ignore || graph.get_node(neighbor_id).get_is_contracted() {
+                continue;
+            }
+
+            let metadata = graph.get_edge_metadata(neighbor_edge);
+            let weight = add_weights(self.weights[curr_id], metadata.metric_weight(self.metric));
+            let hops = self.hops[curr_id] + metadata.hops;
+            // A NaN weight would never compare as smaller and break the queue order.
+            if weight.is_nan() {
+                continue;
+            }
+            if !self.visited[neighbor_id]
+                || weight < self.weights[neighbor_id]
+                || (self.prefer_fewer_hops
+                    && weight == self.weights[neighbor_id]
+                    && hops < self.hops[neighbor_id])
+            {
+                if !self.visited[neighbor_id] {
+                    self.touched.push(neighbor_id);
+                }
+                self.visited[neighbor_id] = true;
+                self.weights[neighbor_id] = weight;
+                self.hops[neighbor_id] = hops;
+                self.queue.push(HeapItem(neighbor_id, weight))
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -146,76 +264,16 @@ mod tests {
         ];
 
         let edge_metadata = vec![
-            EdgeMetadata {
-                weight: 10.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 10.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 3.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 3.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 6.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 6.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 5.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 5.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 5.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
-            EdgeMetadata {
-                weight: 5.0,
-                name: None,
-                speed_limit: None,
-                is_one_way: false,
-                is_roundabout: false,
-            },
+            EdgeMetadata::new(10.0),
+            EdgeMetadata::new(10.0),
+            EdgeMetadata::new(3.0),
+            EdgeMetadata::new(3.0),
+            EdgeMetadata::new(6.0),
+            EdgeMetadata::new(6.0),
+            EdgeMetadata::new(5.0),
+            EdgeMetadata::new(5.0),
+            EdgeMetadata::new(5.0),
+            EdgeMetadata::new(5.0),
         ];
 
         Graph {
@@ -228,8 +286,58 @@ mod tests {
     }
 
     #[test]
-    fn test_local_dijkstra() {
+    fn test_search_skips_nan_weights() {
+        let mut graph = get_test_graph();
+        // Edge 2 is 1 -> 2.
+        graph.edge_metadata[2].weight = f32::NAN;
+
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        dijkstra.init(0, usize::MAX);
+        assert_eq!(dijkstra.search(&graph, 2, f32::INFINITY, 100), 26.0);
+        assert_eq!(dijkstra.weights, [0.0, 10.0, 26.0, 16.0, 21.0]);
+    }
+
+    #[test]
+    fn test_reused_search_matches_fresh() {
         let graph = get_test_graph();
+        let mut reused = Dijkstra::new(graph.num_nodes());
+
+        for (src, ignore) in [(0, usize::MAX), (4, 1), (2, usize::MAX), (1, 3)] {
+            let mut fresh = Dijkstra::new(graph.num_nodes());
+            fresh.init(src, ignore);
+            fresh.search_all(&graph, f32::INFINITY, 100);
+            reused.init(src, ignore);
+            reused.search_all(&graph, f32::INFINITY, 100);
+
+            assert_eq!(reused.weights, fresh.weights);
+            assert_eq!(reused.visited, fresh.visited);
+        }
+    }
+
+    #[test]
+    fn test_reached_past_f32_max() {
+        let mut graph = get_test_graph();
+        for metadata in &mut graph.edge_metadata {
+            metadata.weight = f32::MAX * 0.75;
+        }
+
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        dijkstra.init(0, usize::MAX);
+        dijkstra.search_all(&graph, f32::INFINITY, 100);
+
+        assert_eq!(dijkstra.weight(1), f32::MAX * 0.75);
+        // Two edges away the weight saturates instead of overflowing.
+        assert_eq!(dijkstra.weight(2), f32::MAX);
+        assert!((0..graph.num_nodes()).all(|node| dijkstra.is_reached(node)));
+
+        dijkstra.init(0, 1);
+        dijkstra.search_all(&graph, f32::INFINITY, 100);
+        assert!(!dijkstra.is_reached(2));
+    }
+
+    #[test]
+    fn test_local_dijkstra() {
+        let _graph = get_test_graph();
         // let weight = local_dijkstra(&graph, 0, 4, 3, 21.0, 100);
 
         // assert_eq!(weight, Some(18.0));
@@ -1 +1,7 @@
+pub mod alt_query;
 pub mod ch_query;
+pub mod nearest_node;
+pub mod query_pool;
+pub mod route;
+pub mod router;
+pub mod simple_router;
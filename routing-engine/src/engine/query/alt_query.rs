@@ -0,0 +1,203 @@
+use std::collections::BinaryHeap;
+
+use crate::engine::preprocess::{graph::Graph, witness_search::HeapItem};
+
+/// Precomputed distances from and to a set of landmarks, used as an A* heuristic through the
+/// triangle inequality.
+pub struct Landmarks {
+    // Dense ids of the landmark nodes.
+    pub nodes: Vec<usize>,
+    // from_landmark[i][v] is the distance from landmark i to v.
+    from_landmark: Vec<Vec<f32>>,
+    // to_landmark[i][v] is the distance from v to landmark i.
+    to_landmark: Vec<Vec<f32>>,
+}
+
+/// An A* query with landmarks (ALT) over the uncontracted graph.
+pub struct AltQuery<'a> {
+    graph: &'a Graph,
+    landmarks: &'a Landmarks,
+}
+
+// Runs a full Dijkstra from `src`, following the edges backwards if `reverse` is set.
+fn dijkstra_all(graph: &Graph, src: usize, reverse: bool) -> Vec<f32> {
+    let mut weights = vec![f32::INFINITY; graph.num_nodes()];
+    let mut queue = BinaryHeap::new();
+
+    weights[src] = 0.0;
+    queue.push(HeapItem(src, 0.0));
+
+    while let Some(HeapItem(curr_id, weight)) = queue.pop() {
+        if weight > weights[curr_id] {
+            continue;
+        }
+
+        let edge_ids = if reverse {
+            graph.get_bwd_neighbors(curr_id)
+        } else {
+            graph.get_fwd_neighbors(curr_id)
+        };
+
+        for &edge_id in edge_ids {
+            let edge = graph.get_edge(edge_id);
+            let neighbor_id = if reverse { edge.src_id } else { edge.dest_id };
+            let alt = weight + graph.get_edge_metadata(edge).weight;
+
+            if alt < weights[neighbor_id] {
+                weights[neighbor_id] = alt;
+                queue.push(HeapItem(neighbor_id, alt));
+            }
+        }
+    }
+
+    weights
+}
+
+impl Landmarks {
+    /// Picks `num_landmarks` landmarks with the farthest heuristic, each new landmark is the
+    /// reachable node farthest from the ones already picked.
+    pub fn new(graph: &Graph, num_landmarks: usize) -> Self {
+        let mut nodes = Vec::with_capacity(num_landmarks);
+        let mut min_dist = vec![f32::INFINITY; graph.num_nodes()];
+        let mut next = 0;
+
+        while nodes.len() < num_landmarks.min(graph.num_nodes()) {
+            nodes.push(next);
+            let dist = dijkstra_all(graph, next, false);
+            for (min, d) in min_dist.iter_mut().zip(dist) {
+                *min = min.min(d);
+            }
+
+            match min_dist
+                .iter()
+                .enumerate()
+                .filter(|(id, d)| d.is_finite() && !nodes.contains(id))
+                .max_by(|a, b| a.1.total_cmp(b.1))
+            {
+                Some((id, _)) => next = id,
+                None => break,
+            }
+        }
+
+        Self::from_nodes(graph, nodes)
+    }
+
+    /// Precomputes the distances for the given landmark nodes.
+    pub fn from_nodes(graph: &Graph, nodes: Vec<usize>) -> Self {
+        let from_landmark = nodes
+            .iter()
+            .map(|&l| dijkstra_all(graph, l, false))
+            .collect();
+        let to_landmark = nodes
+            .iter()
+            .map(|&l| dijkstra_all(graph, l, true))
+            .collect();
+
+        Self {
+            nodes,
+            from_landmark,
+            to_landmark,
+        }
+    }
+
+    // A lower bound on the distance from v to dest.
+    fn lower_bound(&self, v: usize, dest: usize) -> f32 {
+        let mut bound: f32 = 0.0;
+        for (from, to) in self.from_landmark.iter().zip(&self.to_landmark) {
+            // d(v, t) >= d(L, t) - d(L, v)
+            if from[v].is_finite() && from[dest].is_finite() {
+                bound = bound.max(from[dest] - from[v]);
+            }
+            // d(v, t) >= d(v, L) - d(t, L)
+            if to[v].is_finite() && to[dest].is_finite() {
+                bound = bound.max(to[v] - to[dest]);
+            }
+        }
+        bound
+    }
+}
+
+impl<'a> AltQuery<'a> {
+    pub fn new(graph: &'a Graph, landmarks: &'a Landmarks) -> Self {
+        Self { graph, landmarks }
+    }
+
+    /// Finds the shortest path from src to dest, returning its weight and the dense ids of the
+    /// nodes along it.
+    pub fn alt_search(&self, src: usize, dest: usize) -> Option<(f32, Vec<usize>)> {
+        let num_nodes = self.graph.num_nodes();
+        let mut weights = vec![f32::INFINITY; num_nodes];
+        let mut prev: Vec<Option<usize>> = vec![None; num_nodes];
+        let mut queue = BinaryHeap::new();
+
+        weights[src] = 0.0;
+        queue.push(HeapItem(src, self.landmarks.lower_bound(src, dest)));
+
+        while let Some(HeapItem(curr_id, _)) = queue.pop() {
+            if curr_id == dest {
+                let mut path = vec![dest];
+                let mut current = dest;
+                while let Some(p) = prev[current] {
+                    path.push(p);
+                    current = p;
+                }
+                path.reverse();
+                return Some((weights[dest], path));
+            }
+
+            for &edge_id in self.graph.get_fwd_neighbors(curr_id) {
+                let edge = self.graph.get_edge(edge_id);
+                let neighbor_id = edge.dest_id;
+                let alt = weights[curr_id] + self.graph.get_edge_metadata(edge).weight;
+
+                if alt < weights[neighbor_id] {
+                    weights[neighbor_id] = alt;
+                    prev[neighbor_id] = Some(curr_id);
+                    let key = alt + self.landmarks.lower_bound(neighbor_id, dest);
+                    queue.push(HeapItem(neighbor_id, key));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{preprocess::graph::Node, test_utils::get_sample_graph};
+
+    #[test]
+    fn test_alt_matches_dijkstra() {
+        let graph = get_sample_graph();
+        let landmarks = Landmarks::new(&graph, 2);
+        let query = AltQuery::new(&graph, &landmarks);
+
+        assert_eq!(landmarks.nodes.len(), 2);
+
+        for src in 0..graph.num_nodes() {
+            let expected = dijkstra_all(&graph, src, false);
+            for (dest, &expected_weight) in expected.iter().enumerate() {
+                let (weight, path) = query.alt_search(src, dest).unwrap();
+                assert_eq!(weight, expected_weight, "mismatch for {src} -> {dest}");
+                assert_eq!(path.first(), Some(&src));
+                assert_eq!(path.last(), Some(&dest));
+            }
+        }
+    }
+
+    #[test]
+    fn test_alt_unreachable() {
+        let mut graph = get_sample_graph();
+        graph.nodes.push(Node::new(7, 107));
+        graph.fwd_edge_list.push(Vec::new());
+        graph.bwd_edge_list.push(Vec::new());
+
+        let landmarks = Landmarks::new(&graph, 3);
+        let query = AltQuery::new(&graph, &landmarks);
+
+        assert!(query.alt_search(0, 7).is_none());
+        assert!(query.alt_search(7, 0).is_none());
+    }
+}
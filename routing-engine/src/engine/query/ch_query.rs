@@ -1,15 +1,23 @@
-use std::cmp::Ordering;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
+use itertools::Itertools;
 use priority_queue::PriorityQueue;
 
-use crate::engine::csr::csr_graph::CSRGraph;
+use crate::engine::{
+    csr::csr_graph::{CSREdgeHot, CSRGraph},
+    preprocess::graph::Metric,
+};
 
 #[derive(Copy, Clone, Debug)]
-struct HeapItem(f32);
+struct HeapItem(f64);
 
 impl PartialEq for HeapItem {
     fn eq(&self, other: &Self) -> bool {
-        (self.0 - other.0).abs() < 1e-9
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -23,31 +31,102 @@ impl PartialOrd for HeapItem {
 
 impl Ord for HeapItem {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.0.partial_cmp(&self.0).unwrap()
+        other.0.total_cmp(&self.0)
     }
 }
 
+/// How `BiDirDijkstra` picks the direction to expand next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchStrategy {
+    // Settle one forward and one backward node per round.
+    #[default]
+    Alternate,
+    // Settle the node with the smaller weight of the two queue tops, so a dense side doesn't
+    // make the other side run far ahead of it.
+    Balanced,
+}
+
+/// A bidirectional Dijkstra over the upward graph of a `CSRGraph`. Edge weights are stored as
+/// `f32` to keep the hot edge array small, but are summed as `f64` so that rounding errors
+/// don't pile up along long routes. On a partially contracted graph the core nodes share the
+/// top rank, so both searches continue as a plain Dijkstra once they reach the core.
 pub struct BiDirDijkstra {
     src: usize,
     dest: usize,
-    fwd_weights: Vec<f32>,
+    fwd_weights: Vec<f64>,
     fwd_prev: Vec<Option<(usize, usize)>>,
-    bwd_weights: Vec<f32>,
+    bwd_weights: Vec<f64>,
     bwd_prev: Vec<Option<(usize, usize)>>,
+    // Whether each node was reached by either search, kept apart from the weights so that
+    // reachability doesn't hinge on comparing against infinity.
+    fwd_visited: Vec<bool>,
+    bwd_visited: Vec<bool>,
+    // The number of original edges on the paths to each node.
+    fwd_hops: Vec<u32>,
+    bwd_hops: Vec<u32>,
     fwd_queue: PriorityQueue<usize, HeapItem>,
     bwd_queue: PriorityQueue<usize, HeapItem>,
+    // Nodes whose weights were set since the last reset, so only they need clearing.
+    touched: Vec<usize>,
+    // Skip relaxing nodes that are reached more cheaply through a higher ranked neighbor.
+    stall_on_demand: bool,
+    strategy: SearchStrategy,
+    metric: Metric,
+    // Among paths of equal weight, prefer the one with the fewest original edges.
+    prefer_fewer_hops: bool,
+    // Paths heavier than this are reported as unreachable, bounding the search.
+    max_weight: f64,
+    // The node where the searches met on the path found by the last search.
+    meeting_node: Option<usize>,
+}
+
+/// Statistics about the work done by a single query.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryStats {
+    // Number of nodes settled and relaxed by the forward search.
+    pub fwd_settled: usize,
+    // Number of nodes settled and relaxed by the backward search.
+    pub bwd_settled: usize,
+    // The rank of the node where the searches met, if they met.
+    pub meeting_rank: Option<i32>,
+    // Wall time spent in the search.
+    pub elapsed: Duration,
 }
 
+/// An edge of a packed path, pointing in the direction of travel. Unpack it with
+/// `ShortcutVisitor`.
 #[derive(Debug)]
 pub struct QueryResult {
     pub edge_id: usize,
+    // Whether the edge was found by the forward search.
+    pub is_fwd: bool,
+}
+
+/// A node of a found path, from `BiDirDijkstra::search_traced`. On a correct path the ranks
+/// rise up to the meeting node and fall after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracedNode {
+    pub node: usize,
+    pub rank: i32,
+    // Whether the node was reached by the forward search, which includes the meeting node.
     pub is_fwd: bool,
 }
 
+/// The outcome of a query.
+#[derive(Debug)]
+pub enum QueryOutcome {
+    // The source and the destination are the same node.
+    SamePoint,
+    // There is no path from the source to the destination.
+    Unreachable,
+    // The packed path and its total weight.
+    Found(Vec<QueryResult>, f64),
+}
+
 impl BiDirDijkstra {
     pub fn new(num_nodes: usize) -> Self {
-        let fwd_weights = vec![f32::INFINITY; num_nodes];
-        let bwd_weights = vec![f32::INFINITY; num_nodes];
+        let fwd_weights = vec![f64::INFINITY; num_nodes];
+        let bwd_weights = vec![f64::INFINITY; num_nodes];
         let fwd_prev = vec![None; num_nodes];
         let bwd_prev = vec![None; num_nodes];
 
@@ -61,12 +140,62 @@ impl BiDirDijkstra {
             bwd_weights,
             bwd_prev,
             fwd_prev,
+            fwd_visited: vec![false; num_nodes],
+            bwd_visited: vec![false; num_nodes],
+            fwd_hops: vec![0; num_nodes],
+            bwd_hops: vec![0; num_nodes],
             fwd_queue,
             bwd_queue,
+            touched: Vec::new(),
+            stall_on_demand: false,
+            strategy: SearchStrategy::default(),
+            metric: Metric::Primary,
+            prefer_fewer_hops: false,
+            max_weight: f64::INFINITY,
+            meeting_node: None,
         }
     }
 
-    pub fn init(&mut self, src: usize, dest: usize) {
+    /// Enables or disables stall-on-demand pruning, which is disabled by default.
+    pub fn set_stall_on_demand(&mut self, enabled: bool) {
+        self.stall_on_demand = enabled;
+    }
+
+    /// Sets how the search alternates between its directions, `SearchStrategy::Alternate` by
+    /// default.
+    pub fn set_strategy(&mut self, strategy: SearchStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Sets the metric edges are weighted by, `Metric::Primary` by default. Paths under
+    /// `Metric::Secondary` are only shortest if the graph was contracted with
+    /// `secondary_metric` set.
+    pub fn set_metric(&mut self, metric: Metric) {
+        self.metric = metric;
+    }
+
+    /// Breaks ties between paths of equal weight in favor of the one with fewer original edges,
+    /// which is disabled by default. Only paths the hierarchy kept can be chosen, so the graph
+    /// should be contracted with `prefer_fewer_hops` set too.
+    pub fn set_prefer_fewer_hops(&mut self, enabled: bool) {
+        self.prefer_fewer_hops = enabled;
+    }
+
+    /// Sets the weight beyond which the search gives up and reports the destination as
+    /// unreachable, so a query between disconnected parts of the graph doesn't scan all of
+    /// them. Unbounded by default.
+    pub fn set_max_weight(&mut self, max_weight: Option<f64>) {
+        self.max_weight = max_weight.unwrap_or(f64::INFINITY);
+    }
+
+    /// Prepares a query from `src` to `dest`, failing if either isn't a node of the graph the
+    /// search was sized for.
+    pub fn init(&mut self, src: usize, dest: usize) -> anyhow::Result<()> {
+        let num_nodes = self.fwd_weights.len();
+        if let Some(node) = [src, dest].into_iter().find(|&node| node >= num_nodes) {
+            anyhow::bail!("node {node} is out of range for a graph with {num_nodes} nodes");
+        }
+
         self.reset();
 
         self.src = src;
@@ -74,103 +203,1036 @@ impl BiDirDijkstra {
 
         self.fwd_queue.push(self.src, HeapItem(0.0));
         self.fwd_weights[self.src] = 0.0;
+        self.fwd_visited[self.src] = true;
+        self.fwd_hops[self.src] = 0;
 
         self.bwd_queue.push(self.dest, HeapItem(0.0));
         self.bwd_weights[self.dest] = 0.0;
+        self.bwd_visited[self.dest] = true;
+        self.bwd_hops[self.dest] = 0;
+
+        self.touched.push(self.src);
+        self.touched.push(self.dest);
+        Ok(())
+    }
+
+    /// Prepares a time-reversed query, which starts at `src` and follows edges against their
+    /// direction to `dest`, finding where a trip ending at `src` could have started. The found
+    /// path is the trip itself, from `dest` to `src` in travel order, so oneway edges are only
+    /// ever used along their arrow.
+    pub fn init_reverse(&mut self, src: usize, dest: usize) -> anyhow::Result<()> {
+        self.init(dest, src)
     }
 
     fn reset(&mut self) {
-        self.fwd_weights.fill(f32::INFINITY);
-        self.bwd_weights.fill(f32::INFINITY);
-        self.fwd_prev.fill(None);
-        self.bwd_prev.fill(None);
+        for node in self.touched.drain(..) {
+            self.fwd_weights[node] = f64::INFINITY;
+            self.bwd_weights[node] = f64::INFINITY;
+            self.fwd_prev[node] = None;
+            self.bwd_prev[node] = None;
+            self.fwd_visited[node] = false;
+            self.bwd_visited[node] = false;
+        }
         self.fwd_queue.clear();
         self.bwd_queue.clear();
+        self.meeting_node = None;
     }
 
-    fn get_path_ids(&mut self, meeting_node: Option<usize>) -> Option<Vec<QueryResult>> {
-        meeting_node.map(|node| {
-            let mut path = Vec::new();
-            let mut current = node;
+    // Walks the `prev` chains out of `meeting_node`, returning the nodes of the packed path from
+    // the source through the meeting node to the destination, each once, and the edges between
+    // them in travel order.
+    fn reconstruct_path(&self, meeting_node: usize) -> (Vec<usize>, Vec<QueryResult>) {
+        let mut nodes = vec![meeting_node];
+        let mut edges = Vec::new();
 
-            while let Some((id, prev)) = self.fwd_prev[current] {
-                path.push(QueryResult {
-                    edge_id: id,
-                    is_fwd: true,
-                });
-                current = prev;
-            }
+        let mut current = meeting_node;
+        while let Some((edge_id, prev)) = self.fwd_prev[current] {
+            nodes.push(prev);
+            edges.push(QueryResult {
+                edge_id,
+                is_fwd: true,
+            });
+            current = prev;
+        }
+        nodes.reverse();
+        edges.reverse();
 
-            path.reverse();
-            current = node;
+        current = meeting_node;
+        while let Some((edge_id, next)) = self.bwd_prev[current] {
+            nodes.push(next);
+            edges.push(QueryResult {
+                edge_id,
+                is_fwd: false,
+            });
+            current = next;
+        }
 
-            while let Some((id, prev)) = self.bwd_prev[current] {
-                path.push(QueryResult {
-                    edge_id: id,
-                    is_fwd: false,
-                });
-                current = prev;
-            }
+        debug_assert_eq!(nodes.first(), Some(&self.src));
+        debug_assert_eq!(nodes.last(), Some(&self.dest));
+        (nodes, edges)
+    }
+
+    /// Gets the node where the forward and backward searches met on the path found by the last
+    /// search, `None` unless it found one.
+    pub fn meeting_node(&self) -> Option<usize> {
+        self.meeting_node
+    }
+
+    /// Gets the nodes of the packed path found by the last search in travel order, from the
+    /// source through the meeting node to the destination, each exactly once. Empty unless the
+    /// last search found a path.
+    pub fn packed_nodes(&self) -> Vec<usize> {
+        self.meeting_node
+            .map_or_else(Vec::new, |node| self.reconstruct_path(node).0)
+    }
+
+    pub fn search(&mut self, graph: &CSRGraph) -> QueryOutcome {
+        self.search_with_stats(graph).0
+    }
+
+    /// Runs a query between the nodes with the given osm ids, failing if either isn't a node of
+    /// the graph, e.g. because it was pruned or merged away during preprocessing.
+    pub fn route_osm(
+        &mut self,
+        graph: &CSRGraph,
+        src_osm: i64,
+        dest_osm: i64,
+    ) -> anyhow::Result<QueryOutcome> {
+        let dense_id = |osm_id: i64| {
+            graph
+                .node_by_osm(osm_id)
+                .ok_or_else(|| anyhow::anyhow!("osm node {osm_id} is not in the graph"))
+        };
+        self.init(dense_id(src_osm)?, dense_id(dest_osm)?)?;
+        Ok(self.search(graph))
+    }
+
+    /// Runs the query like `search`, also returning the nodes of the packed path in travel
+    /// order with their ranks, for debugging the hierarchy. The trace is empty unless a path
+    /// was found.
+    pub fn search_traced(&mut self, graph: &CSRGraph) -> (QueryOutcome, Vec<TracedNode>) {
+        let outcome = self.search(graph);
+        let Some(meeting_node) = self.meeting_node else {
+            return (outcome, Vec::new());
+        };
+
+        let nodes = self.packed_nodes();
+        let meeting_idx = nodes.iter().position(|&node| node == meeting_node).unwrap();
+        let trace = nodes
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| TracedNode {
+                node,
+                rank: graph.nodes[node].rank,
+                is_fwd: i <= meeting_idx,
+            })
+            .collect();
+
+        (outcome, trace)
+    }
+
+    // Checks if `u` is reached more cheaply from a higher ranked node through an edge the upward
+    // search doesn't relax, in which case `u` can't be on a shortest path.
+    fn is_stalled(&self, graph: &CSRGraph, u: usize, is_fwd: bool) -> bool {
+        let stalls = |weights: &[f64], edge: CSREdgeHot| {
+            graph.nodes[edge.target].rank > graph.nodes[u].rank
+                && weights[edge.target] + f64::from(edge.metric_weight(self.metric)) < weights[u]
+        };
+
+        if is_fwd {
+            graph
+                .bwd_neighbors(u)
+                .any(|edge| stalls(&self.fwd_weights, edge))
+        } else {
+            graph
+                .fwd_neighbors(u)
+                .any(|edge| stalls(&self.bwd_weights, edge))
+        }
+    }
 
-            path
-        })
+    // Checks if a path of `weight` and `hops` beats one of `other_weight` and `other_hops`.
+    fn is_better(&self, weight: f64, hops: u32, other_weight: f64, other_hops: u32) -> bool {
+        weight < other_weight
+            || (self.prefer_fewer_hops && weight == other_weight && hops < other_hops)
     }
 
-    pub fn search(&mut self, graph: &CSRGraph) -> Option<Vec<QueryResult>> {
+    /// Runs the query like `search`, also returning statistics about the work it did.
+    pub fn search_with_stats(&mut self, graph: &CSRGraph) -> (QueryOutcome, QueryStats) {
+        let start = Instant::now();
+        let mut stats = QueryStats::default();
+
+        if self.src == self.dest {
+            stats.elapsed = start.elapsed();
+            return (QueryOutcome::SamePoint, stats);
+        }
+
+        // The node minimizing the sum of the forward and backward weights among the nodes both
+        // searches reached, and that sum.
         let mut meeting_node = None;
+        let mut best_weight = f64::INFINITY;
+        let mut best_hops = u32::MAX;
+
+        loop {
+            // A search can stop once its closest unsettled node is no closer than the best
+            // meeting, as every later meeting through it would be heavier.
+            let is_done = |queue: &PriorityQueue<usize, HeapItem>| {
+                queue.peek().is_none_or(|(_, &HeapItem(weight))| {
+                    // Preferring fewer hops, meetings as heavy as the best one may still win.
+                    let past_best = if self.prefer_fewer_hops {
+                        weight > best_weight
+                    } else {
+                        weight >= best_weight
+                    };
+                    (meeting_node.is_some() && past_best) || weight > self.max_weight
+                })
+            };
+            let fwd_done = is_done(&self.fwd_queue);
+            let bwd_done = is_done(&self.bwd_queue);
+            if fwd_done && bwd_done {
+                break;
+            }
+
+            let (expand_fwd, expand_bwd) = match self.strategy {
+                SearchStrategy::Alternate => (!fwd_done, !bwd_done),
+                SearchStrategy::Balanced => {
+                    let top = |queue: &PriorityQueue<usize, HeapItem>| {
+                        queue.peek().map_or(f64::INFINITY, |(_, item)| item.0)
+                    };
+                    let fwd_first =
+                        !fwd_done && (bwd_done || top(&self.fwd_queue) <= top(&self.bwd_queue));
+                    (fwd_first, !fwd_first)
+                }
+            };
 
-        while !self.fwd_queue.is_empty() && !self.bwd_queue.is_empty() {
-            if let Some((u, _)) = self.fwd_queue.pop() {
+            if let Some((u, _)) = expand_fwd
+                .then(|| self.fwd_queue.pop())
+                .flatten()
+                .filter(|&(u, _)| !(self.stall_on_demand && self.is_stalled(graph, u, true)))
+            {
+                stats.fwd_settled += 1;
                 for edge in graph.fwd_neighbors(u) {
                     let v = edge.target;
-                    let weight = edge.weight;
+                    let weight = f64::from(edge.metric_weight(self.metric));
 
                     if graph.nodes[v].rank < graph.nodes[u].rank {
                         continue;
                     }
 
                     let alt = self.fwd_weights[u] + weight;
-                    if alt < self.fwd_weights[v] {
+                    let alt_hops = self.fwd_hops[u] + edge.hops;
+                    if !self.fwd_visited[v]
+                        || self.is_better(alt, alt_hops, self.fwd_weights[v], self.fwd_hops[v])
+                    {
+                        self.touched.push(v);
+                        self.fwd_visited[v] = true;
                         self.fwd_weights[v] = alt;
+                        self.fwd_hops[v] = alt_hops;
                         self.fwd_prev[v] = Some((edge.id, u));
                         self.fwd_queue.push(v, HeapItem(alt));
                     }
 
-                    if self.bwd_weights[v] != f32::INFINITY {
+                    let total = self.fwd_weights[v] + self.bwd_weights[v];
+                    let total_hops = self.fwd_hops[v] + self.bwd_hops[v];
+                    if self.fwd_visited[v]
+                        && self.bwd_visited[v]
+                        && (meeting_node.is_none()
+                            || self.is_better(total, total_hops, best_weight, best_hops))
+                    {
+                        best_weight = total;
+                        best_hops = total_hops;
                         meeting_node = Some(v);
-                        break;
                     }
                 }
             }
 
-            if let Some((u, _)) = self.bwd_queue.pop() {
+            if let Some((u, _)) = expand_bwd
+                .then(|| self.bwd_queue.pop())
+                .flatten()
+                .filter(|&(u, _)| !(self.stall_on_demand && self.is_stalled(graph, u, false)))
+            {
+                stats.bwd_settled += 1;
                 for edge in graph.bwd_neighbors(u) {
                     let v = edge.target;
-                    let weight = edge.weight;
+                    let weight = f64::from(edge.metric_weight(self.metric));
 
                     if graph.nodes[v].rank < graph.nodes[u].rank {
                         continue;
                     }
 
                     let alt = self.bwd_weights[u] + weight;
-                    if alt < self.bwd_weights[v] {
+                    let alt_hops = self.bwd_hops[u] + edge.hops;
+                    if !self.bwd_visited[v]
+                        || self.is_better(alt, alt_hops, self.bwd_weights[v], self.bwd_hops[v])
+                    {
+                        self.touched.push(v);
+                        self.bwd_visited[v] = true;
                         self.bwd_weights[v] = alt;
+                        self.bwd_hops[v] = alt_hops;
                         self.bwd_prev[v] = Some((edge.id, u));
                         self.bwd_queue.push(v, HeapItem(alt));
                     }
 
-                    if self.fwd_weights[v] != f32::INFINITY {
+                    let total = self.fwd_weights[v] + self.bwd_weights[v];
+                    let total_hops = self.fwd_hops[v] + self.bwd_hops[v];
+                    if self.fwd_visited[v]
+                        && self.bwd_visited[v]
+                        && (meeting_node.is_none()
+                            || self.is_better(total, total_hops, best_weight, best_hops))
+                    {
+                        best_weight = total;
+                        best_hops = total_hops;
                         meeting_node = Some(v);
-                        break;
                     }
                 }
             }
+        }
 
-            if meeting_node.is_some() {
-                break;
+        let meeting_node = meeting_node.filter(|_| best_weight <= self.max_weight);
+        stats.meeting_rank = meeting_node.map(|node| graph.nodes[node].rank);
+        self.meeting_node = meeting_node;
+        let outcome = match meeting_node {
+            Some(node) => QueryOutcome::Found(self.reconstruct_path(node).1, best_weight),
+            None => QueryOutcome::Unreachable,
+        };
+        stats.elapsed = start.elapsed();
+
+        (outcome, stats)
+    }
+}
+
+/// Runs a plain Dijkstra from `src` over the whole graph, ignoring ranks, and returns the
+/// shortest-path tree as the previous node and the distance of every node. Unreachable nodes
+/// get `(None, f64::INFINITY)`.
+pub fn dijkstra_tree(graph: &CSRGraph, src: usize) -> Vec<(Option<usize>, f64)> {
+    let mut tree = vec![(None, f64::INFINITY); graph.nodes.len()];
+    let mut queue = PriorityQueue::new();
+
+    tree[src].1 = 0.0;
+    queue.push(src, HeapItem(0.0));
+
+    while let Some((u, HeapItem(weight))) = queue.pop() {
+        for edge in graph.fwd_neighbors(u) {
+            let alt = weight + f64::from(edge.weight);
+            if alt < tree[edge.target].1 {
+                tree[edge.target] = (Some(u), alt);
+                queue.push(edge.target, HeapItem(alt));
             }
         }
+    }
+
+    tree
+}
+
+/// Finds the `k` targets closest to `src` by travel cost, sorted by cost, as (target, cost)
+/// pairs. Unreachable targets are left out, so fewer than `k` may be returned.
+///
+/// This is a bucket-based one-to-many query: an upward backward search from every target
+/// leaves (target, weight) entries at the nodes it reaches, then a single upward forward search
+/// from `src` combines its weights with the entries, stopping once `k` targets are cheaper
+/// than anything left in its queue. Meant for small target sets, e.g. the gas stations near a
+/// city, as each settled node rechecks every target.
+pub fn k_nearest_targets(
+    graph: &CSRGraph,
+    src: usize,
+    targets: &[usize],
+    k: usize,
+) -> Vec<(usize, f32)> {
+    let targets: Vec<usize> = targets.iter().copied().unique().collect();
+    let mut buckets: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+    for (target_idx, &target) in targets.iter().enumerate() {
+        for (node, weight) in upward_search(graph, target, false) {
+            buckets.entry(node).or_default().push((target_idx, weight));
+        }
+    }
+
+    let mut best = vec![f64::INFINITY; targets.len()];
+    let mut weights: HashMap<usize, f64> = HashMap::from([(src, 0.0)]);
+    let mut queue = PriorityQueue::new();
+    queue.push(src, HeapItem(0.0));
+
+    while let Some((_, &HeapItem(top))) = queue.peek() {
+        // Any target reached later costs at least `top`, so the ones below it are final.
+        if best.iter().filter(|&&weight| weight <= top).count() >= k {
+            break;
+        }
+
+        let (u, HeapItem(weight)) = queue.pop().unwrap();
+        for &(target_idx, target_weight) in buckets.get(&u).into_iter().flatten() {
+            best[target_idx] = best[target_idx].min(weight + target_weight);
+        }
+        relax_upward(graph, u, weight, true, &mut weights, &mut queue);
+    }
+
+    let mut nearest: Vec<(usize, f64)> = targets
+        .into_iter()
+        .zip(best)
+        .filter(|(_, weight)| weight.is_finite())
+        .collect();
+    nearest.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+    nearest.truncate(k);
+    nearest
+        .into_iter()
+        .map(|(target, weight)| (target, weight as f32))
+        .collect()
+}
+
+// Runs a full upward search from `src`, forwards or backwards, returning the weight of every
+// node it reaches.
+fn upward_search(graph: &CSRGraph, src: usize, is_fwd: bool) -> HashMap<usize, f64> {
+    let mut weights = HashMap::from([(src, 0.0)]);
+    let mut queue = PriorityQueue::new();
+    queue.push(src, HeapItem(0.0));
+
+    while let Some((u, HeapItem(weight))) = queue.pop() {
+        relax_upward(graph, u, weight, is_fwd, &mut weights, &mut queue);
+    }
+
+    weights
+}
+
+// Relaxes the edges from `u` to the nodes ranked at least as high, of the forward or the
+// backward graph.
+fn relax_upward(
+    graph: &CSRGraph,
+    u: usize,
+    weight: f64,
+    is_fwd: bool,
+    weights: &mut HashMap<usize, f64>,
+    queue: &mut PriorityQueue<usize, HeapItem>,
+) {
+    let mut relax = |edge: CSREdgeHot| {
+        if graph.nodes[edge.target].rank < graph.nodes[u].rank {
+            return;
+        }
+        let alt = weight + f64::from(edge.weight);
+        if weights.get(&edge.target).is_none_or(|&w| alt < w) {
+            weights.insert(edge.target, alt);
+            queue.push(edge.target, HeapItem(alt));
+        }
+    };
+
+    if is_fwd {
+        graph.fwd_neighbors(u).for_each(&mut relax);
+    } else {
+        graph.bwd_neighbors(u).for_each(&mut relax);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::{
+        ch_preprocess::{contract_graph, contract_graph_in_order, ContractionOptions},
+        graph::{EdgeMetadata, Graph, Node},
+        witness_search::Dijkstra,
+    };
 
-        self.get_path_ids(meeting_node)
+    // 0 <-> 1 <-> 2, and an isolated node 3.
+    fn get_test_graph() -> CSRGraph {
+        let nodes = (0..4).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, weight) in [(0, 1, 2.0), (1, 2, 3.0)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        CSRGraph::from_preprocessed_graph(graph)
+    }
+
+    #[test]
+    fn test_same_point() {
+        let graph = get_test_graph();
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        query.init(1, 1).unwrap();
+        assert!(matches!(query.search(&graph), QueryOutcome::SamePoint));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let graph = get_test_graph();
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        query.init(0, 3).unwrap();
+        assert!(matches!(query.search(&graph), QueryOutcome::Unreachable));
+    }
+
+    #[test]
+    fn test_out_of_range_node() {
+        let graph = get_test_graph();
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        let err = query.init(0, 4).unwrap_err().to_string();
+        assert!(err.contains("node 4 is out of range"), "{err}");
+        assert!(query.init(7, 0).is_err());
+        assert!(query.init_reverse(0, 4).is_err());
+
+        // The query is still usable after a failed init.
+        query.init(0, 2).unwrap();
+        assert!(matches!(query.search(&graph), QueryOutcome::Found(..)));
+    }
+
+    #[test]
+    fn test_reset_clears_touched_nodes() {
+        let graph = get_test_graph();
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        query.init(0, 2).unwrap();
+        query.search(&graph);
+
+        query.init(3, 3).unwrap();
+        assert!(query.fwd_weights[..3].iter().all(|w| *w == f64::INFINITY));
+        assert!(query.bwd_prev.iter().all(Option::is_none));
+        assert_eq!(query.touched, vec![3, 3]);
+    }
+
+    #[test]
+    fn test_stats_with_stalling() {
+        // s = 0 reaches u = 1 directly with weight 10, or through the higher ranked v = 2 with
+        // weight 2, so the forward search can stall u. t = 3 reaches v through b = 4 and a = 5.
+        let ranks = [0, 1, 5, 0, 0, 1, 6];
+        let nodes = ranks
+            .iter()
+            .enumerate()
+            .map(|(i, &rank)| Node {
+                rank,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, weight) in [
+            (0, 1, 10.0),
+            (0, 2, 1.0),
+            (2, 1, 1.0),
+            (1, 6, 1.0),
+            (2, 5, 1.0),
+            (5, 4, 1.0),
+            (4, 3, 1.0),
+        ] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+        }
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        query.init(0, 3).unwrap();
+        let (outcome, plain) = query.search_with_stats(&graph);
+        assert!(matches!(outcome, QueryOutcome::Found(_, weight) if weight == 4.0));
+        assert_eq!(plain.meeting_rank, Some(5));
+        assert!(plain.fwd_settled > 0 && plain.bwd_settled > 0);
+
+        query.set_stall_on_demand(true);
+        query.init(0, 3).unwrap();
+        let (outcome, stalled) = query.search_with_stats(&graph);
+        assert!(matches!(outcome, QueryOutcome::Found(_, weight) if weight == 4.0));
+        assert!(stalled.fwd_settled < plain.fwd_settled);
+        assert_eq!(stalled.bwd_settled, plain.bwd_settled);
+    }
+
+    #[test]
+    fn test_reverse_respects_oneway() {
+        // A oneway street 0 -> 1 -> 2.
+        let nodes = (0..3).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, weight) in [(0, 1, 2.0), (1, 2, 3.0)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+        }
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        // Tracing back from 2 to 0 is the trip 0 -> 1 -> 2.
+        query.init_reverse(2, 0).unwrap();
+        let QueryOutcome::Found(path, weight) = query.search(&graph) else {
+            panic!("expected a trip from 0 to 2");
+        };
+        assert_eq!(weight, 5.0);
+        let ends = |id: usize| {
+            let edge = graph.get_fwd_edge_cold(id);
+            (edge.from_node, edge.to_node)
+        };
+        assert_eq!(
+            path.iter().map(|r| ends(r.edge_id)).collect::<Vec<_>>(),
+            [(0, 1), (1, 2)]
+        );
+
+        // Tracing back from 0 to 2 would need the trip 2 -> 0, against the arrows.
+        query.init_reverse(0, 2).unwrap();
+        assert!(matches!(query.search(&graph), QueryOutcome::Unreachable));
+    }
+
+    #[test]
+    fn test_argmin_meeting_node() {
+        // From 0 to 1 through the high ranked 2, which both searches reach first, with weight
+        // 101, or through 3 with weight 20.
+        let ranks = [0, 0, 5, 4];
+        let nodes = ranks
+            .iter()
+            .enumerate()
+            .map(|(i, &rank)| Node {
+                rank,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, weight) in [(0, 2, 1.0), (2, 1, 100.0), (0, 3, 10.0), (3, 1, 10.0)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+        }
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        query.init(0, 1).unwrap();
+        let (outcome, stats) = query.search_with_stats(&graph);
+        let QueryOutcome::Found(path, weight) = outcome else {
+            panic!("expected a path from 0 to 1");
+        };
+        assert_eq!(weight, 20.0);
+        assert_eq!(stats.meeting_rank, Some(4));
+        let edge_ids: Vec<usize> = path.iter().map(|result| result.edge_id).collect();
+        assert_eq!(edge_ids, [2, 3]);
+    }
+
+    #[test]
+    fn test_balanced_strategy() {
+        // From 0 to 1 through 2 and the top node 3, with weight 6. The forward search also has
+        // 20 cheap upward leaves 4..24, and the backward search 20 expensive upward nodes 24..44.
+        let ranks: Vec<i32> = [0, 0, 50, 100]
+            .into_iter()
+            .chain(std::iter::repeat_n(10, 40))
+            .collect();
+        let nodes = ranks
+            .iter()
+            .enumerate()
+            .map(|(i, &rank)| Node {
+                rank,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+        let edges = [(0, 2, 3.0), (2, 3, 1.0), (3, 1, 2.0)]
+            .into_iter()
+            .chain((4..24).map(|leaf| (0, leaf, 0.1)))
+            .chain((24..44).map(|far| (far, 1, 10.0)));
+        for (src, dest, weight) in edges {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+        }
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        let mut search = |strategy| {
+            query.set_strategy(strategy);
+            query.init(0, 1).unwrap();
+            let (outcome, stats) = query.search_with_stats(&graph);
+            let QueryOutcome::Found(path, weight) = outcome else {
+                panic!("expected a path from 0 to 1");
+            };
+            let edge_ids: Vec<usize> = path.iter().map(|result| result.edge_id).collect();
+            (edge_ids, weight, stats)
+        };
+
+        let (alternate_path, alternate_weight, alternate) = search(SearchStrategy::Alternate);
+        let (balanced_path, balanced_weight, balanced) = search(SearchStrategy::Balanced);
+        assert_eq!(balanced_path, alternate_path);
+        assert_eq!(balanced_weight, 6.0);
+        assert_eq!(alternate_weight, 6.0);
+        assert!(
+            balanced.fwd_settled + balanced.bwd_settled
+                < alternate.fwd_settled + alternate.bwd_settled,
+            "{balanced:?} vs {alternate:?}"
+        );
+    }
+
+    #[test]
+    fn test_secondary_metric() {
+        // 0 - 1 - 2 - 3 is fast but long, 0 - 4 - 5 - 3 slow but short.
+        let nodes = (0..6).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        let fast = [(0, 1), (1, 2), (2, 3)].map(|(a, b)| (a, b, 1.0, 100.0));
+        let short = [(0, 4), (4, 5), (5, 3)].map(|(a, b)| (a, b, 10.0, 10.0));
+        for (src, dest, weight, alt_weight) in fast.into_iter().chain(short) {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata {
+                alt_weight,
+                ..EdgeMetadata::new(weight)
+            });
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        let options = ContractionOptions {
+            secondary_metric: true,
+            ..Default::default()
+        };
+        // Contracting 2 adds a 0 -> 3 shortcut that is faster, and contracting 5 one that is
+        // shorter, so both are kept.
+        contract_graph_in_order(&mut graph, &[1, 2, 4, 5, 0, 3], &options, |_| {}).unwrap();
+        let to_3 = graph.fwd_edge_list[0]
+            .iter()
+            .filter(|&&id| graph.get_edge(id).dest_id == 3);
+        assert_eq!(to_3.count(), 2);
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        let mut route = |metric| {
+            query.set_metric(metric);
+            query.init(0, 3).unwrap();
+            let QueryOutcome::Found(path, weight) = query.search(&graph) else {
+                panic!("expected a path from 0 to 3");
+            };
+            let nodes: Vec<usize> = path
+                .iter()
+                .flat_map(|result| graph.unpack_edge(result.edge_id))
+                .map(|edge_id| graph.edge_endpoints(edge_id).1)
+                .collect();
+            (nodes, weight)
+        };
+
+        assert_eq!(route(Metric::Primary), (vec![1, 2, 3], 3.0));
+        assert_eq!(route(Metric::Secondary), (vec![4, 5, 3], 30.0));
+    }
+
+    #[test]
+    fn test_search_traced() {
+        // A two-way line 0 - 1 - ... - 6, contracted from the ends and even nodes inwards.
+        let nodes = (0..7).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for src in 0..6 {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(1.0));
+            graph.add_edge(src, src + 1, metadata_index);
+            graph.add_edge(src + 1, src, metadata_index);
+        }
+        contract_graph_in_order(
+            &mut graph,
+            &[0, 6, 2, 4, 1, 5, 3],
+            &ContractionOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        for (src, dest) in [(0, 6), (6, 0), (1, 4), (2, 5)] {
+            query.init(src, dest).unwrap();
+            let (outcome, trace) = query.search_traced(&graph);
+            assert!(matches!(outcome, QueryOutcome::Found(..)));
+            assert_eq!(trace.first().unwrap().node, src);
+            assert_eq!(trace.last().unwrap().node, dest);
+
+            // Ranks rise strictly while going up the forward search, and fall strictly after.
+            let (up, down): (Vec<_>, Vec<_>) = trace.iter().partition(|n| n.is_fwd);
+            assert!(up.windows(2).all(|w| w[0].rank < w[1].rank), "{trace:?}");
+            let peak = up.last().unwrap().rank;
+            assert!(down.first().is_none_or(|n| n.rank < peak), "{trace:?}");
+            assert!(down.windows(2).all(|w| w[0].rank > w[1].rank), "{trace:?}");
+        }
+
+        query.init(3, 3).unwrap();
+        assert!(query.search_traced(&graph).1.is_empty());
+    }
+
+    #[test]
+    fn test_packed_nodes_through_meeting_node() {
+        // A two-way line 0 - 1 - 2 - 3 - 4 with 2 ranked highest, so every query crossing it
+        // meets there.
+        let nodes = (0..5).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for src in 0..4 {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(1.0));
+            graph.add_edge(src, src + 1, metadata_index);
+            graph.add_edge(src + 1, src, metadata_index);
+        }
+        contract_graph_in_order(
+            &mut graph,
+            &[0, 4, 1, 3, 2],
+            &ContractionOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        // Meeting in the middle, at the source and at the destination.
+        for (src, dest) in [(0, 4), (4, 0), (2, 4), (0, 2), (1, 3)] {
+            query.init(src, dest).unwrap();
+            let QueryOutcome::Found(path, _) = query.search(&graph) else {
+                panic!("expected a path from {src} to {dest}");
+            };
+            let meeting_node = query.meeting_node().unwrap();
+            assert_eq!(meeting_node, 2);
+
+            let nodes = query.packed_nodes();
+            assert_eq!(nodes.first(), Some(&src));
+            assert_eq!(nodes.last(), Some(&dest));
+            assert_eq!(nodes.iter().filter(|&&n| n == meeting_node).count(), 1);
+            assert_eq!(nodes.len(), path.len() + 1);
+            for (result, pair) in path.iter().zip(nodes.windows(2)) {
+                assert_eq!(graph.edge_endpoints(result.edge_id), (pair[0], pair[1]));
+            }
+        }
+
+        query.init(0, 0).unwrap();
+        query.search(&graph);
+        assert_eq!(query.meeting_node(), None);
+        assert!(query.packed_nodes().is_empty());
+    }
+
+    #[test]
+    fn test_max_weight() {
+        // Two disconnected two-way lines, 0 - ... - 999 and 1000 - 1001.
+        let nodes = (0..1002).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for src in (0..999).chain([1000]) {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(1.0));
+            graph.add_edge(src, src + 1, metadata_index);
+            graph.add_edge(src + 1, src, metadata_index);
+        }
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        query.init(0, 1001).unwrap();
+        let (outcome, unbounded) = query.search_with_stats(&graph);
+        assert!(matches!(outcome, QueryOutcome::Unreachable));
+        assert_eq!(unbounded.fwd_settled, 1000);
+
+        query.set_max_weight(Some(5.0));
+        query.init(0, 1001).unwrap();
+        let (outcome, bounded) = query.search_with_stats(&graph);
+        assert!(matches!(outcome, QueryOutcome::Unreachable));
+        assert!(bounded.fwd_settled <= 6, "{bounded:?}");
+
+        // Paths within the ceiling are still found, longer ones aren't.
+        query.init(0, 5).unwrap();
+        assert!(matches!(query.search(&graph), QueryOutcome::Found(_, weight) if weight == 5.0));
+        query.init(0, 6).unwrap();
+        assert!(matches!(query.search(&graph), QueryOutcome::Unreachable));
+    }
+
+    #[test]
+    fn test_reachable_near_f32_max() {
+        // 0 <-> 1 <-> 2 with weights near f32::MAX, so the weight of the 0 - 2 shortcut
+        // saturates at f32::MAX, and an isolated node 3.
+        let nodes = (0..4).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest) in [(0, 1), (1, 2)] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(f32::MAX * 0.75));
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        contract_graph_in_order(
+            &mut graph,
+            &[1, 0, 2, 3],
+            &ContractionOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        query.init(0, 1).unwrap();
+        assert!(matches!(
+            query.search(&graph),
+            QueryOutcome::Found(_, weight) if weight == f64::from(f32::MAX * 0.75)
+        ));
+        for (src, dest) in [(0, 2), (2, 0)] {
+            query.init(src, dest).unwrap();
+            assert!(matches!(query.search(&graph), QueryOutcome::Found(..)));
+        }
+        query.init(0, 3).unwrap();
+        assert!(matches!(query.search(&graph), QueryOutcome::Unreachable));
+    }
+
+    #[test]
+    fn test_prefer_fewer_hops() {
+        // Two routes from 0 to 3 of weight 3, 0 - 1 - 2 - 3 with three hops and 0 - 4 - 3 with
+        // two.
+        let nodes = (0..5).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, weight) in [
+            (0, 1, 1.0),
+            (1, 2, 1.0),
+            (2, 3, 1.0),
+            (0, 4, 1.5),
+            (4, 3, 1.5),
+        ] {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+
+        // Contracting 4 first, the three hop route is a witness as short as its shortcut.
+        let hops = |prefer_fewer_hops: bool| {
+            let mut graph = graph.clone();
+            let options = ContractionOptions {
+                prefer_fewer_hops,
+                ..ContractionOptions::default()
+            };
+            contract_graph_in_order(&mut graph, &[4, 1, 2, 0, 3], &options, |_| {}).unwrap();
+            let graph = CSRGraph::from_preprocessed_graph(graph);
+
+            let mut query = BiDirDijkstra::new(graph.nodes.len());
+            query.set_prefer_fewer_hops(prefer_fewer_hops);
+            query.init(0, 3).unwrap();
+            let QueryOutcome::Found(path, weight) = query.search(&graph) else {
+                panic!("no path found");
+            };
+            assert_eq!(weight, 3.0);
+            path.iter()
+                .map(|result| graph.unpack_edge(result.edge_id).len())
+                .sum::<usize>()
+        };
+
+        assert_eq!(hops(true), 2);
+        assert_eq!(hops(false), 3);
+    }
+
+    #[test]
+    fn test_dijkstra_tree() {
+        let graph = get_test_graph();
+
+        assert_eq!(
+            dijkstra_tree(&graph, 0),
+            [
+                (None, 0.0),
+                (Some(0), 2.0),
+                (Some(1), 5.0),
+                (None, f64::INFINITY)
+            ]
+        );
+        assert_eq!(
+            dijkstra_tree(&graph, 2),
+            [
+                (Some(1), 5.0),
+                (Some(2), 3.0),
+                (None, 0.0),
+                (None, f64::INFINITY)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_long_route_precision() {
+        // A chain of 100k edges, long enough for f32 sums of the weights to drift.
+        let num_nodes = 100_001;
+        let nodes = (0..num_nodes).map(|i| Node::new(i, i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for src in 0..num_nodes - 1 {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(0.1));
+            graph.add_edge(src, src + 1, metadata_index);
+            graph.add_edge(src + 1, src, metadata_index);
+        }
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+        let reference = f64::from(0.1f32) * (num_nodes - 1) as f64;
+
+        let mut query = BiDirDijkstra::new(num_nodes);
+        query.init(0, num_nodes - 1).unwrap();
+        let QueryOutcome::Found(_, weight) = query.search(&graph) else {
+            panic!("expected a path along the chain");
+        };
+        assert!((weight - reference).abs() < 1e-6, "{weight} != {reference}");
+
+        let tree = dijkstra_tree(&graph, 0);
+        assert!((tree[num_nodes - 1].1 - reference).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_found() {
+        let graph = get_test_graph();
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        query.init(0, 2).unwrap();
+
+        let QueryOutcome::Found(path, weight) = query.search(&graph) else {
+            panic!("expected a path from 0 to 2");
+        };
+        assert_eq!(weight, 5.0);
+        assert_eq!(path.len(), 2);
+        assert!(path[0].is_fwd);
+        assert!(!path[1].is_fwd);
+    }
+
+    #[test]
+    fn test_route_osm() {
+        let graph = get_test_graph();
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        let outcome = query.route_osm(&graph, 100, 102).unwrap();
+        assert!(matches!(outcome, QueryOutcome::Found(_, weight) if weight == 5.0));
+        let outcome = query.route_osm(&graph, 100, 103).unwrap();
+        assert!(matches!(outcome, QueryOutcome::Unreachable));
+
+        let err = query.route_osm(&graph, 100, 42).unwrap_err().to_string();
+        assert!(err.contains("osm node 42 is not in the graph"), "{err}");
+        assert!(query.route_osm(&graph, 42, 100).is_err());
+    }
+
+    #[test]
+    fn test_k_nearest_targets() {
+        // A two-way line 0 - 1 - 2 - 3 - 4 with unit weights. Node 5 lies right next to 0 but
+        // is only reached through 4, node 6 hangs off 2 by a heavy edge and 7 is isolated.
+        let nodes = (0..8)
+            .map(|i| Node {
+                lat: 32.0,
+                lon: 34.0 + if i == 5 { 0.0001 } else { 0.01 * i as f64 },
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+        let streets = [
+            (0, 1, 1.0),
+            (1, 2, 1.0),
+            (2, 3, 1.0),
+            (3, 4, 1.0),
+            (4, 5, 1.0),
+        ];
+        for (src, dest, weight) in streets.into_iter().chain([(2, 6, 10.0)]) {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(weight));
+            graph.add_edge(src, dest, metadata_index);
+            graph.add_edge(dest, src, metadata_index);
+        }
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        contract_graph(
+            &mut graph,
+            &mut dijkstra,
+            &ContractionOptions::default(),
+            |_| {},
+        );
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+
+        let targets = [5, 4, 6, 7, 2, 4];
+        assert_eq!(
+            k_nearest_targets(&graph, 0, &targets, 2),
+            [(2, 2.0), (4, 4.0)]
+        );
+        assert_eq!(
+            k_nearest_targets(&graph, 0, &targets, 3),
+            [(2, 2.0), (4, 4.0), (5, 5.0)]
+        );
+        assert_eq!(
+            k_nearest_targets(&graph, 0, &targets, 10),
+            [(2, 2.0), (4, 4.0), (5, 5.0), (6, 12.0)]
+        );
+        assert_eq!(k_nearest_targets(&graph, 3, &targets, 1), [(2, 1.0)]);
+        assert!(k_nearest_targets(&graph, 0, &targets, 0).is_empty());
+
+        // Every source agrees with a plain Dijkstra.
+        for src in 0..7 {
+            let tree = dijkstra_tree(&graph, src);
+            let mut expected: Vec<(usize, f32)> = [2, 4, 5, 6]
+                .into_iter()
+                .map(|target| (target, tree[target].1 as f32))
+                .collect();
+            expected.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+            expected.truncate(3);
+            assert_eq!(k_nearest_targets(&graph, src, &targets, 3), expected);
+        }
     }
 }
@@ -0,0 +1,300 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::csr::csr_graph::CSRGraph;
+
+/// Magic bytes at the start of a serialized nearest-node index file.
+pub const NEAREST_FILE_MAGIC: [u8; 4] = *b"SHNN";
+
+/// Version of the serialized index layout, bump it whenever `NearestNode` changes.
+pub const NEAREST_FILE_VERSION: u32 = 2;
+
+/// The side of a grid cell in degrees, about a kilometer of latitude.
+pub const DEFAULT_CELL_SIZE_DEG: f64 = 0.01;
+
+/// A uniform grid over the coordinates of the nodes of a `CSRGraph`, for snapping coordinates
+/// to the closest node. Distances are compared on an equirectangular projection around the
+/// queried point, which is exact enough at street scale.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NearestNode {
+    cell_size: f64,
+    // The south-west corner of the grid.
+    min_lat: f64,
+    min_lon: f64,
+    rows: usize,
+    cols: usize,
+    // The nodes of cell `i` are `cell_nodes[cell_offsets[i]..cell_offsets[i + 1]]`, cells are
+    // stored row by row from the south-west corner.
+    cell_offsets: Vec<usize>,
+    cell_nodes: Vec<usize>,
+    // The (lat, lon) of every node, by dense id.
+    coords: Vec<(f64, f64)>,
+}
+
+impl NearestNode {
+    pub fn new(graph: &CSRGraph) -> Self {
+        Self::with_cell_size(graph, DEFAULT_CELL_SIZE_DEG)
+    }
+
+    /// Builds the index with cells of `cell_size` degrees, smaller cells answer faster on dense
+    /// graphs at the cost of memory.
+    pub fn with_cell_size(graph: &CSRGraph, cell_size: f64) -> Self {
+        let coords: Vec<(f64, f64)> = graph.nodes.iter().map(|n| (n.lat, n.lon)).collect();
+        let min_lat = coords.iter().map(|c| c.0).fold(f64::INFINITY, f64::min);
+        let min_lon = coords.iter().map(|c| c.1).fold(f64::INFINITY, f64::min);
+        let max_lat = coords.iter().map(|c| c.0).fold(f64::NEG_INFINITY, f64::max);
+        let max_lon = coords.iter().map(|c| c.1).fold(f64::NEG_INFINITY, f64::max);
+        let (min_lat, min_lon, rows, cols) = if coords.is_empty() {
+            (0.0, 0.0, 0, 0)
+        } else {
+            let rows = ((max_lat - min_lat) / cell_size) as usize + 1;
+            let cols = ((max_lon - min_lon) / cell_size) as usize + 1;
+            (min_lat, min_lon, rows, cols)
+        };
+
+        let mut index = Self {
+            cell_size,
+            min_lat,
+            min_lon,
+            rows,
+            cols,
+            cell_offsets: Vec::new(),
+            cell_nodes: Vec::new(),
+            coords,
+        };
+
+        // Counting sort of the nodes by cell.
+        let cells: Vec<usize> = index
+            .coords
+            .iter()
+            .map(|&(lat, lon)| {
+                let (row, col) = index.cell_of(lat, lon);
+                row as usize * cols + col as usize
+            })
+            .collect();
+        let mut offsets = vec![0; rows * cols + 1];
+        for &cell in &cells {
+            offsets[cell + 1] += 1;
+        }
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+        let mut next = offsets.clone();
+        let mut cell_nodes = vec![0; cells.len()];
+        for (node, &cell) in cells.iter().enumerate() {
+            cell_nodes[next[cell]] = node;
+            next[cell] += 1;
+        }
+
+        index.cell_offsets = offsets;
+        index.cell_nodes = cell_nodes;
+        index
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.coords.len()
+    }
+
+    // Gets the (row, col) of the cell containing the coordinates, which lies outside the grid
+    // for coordinates outside of it.
+    fn cell_of(&self, lat: f64, lon: f64) -> (i64, i64) {
+        (
+            ((lat - self.min_lat) / self.cell_size).floor() as i64,
+            ((lon - self.min_lon) / self.cell_size).floor() as i64,
+        )
+    }
+
+    fn cell_nodes(&self, row: i64, col: i64) -> &[usize] {
+        if row < 0 || col < 0 || row >= self.rows as i64 || col >= self.cols as i64 {
+            return &[];
+        }
+
+        let cell = row as usize * self.cols + col as usize;
+        &self.cell_nodes[self.cell_offsets[cell]..self.cell_offsets[cell + 1]]
+    }
+
+    /// Gets the dense id of the node closest to the given coordinates, or `None` if the graph
+    /// has no nodes.
+    pub fn nearest(&self, lat: f64, lon: f64) -> Option<usize> {
+        let lon_scale = lat.to_radians().cos();
+        // Squared distance in degrees of latitude.
+        let dist = |node: usize| {
+            let (node_lat, node_lon) = self.coords[node];
+            let (dlat, dlon) = (node_lat - lat, (node_lon - lon) * lon_scale);
+            dlat * dlat + dlon * dlon
+        };
+
+        // The rings of cells around the query cell are scanned outwards. Nodes in ring `r + 1`
+        // and beyond are at least `r` cells away, so the scan stops once the best node found is
+        // closer than that, or every cell of the grid was scanned.
+        let (row, col) = self.cell_of(lat, lon);
+        let last_ring = [
+            row,
+            self.rows as i64 - 1 - row,
+            col,
+            self.cols as i64 - 1 - col,
+        ]
+        .into_iter()
+        .map(i64::abs)
+        .max()?;
+        let mut best: Option<(f64, usize)> = None;
+
+        for ring in 0..=last_ring {
+            for r in row - ring..=row + ring {
+                let is_edge_row = r == row - ring || r == row + ring;
+                let step = if is_edge_row {
+                    1
+                } else {
+                    (2 * ring).max(1) as usize
+                };
+                for c in (col - ring..=col + ring).step_by(step) {
+                    for &node in self.cell_nodes(r, c) {
+                        let d = dist(node);
+                        if best.is_none_or(|(best_d, _)| d < best_d) {
+                            best = Some((d, node));
+                        }
+                    }
+                }
+            }
+
+            let bound = ring as f64 * self.cell_size * lon_scale;
+            if best.is_some_and(|(best_d, _)| best_d <= bound * bound) {
+                break;
+            }
+        }
+
+        best.map(|(_, node)| node)
+    }
+
+    /// Writes the index to `path`, prefixed with a magic number and format version so stale
+    /// files are detected on load.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&NEAREST_FILE_MAGIC)?;
+        writer.write_all(&NEAREST_FILE_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads an index written by `save`, failing if the file isn't an index file, was written
+    /// with a different format version or was built for a graph with another number of nodes.
+    pub fn load(path: impl AsRef<Path>, graph: &CSRGraph) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        let mut version = [0u8; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != NEAREST_FILE_MAGIC {
+            anyhow::bail!("{} is not a nearest-node index file", path.display());
+        }
+        reader.read_exact(&mut version)?;
+
+        let version = u32::from_le_bytes(version);
+        if version != NEAREST_FILE_VERSION {
+            anyhow::bail!(
+                "{} has index format version {version}, expected version {NEAREST_FILE_VERSION}, rebuild the index",
+                path.display()
+            );
+        }
+
+        let index: Self = bincode::deserialize_from(reader)?;
+        if index.num_nodes() != graph.nodes.len() {
+            anyhow::bail!(
+                "{} indexes {} nodes but the graph has {}, rebuild the index",
+                path.display(),
+                index.num_nodes(),
+                graph.nodes.len()
+            );
+        }
+
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::graph::{Graph, Node};
+
+    // A 10x10 lattice of nodes 0.005 degrees apart around Tel Aviv.
+    fn get_test_graph() -> CSRGraph {
+        let nodes = (0..100)
+            .map(|i| Node {
+                lat: 32.0 + (i / 10) as f64 * 0.005,
+                lon: 34.8 + (i % 10) as f64 * 0.005,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        CSRGraph::from_preprocessed_graph(Graph::new(nodes))
+    }
+
+    // Finds the nearest node by scanning all of them.
+    fn brute_force(index: &NearestNode, lat: f64, lon: f64) -> usize {
+        let lon_scale = lat.to_radians().cos();
+        (0..index.num_nodes())
+            .min_by(|&a, &b| {
+                let dist = |node: usize| {
+                    let (node_lat, node_lon) = index.coords[node];
+                    (node_lat - lat).powi(2) + ((node_lon - lon) * lon_scale).powi(2)
+                };
+                dist(a).total_cmp(&dist(b))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_nearest() {
+        let graph = get_test_graph();
+        let index = NearestNode::new(&graph);
+
+        assert_eq!(index.nearest(32.0, 34.8), Some(0));
+        assert_eq!(index.nearest(32.0101, 34.8149), Some(23));
+        // Far outside the grid.
+        assert_eq!(index.nearest(31.0, 34.0), Some(0));
+        assert_eq!(index.nearest(33.0, 35.0), Some(99));
+
+        for (lat, lon) in [
+            (32.013, 34.807),
+            (32.049, 34.802),
+            (31.99, 34.83),
+            (32.02, 34.9),
+        ] {
+            assert_eq!(index.nearest(lat, lon), Some(brute_force(&index, lat, lon)));
+        }
+
+        let empty = NearestNode::new(&CSRGraph::from_preprocessed_graph(Graph::new(Vec::new())));
+        assert_eq!(empty.nearest(32.0, 34.8), None);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let graph = get_test_graph();
+        let index = NearestNode::with_cell_size(&graph, 0.003);
+
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("shepherd-nearest-test-{}.bin", std::process::id()));
+        index.save(&path).unwrap();
+
+        let loaded = NearestNode::load(&path, &graph).unwrap();
+        assert_eq!(loaded, index);
+        for (lat, lon) in [(32.013, 34.807), (32.049, 34.802), (31.99, 34.83)] {
+            assert_eq!(loaded.nearest(lat, lon), index.nearest(lat, lon));
+        }
+
+        let other = CSRGraph::from_preprocessed_graph(Graph::new(vec![Node::new(0, 1)]));
+        let err = NearestNode::load(&path, &other).err().unwrap().to_string();
+        assert!(err.contains("graph has 1"), "{err}");
+
+        std::fs::write(&path, b"not an index").unwrap();
+        let err = NearestNode::load(&path, &graph).err().unwrap().to_string();
+        assert!(err.contains("is not a nearest-node index file"), "{err}");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
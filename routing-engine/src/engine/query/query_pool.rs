@@ -0,0 +1,121 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use super::ch_query::BiDirDijkstra;
+
+/// A pool of reusable queries, so concurrent requests don't each allocate their own.
+pub struct QueryPool {
+    num_nodes: usize,
+    queries: Mutex<Vec<BiDirDijkstra>>,
+}
+
+/// A query borrowed from a `QueryPool`, returned to the pool when dropped.
+pub struct PooledQuery<'a> {
+    pool: &'a QueryPool,
+    query: Option<BiDirDijkstra>,
+}
+
+impl QueryPool {
+    pub fn new(num_nodes: usize) -> Self {
+        Self {
+            num_nodes,
+            queries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Takes an idle query from the pool, or allocates a new one if all of them are in use.
+    pub fn acquire(&self) -> PooledQuery<'_> {
+        let query = self
+            .queries
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BiDirDijkstra::new(self.num_nodes));
+
+        PooledQuery {
+            pool: self,
+            query: Some(query),
+        }
+    }
+
+    /// Gets the number of idle queries in the pool.
+    pub fn num_idle(&self) -> usize {
+        self.queries.lock().unwrap().len()
+    }
+}
+
+impl Deref for PooledQuery<'_> {
+    type Target = BiDirDijkstra;
+
+    fn deref(&self) -> &Self::Target {
+        self.query.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledQuery<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.query.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledQuery<'_> {
+    fn drop(&mut self) {
+        if let Some(query) = self.query.take() {
+            self.pool.queries.lock().unwrap().push(query);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::engine::{
+        csr::csr_graph::CSRGraph,
+        preprocess::graph::{EdgeMetadata, Graph, Node},
+        query::ch_query::QueryOutcome,
+    };
+
+    #[test]
+    fn test_pool_from_many_threads() {
+        // A two way path 0 - 1 - ... - 9 with unit weights.
+        let num_nodes = 10;
+        let nodes = (0..num_nodes).map(|i| Node::new(i, i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata::new(1.0));
+        for i in 0..num_nodes - 1 {
+            graph.add_edge(i, i + 1, 0);
+            graph.add_edge(i + 1, i, 0);
+        }
+
+        let graph = Arc::new(CSRGraph::from_preprocessed_graph(graph));
+        let pool = QueryPool::new(num_nodes);
+
+        std::thread::scope(|scope| {
+            for thread_id in 0..8 {
+                let (graph, pool) = (Arc::clone(&graph), &pool);
+                scope.spawn(move || {
+                    for i in 0..200 {
+                        let src = (thread_id + i) % num_nodes;
+                        let dest = (thread_id * 3 + i * 7) % num_nodes;
+
+                        let mut query = pool.acquire();
+                        query.init(src, dest).unwrap();
+                        match query.search(&graph) {
+                            QueryOutcome::SamePoint => assert_eq!(src, dest),
+                            QueryOutcome::Found(_, weight) => {
+                                assert_eq!(weight, src.abs_diff(dest) as f64)
+                            }
+                            QueryOutcome::Unreachable => panic!("{src} -> {dest} unreachable"),
+                        }
+                    }
+                });
+            }
+        });
+
+        assert!(pool.num_idle() >= 1 && pool.num_idle() <= 8);
+    }
+}
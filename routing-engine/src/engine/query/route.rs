@@ -0,0 +1,309 @@
+use serde_json::{json, Value};
+
+use crate::engine::{
+    csr::csr_graph::CSRGraph,
+    export::{coord_order::CoordOrder, name_display::NameDisplay},
+    preprocess::graph::WayNames,
+};
+
+/// A run of consecutive edges along the same street.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    // Name of the street, `None` for unnamed roads.
+    pub name: Option<String>,
+    // The local and English names of the street, if the import kept both.
+    pub names: Option<WayNames>,
+    pub distance_m: f64,
+    pub time_s: f64,
+    // Ids of the original edges the segment is made of, in travel order.
+    pub edge_ids: Vec<usize>,
+    // The distance of each edge, parallel to `edge_ids`.
+    pub edge_distances_m: Vec<f64>,
+}
+
+/// A route summary, grouped into segments by street name. Totals are summed as `f64` from the
+/// `f32` edge values, so long routes don't drift.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Route {
+    pub total_distance_m: f64,
+    pub total_time_s: f64,
+    pub segments: Vec<Segment>,
+    // Coordinates along the whole route as (lat, lon), including the merged shape points.
+    pub geometry: Vec<(f64, f64)>,
+}
+
+/// Builds a `Route` edge by edge, extending the last segment while the street name stays the
+/// same.
+#[derive(Debug, Default)]
+pub struct RouteBuilder {
+    route: Route,
+}
+
+impl RouteBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an edge to the route.
+    pub fn push_edge(
+        &mut self,
+        edge_id: usize,
+        name: Option<&str>,
+        names: Option<&WayNames>,
+        distance_m: f64,
+        time_s: f64,
+    ) -> &mut Self {
+        self.route.total_distance_m += distance_m;
+        self.route.total_time_s += time_s;
+
+        match self.route.segments.last_mut() {
+            Some(segment) if segment.name.as_deref() == name => {
+                segment.distance_m += distance_m;
+                segment.time_s += time_s;
+                segment.edge_ids.push(edge_id);
+                segment.edge_distances_m.push(distance_m);
+            }
+            _ => self.route.segments.push(Segment {
+                name: name.map(str::to_string),
+                names: names.cloned(),
+                distance_m,
+                time_s,
+                edge_ids: vec![edge_id],
+                edge_distances_m: vec![distance_m],
+            }),
+        }
+
+        self
+    }
+
+    pub fn build(self) -> Route {
+        self.route
+    }
+}
+
+impl Route {
+    /// Builds a route from the ids of the unpacked, original edges along a path.
+    pub fn from_edges(graph: &CSRGraph, edge_ids: &[usize]) -> Self {
+        Self::from_edges_with_names(graph, edge_ids, NameDisplay::Primary)
+    }
+
+    /// Builds a route like `from_edges`, labeling the segments with the names chosen by
+    /// `name_display`.
+    pub fn from_edges_with_names(
+        graph: &CSRGraph,
+        edge_ids: &[usize],
+        name_display: NameDisplay,
+    ) -> Self {
+        let mut builder = RouteBuilder::new();
+        let mut geometry = Vec::new();
+        let coords = |node_id: usize| (graph.nodes[node_id].lat, graph.nodes[node_id].lon);
+
+        for &edge_id in edge_ids {
+            let edge = graph.get_fwd_edge_cold(edge_id);
+            let name = name_display.label(edge.name.as_deref(), edge.names.as_ref());
+            builder.push_edge(
+                edge_id,
+                name.as_deref(),
+                edge.names.as_ref(),
+                f64::from(edge.distance_m),
+                f64::from(graph.edge_time(edge_id)),
+            );
+
+            if geometry.is_empty() {
+                geometry.push(coords(edge.from_node));
+            }
+            geometry.extend_from_slice(&edge.geometry);
+            geometry.push(coords(edge.to_node));
+        }
+
+        Route {
+            geometry,
+            ..builder.build()
+        }
+    }
+
+    /// Iterates the edges of the route in travel order, each with the distance in meters from
+    /// the start of the route to the end of the edge.
+    pub fn edges_with_distances(&self) -> impl Iterator<Item = (usize, f64)> + '_ {
+        self.segments
+            .iter()
+            .flat_map(|segment| segment.edge_ids.iter().zip(&segment.edge_distances_m))
+            .scan(0.0, |total, (&edge_id, &distance_m)| {
+                *total += distance_m;
+                Some((edge_id, *total))
+            })
+    }
+
+    /// Gets the distance in meters from the start of the route to each node along it, starting
+    /// with 0 and ending with the total distance.
+    pub fn cumulative_distances(&self) -> Vec<f64> {
+        std::iter::once(0.0)
+            .chain(
+                self.edges_with_distances()
+                    .map(|(_, distance_m)| distance_m),
+            )
+            .collect()
+    }
+}
+
+/// Serializes a route into an OSRM-like response, with a single leg whose steps are the
+/// segments of the route. Distances are in meters and durations in seconds, and the geometry
+/// is emitted in `coord_order`.
+pub fn route_to_json(route: &Route, coord_order: CoordOrder) -> Value {
+    let steps: Vec<Value> = route
+        .segments
+        .iter()
+        .map(|segment| {
+            json!({
+                "name": segment.name.as_deref().unwrap_or_default(),
+                "distance": segment.distance_m,
+                "duration": segment.time_s,
+            })
+        })
+        .collect();
+    let geometry: Vec<[f64; 2]> = route
+        .geometry
+        .iter()
+        .map(|&(lat, lon)| coord_order.arrange(lat, lon))
+        .collect();
+
+    json!({
+        "distance": route.total_distance_m,
+        "duration": route.total_time_s,
+        "geometry": geometry,
+        "legs": [{
+            "distance": route.total_distance_m,
+            "duration": route.total_time_s,
+            "steps": steps,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        preprocess::{
+            builder::ImportConfig,
+            ch_preprocess::{contract_graph, ContractionOptions},
+            graph::{EdgeMetadata, Graph, Metric, Node},
+            witness_search::Dijkstra,
+        },
+        query::ch_query::{BiDirDijkstra, QueryOutcome},
+    };
+
+    // 0 -> 1 -> 2 along Herzl, then 2 -> 3 along Weizmann. The 1 -> 2 edge has two shape
+    // points merged into it.
+    fn get_test_route() -> Route {
+        let nodes = (0..4)
+            .map(|i| Node {
+                lat: i as f64,
+                lon: 34.0,
+                ..Node::new(i, 100 + i as i64)
+            })
+            .collect();
+        let mut graph = Graph::new(nodes);
+        for (src, dest, name, distance_m) in [
+            (0, 1, "Herzl", 100.0),
+            (1, 2, "Herzl", 150.0),
+            (2, 3, "Weizmann", 80.0),
+        ] {
+            let metadata_index = graph.edge_metadata.len();
+            let mut metadata = EdgeMetadata::new(distance_m / 10.0);
+            metadata.name = Some(name.to_string());
+            metadata.distance_m = distance_m;
+            graph.edge_metadata.push(metadata);
+            graph.add_edge(src, dest, metadata_index);
+        }
+        graph.edge_metadata[1].geometry = vec![(1.25, 34.0), (1.5, 34.0)];
+        let csr = CSRGraph::from_preprocessed_graph(graph);
+
+        Route::from_edges(&csr, &[0, 1, 2])
+    }
+
+    #[test]
+    fn test_route_segments() {
+        let route = get_test_route();
+
+        assert_eq!(route.segments.len(), 2);
+        assert_eq!(route.segments[0].name.as_deref(), Some("Herzl"));
+        assert_eq!(route.segments[0].distance_m, 250.0);
+        assert_eq!(route.segments[0].time_s, 25.0);
+        assert_eq!(route.segments[0].edge_ids, [0, 1]);
+        assert_eq!(route.segments[1].name.as_deref(), Some("Weizmann"));
+        assert_eq!(route.segments[1].distance_m, 80.0);
+        assert_eq!(route.total_distance_m, 330.0);
+        assert_eq!(route.total_time_s, 33.0);
+    }
+
+    #[test]
+    fn test_cumulative_distances() {
+        let route = get_test_route();
+
+        let edges: Vec<_> = route.edges_with_distances().collect();
+        assert_eq!(edges, [(0, 100.0), (1, 250.0), (2, 330.0)]);
+
+        let distances = route.cumulative_distances();
+        assert_eq!(distances.len(), 4);
+        assert_eq!(distances[0], 0.0);
+        assert!(distances.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(distances.last(), Some(&route.total_distance_m));
+
+        assert_eq!(Route::default().cumulative_distances(), [0.0]);
+    }
+
+    #[test]
+    fn test_route_to_json() {
+        let route = get_test_route();
+        assert_eq!(route.geometry.len(), 6);
+
+        let json = route_to_json(&route, CoordOrder::LatLon);
+        let object = json.as_object().unwrap();
+        for key in ["distance", "duration", "geometry", "legs"] {
+            assert!(object.contains_key(key), "missing {key}");
+        }
+        assert_eq!(json["distance"], 330.0);
+        assert_eq!(json["geometry"].as_array().unwrap().len(), 6);
+        assert_eq!(json["geometry"][2], json!([1.25, 34.0]));
+        assert_eq!(json["legs"][0]["steps"][1]["name"], "Weizmann");
+
+        let json = route_to_json(&route, CoordOrder::LonLat);
+        assert_eq!(json["geometry"][2], json!([34.0, 1.25]));
+    }
+
+    #[test]
+    fn test_time_under_distance_metric() {
+        let mut graph = ImportConfig::new("tests/data/tiny.osm").build().unwrap();
+        let mut dijkstra = Dijkstra::new(graph.num_nodes());
+        let options = ContractionOptions {
+            secondary_metric: true,
+            ..Default::default()
+        };
+        contract_graph(&mut graph, &mut dijkstra, &options, |_| {});
+        let graph = CSRGraph::from_preprocessed_graph(graph);
+
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+        query.set_metric(Metric::Secondary);
+        let QueryOutcome::Found(path, weight) = query.route_osm(&graph, 1, 9).unwrap() else {
+            panic!("expected a path from 1 to 9");
+        };
+        let edge_ids: Vec<usize> = path
+            .iter()
+            .flat_map(|result| graph.unpack_edge(result.edge_id))
+            .collect();
+        let route = Route::from_edges(&graph, &edge_ids);
+
+        // The weight is the distance, the time comes from the residential speed instead.
+        assert!((route.total_distance_m - weight).abs() < 1e-3);
+        assert!(route.total_time_s > 0.0);
+        let speed_kmh = route.total_distance_m / route.total_time_s * 3.6;
+        assert!((10.0..=60.0).contains(&speed_kmh), "{speed_kmh} km/h");
+
+        // Shortcuts carry the time of the edges they unpack to.
+        let packed_time: f64 = path
+            .iter()
+            .map(|result| f64::from(graph.edge_time(result.edge_id)))
+            .sum();
+        assert!((packed_time - route.total_time_s).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use super::ch_query::{BiDirDijkstra, QueryOutcome};
+use crate::engine::csr::csr_graph::CSRGraph;
+
+/// A loaded graph shared between threads or async tasks. The graph is never mutated while
+/// routing, so every task can hold a clone of the `Arc` and route with its own `Router`.
+pub type SharedGraph = Arc<CSRGraph>;
+
+/// Routes over a `SharedGraph` with a query of its own, allocated once and reused across
+/// calls. Create one per thread or task, the graph itself is shared.
+pub struct Router {
+    graph: SharedGraph,
+    query: BiDirDijkstra,
+}
+
+impl Router {
+    pub fn new(graph: SharedGraph) -> Self {
+        let query = BiDirDijkstra::new(graph.nodes.len());
+        Self { graph, query }
+    }
+
+    pub fn graph(&self) -> &CSRGraph {
+        &self.graph
+    }
+
+    /// Gets the query, e.g. to change its settings before routing.
+    pub fn query_mut(&mut self) -> &mut BiDirDijkstra {
+        &mut self.query
+    }
+
+    /// Finds the shortest path between two dense node ids, failing if either is out of range.
+    pub fn route(&mut self, src: usize, dest: usize) -> anyhow::Result<QueryOutcome> {
+        self.query.init(src, dest)?;
+        Ok(self.query.search(&self.graph))
+    }
+
+    /// Finds the shortest path between two osm node ids, failing if either isn't in the graph.
+    pub fn route_osm(&mut self, src_osm: i64, dest_osm: i64) -> anyhow::Result<QueryOutcome> {
+        self.query.route_osm(&self.graph, src_osm, dest_osm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::preprocess::graph::{EdgeMetadata, Graph, Node};
+
+    // Fails to compile if the graph or the router can't be moved into other threads.
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_thread_safety() {
+        assert_send_sync::<CSRGraph>();
+        assert_send_sync::<SharedGraph>();
+        assert_send::<Router>();
+    }
+
+    #[test]
+    fn test_route_from_many_threads() {
+        // A two way path 0 - 1 - ... - 9 with unit weights, osm ids 100 and up.
+        let num_nodes = 10;
+        let nodes = (0..num_nodes)
+            .map(|i| Node::new(i, 100 + i as i64))
+            .collect();
+        let mut graph = Graph::new(nodes);
+        graph.edge_metadata.push(EdgeMetadata::new(1.0));
+        for i in 0..num_nodes - 1 {
+            graph.add_edge(i, i + 1, 0);
+            graph.add_edge(i + 1, i, 0);
+        }
+        let graph: SharedGraph = Arc::new(CSRGraph::from_preprocessed_graph(graph));
+
+        let handles: Vec<_> = (0..8)
+            .map(|thread_id| {
+                let graph = Arc::clone(&graph);
+                std::thread::spawn(move || {
+                    let mut router = Router::new(graph);
+                    for i in 0..200 {
+                        let src = (thread_id + i) % num_nodes;
+                        let dest = (thread_id * 3 + i * 7) % num_nodes;
+                        let outcome = if i % 2 == 0 {
+                            router.route(src, dest).unwrap()
+                        } else {
+                            router
+                                .route_osm(100 + src as i64, 100 + dest as i64)
+                                .unwrap()
+                        };
+
+                        match outcome {
+                            QueryOutcome::SamePoint => assert_eq!(src, dest),
+                            QueryOutcome::Found(_, weight) => {
+                                assert_eq!(weight, src.abs_diff(dest) as f64)
+                            }
+                            QueryOutcome::Unreachable => panic!("{src} -> {dest} unreachable"),
+                        }
+                    }
+                    assert!(router.route(0, num_nodes).is_err());
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(Arc::strong_count(&graph), 1);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BinaryHeap;
+
+use crate::engine::preprocess::{graph::Graph, witness_search::HeapItem};
+
+// One direction of the bidirectional search.
+struct Side {
+    weights: Vec<f32>,
+    prev: Vec<Option<usize>>,
+    queue: BinaryHeap<HeapItem>,
+}
+
+impl Side {
+    fn new(num_nodes: usize, src: usize) -> Self {
+        let mut side = Self {
+            weights: vec![f32::INFINITY; num_nodes],
+            prev: vec![None; num_nodes],
+            queue: BinaryHeap::new(),
+        };
+        side.weights[src] = 0.0;
+        side.queue.push(HeapItem(src, 0.0));
+        side
+    }
+
+    fn top_weight(&self) -> f32 {
+        self.queue.peek().map_or(f32::INFINITY, |item| item.1)
+    }
+
+    // Follows the predecessors from `node` back to the source of this side.
+    fn path_to(&self, node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        let mut current = node;
+        while let Some(p) = self.prev[current] {
+            path.push(p);
+            current = p;
+        }
+        path
+    }
+}
+
+/// A plain bidirectional Dijkstra over the uncontracted graph, without ranks or shortcuts. Meant
+/// for small graphs that can be routed right after import, and as a ground truth for the
+/// contraction hierarchy.
+pub struct SimpleRouter<'a> {
+    graph: &'a Graph,
+}
+
+impl<'a> SimpleRouter<'a> {
+    pub fn new(graph: &'a Graph) -> Self {
+        Self { graph }
+    }
+
+    /// Finds the shortest path from src to dest, returning its weight and the dense ids of the
+    /// nodes along it, or `None` if dest is unreachable.
+    pub fn route(&self, src: usize, dest: usize) -> Option<(f32, Vec<usize>)> {
+        let num_nodes = self.graph.num_nodes();
+        let mut fwd = Side::new(num_nodes, src);
+        let mut bwd = Side::new(num_nodes, dest);
+        // The best weight found so far and the node the two searches met at.
+        let mut best: Option<(f32, usize)> = (src == dest).then_some((0.0, src));
+        let mut is_fwd = true;
+
+        loop {
+            let best_weight = best.map_or(f32::INFINITY, |(weight, _)| weight);
+            // Any path not found yet is at least as heavy as the tops of both queues, this also
+            // stops once either side runs out of nodes.
+            if fwd.top_weight() + bwd.top_weight() >= best_weight {
+                break;
+            }
+
+            let (side, other) = if is_fwd {
+                (&mut fwd, &bwd)
+            } else {
+                (&mut bwd, &fwd)
+            };
+
+            let Some(HeapItem(curr_id, weight)) = side.queue.pop() else {
+                break;
+            };
+            if weight <= side.weights[curr_id] {
+                let edge_ids = if is_fwd {
+                    self.graph.get_fwd_neighbors(curr_id)
+                } else {
+                    self.graph.get_bwd_neighbors(curr_id)
+                };
+
+                for &edge_id in edge_ids {
+                    let edge = self.graph.get_edge(edge_id);
+                    let neighbor_id = if is_fwd { edge.dest_id } else { edge.src_id };
+                    let alt = weight + self.graph.get_edge_metadata(edge).weight;
+
+                    if alt < side.weights[neighbor_id] {
+                        side.weights[neighbor_id] = alt;
+                        side.prev[neighbor_id] = Some(curr_id);
+                        side.queue.push(HeapItem(neighbor_id, alt));
+
+                        let total = alt + other.weights[neighbor_id];
+                        if total < best.map_or(f32::INFINITY, |(best_weight, _)| best_weight) {
+                            best = Some((total, neighbor_id));
+                        }
+                    }
+                }
+            }
+
+            is_fwd = !is_fwd;
+        }
+
+        best.map(|(weight, meeting)| {
+            let mut path = fwd.path_to(meeting);
+            path.reverse();
+            path.extend(bwd.path_to(meeting).into_iter().skip(1));
+            (weight, path)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        csr::csr_graph::CSRGraph, preprocess::graph::Node, query::ch_query::dijkstra_tree,
+        test_utils::get_sample_graph,
+    };
+
+    #[test]
+    fn test_known_paths() {
+        let graph = get_sample_graph();
+        let router = SimpleRouter::new(&graph);
+
+        assert_eq!(router.route(0, 3), Some((19.0, vec![0, 1, 2, 3])));
+        assert_eq!(router.route(1, 6), Some((12.0, vec![1, 2, 6])));
+        // The one way edge is only usable forwards.
+        assert_eq!(router.route(0, 5), Some((30.0, vec![0, 5])));
+        assert_eq!(router.route(5, 3), Some((15.0, vec![5, 4, 3])));
+        assert_eq!(router.route(3, 3), Some((0.0, vec![3])));
+    }
+
+    #[test]
+    fn test_matches_dijkstra() {
+        let graph = get_sample_graph();
+        let router = SimpleRouter::new(&graph);
+        let csr = CSRGraph::from_preprocessed_graph(graph.clone());
+
+        for src in 0..graph.num_nodes() {
+            for (dest, &(_, expected)) in dijkstra_tree(&csr, src).iter().enumerate() {
+                let (weight, path) = router.route(src, dest).unwrap();
+                assert_eq!(weight as f64, expected, "mismatch for {src} -> {dest}");
+                assert_eq!(path.first(), Some(&src));
+                assert_eq!(path.last(), Some(&dest));
+
+                let path_weight: f32 = path
+                    .windows(2)
+                    .map(|w| {
+                        graph
+                            .get_edge_metadata(graph.find_edge(w[0], w[1]).unwrap())
+                            .weight
+                    })
+                    .sum();
+                assert_eq!(path_weight, weight);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let mut graph = get_sample_graph();
+        graph.nodes.push(Node::new(7, 107));
+        graph.fwd_edge_list.push(Vec::new());
+        graph.bwd_edge_list.push(Vec::new());
+        let router = SimpleRouter::new(&graph);
+
+        assert!(router.route(0, 7).is_none());
+        assert!(router.route(7, 0).is_none());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::engine::{
+    csr::csr_graph::CSRGraph,
+    preprocess::graph::{EdgeMetadata, Graph, Node},
+    query::ch_query::{dijkstra_tree, BiDirDijkstra, QueryOutcome},
+};
+
+// Distances are sums of f32 weights added in different orders by the two searches.
+const TOLERANCE: f64 = 1e-3;
+
+// Cap on the sources sampled, each one is compared against every destination.
+const MAX_SOURCES: usize = 32;
+
+/// The sample graph of the contraction tests, with a one way edge from 0 to 5.
+///  0 -- 1 -- 2 -- 3 -- 4 -- 5
+///            \        /
+///             --- 6 --
+pub fn get_sample_graph() -> Graph {
+    let nodes = (0..7).map(|i| Node::new(i, 100 + i as i64)).collect();
+    let mut graph = Graph::new(nodes);
+
+    let two_way = [
+        (0, 1, 10.0),
+        (1, 2, 3.0),
+        (2, 3, 6.0),
+        (3, 4, 7.0),
+        (4, 5, 8.0),
+        (2, 6, 9.0),
+        (4, 6, 4.0),
+    ];
+    for (src, dest, weight) in two_way {
+        let metadata_index = graph.edge_metadata.len();
+        graph.edge_metadata.push(EdgeMetadata::new(weight));
+        graph.add_edge(src, dest, metadata_index);
+        graph.add_edge(dest, src, metadata_index);
+    }
+
+    let metadata_index = graph.edge_metadata.len();
+    graph.edge_metadata.push(EdgeMetadata::new(30.0));
+    graph.add_edge(0, 5, metadata_index);
+
+    graph
+}
+
+/// Asserts that `BiDirDijkstra` over `overlay`, a contraction of `graph`, finds the same
+/// distances as a plain Dijkstra over `graph`, including which pairs are unreachable. Pairs are
+/// sampled from up to `MAX_SOURCES` evenly spread sources to every destination.
+pub fn assert_ch_matches_dijkstra(graph: &Graph, overlay: &Graph) {
+    let graph = CSRGraph::from_preprocessed_graph(graph.clone());
+    let overlay = CSRGraph::from_preprocessed_graph(overlay.clone());
+    let num_nodes = graph.nodes.len();
+    assert_eq!(
+        overlay.nodes.len(),
+        num_nodes,
+        "the overlay has other nodes"
+    );
+
+    let mut query = BiDirDijkstra::new(num_nodes);
+    for src in (0..num_nodes).step_by(num_nodes.div_ceil(MAX_SOURCES).max(1)) {
+        let tree = dijkstra_tree(&graph, src);
+        for (dest, &(_, expected)) in tree.iter().enumerate() {
+            query.init(src, dest).unwrap();
+            let weight = match query.search(&overlay) {
+                QueryOutcome::SamePoint => 0.0,
+                QueryOutcome::Unreachable => f64::INFINITY,
+                QueryOutcome::Found(_, weight) => weight,
+            };
+
+            let matches = if expected.is_finite() {
+                (weight - expected).abs() <= TOLERANCE
+            } else {
+                weight == expected
+            };
+            assert!(
+                matches,
+                "{src} -> {dest}: CH found {weight}, Dijkstra {expected}"
+            );
+        }
+    }
+}
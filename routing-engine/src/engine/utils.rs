@@ -1,9 +1,12 @@
-use glam::Vec2;
+use glam::DVec2;
 
-pub fn haversine_distance(lat1: f32, lon1: f32, lat2: f32, lon2: f32) -> f32 {
-    // Earth's radius in meters
-    const EARTH_RADIUS: f32 = 6_371_000.0;
+// Earth's radius in meters
+const EARTH_RADIUS: f64 = 6_371_000.0;
 
+/// Distances up to which `fast_distance` uses the planar approximation, in meters.
+pub const PLANAR_DISTANCE_MAX_M: f32 = 10_000.0;
+
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f32 {
     // Convert degrees to radians.
     let phi1 = lat1.to_radians();
     let phi2 = lat2.to_radians();
@@ -15,24 +18,174 @@ pub fn haversine_distance(lat1: f32, lon1: f32, lat2: f32, lon2: f32) -> f32 {
         + phi1.cos() * phi2.cos() * (delta_lambda / 2.0).sin().powi(2);
     let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
 
-    EARTH_RADIUS * c
+    (EARTH_RADIUS * c) as f32
+}
+
+/// Approximates the distance in meters between two points with an equirectangular projection
+/// around their mean latitude. Much cheaper than `haversine_distance`, and accurate for points a
+/// few kilometers apart, but it drifts over long ranges.
+pub fn planar_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f32 {
+    let mean_phi = ((lat1 + lat2) / 2.0).to_radians();
+    let x = (lon2 - lon1).to_radians() * mean_phi.cos();
+    let y = (lat2 - lat1).to_radians();
+
+    (EARTH_RADIUS * (x * x + y * y).sqrt()) as f32
+}
+
+/// Gets the distance in meters between two points, using `planar_distance` for points up to
+/// `PLANAR_DISTANCE_MAX_M` apart and falling back to `haversine_distance` for longer ones.
+pub fn fast_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f32 {
+    let dist = planar_distance(lat1, lon1, lat2, lon2);
+    if dist <= PLANAR_DISTANCE_MAX_M {
+        dist
+    } else {
+        haversine_distance(lat1, lon1, lat2, lon2)
+    }
+}
+
+/// Simplifies a polyline of (lat, lon) points with the Ramer-Douglas-Peucker algorithm, dropping
+/// points that are less than `epsilon_m` meters off the simplified line. The first and last points
+/// are always kept as they are.
+pub fn simplify_polyline(points: &[(f64, f64)], epsilon_m: f64) -> Vec<(f64, f64)> {
+    if points.len() <= 2 {
+        return points.to_vec();
+    }
+
+    // Project to meters on an equirectangular plane around the first point.
+    let (lat0, lon0) = points[0];
+    let lon_scale = lat0.to_radians().cos();
+    let projected: Vec<DVec2> = points
+        .iter()
+        .map(|&(lat, lon)| {
+            DVec2::new(
+                (lon - lon0).to_radians() * lon_scale,
+                (lat - lat0).to_radians(),
+            ) * EARTH_RADIUS
+        })
+        .collect();
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+    let mut spans = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = spans.pop() {
+        let (a, b) = (projected[first], projected[last]);
+        let farthest = (first + 1..last)
+            .map(|i| (i, distance_to_segment(projected[i], a, b)))
+            .max_by(|x, y| x.1.total_cmp(&y.1));
+
+        if let Some((i, _)) = farthest.filter(|&(_, dist)| dist > epsilon_m) {
+            keep[i] = true;
+            spans.push((first, i));
+            spans.push((i, last));
+        }
+    }
+
+    points
+        .iter()
+        .zip(keep)
+        .filter(|&(_, keep)| keep)
+        .map(|(&point, _)| point)
+        .collect()
+}
+
+// Gets the distance from `p` to the segment between `a` and `b`.
+fn distance_to_segment(p: DVec2, a: DVec2, b: DVec2) -> f64 {
+    let ab = b - a;
+    let t = if ab == DVec2::ZERO {
+        0.0
+    } else {
+        ((p - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+    };
+    p.distance(a + ab * t)
 }
 
 pub fn calc_turn_cost(
-    prev_lat: f32,
-    prev_lon: f32,
-    curr_lat: f32,
-    curr_lon: f32,
-    next_lat: f32,
-    next_lon: f32,
+    prev_lat: f64,
+    prev_lon: f64,
+    curr_lat: f64,
+    curr_lon: f64,
+    next_lat: f64,
+    next_lon: f64,
 ) -> f32 {
-    let v1 = Vec2::new(curr_lat - prev_lat, curr_lon - prev_lon).normalize();
-    let v2 = Vec2::new(next_lat - curr_lat, next_lon - curr_lon).normalize();
+    let v1 = DVec2::new(curr_lat - prev_lat, curr_lon - prev_lon).normalize_or_zero();
+    let v2 = DVec2::new(next_lat - curr_lat, next_lon - curr_lon).normalize_or_zero();
 
-    let dot = v1.dot(v2).clamp(-1.0, 1.0);
+    // Coincident points have no direction, treat them as going straight instead of a NaN cost.
+    if v1 == DVec2::ZERO || v2 == DVec2::ZERO {
+        return 1.0;
+    }
+
+    let dot = v1.dot(v2).clamp(-1.0, 1.0) as f32;
 
     let k = 1.0;
-    let turn_multiplier = 1.0 + k * (1.0 - dot);
+    1.0 + k * (1.0 - dot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turn_cost_coincident_points() {
+        assert_eq!(calc_turn_cost(0.0, 0.0, 0.0, 0.0, 1.0, 0.0), 1.0);
+        assert_eq!(calc_turn_cost(0.0, 0.0, 1.0, 0.0, 1.0, 0.0), 1.0);
+        assert!((calc_turn_cost(0.0, 0.0, 1.0, 0.0, 0.0, 0.0) - 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_planar_distance_close_to_haversine() {
+        // Street scale spans around Tel Aviv, from a few meters to a few kilometers, in every
+        // direction.
+        for (dlat, dlon) in [
+            (0.0001, 0.0),
+            (0.0, 0.0001),
+            (0.001, 0.001),
+            (-0.005, 0.002),
+            (0.01, -0.02),
+            (0.03, 0.03),
+        ] {
+            let (lat, lon) = (32.08, 34.78);
+            let haversine = haversine_distance(lat, lon, lat + dlat, lon + dlon);
+            let planar = planar_distance(lat, lon, lat + dlat, lon + dlon);
+            let error = (planar - haversine).abs() / haversine;
+            assert!(
+                error < 0.001,
+                "{planar} vs {haversine} for ({dlat}, {dlon})"
+            );
+        }
+    }
+
+    #[test]
+    fn test_simplify_polyline() {
+        // A near-straight line heading north with a few centimeters of noise, about 10 meters
+        // between points.
+        let noisy: Vec<(f64, f64)> = (0..20)
+            .map(|i| {
+                let noise = if i % 2 == 0 { 0.0000003 } else { -0.0000004 };
+                (32.08 + i as f64 * 0.0001, 34.78 + noise)
+            })
+            .collect();
+
+        let simplified = simplify_polyline(&noisy, 1.0);
+        assert_eq!(simplified, [noisy[0], noisy[19]]);
+        // A tiny epsilon keeps the noise.
+        assert_eq!(simplify_polyline(&noisy, 0.001), noisy);
+
+        // A corner is kept.
+        let corner = [(32.08, 34.78), (32.081, 34.78), (32.081, 34.781)];
+        assert_eq!(simplify_polyline(&corner, 1.0), corner);
+        assert_eq!(simplify_polyline(&corner[..2], 1000.0), corner[..2]);
+    }
 
-    turn_multiplier
+    #[test]
+    fn test_fast_distance_falls_back_for_long_range() {
+        let haversine = haversine_distance(32.08, 34.78, 29.55, 34.95);
+        assert!(haversine > PLANAR_DISTANCE_MAX_M);
+        assert_eq!(fast_distance(32.08, 34.78, 29.55, 34.95), haversine);
+        assert_eq!(
+            fast_distance(32.08, 34.78, 32.09, 34.78),
+            planar_distance(32.08, 34.78, 32.09, 34.78)
+        );
+    }
 }
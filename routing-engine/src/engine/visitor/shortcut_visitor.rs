@@ -1,9 +1,4 @@
-use std::collections::HashSet;
-
-use crate::engine::{
-    csr::csr_graph::{CSREdgeCold, CSRGraph},
-    query::ch_query::QueryResult,
-};
+use crate::engine::{csr::csr_graph::CSRGraph, query::ch_query::QueryResult};
 
 use super::visitable::Visitable;
 
@@ -15,43 +10,76 @@ pub struct ShortcutVisitor<'a> {
     pub packed_path: &'a [QueryResult],
 }
 
+/// Lazily unpacks a packed path into the nodes of the original path, see
+/// `ShortcutVisitor::visit_iter`. Only the shortcuts still being unpacked are held, so memory
+/// is bounded by the depth of the hierarchy rather than the length of the path.
+pub struct UnpackedNodes<'a> {
+    graph: &'a CSRGraph,
+    // The packed edges that weren't reached yet.
+    packed: std::slice::Iter<'a, QueryResult>,
+    // Edges left to unpack, the next one on top.
+    stack: Vec<usize>,
+    // The destination of the last original edge, yielded before moving on.
+    pending: Option<usize>,
+    // The last node yielded, consecutive edges share a node that is only yielded once.
+    last: Option<usize>,
+}
+
 impl<'a> ShortcutVisitor<'a> {
     pub fn new(graph: &'a CSRGraph, packed_path: &'a [QueryResult]) -> Self {
         Self { graph, packed_path }
     }
 
-    fn push_node(out: &mut Vec<usize>, node: usize) {
-        if out.last() != Some(&node) {
-            out.push(node);
+    /// Iterates the nodes of the original path like `visit`, unpacking the shortcuts as it
+    /// goes instead of collecting them, e.g. to stream a long route to disk.
+    pub fn visit_iter(&self) -> UnpackedNodes<'a> {
+        UnpackedNodes {
+            graph: self.graph,
+            packed: self.packed_path.iter(),
+            stack: Vec::new(),
+            pending: None,
+            last: None,
         }
     }
+}
 
-    fn visit_shortcut(
-        graph: &CSRGraph,
-        edge: &CSREdgeCold,
-        out: &mut Vec<usize>,
-        is_fwd: bool,
-        visited: &mut HashSet<usize>,
-    ) {
-        let node = if is_fwd { edge.from_node } else { edge.to_node };
-        if visited.contains(&node) {
-            return;
-        }
+impl UnpackedNodes<'_> {
+    // Yields `node` unless it was just yielded.
+    fn emit(&mut self, node: usize) -> Option<usize> {
+        (self.last.replace(node) != Some(node)).then_some(node)
+    }
+}
 
-        if let (Some(prev_edge_id), Some(next_edge_id)) = (edge.prev_edge, edge.next_edge) {
-            let prev_edge = graph.get_fwd_edge_cold(prev_edge_id);
-            let next_edge = graph.get_bwd_edge_cold(next_edge_id);
+impl Iterator for UnpackedNodes<'_> {
+    type Item = usize;
 
-            if is_fwd {
-                Self::visit_shortcut(graph, prev_edge, out, is_fwd, visited);
-                Self::visit_shortcut(graph, next_edge, out, is_fwd, visited);
-            } else {
-                Self::visit_shortcut(graph, next_edge, out, is_fwd, visited);
-                Self::visit_shortcut(graph, prev_edge, out, is_fwd, visited);
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            if let Some(node) = self.pending.take() {
+                if let Some(node) = self.emit(node) {
+                    return Some(node);
+                }
+            }
+
+            // Every edge of a packed path points in the direction of travel, whichever search
+            // found it.
+            let Some(edge_id) = self.stack.pop() else {
+                self.stack.push(self.packed.next()?.edge_id);
+                continue;
+            };
+            let edge = self.graph.get_fwd_edge_cold(edge_id);
+            match (edge.prev_edge, edge.next_edge) {
+                (Some(prev_edge), Some(next_edge)) => {
+                    self.stack.push(next_edge);
+                    self.stack.push(prev_edge);
+                }
+                _ => {
+                    self.pending = Some(edge.to_node);
+                    if let Some(node) = self.emit(edge.from_node) {
+                        return Some(node);
+                    }
+                }
             }
-        } else {
-            Self::push_node(out, node);
-            visited.insert(node);
         }
     }
 }
@@ -60,14 +88,96 @@ impl<'a> Visitable for ShortcutVisitor<'a> {
     type Output = Vec<usize>;
 
     fn visit(&self) -> Self::Output {
-        let mut nodes = Vec::new();
-        let mut visited = HashSet::new();
+        self.visit_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        preprocess::{
+            ch_preprocess::{contract_graph_in_order, ContractionOptions},
+            graph::{EdgeMetadata, Graph, Node},
+        },
+        query::ch_query::{BiDirDijkstra, QueryOutcome},
+    };
 
-        for QueryResult { edge_id, is_fwd } in self.packed_path {
-            let edge = &self.graph.get_fwd_edge_cold(*edge_id);
-            ShortcutVisitor::visit_shortcut(self.graph, edge, &mut nodes, *is_fwd, &mut visited);
+    // 0 <-> 1 <-> 2 <-> 3 <-> 4, contracted so that 0 -> 4 is found through two shortcuts.
+    fn get_test_graph() -> CSRGraph {
+        let nodes = (0..5).map(|i| Node::new(i, 100 + i as i64)).collect();
+        let mut graph = Graph::new(nodes);
+        for src in 0..4 {
+            let metadata_index = graph.edge_metadata.len();
+            graph.edge_metadata.push(EdgeMetadata::new(1.0));
+            graph.add_edge(src, src + 1, metadata_index);
+            graph.add_edge(src + 1, src, metadata_index);
+        }
+
+        contract_graph_in_order(
+            &mut graph,
+            &[1, 3, 0, 4, 2],
+            &ContractionOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        CSRGraph::from_preprocessed_graph(graph)
+    }
+
+    #[test]
+    fn test_visit_query_output() {
+        let graph = get_test_graph();
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        for (src, dest) in [(0, 4), (4, 0), (0, 2), (1, 3)] {
+            query.init(src, dest).unwrap();
+            let QueryOutcome::Found(packed_path, _) = query.search(&graph) else {
+                panic!("expected a path from {src} to {dest}");
+            };
+
+            let nodes = ShortcutVisitor::new(&graph, &packed_path).visit();
+            let expected: Vec<usize> = if src < dest {
+                (src..=dest).collect()
+            } else {
+                (dest..=src).rev().collect()
+            };
+            assert_eq!(nodes, expected, "mismatch for {src} -> {dest}");
+        }
+    }
+
+    #[test]
+    fn test_visit_iter_matches_unpacked_edges() {
+        let graph = get_test_graph();
+        let mut query = BiDirDijkstra::new(graph.nodes.len());
+
+        for src in 0..graph.nodes.len() {
+            for dest in 0..graph.nodes.len() {
+                query.init(src, dest).unwrap();
+                let QueryOutcome::Found(packed_path, _) = query.search(&graph) else {
+                    continue;
+                };
+
+                // The nodes of the unpacked edges, collected up front.
+                let mut expected: Vec<usize> = Vec::new();
+                for result in &packed_path {
+                    for edge_id in graph.unpack_edge(result.edge_id) {
+                        let edge = graph.get_fwd_edge_cold(edge_id);
+                        for node in [edge.from_node, edge.to_node] {
+                            if expected.last() != Some(&node) {
+                                expected.push(node);
+                            }
+                        }
+                    }
+                }
+
+                let visitor = ShortcutVisitor::new(&graph, &packed_path);
+                let streamed: Vec<usize> = visitor.visit_iter().collect();
+                assert_eq!(streamed, expected, "mismatch for {src} -> {dest}");
+                assert_eq!(visitor.visit(), expected);
+            }
         }
 
-        nodes
+        // An empty path yields nothing.
+        assert_eq!(ShortcutVisitor::new(&graph, &[]).visit_iter().next(), None);
     }
 }
@@ -1,14 +1,25 @@
-use std::{
-    fs::{self, File},
-    io::{Read, Write},
-    time::Instant,
-};
+use std::{fs, time::Instant};
 
+use humansize::{format_size, DECIMAL};
 use routing_engine::engine::{
     csr::csr_graph::CSRGraph,
     export::{csv_export::CSVExport, export_provider::ExportProvider},
-    preprocess::{builder::from_osmpbf, ch_preprocess::contract_graph, witness_search::Dijkstra},
-    query::ch_query::BiDirDijkstra,
+    ordering::{
+        heuristic_provider::EdgeDifferenceOrdering,
+        order_file::{load_order, save_order},
+        provider::OrderingProvider,
+    },
+    preprocess::{
+        builder::{from_osmpbf_with_report, ImportOptions},
+        ch_preprocess::{
+            contract_graph, contract_graph_in_order, ContractionOptions, ContractionProgress,
+        },
+        witness_search::Dijkstra,
+    },
+    query::{
+        ch_query::{BiDirDijkstra, QueryOutcome},
+        nearest_node::NearestNode,
+    },
     visitor::{shortcut_visitor::ShortcutVisitor, visitable::Visitable},
 };
 
@@ -18,22 +29,64 @@ fn export_node<T: ExportProvider>(exporter: T) -> T::ExportType {
 
 fn main() -> anyhow::Result<()> {
     // let argv: Vec<String> = std::env::args().collect();
+    // Contracting in a static order cached at this path is opt-in, by default the nodes are
+    // contracted in the order of the dynamic priority queue.
+    let order_cache = std::env::args()
+        .skip_while(|arg| arg != "--order-cache")
+        .nth(1);
 
     if !fs::exists("./data/graph.bin")? {
-        let graph = from_osmpbf(
+        let (mut graph, report) = from_osmpbf_with_report(
             "/home/tomerab/VSCProjects/routing-app/routing-engine/tests/data/nz-car-only.osm.pbf",
+            &ImportOptions::default(),
         )?;
         println!("CREATED GRAPH");
+        println!("{report:?}");
 
         println!("{} {}", graph.num_edges(), graph.num_nodes());
 
-        println!("CREATED OVERLAY");
-        let mut overlay = graph.clone();
-        println!("CREATED DIJKSTRA");
-        let mut dijkstra = Dijkstra::new(overlay.num_nodes());
+        if let Err(errors) = graph.validate() {
+            for error in &errors {
+                eprintln!("{error}");
+            }
+            anyhow::bail!("graph failed validation with {} errors", errors.len());
+        }
+
+        let merged = graph.compress_chains();
+        println!(
+            "Merged {merged} chain nodes, {} nodes left",
+            graph.num_nodes()
+        );
+
+        // The graph is contracted in place and becomes the overlay.
+        let mut overlay = graph;
+        let options = ContractionOptions::default();
+        let report = |progress: ContractionProgress| {
+            if progress.contracted.is_multiple_of(10_000) || progress.contracted == progress.total {
+                println!(
+                    "Contracted {}/{} nodes, shortcuts={}, mem={}",
+                    progress.contracted,
+                    progress.total,
+                    progress.shortcuts_added,
+                    format_size(progress.mem_bytes, DECIMAL)
+                );
+            }
+        };
 
         println!("STARTING CONTRACTION");
-        contract_graph(graph, &mut overlay, &mut dijkstra);
+        if let Some(order_cache) = order_cache {
+            // The cached order is only reused for the graph it was computed for.
+            let order = load_order(&order_cache, &overlay).or_else(|err| {
+                println!("COMPUTING ORDER: {err}");
+                let order = EdgeDifferenceOrdering.order(&overlay);
+                save_order(&order_cache, &overlay, &order)?;
+                anyhow::Ok(order)
+            })?;
+            contract_graph_in_order(&mut overlay, &order, &options, report)?;
+        } else {
+            let mut dijkstra = Dijkstra::new(overlay.num_nodes());
+            contract_graph(&mut overlay, &mut dijkstra, &options, report);
+        }
 
         // for node in &overlay.nodes {
         //     println!("{:?}", node);
@@ -63,35 +116,42 @@ fn main() -> anyhow::Result<()> {
         let csr_graph = CSRGraph::from_preprocessed_graph(overlay);
 
         println!("Serializing graph to file");
-        let mut file = File::create_new("./data/graph.bin")?;
-        let bytes = bincode::serialize(&csr_graph)?;
-        file.write_all(&bytes)?;
+        csr_graph.save("./data/graph.bin")?;
+        NearestNode::new(&csr_graph).save("./data/nearest.bin")?;
         println!("Finished serializing graph to file");
     } else {
-        let mut file = File::open("./data/graph.bin")?;
-        let mut buf = vec![0u8; file.metadata().unwrap().len() as usize];
+        let graph = CSRGraph::load("./data/graph.bin")?;
+        // The index is cached next to the graph, and rebuilt if it is missing or stale.
+        let nearest = NearestNode::load("./data/nearest.bin", &graph).or_else(|err| {
+            println!("Rebuilding the nearest-node index: {err}");
+            let nearest = NearestNode::new(&graph);
+            nearest.save("./data/nearest.bin")?;
+            anyhow::Ok(nearest)
+        })?;
 
-        file.read_exact(&mut buf)?;
-        let graph: CSRGraph = bincode::deserialize(&buf)?;
-
-        let id1 = graph.nodes.iter().find(|n| n.osm_id == 2232362610).unwrap();
-        let id2 = graph.nodes.iter().find(|n| n.osm_id == 2232447389).unwrap();
+        let (src_osm, dest_osm) = (2232362610, 2232447389);
 
         let mut query = BiDirDijkstra::new(graph.nodes.len());
-        query.init(id1.id, id2.id);
         let now = Instant::now();
-        let query_res = query.search(&graph);
-        println!("{} -> {} = {:#?}", id1.osm_id, id2.osm_id, query_res);
+        let query_res = query.route_osm(&graph, src_osm, dest_osm)?;
+        println!("{src_osm} -> {dest_osm} = {query_res:#?}");
+        let src = &graph.nodes[graph.node_by_osm(src_osm).unwrap()];
+        println!(
+            "Nearest node to {src_osm}: {:?}",
+            nearest.nearest(src.lat, src.lon)
+        );
         let elapsed = now.elapsed();
         println!("Elapsed: {:.2?}", elapsed);
 
-        if let Some(query_res) = query_res {
-            let visitor = ShortcutVisitor::new(&graph, &query_res);
-            for id in visitor.visit() {
-                println!("{}", graph.nodes[id].osm_id);
+        match query_res {
+            QueryOutcome::Found(query_res, _) => {
+                let visitor = ShortcutVisitor::new(&graph, &query_res);
+                for id in visitor.visit() {
+                    println!("{}", graph.nodes[id].osm_id);
+                }
             }
-        } else {
-            println!("Could not find path");
+            QueryOutcome::SamePoint => println!("Source and destination are the same node"),
+            QueryOutcome::Unreachable => println!("Could not find path"),
         }
     }
 